@@ -0,0 +1,119 @@
+//! Restricting parts of the capsule to clients with particular certificates, identified by their
+//! SHA-256 fingerprints.
+
+use crate::status::StatusError;
+use anyhow::{anyhow, Result};
+
+/// Which certificates may see which paths.
+#[derive(Debug, Clone, Default)]
+pub struct AccessRules {
+    // Path prefixes and the fingerprints allowed under them.
+    rules: Vec<(String, Vec<String>)>,
+}
+
+impl AccessRules {
+    /// Creates rules from prefixes and fingerprints. A prefix can be given several times to allow
+    /// several certificates.
+    pub fn new(rules: &[(String, String)]) -> Self {
+        let mut access = Self::default();
+        for (prefix, fingerprint) in rules {
+            let fingerprint = normalize_fingerprint(fingerprint);
+            match access.rules.iter_mut().find(|(p, _)| p == prefix) {
+                Some((_, fingerprints)) => fingerprints.push(fingerprint),
+                None => access.rules.push((prefix.clone(), vec![fingerprint])),
+            }
+        }
+        access
+    }
+
+    /// Whether any paths are restricted, in which case clients should be asked for certificates.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether any prefix covers the path.
+    pub fn covers(&self, path: &str) -> bool {
+        self.rules.iter().any(|(prefix, _)| covers(prefix, path))
+    }
+
+    /// Checks whether a client with the given certificate fingerprint can see the path. The most
+    /// specific prefix covering the path decides. Fails with `60` if a certificate is needed but
+    /// wasn't given, and `61` if it isn't one of the allowed ones.
+    pub fn check(&self, path: &str, fingerprint: Option<&str>) -> Result<(), StatusError> {
+        let rule = self
+            .rules
+            .iter()
+            .filter(|(prefix, _)| covers(prefix, path))
+            .max_by_key(|(prefix, _)| prefix.len());
+        let allowed = match rule {
+            Some((_, allowed)) => allowed,
+            None => return Ok(()),
+        };
+        match fingerprint {
+            None => Err(StatusError::new(60, "Certificate required")),
+            Some(fingerprint) if allowed.iter().any(|allowed| allowed == fingerprint) => Ok(()),
+            Some(_) => Err(StatusError::new(61, "Certificate not authorised")),
+        }
+    }
+}
+
+/// Whether the prefix covers the path, only matching whole segments, so that `/private` covers
+/// `/private/notes.md` but not `/privateer.md`.
+pub fn covers(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Puts a fingerprint in the form `tls::fingerprint` returns, allowing for the colon-separated
+/// uppercase form tools like OpenSSL print.
+fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.replace(':', "").to_ascii_lowercase()
+}
+
+/// Parses a rule given on the command line, as PREFIX=FINGERPRINT.
+pub fn parse_rule(s: &str) -> Result<(String, String)> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(prefix), Some(fingerprint)) if prefix.starts_with('/') && !fingerprint.is_empty() => {
+            Ok((prefix.to_owned(), fingerprint.to_owned()))
+        }
+        _ => Err(anyhow!("expected /PREFIX=FINGERPRINT, got {}", s)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rules() -> AccessRules {
+        AccessRules::new(&[
+            ("/private".to_owned(), "AB:CD".to_owned()),
+            ("/private".to_owned(), "ef01".to_owned()),
+            ("/private/shared/".to_owned(), "1234".to_owned()),
+        ])
+    }
+
+    #[test]
+    fn check() {
+        let rules = rules();
+        let status = |path, fingerprint| rules.check(path, fingerprint).map_err(|e| e.code);
+        assert_eq!(status("/public.md", None), Ok(()));
+        assert_eq!(status("/privateer.md", None), Ok(()));
+        assert_eq!(status("/private/a.md", None), Err(60));
+        assert_eq!(status("/private", Some("abcd")), Ok(()));
+        assert_eq!(status("/private/a.md", Some("ef01")), Ok(()));
+        assert_eq!(status("/private/a.md", Some("1234")), Err(61));
+        assert_eq!(status("/private/shared/a.md", Some("1234")), Ok(()));
+        assert_eq!(status("/private/shared/a.md", Some("abcd")), Err(61));
+    }
+
+    #[test]
+    fn rules_from_flags() {
+        assert!(parse_rule("/private=abcd").is_ok());
+        assert!(parse_rule("private=abcd").is_err());
+        assert!(parse_rule("/private").is_err());
+    }
+}
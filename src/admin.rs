@@ -0,0 +1,73 @@
+//! An admin socket for changing a running server without restarting it. It's a Unix socket that
+//! only the server's user can connect to, which takes one command per line and answers each with
+//! a line, so it can be driven with `socat` or `nc -U`.
+
+use anyhow::Result;
+use std::path::Path;
+
+/// Listens for commands on a socket at the path in the background, answering each with the
+/// handler. A socket left at the path by a server that didn't shut down cleanly is replaced.
+#[cfg(unix)]
+pub async fn start<F>(path: &Path, handle: F) -> Result<()>
+where
+    F: Fn(&str) -> String + Send + Sync + 'static,
+{
+    use anyhow::{bail, Context};
+    use async_std::io::prelude::*;
+    use async_std::io::BufReader;
+    use async_std::os::unix::net::UnixListener;
+    use async_std::prelude::*;
+    use async_std::task;
+    use log::{error, info};
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    use std::sync::Arc;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => bail!("{} exists and isn't a socket", path.display()),
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)
+        .await
+        .with_context(|| format!("failed to bind {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    info!("Listening for admin commands on {}", path.display());
+
+    let handle = Arc::new(handle);
+    task::spawn(async move {
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Failed to accept an admin connection: {}", e);
+                    continue;
+                }
+            };
+            let handle = handle.clone();
+            task::spawn(async move {
+                let mut lines = BufReader::new(stream.clone()).lines();
+                while let Some(Ok(command)) = lines.next().await {
+                    let command = command.trim();
+                    if command.is_empty() {
+                        continue;
+                    }
+                    info!("Admin command: {}", command);
+                    let answer = format!("{}\n", handle(command));
+                    if stream.write_all(answer.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn start<F>(_path: &Path, _handle: F) -> Result<()>
+where
+    F: Fn(&str) -> String + Send + Sync + 'static,
+{
+    anyhow::bail!("--admin-socket is only supported on Unix")
+}
@@ -0,0 +1,157 @@
+//! Gemtext decorated with ANSI escape codes, for reading in a terminal. Headings, link URLs,
+//! quotes, list bullets and preformatted text are colored, and everything else is left as it is,
+//! so the output is still Gemtext once the escape codes are stripped.
+
+use std::io::{self, Write};
+
+const RESET: &str = "\x1b[0m";
+/// Headings by level, from `#` to `###`.
+const HEADINGS: [&str; 3] = ["\x1b[1;35m", "\x1b[1;36m", "\x1b[1;34m"];
+const LINK_ARROW: &str = "\x1b[2m";
+const LINK_URL: &str = "\x1b[4;34m";
+const QUOTE: &str = "\x1b[3;32m";
+const BULLET: &str = "\x1b[33m";
+const PREFORMATTED: &str = "\x1b[2m";
+
+/// Colors a whole Gemtext document.
+pub fn colorize(gemini: &str) -> String {
+    let mut colorizer = Colorizer::new(vec![]);
+    // Writing to a vector can't fail.
+    colorizer.write_all(gemini.as_bytes()).unwrap();
+    colorizer.flush().unwrap();
+    String::from_utf8(colorizer.into_inner()).unwrap()
+}
+
+/// Colors the Gemtext written to it a line at a time before passing it on. A line that hasn't
+/// been finished is only written when the colorizer is flushed, so it should only be flushed once
+/// everything has been written.
+pub struct Colorizer<W: Write> {
+    inner: W,
+    // The line currently being written, which is held back until we see its end.
+    line: Vec<u8>,
+    preformatted: bool,
+}
+
+impl<W: Write> Colorizer<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            line: vec![],
+            preformatted: false,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        let line = String::from_utf8_lossy(line);
+        let colored = if line.starts_with("```") {
+            self.preformatted = !self.preformatted;
+            paint(PREFORMATTED, &line)
+        } else if self.preformatted {
+            paint(PREFORMATTED, &line)
+        } else if line.starts_with('#') {
+            let depth = line.chars().take_while(|&c| c == '#').count();
+            paint(HEADINGS[depth.min(HEADINGS.len()) - 1], &line)
+        } else if let Some(link) = line.strip_prefix("=>") {
+            colorize_link(link)
+        } else if line.starts_with('>') {
+            paint(QUOTE, &line)
+        } else if let Some(item) = line.strip_prefix("* ") {
+            format!("{} {}", paint(BULLET, "*"), item)
+        } else {
+            line.into_owned()
+        };
+        self.inner.write_all(colored.as_bytes())
+    }
+}
+
+impl<W: Write> Write for Colorizer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while let Some(end) = rest.iter().position(|&b| b == b'\n') {
+            let mut line = std::mem::take(&mut self.line);
+            line.extend_from_slice(&rest[..end]);
+            self.write_line(&line)?;
+            self.inner.write_all(b"\n")?;
+            rest = &rest[end + 1..];
+        }
+        self.line.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.write_line(&line)?;
+        }
+        self.inner.flush()
+    }
+}
+
+/// Wraps the text in the escape code, resetting the style after it.
+fn paint(code: &str, text: &str) -> String {
+    format!("{}{}{}", code, text, RESET)
+}
+
+/// Colors the rest of a link line after its `=>`, underlining the URL but leaving the label plain.
+fn colorize_link(link: &str) -> String {
+    let start = link.len() - link.trim_start().len();
+    let url_end = link[start..]
+        .find(char::is_whitespace)
+        .map_or(link.len(), |end| start + end);
+    format!(
+        "{}{}{}{}",
+        paint(LINK_ARROW, "=>"),
+        &link[..start],
+        paint(LINK_URL, &link[start..url_end]),
+        &link[url_end..]
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn colors_lines() {
+        assert_eq!(
+            colorize("# Hi\n### Deep\n#### Deeper\ntext"),
+            "\x1b[1;35m# Hi\x1b[0m\n\x1b[1;34m### Deep\x1b[0m\n\x1b[1;34m#### Deeper\x1b[0m\ntext"
+        );
+        assert_eq!(
+            colorize("=> gemini://a.example A page\n=>/b\n"),
+            "\x1b[2m=>\x1b[0m \x1b[4;34mgemini://a.example\x1b[0m A page\n\
+             \x1b[2m=>\x1b[0m\x1b[4;34m/b\x1b[0m\n"
+        );
+        assert_eq!(
+            colorize("> quote\n* item"),
+            "\x1b[3;32m> quote\x1b[0m\n\x1b[33m*\x1b[0m item"
+        );
+    }
+
+    #[test]
+    fn preformatted() {
+        assert_eq!(
+            colorize("```\n# not a heading\n```\n# heading"),
+            "\x1b[2m```\x1b[0m\n\x1b[2m# not a heading\x1b[0m\n\x1b[2m```\x1b[0m\n\
+             \x1b[1;35m# heading\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn lines_split_between_writes() -> io::Result<()> {
+        let mut colorizer = Colorizer::new(vec![]);
+        colorizer.write_all(b"#")?;
+        colorizer.write_all(b" Hi\n> a")?;
+        colorizer.write_all(b"\n")?;
+        colorizer.flush()?;
+        assert_eq!(
+            String::from_utf8(colorizer.into_inner()).unwrap(),
+            "\x1b[1;35m# Hi\x1b[0m\n\x1b[3;32m> a\x1b[0m\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+//! Crash-safe replacement of files that exarch maintains itself, like the bookmark data and the
+//! tinylog. A plain `fs::write` truncates the file before writing it, so a crash or power cut in
+//! between leaves it empty or half-written. Instead, the new contents go to a temporary file next
+//! to it, which is synced and then renamed over the original, so the file is always either
+//! entirely old or entirely new. Symlinks are swapped the same way.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Replaces the file's contents atomically.
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let temporary = temporary_path(path);
+    let result = (|| {
+        let mut file = File::create(&temporary)?;
+        file.write_all(contents.as_ref())?;
+        file.sync_all()?;
+        std::fs::rename(&temporary, path)?;
+        sync_parent(path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+    result
+}
+
+/// Points the symlink at the target, replacing whatever the link was before, so that anything
+/// following it sees either the old target or the new one and never nothing.
+#[cfg(unix)]
+pub fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    let temporary = temporary_path(link);
+    // Left over from an interrupted swap.
+    let _ = std::fs::remove_file(&temporary);
+    std::os::unix::fs::symlink(target, &temporary)?;
+    if let Err(e) = std::fs::rename(&temporary, link) {
+        let _ = std::fs::remove_file(&temporary);
+        return Err(e);
+    }
+    sync_parent(link)
+}
+
+#[cfg(not(unix))]
+pub fn symlink(_target: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "symlinks can only be swapped on Unix",
+    ))
+}
+
+/// The temporary file used while replacing the file. If a write was interrupted, it's left behind
+/// and overwritten by the next one.
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    let mut temporary = path.to_path_buf();
+    temporary.set_file_name(name);
+    temporary
+}
+
+/// Makes the rename itself durable. Only possible on Unix, where directories can be opened.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Drops a line left incomplete by an interrupted append, so that a crash while adding to a
+/// line-based file can't corrupt the entry after it.
+pub fn complete_lines(data: &str) -> &str {
+    match data.rfind('\n') {
+        Some(end) => &data[..=end],
+        None => "",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn replaces_file() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("exarch-atomic-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("data.tsv");
+        std::fs::write(&path, "old")?;
+        // Left over from an interrupted write.
+        std::fs::write(temporary_path(&path), "partial")?;
+        write(&path, "new")?;
+        assert_eq!(std::fs::read_to_string(&path)?, "new");
+        assert!(!temporary_path(&path).exists());
+        std::fs::remove_dir_all(&dir)
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn swaps_symlink() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("exarch-symlink-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("a"))?;
+        std::fs::create_dir_all(dir.join("b"))?;
+        let link = dir.join("current");
+        symlink(Path::new("a"), &link)?;
+        assert_eq!(std::fs::read_link(&link)?, Path::new("a"));
+        symlink(Path::new("b"), &link)?;
+        assert_eq!(std::fs::read_link(&link)?, Path::new("b"));
+        assert!(!temporary_path(&link).exists());
+        std::fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn incomplete_lines() {
+        assert_eq!(complete_lines("a\nb\n"), "a\nb\n");
+        assert_eq!(complete_lines("a\nb"), "a\n");
+        assert_eq!(complete_lines("b"), "");
+    }
+}
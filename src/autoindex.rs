@@ -0,0 +1,185 @@
+//! Generated listings of directories without an index file, so that a directory of downloads can
+//! be browsed without writing a page for it.
+
+use crate::date::Date;
+use anyhow::{anyhow, Context, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+
+/// The characters escaped in links to entries, which are relative paths.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// How listings are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Each file with its size and when it was last modified.
+    Detailed,
+    /// Just the names.
+    Compact,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Detailed
+    }
+}
+
+impl FromStr for Layout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "detailed" => Ok(Layout::Detailed),
+            "compact" => Ok(Layout::Compact),
+            _ => Err(anyhow!("unknown listing layout {}", s)),
+        }
+    }
+}
+
+/// A file or directory in a listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    // When the entry was last modified, as seconds since the Unix epoch.
+    modified: Option<i64>,
+}
+
+/// Lists the directory as Gemtext, under a heading naming the given URL path. Hidden entries,
+/// whose names start with a dot, are left out.
+pub fn listing(dir: &Path, url_path: &str, layout: Layout) -> Result<String> {
+    let mut entries = vec![];
+    let read =
+        std::fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+    for entry in read {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs() as i64);
+        entries.push(Entry {
+            name,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified,
+        });
+    }
+    Ok(render(url_path, entries, layout))
+}
+
+/// Writes the listing, with directories first and each group sorted by name.
+fn render(url_path: &str, mut entries: Vec<Entry>, layout: Layout) -> String {
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    let mut page = format!("# Index of {}\n\n", url_path);
+    if url_path != "/" {
+        page.push_str("=> ../ Parent directory\n");
+    }
+    for entry in entries {
+        let link = utf8_percent_encode(&entry.name, PATH_SEGMENT);
+        if entry.is_dir {
+            page.push_str(&format!("=> {}/ {}/\n", link, entry.name));
+            continue;
+        }
+        if layout == Layout::Compact {
+            page.push_str(&format!("=> {} {}\n", link, entry.name));
+            continue;
+        }
+        let mut details = format_size(entry.size);
+        if let Some(modified) = entry.modified {
+            let date = Date::from_days(modified.div_euclid(86400));
+            details.push_str(&format!(", {}", date));
+        }
+        page.push_str(&format!("=> {} {} ({})\n", link, entry.name, details));
+    }
+    page
+}
+
+/// Formats a size in bytes for people, like `1.5 KiB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn render_listing() {
+        let entry = |name: &str, is_dir, size, modified| Entry {
+            name: name.to_owned(),
+            is_dir,
+            size,
+            modified,
+        };
+        let entries = vec![
+            entry("b.txt", false, 2048, Some(18457 * 86400)),
+            entry("my photo.png", false, 10, None),
+            entry("z", true, 0, None),
+            entry("a.txt", false, 0, None),
+        ];
+        assert_eq!(
+            render("/files/", entries.clone(), Layout::Detailed),
+            indoc!(
+                "
+                # Index of /files/
+
+                => ../ Parent directory
+                => z/ z/
+                => a.txt a.txt (0 B)
+                => b.txt b.txt (2.0 KiB, 2020-07-14)
+                => my%20photo.png my photo.png (10 B)
+                "
+            )
+        );
+        assert_eq!(
+            render("/", entries, Layout::Compact),
+            indoc!(
+                "
+                # Index of /
+
+                => z/ z/
+                => a.txt a.txt
+                => b.txt b.txt
+                => my%20photo.png my photo.png
+                "
+            )
+        );
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 << 30), "3.0 GiB");
+    }
+}
@@ -0,0 +1,132 @@
+use anyhow::{bail, Context, Result};
+use exarch::flags::ConverterFlags;
+use exarch::markgem::{self, Timings};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct BenchOpt {
+    /// The root of the tree to convert. Every Markdown file under it is converted.
+    #[structopt(parse(from_os_str))]
+    root: PathBuf,
+
+    /// How many times to convert the whole tree.
+    #[structopt(
+        short = "n",
+        long,
+        default_value = "10",
+        parse(try_from_str = parse_iterations)
+    )]
+    iterations: u32,
+
+    #[structopt(flatten)]
+    converter: ConverterFlags,
+}
+
+pub fn bench(options: BenchOpt) -> Result<()> {
+    let converter_options = options.converter.options()?;
+    let mut pages = vec![];
+    collect_pages(&options.root, &mut pages)?;
+    if pages.is_empty() {
+        bail!("no Markdown files under {}", options.root.display());
+    }
+    let bytes: usize = pages.iter().map(String::len).sum();
+    // Written to a scratch file, so the write stage includes a real write(2).
+    let mut scratch = Scratch::create()?;
+
+    let mut total = Timings::default();
+    let mut write = Duration::default();
+    let start = Instant::now();
+    for _ in 0..options.iterations {
+        // Each iteration starts over, so the file stays the size of one conversion of the tree.
+        scratch.file.set_len(0)?;
+        scratch.file.seek(SeekFrom::Start(0))?;
+        for page in &pages {
+            let (gemini, timings) = markgem::to_gemini_timed(page, &converter_options)?;
+            total.parse += timings.parse;
+            total.convert += timings.convert;
+            let write_start = Instant::now();
+            scratch.file.write_all(&gemini)?;
+            write += write_start.elapsed();
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let secs = elapsed.as_secs_f64();
+    let converted = options.iterations as f64;
+    println!(
+        "{} pages, {} bytes, {} iterations in {:.3}s",
+        pages.len(),
+        bytes,
+        options.iterations,
+        secs
+    );
+    println!(
+        "{:.2} MB/s, {:.1} pages/s",
+        bytes as f64 * converted / secs / 1_000_000.0,
+        pages.len() as f64 * converted / secs
+    );
+    for (stage, time) in &[
+        ("parse", total.parse),
+        ("convert", total.convert),
+        ("write", write),
+    ] {
+        println!(
+            "{:>8}: {:.3}s ({:.1}%)",
+            stage,
+            time.as_secs_f64(),
+            time.as_secs_f64() / secs * 100.0
+        );
+    }
+    Ok(())
+}
+
+fn parse_iterations(s: &str) -> Result<u32> {
+    match s.parse()? {
+        0 => bail!("has to be at least 1"),
+        iterations => Ok(iterations),
+    }
+}
+
+/// The file the converted pages are written to, which is removed when it's dropped, including
+/// when a page fails to convert.
+struct Scratch {
+    path: PathBuf,
+    file: File,
+}
+
+impl Scratch {
+    fn create() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("exarch-bench-{}", std::process::id()));
+        let file =
+            File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+        Ok(Self { path, file })
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Reads every Markdown file under the directory.
+fn collect_pages(dir: &Path, pages: &mut Vec<String>) -> Result<()> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_pages(&path, pages)?;
+        } else if path.extension() == Some(OsStr::new("md")) {
+            let page = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            pages.push(page);
+        }
+    }
+    Ok(())
+}
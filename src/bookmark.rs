@@ -0,0 +1,142 @@
+//! A reading list of links to other people's writing. Bookmarks are kept in a tab-separated data
+//! file, from which a page is regenerated every time one is added. The page lists links with their
+//! dates, so it doubles as a Gemini subscription feed.
+
+use crate::tofu::KnownHostsFlags;
+use anyhow::{bail, Context, Result};
+use async_std::task;
+use exarch::atomic;
+use exarch::date::Date;
+use exarch::fetch::Fetcher;
+use exarch::known_hosts::KnownHosts;
+use exarch::resolve;
+use log::warn;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use structopt::StructOpt;
+use url::Url;
+
+#[derive(Debug, StructOpt)]
+pub struct BookmarkOpt {
+    /// The URL to add.
+    url: Url,
+
+    /// The title to list the URL with. By default, this is fetched from the page itself for
+    /// Gemini URLs.
+    #[structopt(short, long)]
+    title: Option<String>,
+
+    /// The file bookmarks are kept in.
+    #[structopt(long, parse(from_os_str), default_value = "bookmarks.tsv")]
+    data: PathBuf,
+
+    /// The reading list page to regenerate.
+    #[structopt(long, parse(from_os_str), default_value = "reading-list.gmi")]
+    page: PathBuf,
+
+    #[structopt(flatten)]
+    known_hosts: KnownHostsFlags,
+}
+
+#[derive(Debug, PartialEq)]
+struct Bookmark {
+    date: String,
+    url: String,
+    title: String,
+}
+
+pub fn bookmark(options: BookmarkOpt) -> Result<()> {
+    let mut url = options.url.clone();
+    resolve::normalize_host(&mut url)?;
+    let title = match &options.title {
+        Some(title) => title.clone(),
+        None => {
+            let known_hosts = options.known_hosts.load_shared()?;
+            let title = task::block_on(fetch_title(&url, known_hosts.clone()));
+            options.known_hosts.save_shared(&known_hosts)?;
+            title.unwrap_or_else(|e| {
+                warn!("Couldn't fetch the title of {}: {:#}", url, e);
+                url.to_string()
+            })
+        }
+    };
+    let bookmark = Bookmark {
+        date: Date::today().to_string(),
+        url: url.to_string(),
+        title: title.replace(&['\t', '\n', '\r'][..], " "),
+    };
+    let data = match std::fs::read_to_string(&options.data) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read {}", options.data.display()))
+        }
+    };
+    let bookmarks = format!(
+        "{}{}\t{}\t{}\n",
+        atomic::complete_lines(&data),
+        bookmark.date,
+        bookmark.url,
+        bookmark.title
+    );
+    atomic::write(&options.data, &bookmarks)
+        .with_context(|| format!("failed to write {}", options.data.display()))?;
+    atomic::write(&options.page, render(&parse(&bookmarks)))
+        .with_context(|| format!("failed to write {}", options.page.display()))?;
+    Ok(())
+}
+
+/// Fetches a Gemini page, returning its first top-level heading.
+async fn fetch_title(url: &Url, known_hosts: Arc<Mutex<KnownHosts>>) -> Result<String> {
+    let response = Fetcher::new().known_hosts(known_hosts).fetch(url).await?;
+    if response.status != 20 || !response.meta.starts_with("text/gemini") {
+        bail!("unexpected response {} {}", response.status, response.meta);
+    }
+    String::from_utf8_lossy(&response.body)
+        .lines()
+        .find_map(|line| line.strip_prefix("# "))
+        .map(|title| title.trim().to_owned())
+        .context("page has no title")
+}
+
+fn parse(data: &str) -> Vec<Bookmark> {
+    data.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            Some(Bookmark {
+                date: fields.next()?.to_owned(),
+                url: fields.next()?.to_owned(),
+                title: fields.next()?.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Renders the reading list, newest bookmarks first.
+fn render(bookmarks: &[Bookmark]) -> String {
+    let mut page = String::from("# Reading list\n\n");
+    for bookmark in bookmarks.iter().rev() {
+        page.push_str(&format!(
+            "=> {} {} {}\n",
+            bookmark.url, bookmark.date, bookmark.title
+        ));
+    }
+    page
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = "2020-07-01\tgemini://a.org/\tFirst\n2020-07-02\thttps://b.org/x\tSecond post\n";
+        let bookmarks = parse(data);
+        assert_eq!(
+            render(&bookmarks),
+            "# Reading list\n\n\
+             => https://b.org/x 2020-07-02 Second post\n\
+             => gemini://a.org/ 2020-07-01 First\n"
+        );
+    }
+}
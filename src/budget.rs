@@ -0,0 +1,132 @@
+//! A global memory budget for the server, so that a spike in traffic sheds load instead of getting
+//! the process killed. Each request reserves an estimate of what it'll use before doing any work,
+//! and gives it back when it's done.
+
+use anyhow::{anyhow, Result};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Tracks how much memory in-flight requests are using.
+#[derive(Debug)]
+pub struct Budget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl Budget {
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            used: AtomicUsize::new(0),
+        })
+    }
+
+    /// Reserves the given number of bytes, returning `None` if that would go over the limit. The
+    /// bytes are released when the reservation is dropped.
+    pub fn reserve(self: &Arc<Self>, bytes: usize) -> Option<Reservation> {
+        if !self.take(bytes) {
+            return None;
+        }
+        Some(Reservation {
+            budget: self.clone(),
+            bytes,
+        })
+    }
+
+    /// Adds the bytes to those in use, unless that would go over the limit.
+    fn take(&self, bytes: usize) -> bool {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let new = match used.checked_add(bytes).filter(|&new| new <= self.limit) {
+                Some(new) => new,
+                None => return false,
+            };
+            match self
+                .used
+                .compare_exchange_weak(used, new, Ordering::AcqRel, Ordering::Relaxed)
+            {
+                Ok(_) => return true,
+                Err(actual) => used = actual,
+            }
+        }
+    }
+
+    /// How many bytes are currently reserved.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+}
+
+/// Bytes reserved from a `Budget`.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: Arc<Budget>,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// Reserves more bytes as part of this reservation, returning whether there was room.
+    pub fn grow(&mut self, bytes: usize) -> bool {
+        let grown = self.budget.take(bytes);
+        if grown {
+            self.bytes += bytes;
+        }
+        grown
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+/// Parses a size like `256M`, with an optional K, M or G suffix for powers of 1024.
+pub fn parse_size(s: &str) -> Result<usize> {
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k')) | Some((i, 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm')) | Some((i, 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g')) | Some((i, 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| anyhow!("invalid size {}", s))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reservations() {
+        let budget = Budget::new(100);
+        let first = budget.reserve(60).unwrap();
+        assert!(budget.reserve(50).is_none());
+        let second = budget.reserve(40).unwrap();
+        assert_eq!(budget.used(), 100);
+        drop(first);
+        drop(second);
+        assert_eq!(budget.used(), 0);
+        assert!(budget.reserve(101).is_none());
+        let mut growing = budget.reserve(60).unwrap();
+        assert!(growing.grow(40));
+        assert!(!growing.grow(1));
+        assert_eq!(budget.used(), 100);
+        drop(growing);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn sizes() -> Result<()> {
+        assert_eq!(parse_size("512")?, 512);
+        assert_eq!(parse_size("4k")?, 4096);
+        assert_eq!(parse_size("256M")?, 256 << 20);
+        assert_eq!(parse_size("1G")?, 1 << 30);
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("12X").is_err());
+        Ok(())
+    }
+}
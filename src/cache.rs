@@ -0,0 +1,358 @@
+//! A cache of converted pages, so that popular pages aren't read and converted again for every
+//! request. Pages are looked up by a key like their path and only used while the file's
+//! modification time is the one they were converted from, and the least recently used ones are
+//! dropped to stay under a size limit, and under the server's memory budget if it has one. A
+//! watcher can drop pages as their files change instead, so that hits don't need to look at the
+//! file at all. Rules can keep the pages under a prefix for only so long, or out of the cache.
+
+use crate::access;
+use crate::budget::{Budget, Reservation};
+use crate::date;
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Converted pages, up to a total size in bytes.
+pub struct Cache<K> {
+    capacity: usize,
+    budget: Option<Arc<Budget>>,
+    inner: Mutex<Inner<K>>,
+}
+
+struct Inner<K> {
+    entries: HashMap<K, Entry>,
+    // The keys in the order they were last used, by when that was.
+    order: BTreeMap<u64, K>,
+    next_use: u64,
+    size: usize,
+    // Pages can say how old they are, so they're only kept for the day they were converted on.
+    day: i64,
+    // How many times pages have been dropped because their files changed.
+    changes: u64,
+}
+
+struct Entry {
+    modified: SystemTime,
+    // When a rule says the page has to be converted again.
+    expires: Option<Instant>,
+    gemtext: Arc<[u8]>,
+    last_use: u64,
+    _reservation: Option<Reservation>,
+}
+
+impl<K: Hash + Eq + Clone> Cache<K> {
+    pub fn new(capacity: usize, budget: Option<Arc<Budget>>) -> Self {
+        Self {
+            capacity,
+            budget,
+            inner: Mutex::new(Inner::new(0, 0)),
+        }
+    }
+
+    /// The converted page, if it was converted from the file as it was modified then. With no
+    /// modification time, any page that's there is returned, for when a watcher drops pages as
+    /// their files change.
+    pub fn get(&self, key: &K, modified: Option<SystemTime>) -> Option<Arc<[u8]>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.expire();
+        let inner = &mut *inner;
+        let entry = inner.entries.get_mut(key)?;
+        if modified.map_or(false, |modified| entry.modified != modified) {
+            return None;
+        }
+        if entry
+            .expires
+            .map_or(false, |expires| expires <= Instant::now())
+        {
+            inner.remove(key);
+            return None;
+        }
+        inner.order.remove(&entry.last_use);
+        entry.last_use = inner.next_use;
+        inner.next_use += 1;
+        inner.order.insert(entry.last_use, key.clone());
+        Some(entry.gemtext.clone())
+    }
+
+    /// How many times pages have been dropped because their files changed, to pass to `insert`.
+    pub fn changes(&self) -> u64 {
+        self.inner.lock().unwrap().changes
+    }
+
+    /// Stores the converted page, dropping the least recently used ones to make room in the cache
+    /// and the budget. Pages that don't fit even in an empty cache aren't stored, and neither are
+    /// pages that might have been converted from a file as it was before it changed: those that
+    /// pages have been dropped for changing since `changes` was called. With a lifetime, the page
+    /// is only used for that long.
+    pub fn insert(
+        &self,
+        changes: u64,
+        key: K,
+        modified: SystemTime,
+        lifetime: Option<Duration>,
+        gemtext: Arc<[u8]>,
+    ) {
+        if gemtext.len() > self.capacity {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.expire();
+        if inner.changes != changes {
+            return;
+        }
+        inner.remove(&key);
+        while inner.size + gemtext.len() > self.capacity && inner.remove_oldest() {}
+        let reservation = match &self.budget {
+            Some(budget) => loop {
+                match budget.reserve(gemtext.len()) {
+                    Some(reservation) => break Some(reservation),
+                    None if inner.remove_oldest() => continue,
+                    None => return,
+                }
+            },
+            None => None,
+        };
+        let last_use = inner.next_use;
+        inner.next_use += 1;
+        inner.size += gemtext.len();
+        inner.order.insert(last_use, key.clone());
+        inner.entries.insert(
+            key,
+            Entry {
+                modified,
+                expires: lifetime.map(|lifetime| Instant::now() + lifetime),
+                gemtext,
+                last_use,
+                _reservation: reservation,
+            },
+        );
+    }
+
+    /// Drops the pages whose keys `keep` returns false for, because their files changed.
+    pub fn retain(&self, mut keep: impl FnMut(&K) -> bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let dropped: Vec<K> = inner
+            .entries
+            .keys()
+            .filter(|key| !keep(key))
+            .cloned()
+            .collect();
+        for key in &dropped {
+            inner.remove(key);
+        }
+        inner.changes += 1;
+    }
+}
+
+/// How long the pages under a prefix are cached for, from --cache-rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lifetime {
+    Never,
+    For(Duration),
+}
+
+/// Rules for how long pages are cached, by the prefix of their URL's path.
+#[derive(Debug, Default)]
+pub struct Rules {
+    rules: Vec<(String, Lifetime)>,
+}
+
+impl Rules {
+    pub fn new(rules: &[(String, Lifetime)]) -> Self {
+        Self {
+            rules: rules.to_vec(),
+        }
+    }
+
+    /// How long the page at the path is cached for, if a rule says. The most specific prefix
+    /// covering the path decides.
+    pub fn lifetime(&self, path: &str) -> Option<Lifetime> {
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| access::covers(prefix, path))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, lifetime)| *lifetime)
+    }
+}
+
+/// Parses a rule given on the command line, as /PREFIX=LIFETIME. The lifetime is "never", or a
+/// number of seconds, optionally with a suffix for minutes, hours or days, like 10m or 1d.
+pub fn parse_rule(s: &str) -> Result<(String, Lifetime)> {
+    let mut parts = s.splitn(2, '=');
+    let (prefix, lifetime) = match (parts.next(), parts.next()) {
+        (Some(prefix), Some(lifetime)) if prefix.starts_with('/') => (prefix, lifetime),
+        _ => return Err(anyhow!("expected /PREFIX=LIFETIME, got {}", s)),
+    };
+    if lifetime == "never" {
+        return Ok((prefix.to_owned(), Lifetime::Never));
+    }
+    let (digits, multiplier) = match lifetime.char_indices().last() {
+        Some((i, 's')) => (&lifetime[..i], 1),
+        Some((i, 'm')) => (&lifetime[..i], 60),
+        Some((i, 'h')) => (&lifetime[..i], 60 * 60),
+        Some((i, 'd')) => (&lifetime[..i], 24 * 60 * 60),
+        _ => (lifetime, 1),
+    };
+    let seconds = digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| anyhow!("invalid lifetime {}", lifetime))?;
+    Ok((
+        prefix.to_owned(),
+        Lifetime::For(Duration::from_secs(seconds)),
+    ))
+}
+
+impl<K: Hash + Eq> Inner<K> {
+    fn new(day: i64, changes: u64) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_use: 0,
+            size: 0,
+            day,
+            changes,
+        }
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.last_use);
+            self.size -= entry.gemtext.len();
+        }
+    }
+
+    /// Drops the least recently used page, returning whether there was one.
+    fn remove_oldest(&mut self) -> bool
+    where
+        K: Clone,
+    {
+        let oldest = match self.order.values().next() {
+            Some(oldest) => oldest.clone(),
+            None => return false,
+        };
+        self.remove(&oldest);
+        true
+    }
+
+    /// Empties the cache if the day has changed since it was filled.
+    fn expire(&mut self) {
+        let day = date::unix_seconds().div_euclid(86400);
+        if day != self.day {
+            *self = Self::new(day, self.changes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn least_recently_used() {
+        let cache = Cache::new(10, None);
+        let then = SystemTime::UNIX_EPOCH;
+        let page = |s: &str| Arc::from(s.as_bytes());
+        cache.insert(0, "a", then, None, page("aaaa"));
+        cache.insert(0, "b", then, None, page("bbbb"));
+        assert!(cache.get(&"a", Some(then)).is_some());
+        // Only one of them fits alongside this, and b was used longest ago.
+        cache.insert(0, "c", then, None, page("ccc"));
+        assert!(cache.get(&"b", Some(then)).is_none());
+        assert_eq!(cache.get(&"a", Some(then)).as_deref(), Some(&b"aaaa"[..]));
+        assert!(cache.get(&"c", Some(then)).is_some());
+
+        cache.insert(0, "big", then, None, page("12345678901"));
+        assert!(cache.get(&"big", Some(then)).is_none());
+        assert!(cache.get(&"a", Some(then)).is_some());
+    }
+
+    #[test]
+    fn budget() {
+        let budget = Budget::new(10);
+        let cache = Cache::new(100, Some(budget.clone()));
+        let then = SystemTime::UNIX_EPOCH;
+        let page = |s: &str| Arc::from(s.as_bytes());
+        cache.insert(0, "a", then, None, page("aaaa"));
+        cache.insert(0, "b", then, None, page("bbbb"));
+        let _request = budget.reserve(2).unwrap();
+        // The cache has room, but the budget doesn't, so a is dropped for it.
+        cache.insert(0, "c", then, None, page("cccc"));
+        assert!(cache.get(&"a", None).is_none());
+        assert!(cache.get(&"b", None).is_some());
+        assert!(cache.get(&"c", None).is_some());
+        assert_eq!(budget.used(), 10);
+        // Emptying the cache doesn't make room for this.
+        cache.insert(0, "d", then, None, page("123456789"));
+        assert!(cache.get(&"d", None).is_none());
+        assert_eq!(budget.used(), 2);
+    }
+
+    #[test]
+    fn modified() {
+        let cache = Cache::new(10, None);
+        let then = SystemTime::UNIX_EPOCH;
+        cache.insert(0, "a", then, None, Arc::from(&b"old"[..]));
+        let later = then + Duration::from_secs(1);
+        assert!(cache.get(&"a", Some(later)).is_none());
+        assert!(cache.get(&"a", None).is_some());
+        cache.insert(0, "a", later, None, Arc::from(&b"newer"[..]));
+        let inner = cache.inner.lock().unwrap();
+        assert_eq!(inner.size, 5);
+        assert_eq!(inner.entries["a"].modified, later);
+        drop(inner);
+        cache.retain(|key| key != &"a");
+        assert!(cache.get(&"a", None).is_none());
+    }
+
+    #[test]
+    fn changes() {
+        let cache = Cache::new(100, None);
+        let then = SystemTime::UNIX_EPOCH;
+        let page = |s: &str| Arc::from(s.as_bytes());
+        cache.insert(0, "a/1", then, None, page("1"));
+        cache.insert(0, "b/2", then, None, page("2"));
+        let changes = cache.changes();
+        cache.retain(|key| !key.starts_with("a/"));
+        assert!(cache.get(&"a/1", None).is_none());
+        assert!(cache.get(&"b/2", None).is_some());
+        // This might have been converted before a/3 changed.
+        cache.insert(changes, "a/3", then, None, page("3"));
+        assert!(cache.get(&"a/3", None).is_none());
+        cache.insert(cache.changes(), "a/3", then, None, page("3"));
+        assert!(cache.get(&"a/3", None).is_some());
+    }
+
+    #[test]
+    fn lifetimes() -> Result<()> {
+        let rules = Rules::new(&[
+            parse_rule("/guestbook=never")?,
+            parse_rule("/archive=1d")?,
+            parse_rule("/archive/new=90")?,
+        ]);
+        assert_eq!(rules.lifetime("/guestbook/sign.md"), Some(Lifetime::Never));
+        let day = Duration::from_secs(86400);
+        assert_eq!(rules.lifetime("/archive/2020.md"), Some(Lifetime::For(day)));
+        let seconds = Duration::from_secs(90);
+        assert_eq!(
+            rules.lifetime("/archive/new/a.md"),
+            Some(Lifetime::For(seconds))
+        );
+        assert_eq!(rules.lifetime("/archived.md"), None);
+        assert!(parse_rule("archive=1d").is_err());
+        assert!(parse_rule("/archive=soon").is_err());
+
+        let cache = Cache::new(100, None);
+        let then = SystemTime::UNIX_EPOCH;
+        let page = |s: &str| Arc::from(s.as_bytes());
+        cache.insert(0, "a", then, Some(day), page("a"));
+        cache.insert(0, "b", then, Some(Duration::from_secs(0)), page("b"));
+        assert!(cache.get(&"a", None).is_some());
+        assert!(cache.get(&"b", None).is_none());
+        assert_eq!(cache.inner.lock().unwrap().size, 1);
+        Ok(())
+    }
+}
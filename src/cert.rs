@@ -0,0 +1,308 @@
+//! Generating the self-signed certificates that Gemini servers conventionally use, so that
+//! setting one up doesn't take a trip through openssl's flags. Keys are ECDSA P-256, written as
+//! PKCS #8, which is what `serve` loads.
+
+use anyhow::{anyhow, bail, Context, Result};
+use exarch::date::{self, Date};
+use exarch::resolve;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct CertOpt {
+    /// The hostname the certificate is for, which may be internationalized. Can be given
+    /// multiple times for a certificate covering several names; the first is its common name.
+    #[structopt(long = "hostname", required = true, number_of_values = 1)]
+    hostnames: Vec<String>,
+
+    /// The directory to write cert.pem and key.pem to.
+    #[structopt(long, parse(from_os_str), default_value = ".")]
+    out_dir: PathBuf,
+
+    /// How many days the certificate is valid for. Clients pin the certificates of servers they've
+    /// seen, so the default is long enough never to have to change it.
+    #[structopt(long, default_value = "36500")]
+    days: u32,
+
+    /// Overwrite a certificate or key that's already there.
+    #[structopt(long)]
+    force: bool,
+}
+
+pub fn cert(options: CertOpt) -> Result<()> {
+    let cert_path = options.out_dir.join("cert.pem");
+    let key_path = options.out_dir.join("key.pem");
+    for path in &[&cert_path, &key_path] {
+        if path.exists() && !options.force {
+            bail!(
+                "{} already exists; pass --force to replace it",
+                path.display()
+            );
+        }
+    }
+    let hostnames = options
+        .hostnames
+        .iter()
+        .map(|hostname| resolve::ascii_host(hostname))
+        .collect::<Result<Vec<_>>>()?;
+    let not_before = date::unix_seconds();
+    let not_after = not_before + i64::from(options.days) * 86400;
+    let (cert, key) = generate(&hostnames, not_before, not_after)?;
+
+    std::fs::create_dir_all(&options.out_dir)
+        .with_context(|| format!("failed to create {}", options.out_dir.display()))?;
+    write_private(&key_path, &pem("PRIVATE KEY", &key))
+        .with_context(|| format!("failed to write {}", key_path.display()))?;
+    std::fs::write(&cert_path, pem("CERTIFICATE", &cert))
+        .with_context(|| format!("failed to write {}", cert_path.display()))?;
+    println!(
+        "Wrote {} and {}, valid until {}",
+        cert_path.display(),
+        key_path.display(),
+        date::format_timestamp(not_after)
+    );
+    Ok(())
+}
+
+/// Generates a key pair and a certificate for it signed by itself, valid for the hostnames
+/// between the given times, returning the certificate and the PKCS #8 key as DER.
+fn generate(hostnames: &[String], not_before: i64, not_after: i64) -> Result<(Vec<u8>, Vec<u8>)> {
+    let rng = SystemRandom::new();
+    let key = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+        .map_err(|_| anyhow!("failed to generate a key"))?;
+    let key_pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, key.as_ref())
+        .map_err(|_| anyhow!("failed to load the generated key"))?;
+
+    let mut serial = [0; 16];
+    rng.fill(&mut serial)
+        .map_err(|_| anyhow!("failed to generate a serial number"))?;
+    // Positive, and without a leading zero byte, so that it's encoded as it is.
+    serial[0] = (serial[0] & 0x7f) | 0x40;
+
+    let name = sequence(&[der(
+        SET,
+        &sequence(&[
+            der(OBJECT_IDENTIFIER, COMMON_NAME),
+            der(UTF8_STRING, hostnames[0].as_bytes()),
+        ]),
+    )]);
+    let alternative_names: Vec<_> = hostnames
+        .iter()
+        .map(|hostname| der(DNS_NAME, hostname.as_bytes()))
+        .collect();
+    let extensions = sequence(&[sequence(&[
+        der(OBJECT_IDENTIFIER, SUBJECT_ALT_NAME),
+        der(OCTET_STRING, &sequence(&alternative_names)),
+    ])]);
+    let public_key = sequence(&[
+        sequence(&[
+            der(OBJECT_IDENTIFIER, EC_PUBLIC_KEY),
+            der(OBJECT_IDENTIFIER, PRIME256V1),
+        ]),
+        bit_string(key_pair.public_key().as_ref()),
+    ]);
+    let signature_algorithm = sequence(&[der(OBJECT_IDENTIFIER, ECDSA_WITH_SHA256)]);
+    let certificate = sequence(&[
+        // Version 3, written as 2.
+        der(VERSION, &der(INTEGER, &[2])),
+        der(INTEGER, &serial),
+        signature_algorithm.clone(),
+        name.clone(),
+        sequence(&[time(not_before), time(not_after)]),
+        name,
+        public_key,
+        der(EXTENSIONS, &extensions),
+    ]);
+    let signature = key_pair
+        .sign(&rng, &certificate)
+        .map_err(|_| anyhow!("failed to sign the certificate"))?;
+    let cert = sequence(&[
+        certificate,
+        signature_algorithm,
+        bit_string(signature.as_ref()),
+    ]);
+    Ok((cert, key.as_ref().to_vec()))
+}
+
+// The DER tags and object identifiers that certificates are made of.
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const UTF8_STRING: u8 = 0x0c;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const VERSION: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+const DNS_NAME: u8 = 0x82;
+// 2.5.4.3
+const COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+// 2.5.29.17
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+// 1.2.840.10045.2.1
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+// 1.2.840.10045.3.1.7
+const PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+// 1.2.840.10045.4.3.2
+const ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+
+/// Encodes a value with the tag and contents.
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|&b| b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(contents);
+    out
+}
+
+fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    der(SEQUENCE, &items.concat())
+}
+
+fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    // No bits of the last byte are unused.
+    der(BIT_STRING, &[&[0], bytes].concat())
+}
+
+/// Encodes a time given as seconds since the Unix epoch. Years before 2050 have to be written
+/// with two digits, and later ones with four.
+fn time(seconds: i64) -> Vec<u8> {
+    let of_day = seconds.rem_euclid(86400);
+    let date = Date::from_days(seconds.div_euclid(86400)).to_string();
+    let time = format!(
+        "{}{:02}{:02}{:02}Z",
+        date.replace('-', ""),
+        of_day / 3600,
+        of_day / 60 % 60,
+        of_day % 60
+    );
+    if &time[..4] < "2050" {
+        der(UTC_TIME, &time.as_bytes()[2..])
+    } else {
+        der(GENERALIZED_TIME, time.as_bytes())
+    }
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = base64::encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+/// Writes a file that only its owner can read.
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    std::fs::write(path, contents)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use exarch::selfcheck;
+    use rustls::{internal::pemfile, Certificate, PrivateKey};
+
+    #[test]
+    fn lengths() {
+        assert_eq!(der(INTEGER, &[2]), vec![0x02, 0x01, 0x02]);
+        assert_eq!(der(OCTET_STRING, &[0; 200])[..3], [0x04, 0x81, 200]);
+        assert_eq!(der(OCTET_STRING, &[0; 300])[..4], [0x04, 0x82, 0x01, 0x2c]);
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(time(1594713912), der(UTC_TIME, b"200714080512Z"));
+        assert_eq!(time(2556144000), der(GENERALIZED_TIME, b"20510101000000Z"));
+    }
+
+    #[test]
+    fn generated() -> Result<()> {
+        let hostnames = vec!["example.org".to_owned(), "xn--bcher-kva.example".to_owned()];
+        let (cert, key) = generate(&hostnames, 1594713912, 4750387512)?;
+        let cert = pemfile::certs(&mut pem("CERTIFICATE", &cert).as_bytes()).unwrap();
+        let mut keys =
+            pemfile::pkcs8_private_keys(&mut pem("PRIVATE KEY", &key).as_bytes()).unwrap();
+        let (cert, key): (&Certificate, PrivateKey) = (&cert[0], keys.remove(0));
+        selfcheck::check_key_matches(cert, &key)?;
+        selfcheck::check_hostname(cert, "example.org")?;
+        selfcheck::check_hostname(cert, "bücher.example")?;
+        assert!(selfcheck::check_hostname(cert, "example.com").is_err());
+
+        let certificate = values(&cert.0)[0].1;
+        let fields = values(values(certificate)[0].1);
+        // Its issuer and subject are the first hostname.
+        let name = sequence(&[der(
+            SET,
+            &sequence(&[
+                der(OBJECT_IDENTIFIER, COMMON_NAME),
+                der(UTF8_STRING, b"example.org"),
+            ]),
+        )]);
+        assert_eq!(fields[3], values(&name)[0]);
+        assert_eq!(fields[5], values(&name)[0]);
+        // It expires after 2050, so that end is written in four digits.
+        assert_eq!(fields[4].0, SEQUENCE);
+        assert_eq!(
+            values(fields[4].1),
+            vec![
+                (UTC_TIME, &b"200714080512Z"[..]),
+                (GENERALIZED_TIME, &b"21200714080512Z"[..])
+            ]
+        );
+        let (_, expiry) = selfcheck::check_expiry(cert, 1594713912, 30);
+        assert_eq!(expiry, "valid until 2120-07-14 08:05 UTC");
+        Ok(())
+    }
+
+    /// The values one after another in the DER, as their tags and contents.
+    fn values(mut der: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut values = vec![];
+        while let [tag, first, rest @ ..] = der {
+            let (len, rest) = match *first {
+                len if len < 0x80 => (usize::from(len), rest),
+                count => {
+                    let (len, rest) = rest.split_at(usize::from(count & 0x7f));
+                    (
+                        len.iter().fold(0, |len, &b| len << 8 | usize::from(b)),
+                        rest,
+                    )
+                }
+            };
+            values.push((*tag, &rest[..len]));
+            der = &rest[len..];
+        }
+        values
+    }
+}
@@ -0,0 +1,152 @@
+//! Running CGI scripts, for pages generated when they're requested. A script gets the details of
+//! the request in environment variables, and whatever it writes to stdout, header included, is
+//! sent to the client as it's written.
+
+use crate::status::StatusError;
+use anyhow::{anyhow, Context, Result};
+use async_std::io::prelude::*;
+use async_std::task;
+use futures::channel::mpsc;
+use futures::{executor, SinkExt, StreamExt};
+use std::io::{self, Read as _};
+use std::net::IpAddr;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::Duration;
+use url::Url;
+
+/// How many chunks of output a script can get ahead of the client.
+const CHUNKS_IN_FLIGHT: usize = 4;
+const CHUNK_SIZE: usize = 8192;
+/// How often to check whether a script that's closed its output has exited.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a script is told about the request it's answering.
+pub struct Request<'a> {
+    pub url: &'a Url,
+    pub peer_addr: IpAddr,
+    // The SHA-256 fingerprint of the client's certificate, if it presented one.
+    pub fingerprint: Option<&'a str>,
+    pub server_port: u16,
+}
+
+impl Request<'_> {
+    /// The environment the script is run with, following CGI where it makes sense for Gemini.
+    fn environment(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("GATEWAY_INTERFACE", "CGI/1.1".to_owned()),
+            ("SERVER_PROTOCOL", "GEMINI".to_owned()),
+            (
+                "SERVER_SOFTWARE",
+                format!("exarch/{}", env!("CARGO_PKG_VERSION")),
+            ),
+            ("GEMINI_URL", self.url.to_string()),
+            ("SCRIPT_NAME", self.url.path().to_owned()),
+            (
+                "QUERY_STRING",
+                self.url.query().unwrap_or_default().to_owned(),
+            ),
+            (
+                "SERVER_NAME",
+                self.url.host_str().unwrap_or_default().to_owned(),
+            ),
+            ("SERVER_PORT", self.server_port.to_string()),
+            ("REMOTE_ADDR", self.peer_addr.to_string()),
+            ("REMOTE_HOST", self.peer_addr.to_string()),
+        ];
+        if let Some(fingerprint) = self.fingerprint {
+            env.push(("AUTH_TYPE", "CERTIFICATE".to_owned()));
+            env.push(("TLS_CLIENT_HASH", format!("SHA256:{}", fingerprint)));
+        }
+        env
+    }
+}
+
+/// Runs the script, streaming its output to the writer. The script is run in its own directory,
+/// with nothing from the server's environment but `PATH`. If it fails before writing anything,
+/// the error is a `42`.
+pub async fn run<W: Write + Unpin>(
+    script: &Path,
+    request: &Request<'_>,
+    mut writer: W,
+) -> Result<()> {
+    let mut command = Command::new(script);
+    command
+        .env_clear()
+        .envs(request.environment())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped());
+    if let Some(path) = std::env::var_os("PATH") {
+        command.env("PATH", path);
+    }
+    if let Some(dir) = script.parent() {
+        command.current_dir(dir);
+    }
+    let child = command.spawn().map_err(|error| {
+        anyhow!(StatusError::new(42, "CGI error")).context(format!(
+            "failed to run {}: {}",
+            script.display(),
+            error
+        ))
+    })?;
+    let mut running = Running(child);
+    let mut stdout = running.0.stdout.take().context("script has no stdout")?;
+    let (mut sender, mut receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    let reading = task::spawn_blocking(move || {
+        let mut chunk = [0; CHUNK_SIZE];
+        loop {
+            match stdout.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                // If this fails, the client went away, and there's no point reading any more.
+                Ok(len) => {
+                    if executor::block_on(sender.send(chunk[..len].to_vec())).is_err() {
+                        return Ok(());
+                    }
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    });
+    // Once the script has written something, it's too late to send a status.
+    let mut written = false;
+    while let Some(chunk) = receiver.next().await {
+        writer.write_all(&chunk).await?;
+        written = true;
+    }
+    reading.await?;
+    let status = running.wait().await?;
+    if status.success() {
+        return Ok(());
+    }
+    let message = format!("{} exited with {}", script.display(), status);
+    if written {
+        Err(anyhow!(message))
+    } else {
+        Err(anyhow!(StatusError::new(42, "CGI error")).context(message))
+    }
+}
+
+/// A running script, which is killed if it's dropped before it exits, as when the client goes away
+/// or the response times out.
+struct Running(Child);
+
+impl Running {
+    /// Waits for the script to exit. It's closed its output by now, so that's usually at once.
+    async fn wait(&mut self) -> io::Result<ExitStatus> {
+        loop {
+            if let Some(status) = self.0.try_wait()? {
+                return Ok(status);
+            }
+            task::sleep(EXIT_POLL_INTERVAL).await;
+        }
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Ok(None) = self.0.try_wait() {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+}
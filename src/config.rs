@@ -0,0 +1,1014 @@
+//! The configuration file for `exarch serve --config`, for the settings that are unwieldy as
+//! flags or that flags can't express, like virtual hosts and redirects. It's TOML, grouped into a
+//! section per part of the server:
+//!
+//! ```toml
+//! root = "site"
+//!
+//! [listen]
+//! port = 1965
+//! hostname = "example.org"
+//!
+//! [tls]
+//! cert = "cert.pem"
+//! key = "key.pem"
+//!
+//! [[vhost]]
+//! hostname = "notes.example.org"
+//! root = "notes"
+//! cert = "notes.crt"
+//! key = "notes.key"
+//!
+//! [[redirect]]
+//! from = "/blog/old.md"
+//! to = "/blog/new.md"
+//!
+//! [converter]
+//! toc = true
+//! emphasis = "strip"
+//! ```
+//!
+//! Each setting has the name of its flag, without the section's prefix where there is one, so
+//! `--handshake-timeout` is `handshake` under `[timeouts]`. The converter's flags go under
+//! `[converter]`, where they take precedence over the theme's. Relative paths are relative to the
+//! file. Flags given on the command line take precedence over the file, and a list given there
+//! replaces the file's. Only the subset of TOML that this needs is read: strings, integers,
+//! booleans and arrays of them, tables, and arrays of tables. Themes are read the same way.
+//!
+//! Profiles hold settings that are used over the others when they're picked with `--profile`, so
+//! that one file can describe several ways of running, like a staging server. A profile's settings
+//! go under `[profile.NAME]`, and its sections under names like `[profile.NAME.listen]`.
+//!
+//! `${NAME}` in a double-quoted string, or in place of an integer or boolean, is replaced by the
+//! environment variable `NAME`, so that one file can be used on several hosts, like
+//! `cert = "/etc/exarch/${HOSTNAME}.pem"` or `port = ${PORT}`. It's an error if the variable
+//! isn't set. Single-quoted strings are taken as they are.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct Config {
+    pub root: Option<PathBuf>,
+    pub overlay: Option<PathBuf>,
+    pub standby: Option<PathBuf>,
+    pub listen: Listen,
+    pub timeouts: Timeouts,
+    pub tls: Tls,
+    pub logging: Logging,
+    pub content: Content,
+    pub access: Access,
+    pub converter: Converter,
+    pub vhosts: Vec<VirtualHost>,
+    pub redirects: Vec<Redirect>,
+    // Settings that are used over these with --profile, by the profile's name.
+    pub profiles: HashMap<String, Config>,
+}
+
+#[derive(Debug, Default)]
+pub struct Listen {
+    pub port: Option<u16>,
+    pub hostname: Option<String>,
+    pub max_connections: Option<usize>,
+    pub connection_wait_ms: Option<u64>,
+    // A size like `64M`.
+    pub memory_limit: Option<String>,
+    pub admin_socket: Option<PathBuf>,
+    pub unix_socket: Option<PathBuf>,
+    // Permissions in octal, like `"660"`.
+    pub unix_socket_mode: Option<String>,
+    pub no_tcp: Option<bool>,
+}
+
+#[derive(Debug, Default)]
+pub struct Timeouts {
+    pub handshake: Option<u64>,
+    pub request: Option<u64>,
+    pub response: Option<u64>,
+    pub shutdown: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Tls {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub cert_check_interval: Option<u64>,
+    pub expiry_warning_days: Option<u32>,
+    pub allow_expired: Option<bool>,
+    pub ignore_failed_checks: Option<bool>,
+}
+
+#[derive(Debug, Default)]
+pub struct Logging {
+    pub access_log: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+pub struct Content {
+    pub index: Vec<String>,
+    pub autoindex: Option<bool>,
+    // These are in the same formats as their flags.
+    pub mime_types: Vec<String>,
+    pub filters: Vec<String>,
+    pub cgi: Vec<String>,
+    pub web_mirror: Option<String>,
+    pub maintenance_file: Option<PathBuf>,
+    pub maintenance_meta: Option<String>,
+    pub redirects: Option<PathBuf>,
+    // A size like `16M`.
+    pub cache_size: Option<String>,
+    pub cache_watch: Option<bool>,
+    // In the same format as --cache-rule.
+    pub cache_rules: Vec<String>,
+    // A built-in theme's name, or the path of a theme file.
+    pub theme: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Access {
+    // These are in the same formats as their flags.
+    pub restrict: Vec<String>,
+    pub titan: Vec<String>,
+    pub titan_token: Option<String>,
+    pub titan_max_size: Option<String>,
+}
+
+/// How Markdown is converted, with a setting for each of the converter's flags.
+#[derive(Debug, Default)]
+pub struct Converter {
+    pub trailing_newline: Option<bool>,
+    pub wrap: Option<usize>,
+    pub line_escape: Option<String>,
+    pub details_separator: Option<bool>,
+    pub toc: Option<bool>,
+    pub title: Option<bool>,
+    pub date: Option<bool>,
+    pub aging_notice: Option<u32>,
+    pub number_headings: Option<bool>,
+    pub raw_gemtext: Option<bool>,
+    pub link_hosts: Option<bool>,
+    pub no_strikethrough: Option<bool>,
+    pub tables: Option<bool>,
+    pub footnotes: Option<bool>,
+    pub tasklists: Option<bool>,
+    pub definition_lists: Option<bool>,
+    pub wiki_links: Option<bool>,
+    pub sidenotes: Option<bool>,
+    pub admonitions: Option<bool>,
+    // These are in the same formats as their flags.
+    pub emphasis: Option<String>,
+    pub heading_overflow: Option<String>,
+    pub admonition_style: Option<String>,
+    pub schemes: Vec<String>,
+    pub shortcodes: Vec<String>,
+    pub abbreviations: Option<PathBuf>,
+    pub glossary: Option<PathBuf>,
+    pub bibliography: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VirtualHost {
+    pub hostname: String,
+    pub root: PathBuf,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub from: String,
+    pub to: String,
+    pub permanent: bool,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let base = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::parse(&text, base, &env_var)
+            .with_context(|| format!("failed to load {}", path.display()))
+    }
+
+    /// Parses a config file, resolving relative paths against the base directory and looking up
+    /// variables with `vars`.
+    fn parse(text: &str, base: &Path, vars: &dyn Fn(&str) -> Option<String>) -> Result<Self> {
+        let mut document = Parser::new(text, vars).document()?;
+        let root = document.remove(0).table;
+        let mut sections = vec![];
+        // The keys and sections of each profile, like [profile.staging] and
+        // [profile.staging.listen].
+        let mut profiles: HashMap<String, (Table, Vec<Section>)> = HashMap::new();
+        for mut section in document {
+            let (profile, name) = match section.name.strip_prefix("profile.") {
+                Some(rest) => match rest.find('.') {
+                    Some(dot) => (rest[..dot].to_owned(), Some(rest[dot + 1..].to_owned())),
+                    None => (rest.to_owned(), None),
+                },
+                None => {
+                    sections.push(section);
+                    continue;
+                }
+            };
+            let (table, sections) = profiles.entry(profile.clone()).or_insert_with(|| {
+                let name = format!("[profile.{}]", profile);
+                (Table::new(name), vec![])
+            });
+            match name {
+                None if !section.array => *table = section.table,
+                None => bail!("unknown section [[{}]]", section.name),
+                Some(name) => {
+                    section.name = name;
+                    sections.push(section);
+                }
+            }
+        }
+        let mut config = Self::from_sections(root, sections, base)?;
+        for (name, (root, sections)) in profiles {
+            let profile = Self::from_sections(root, sections, base)?;
+            config.profiles.insert(name, profile);
+        }
+        Ok(config)
+    }
+
+    /// Takes out the named profile, whose settings are meant to be used over the others.
+    pub fn take_profile(&mut self, name: &str) -> Result<Self> {
+        self.profiles
+            .remove(name)
+            .ok_or_else(|| anyhow!("there's no [profile.{}]", name))
+    }
+
+    /// Reads the settings from the keys before the first header and the sections.
+    fn from_sections(mut root: Table, sections: Vec<Section>, base: &Path) -> Result<Self> {
+        let mut config = Self {
+            root: root.path("root", base)?,
+            overlay: root.path("overlay", base)?,
+            standby: root.path("standby", base)?,
+            ..Self::default()
+        };
+        root.finish()?;
+
+        for Section {
+            name,
+            array,
+            mut table,
+        } in sections
+        {
+            match (name.as_str(), array) {
+                ("listen", false) => {
+                    config.listen = Listen {
+                        port: table.integer("port")?,
+                        hostname: table.string("hostname")?,
+                        max_connections: table.integer("max_connections")?,
+                        connection_wait_ms: table.integer("connection_wait_ms")?,
+                        memory_limit: table.size("memory_limit")?,
+                        admin_socket: table.path("admin_socket", base)?,
+                        unix_socket: table.path("unix_socket", base)?,
+                        unix_socket_mode: table.string("unix_socket_mode")?,
+                        no_tcp: table.boolean("no_tcp")?,
+                    }
+                }
+                ("timeouts", false) => {
+                    config.timeouts = Timeouts {
+                        handshake: table.integer("handshake")?,
+                        request: table.integer("request")?,
+                        response: table.integer("response")?,
+                        shutdown: table.integer("shutdown")?,
+                    }
+                }
+                ("tls", false) => {
+                    config.tls = Tls {
+                        cert: table.path("cert", base)?,
+                        key: table.path("key", base)?,
+                        cert_check_interval: table.integer("cert_check_interval")?,
+                        expiry_warning_days: table.integer("expiry_warning_days")?,
+                        allow_expired: table.boolean("allow_expired")?,
+                        ignore_failed_checks: table.boolean("ignore_failed_checks")?,
+                    }
+                }
+                ("logging", false) => {
+                    config.logging = Logging {
+                        access_log: table.path("access_log", base)?,
+                        record: table.path("record", base)?,
+                    }
+                }
+                ("content", false) => {
+                    config.content = Content {
+                        index: table.strings("index")?,
+                        autoindex: table.boolean("autoindex")?,
+                        mime_types: table.strings("mime_types")?,
+                        filters: table.strings("filters")?,
+                        cgi: table.strings("cgi")?,
+                        web_mirror: table.string("web_mirror")?,
+                        maintenance_file: table.path("maintenance_file", base)?,
+                        maintenance_meta: table.string("maintenance_meta")?,
+                        redirects: table.path("redirects", base)?,
+                        cache_size: table.size("cache_size")?,
+                        cache_watch: table.boolean("cache_watch")?,
+                        cache_rules: table.strings("cache_rules")?,
+                        theme: table.string("theme")?.map(|theme| {
+                            if theme.ends_with(".toml") {
+                                base.join(theme).to_string_lossy().into_owned()
+                            } else {
+                                theme
+                            }
+                        }),
+                    }
+                }
+                ("access", false) => {
+                    config.access = Access {
+                        restrict: table.strings("restrict")?,
+                        titan: table.strings("titan")?,
+                        titan_token: table.string("titan_token")?,
+                        titan_max_size: table.size("titan_max_size")?,
+                    }
+                }
+                ("converter", false) => {
+                    config.converter = Converter {
+                        trailing_newline: table.boolean("trailing_newline")?,
+                        wrap: table.integer("wrap")?,
+                        line_escape: table.string("line_escape")?,
+                        details_separator: table.boolean("details_separator")?,
+                        toc: table.boolean("toc")?,
+                        title: table.boolean("title")?,
+                        date: table.boolean("date")?,
+                        aging_notice: table.integer("aging_notice")?,
+                        number_headings: table.boolean("number_headings")?,
+                        raw_gemtext: table.boolean("raw_gemtext")?,
+                        link_hosts: table.boolean("link_hosts")?,
+                        no_strikethrough: table.boolean("no_strikethrough")?,
+                        tables: table.boolean("tables")?,
+                        footnotes: table.boolean("footnotes")?,
+                        tasklists: table.boolean("tasklists")?,
+                        definition_lists: table.boolean("definition_lists")?,
+                        wiki_links: table.boolean("wiki_links")?,
+                        sidenotes: table.boolean("sidenotes")?,
+                        admonitions: table.boolean("admonitions")?,
+                        emphasis: table.string("emphasis")?,
+                        heading_overflow: table.string("heading_overflow")?,
+                        admonition_style: table.string("admonition_style")?,
+                        schemes: table.strings("schemes")?,
+                        shortcodes: table.strings("shortcodes")?,
+                        abbreviations: table.path("abbreviations", base)?,
+                        glossary: table.path("glossary", base)?,
+                        bibliography: table.path("bibliography", base)?,
+                    }
+                }
+                ("vhost", true) => config.vhosts.push(VirtualHost {
+                    hostname: table.required("hostname", Table::string)?,
+                    root: table.required("root", |table, key| table.path(key, base))?,
+                    cert: table.required("cert", |table, key| table.path(key, base))?,
+                    key: table.required("key", |table, key| table.path(key, base))?,
+                }),
+                ("redirect", true) => config.redirects.push(Redirect {
+                    from: table.required("from", Table::string)?,
+                    to: table.required("to", Table::string)?,
+                    permanent: !table.boolean("temporary")?.unwrap_or(false),
+                }),
+                (_, false) => bail!("unknown section [{}]", name),
+                (_, true) => bail!("unknown section [[{}]]", name),
+            }
+            table.finish()?;
+        }
+        Ok(config)
+    }
+}
+
+/// A theme: settings for how pages and listings look, which can be shared between sites. It's a
+/// TOML file like the config file, without sections, whose settings have the names of their
+/// flags.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Theme {
+    pub description: Option<String>,
+    pub toc: Option<bool>,
+    pub title: Option<bool>,
+    pub date: Option<bool>,
+    pub number_headings: Option<bool>,
+    pub link_hosts: Option<bool>,
+    pub details_separator: Option<bool>,
+    // These are in the same formats as their flags.
+    pub emphasis: Option<String>,
+    pub admonition_style: Option<String>,
+    pub heading_overflow: Option<String>,
+    pub listing: Option<String>,
+    // Gemtext added to the end of every page.
+    pub footer: Option<String>,
+}
+
+impl Theme {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut document = Parser::new(text, &env_var).document()?;
+        if let Some(section) = document.get(1) {
+            bail!("themes can't have sections, but there's [{}]", section.name);
+        }
+        let mut table = document.remove(0).table;
+        let theme = Self {
+            description: table.string("description")?,
+            toc: table.boolean("toc")?,
+            title: table.boolean("title")?,
+            date: table.boolean("date")?,
+            number_headings: table.boolean("number_headings")?,
+            link_hosts: table.boolean("link_hosts")?,
+            details_separator: table.boolean("details_separator")?,
+            emphasis: table.string("emphasis")?,
+            admonition_style: table.string("admonition_style")?,
+            heading_overflow: table.string("heading_overflow")?,
+            listing: table.string("listing")?,
+            footer: table.string("footer")?,
+        };
+        table.finish()?;
+        Ok(theme)
+    }
+
+    /// The theme's settings for the converter.
+    pub fn converter(&self) -> Converter {
+        Converter {
+            toc: self.toc,
+            title: self.title,
+            date: self.date,
+            number_headings: self.number_headings,
+            link_hosts: self.link_hosts,
+            details_separator: self.details_separator,
+            emphasis: self.emphasis.clone(),
+            admonition_style: self.admonition_style.clone(),
+            heading_overflow: self.heading_overflow.clone(),
+            ..Converter::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// The keys and values under a header, or before the first one.
+#[derive(Debug, Default)]
+struct Table {
+    // How the table is named in errors.
+    name: String,
+    entries: Vec<(String, Value)>,
+}
+
+struct Section {
+    name: String,
+    // Whether this is one of an array of tables, like `[[vhost]]`.
+    array: bool,
+    table: Table,
+}
+
+impl Table {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            entries: vec![],
+        }
+    }
+
+    fn take(&mut self, key: &str) -> Option<Value> {
+        let index = self.entries.iter().position(|(name, _)| name == key)?;
+        Some(self.entries.remove(index).1)
+    }
+
+    fn error(&self, key: &str, expected: &str) -> anyhow::Error {
+        anyhow!("{} in {} should be {}", key, self.name, expected)
+    }
+
+    fn string(&mut self, key: &str) -> Result<Option<String>> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(_) => Err(self.error(key, "a string")),
+        }
+    }
+
+    fn path(&mut self, key: &str, base: &Path) -> Result<Option<PathBuf>> {
+        Ok(self.string(key)?.map(|path| base.join(path)))
+    }
+
+    fn integer<T: TryFrom<i64>>(&mut self, key: &str) -> Result<Option<T>> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::Integer(n)) => T::try_from(n)
+                .map(Some)
+                .map_err(|_| self.error(key, "in range")),
+            Some(_) => Err(self.error(key, "an integer")),
+        }
+    }
+
+    fn boolean(&mut self, key: &str) -> Result<Option<bool>> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::Boolean(b)) => Ok(Some(b)),
+            Some(_) => Err(self.error(key, "true or false")),
+        }
+    }
+
+    /// A size, which can be given as a number of bytes or with a suffix, like `"16M"`.
+    fn size(&mut self, key: &str) -> Result<Option<String>> {
+        match self.take(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(Value::Integer(n)) => Ok(Some(n.to_string())),
+            Some(_) => Err(self.error(key, "a size")),
+        }
+    }
+
+    fn strings(&mut self, key: &str) -> Result<Vec<String>> {
+        let values = match self.take(key) {
+            None => return Ok(vec![]),
+            Some(Value::Array(values)) => values,
+            Some(_) => return Err(self.error(key, "an array of strings")),
+        };
+        values
+            .into_iter()
+            .map(|value| match value {
+                Value::String(s) => Ok(s),
+                _ => Err(self.error(key, "an array of strings")),
+            })
+            .collect()
+    }
+
+    fn required<T>(
+        &mut self,
+        key: &str,
+        get: impl FnOnce(&mut Self, &str) -> Result<Option<T>>,
+    ) -> Result<T> {
+        get(self, key)?.ok_or_else(|| anyhow!("{} is missing {}", self.name, key))
+    }
+
+    /// Fails if any keys weren't taken, since they're most likely misspelled.
+    fn finish(self) -> Result<()> {
+        match self.entries.first() {
+            Some((key, _)) => bail!("unknown setting {} in {}", key, self.name),
+            None => Ok(()),
+        }
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// Reads the subset of TOML described in the module documentation.
+struct Parser<'a> {
+    rest: &'a str,
+    line: usize,
+    // Looks up the variables that `${NAME}` is replaced by.
+    vars: &'a dyn Fn(&str) -> Option<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str, vars: &'a dyn Fn(&str) -> Option<String>) -> Self {
+        Self {
+            rest: text,
+            line: 1,
+            vars,
+        }
+    }
+
+    /// Parses the whole file into its sections, starting with the keys before the first header.
+    fn document(&mut self) -> Result<Vec<Section>> {
+        let mut sections = vec![Section {
+            name: String::new(),
+            array: false,
+            table: Table::new("the top level".to_owned()),
+        }];
+        loop {
+            self.skip_blank();
+            if self.rest.is_empty() {
+                return Ok(sections);
+            }
+            let line = self.line;
+            let result = if self.rest.starts_with('[') {
+                self.header().map(|section| {
+                    let repeated = sections
+                        .iter()
+                        .any(|other| !section.array && other.name == section.name);
+                    (Some(section), repeated)
+                })
+            } else {
+                self.entry().map(|(key, value)| {
+                    let table = &mut sections.last_mut().unwrap().table;
+                    let repeated = table.entries.iter().any(|(other, _)| *other == key);
+                    table.entries.push((key, value));
+                    (None, repeated)
+                })
+            };
+            match result.with_context(|| format!("line {}", line))? {
+                (_, true) => bail!("line {}: defined twice", line),
+                (Some(section), false) => sections.push(section),
+                (None, false) => {}
+            }
+        }
+    }
+
+    /// Parses a `[table]` or `[[array]]` header and the end of its line.
+    fn header(&mut self) -> Result<Section> {
+        let array = self.eat("[[");
+        if !array {
+            self.expect("[")?;
+        }
+        self.skip_spaces();
+        let mut name = self.key()?;
+        while self.eat(".") {
+            name.push('.');
+            name.push_str(&self.key()?);
+        }
+        self.skip_spaces();
+        self.expect(if array { "]]" } else { "]" })?;
+        self.end_of_line()?;
+        let table = Table::new(if array {
+            format!("[[{}]]", name)
+        } else {
+            format!("[{}]", name)
+        });
+        Ok(Section { name, array, table })
+    }
+
+    /// Parses a `key = value` line.
+    fn entry(&mut self) -> Result<(String, Value)> {
+        let key = self.key()?;
+        self.skip_spaces();
+        self.expect("=")?;
+        self.skip_spaces();
+        let value = self.value()?;
+        self.end_of_line()?;
+        Ok((key, value))
+    }
+
+    fn key(&mut self) -> Result<String> {
+        if self.rest.starts_with('"') {
+            return self.string();
+        }
+        let len = self
+            .rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(self.rest.len());
+        if len == 0 {
+            bail!("expected a key");
+        }
+        let key = self.rest[..len].to_owned();
+        self.rest = &self.rest[len..];
+        Ok(key)
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        if self.rest.starts_with('"') || self.rest.starts_with('\'') {
+            return Ok(Value::String(self.string()?));
+        }
+        if self.rest.starts_with("${") {
+            return word(&self.variable()?);
+        }
+        if self.eat("[") {
+            let mut values = vec![];
+            loop {
+                self.skip_blank();
+                if self.eat("]") {
+                    return Ok(Value::Array(values));
+                }
+                values.push(self.value()?);
+                self.skip_blank();
+                if !self.eat(",") {
+                    self.skip_blank();
+                    self.expect("]")?;
+                    return Ok(Value::Array(values));
+                }
+            }
+        }
+        let len = self
+            .rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || "+-_".contains(c)))
+            .unwrap_or(self.rest.len());
+        let value = word(&self.rest[..len])?;
+        self.rest = &self.rest[len..];
+        Ok(value)
+    }
+
+    /// Parses a `${NAME}` reference to an environment variable, returning its value.
+    fn variable(&mut self) -> Result<String> {
+        self.expect("${")?;
+        let end = self
+            .rest
+            .find(&['}', '\n'][..])
+            .filter(|&end| self.rest[end..].starts_with('}'))
+            .ok_or_else(|| anyhow!("unterminated {}", "${"))?;
+        let name = &self.rest[..end];
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            bail!("{:?} isn't a variable name", name);
+        }
+        let value =
+            (self.vars)(name).ok_or_else(|| anyhow!("environment variable {} isn't set", name))?;
+        self.rest = &self.rest[end + 1..];
+        Ok(value)
+    }
+
+    /// Parses a basic string in double quotes, or a literal one in single quotes.
+    fn string(&mut self) -> Result<String> {
+        if self.eat("'") {
+            let end = self
+                .rest
+                .find(&['\'', '\n'][..])
+                .filter(|&end| self.rest[end..].starts_with('\''))
+                .ok_or_else(|| anyhow!("unterminated string"))?;
+            let s = self.rest[..end].to_owned();
+            self.rest = &self.rest[end + 1..];
+            return Ok(s);
+        }
+        self.expect("\"")?;
+        let mut s = String::new();
+        loop {
+            if self.rest.starts_with("${") {
+                s.push_str(&self.variable()?);
+                continue;
+            }
+            let mut chars = self.rest.chars();
+            let c = match chars.next() {
+                Some('"') => {
+                    self.rest = chars.as_str();
+                    return Ok(s);
+                }
+                Some('\\') => match chars.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('\n') | None => bail!("unterminated string"),
+                    Some(c) => bail!("unknown escape \\{}", c),
+                },
+                Some('\n') | None => bail!("unterminated string"),
+                Some(c) => c,
+            };
+            s.push(c);
+            self.rest = chars.as_str();
+        }
+    }
+
+    fn eat(&mut self, prefix: &str) -> bool {
+        match self.rest.strip_prefix(prefix) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, prefix: &str) -> Result<()> {
+        if self.eat(prefix) {
+            Ok(())
+        } else {
+            bail!("expected {}", prefix)
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        self.rest = self.rest.trim_start_matches(&[' ', '\t'][..]);
+    }
+
+    /// Skips whitespace, newlines and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_spaces();
+            if self.rest.starts_with('#') {
+                let end = self.rest.find('\n').unwrap_or(self.rest.len());
+                self.rest = &self.rest[end..];
+            } else if self.eat("\r\n") || self.eat("\n") {
+                self.line += 1;
+            } else {
+                return;
+            }
+        }
+    }
+
+    /// Expects nothing but a comment before the end of the line.
+    fn end_of_line(&mut self) -> Result<()> {
+        self.skip_spaces();
+        if self.rest.starts_with('#') {
+            let end = self.rest.find('\n').unwrap_or(self.rest.len());
+            self.rest = &self.rest[end..];
+        }
+        if self.rest.is_empty() || self.eat("\r\n") || self.eat("\n") {
+            self.line += 1;
+            Ok(())
+        } else {
+            bail!("expected the end of the line")
+        }
+    }
+}
+
+/// Parses an integer or boolean.
+fn word(word: &str) -> Result<Value> {
+    Ok(match word {
+        "true" => Value::Boolean(true),
+        "false" => Value::Boolean(false),
+        _ => Value::Integer(
+            word.replace('_', "")
+                .parse()
+                .map_err(|_| anyhow!("expected a value, got {:?}", word))?,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn parse() -> Result<()> {
+        let text = indoc!(
+            r#"
+            # The main capsule.
+            root = "site"
+
+            [listen]
+            port = 1_966  # Not the default.
+            memory_limit = "64M"
+
+            [converter]
+            toc = true
+            glossary = "glossary.txt"
+
+            [content]
+            index = [
+                "index.md",
+                'README.md',
+            ]
+            autoindex = true
+
+            [[vhost]]
+            hostname = "notes.example"
+            root = "/srv/notes"
+            cert = "notes.crt"
+            key = "notes.key"
+
+            [[redirect]]
+            from = "/old.md"
+            to = "/new \"quoted\".md"
+            temporary = true
+            "#
+        );
+        let config = Config::parse(text, Path::new("/etc/exarch"), &no_vars)?;
+        assert_eq!(config.root, Some(PathBuf::from("/etc/exarch/site")));
+        assert_eq!(config.listen.port, Some(1966));
+        assert_eq!(config.listen.memory_limit.as_deref(), Some("64M"));
+        assert_eq!(config.content.index, vec!["index.md", "README.md"]);
+        assert_eq!(config.content.autoindex, Some(true));
+        assert_eq!(config.converter.toc, Some(true));
+        assert_eq!(
+            config.converter.glossary,
+            Some(PathBuf::from("/etc/exarch/glossary.txt"))
+        );
+        assert_eq!(
+            config.vhosts,
+            vec![VirtualHost {
+                hostname: "notes.example".to_owned(),
+                root: PathBuf::from("/srv/notes"),
+                cert: PathBuf::from("/etc/exarch/notes.crt"),
+                key: PathBuf::from("/etc/exarch/notes.key"),
+            }]
+        );
+        assert_eq!(
+            config.redirects,
+            vec![Redirect {
+                from: "/old.md".to_owned(),
+                to: "/new \"quoted\".md".to_owned(),
+                permanent: false,
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn theme() -> Result<()> {
+        let theme = Theme::parse("toc = true\nemphasis = \"strip\"\nfooter = \"=> / Home\\n\"")?;
+        assert_eq!(theme.toc, Some(true));
+        assert_eq!(theme.emphasis.as_deref(), Some("strip"));
+        assert_eq!(theme.footer.as_deref(), Some("=> / Home\n"));
+        assert_eq!(theme.title, None);
+        assert!(Theme::parse("[listen]\nport = 1").is_err());
+        assert!(Theme::parse("colour = \"red\"").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        let error = |text| {
+            let vars = |name: &str| Some(name).filter(|&name| name == "PORT").map(str::to_owned);
+            format!(
+                "{:#}",
+                Config::parse(text, Path::new(""), &vars).unwrap_err()
+            )
+        };
+        assert_eq!(
+            error("[listen]\nport = \"x\""),
+            "port in [listen] should be an integer"
+        );
+        assert_eq!(
+            error("[listen]\nport = 70000"),
+            "port in [listen] should be in range"
+        );
+        assert_eq!(
+            error("[listen]\nprot = 1"),
+            "unknown setting prot in [listen]"
+        );
+        assert_eq!(error("[lisen]"), "unknown section [lisen]");
+        assert_eq!(
+            error("[[vhost]]\nroot = \"x\""),
+            "[[vhost]] is missing hostname"
+        );
+        assert_eq!(error("root = 'a'\nroot = 'b'"), "line 2: defined twice");
+        assert_eq!(error("\n\nroot = \"a"), "line 3: unterminated string");
+        assert_eq!(
+            error("root = \"a\" b"),
+            "line 1: expected the end of the line"
+        );
+        assert_eq!(
+            error("[tls]\ncert = \"${HOST}.pem\""),
+            "line 2: environment variable HOST isn't set"
+        );
+        assert_eq!(
+            error("[listen]\nport = ${PORT}"),
+            "line 2: expected a value, got \"PORT\""
+        );
+        assert_eq!(error("root = \"${A\""), "line 1: unterminated ${");
+    }
+
+    fn no_vars(_: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn profiles() -> Result<()> {
+        let text = indoc!(
+            r#"
+            root = "site"
+
+            [listen]
+            port = 1965
+            hostname = "example.org"
+
+            [profile.staging]
+            root = "staging"
+
+            [profile.staging.listen]
+            port = 1966
+
+            [[profile.staging.redirect]]
+            from = "/a.md"
+            to = "/b.md"
+
+            [profile.dev.tls]
+            cert = "dev.crt"
+            "#
+        );
+        let mut config = Config::parse(text, Path::new("/srv"), &no_vars)?;
+        let staging = config.take_profile("staging")?;
+        assert_eq!(staging.root, Some(PathBuf::from("/srv/staging")));
+        assert_eq!(staging.listen.port, Some(1966));
+        assert_eq!(staging.listen.hostname, None);
+        assert_eq!(staging.redirects.len(), 1);
+        assert_eq!(config.listen.port, Some(1965));
+        assert!(config.redirects.is_empty());
+        let dev = config.take_profile("dev")?;
+        assert_eq!(dev.tls.cert, Some(PathBuf::from("/srv/dev.crt")));
+        assert!(config.take_profile("prod").is_err());
+
+        let error = |text| {
+            format!(
+                "{:#}",
+                Config::parse(text, Path::new(""), &no_vars).unwrap_err()
+            )
+        };
+        assert_eq!(error("[profile.dev.lisen]"), "unknown section [lisen]");
+        assert_eq!(
+            error("[profile.dev]\nprot = 1"),
+            "unknown setting prot in [profile.dev]"
+        );
+        assert_eq!(error("[[profile.dev]]"), "unknown section [[profile.dev]]");
+        Ok(())
+    }
+
+    #[test]
+    fn variables() -> Result<()> {
+        let vars = |name: &str| match name {
+            "HOST" => Some("capsule.example".to_owned()),
+            "PORT" => Some("1966".to_owned()),
+            _ => None,
+        };
+        let text = "root = '${HOST}'\n[listen]\nport = ${PORT}\nhostname = \"${HOST}\"\n";
+        let config = Config::parse(text, Path::new("/srv"), &vars)?;
+        assert_eq!(config.root, Some(PathBuf::from("/srv/${HOST}")));
+        assert_eq!(config.listen.port, Some(1966));
+        assert_eq!(config.listen.hostname.as_deref(), Some("capsule.example"));
+        Ok(())
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::{anyhow, bail, Context, Result};
+use exarch::flags::ConverterFlags;
+use exarch::output::Registry;
+use exarch::{ansi, gemmark, markgem};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct ConvertOpt {
+    /// The format of the input, either markdown or gemini.
+    #[structopt(long, default_value = "markdown")]
+    from: Input,
+
+    /// The format to convert to. Markdown can be converted to gemini, html, text, gophermap, or
+    /// ansi for Gemtext colored for a terminal. Gemini can be converted to markdown or ansi.
+    #[structopt(long, default_value = "gemini")]
+    to: String,
+
+    /// The file to convert. If it's omitted, the input is read from stdin.
+    #[structopt(parse(from_os_str))]
+    file: Option<PathBuf>,
+
+    /// Where to write the result. If it's omitted, the result is written to stdout.
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Fail instead of converting if an image or preformatted block has no alt text. Either way,
+    /// they're reported on stderr.
+    #[structopt(long)]
+    require_alt_text: bool,
+
+    /// The URL of the same page on the other mirror, linked at the end of the output: the web
+    /// version when converting to Gemini or a format made from it, and the Gemini version when
+    /// converting to HTML.
+    #[structopt(long)]
+    mirror_url: Option<String>,
+
+    #[structopt(flatten)]
+    converter: ConverterFlags,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Input {
+    Markdown,
+    Gemini,
+}
+
+impl FromStr for Input {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "markdown" | "md" => Ok(Input::Markdown),
+            "gemini" | "gemtext" | "gmi" => Ok(Input::Gemini),
+            _ => Err(anyhow!("unknown format {}", s)),
+        }
+    }
+}
+
+pub fn convert(options: ConvertOpt) -> Result<()> {
+    let input = match &options.file {
+        Some(file) => std::fs::read_to_string(file)
+            .with_context(|| format!("failed to read {}", file.display()))?,
+        None => {
+            let mut input = String::new();
+            io::stdin()
+                .read_to_string(&mut input)
+                .context("failed to read stdin")?;
+            input
+        }
+    };
+    if options.from == Input::Markdown {
+        check_alt_text(&options, &input)?;
+    }
+    let stdout = io::stdout();
+    let mut out: Box<dyn Write> = match &options.output {
+        Some(output) => {
+            Box::new(BufWriter::new(File::create(output).with_context(|| {
+                format!("failed to create {}", output.display())
+            })?))
+        }
+        None => Box::new(stdout.lock()),
+    };
+    match (options.from, options.to.as_str()) {
+        (Input::Markdown, to) => {
+            let registry = Registry::new();
+            let format = registry.get(to).with_context(|| {
+                let names: Vec<_> = registry.names().collect();
+                format!(
+                    "can't convert Markdown to {}, only to {}",
+                    to,
+                    names.join(", ")
+                )
+            })?;
+            let mut converter_options = options.converter.options()?;
+            if let Some(mirror_url) = &options.mirror_url {
+                converter_options = converter_options.mirror_url(mirror_url.as_str());
+            }
+            format.convert(&input, &converter_options, &mut out)?
+        }
+        (Input::Gemini, "markdown") | (Input::Gemini, "md") => {
+            out.write_all(gemmark::to_markdown(&input).as_bytes())?
+        }
+        (Input::Gemini, "ansi") => out.write_all(ansi::colorize(&input).as_bytes())?,
+        (from, to) => bail!("can't convert from {:?} to {}", from, to),
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Reports images and preformatted blocks without alt text, failing if they're required.
+fn check_alt_text(options: &ConvertOpt, markdown: &str) -> Result<()> {
+    let lints = markgem::missing_alt_text(markdown);
+    let name = match &options.file {
+        Some(file) => file.display().to_string(),
+        None => "<stdin>".to_owned(),
+    };
+    for lint in &lints {
+        eprintln!("{}: {}", name, lint);
+    }
+    if options.require_alt_text && !lints.is_empty() {
+        bail!("{} is missing alt text in {} places", name, lints.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,60 @@
+//! Running in the background without a service manager.
+
+use anyhow::Result;
+use std::path::Path;
+
+/// Forks into the background and detaches from the controlling terminal, writing the PID of the
+/// background process to `pid_file` if one was given. Only the background process returns.
+///
+/// This has to be called before any threads are started, since only the calling thread survives a
+/// fork.
+#[cfg(unix)]
+pub fn daemonize(pid_file: Option<&Path>) -> Result<()> {
+    use anyhow::Context;
+    use std::fs::{File, OpenOptions};
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+
+    // Open the pid file up front so that failures can still be reported to the terminal.
+    let mut pid_file = pid_file
+        .map(|path| {
+            File::create(path)
+                .with_context(|| format!("failed to create pid file {}", path.display()))
+        })
+        .transpose()?;
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(std::io::Error::last_os_error()).context("setsid failed");
+    }
+    // Fork again so that the daemon isn't a session leader and can never acquire a terminal.
+    fork_and_exit_parent()?;
+    let dev_null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("failed to open /dev/null")?;
+    for fd in 0..=2 {
+        if unsafe { libc::dup2(dev_null.as_raw_fd(), fd) } == -1 {
+            return Err(std::io::Error::last_os_error()).context("failed to redirect stdio");
+        }
+    }
+    if let Some(pid_file) = &mut pid_file {
+        writeln!(pid_file, "{}", std::process::id()).context("failed to write pid file")?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn fork_and_exit_parent() -> Result<()> {
+    use anyhow::Context;
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()).context("fork failed"),
+        0 => Ok(()),
+        _ => std::process::exit(0),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_pid_file: Option<&Path>) -> Result<()> {
+    anyhow::bail!("--daemon is only supported on Unix; use a service manager instead")
+}
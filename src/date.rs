@@ -0,0 +1,145 @@
+//! Just enough calendar handling for dating posts and entries, without pulling in a date crate.
+//! Everything is in UTC.
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A day in the proleptic Gregorian calendar.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    /// Today's date in UTC.
+    pub fn today() -> Self {
+        Self::from_days(unix_seconds().div_euclid(86400))
+    }
+
+    /// Parses the date at the start of a string like `2020-07-14` or `2020-07-14T12:00:00Z`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("invalid date {}", s);
+        let date = s.get(..10).ok_or_else(invalid)?;
+        let mut parts = date.splitn(3, '-');
+        let mut next = || parts.next().ok_or_else(invalid);
+        let year = next()?.parse().map_err(|_| invalid())?;
+        let month = next()?.parse().map_err(|_| invalid())?;
+        let day = next()?.parse().map_err(|_| invalid())?;
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return Err(invalid());
+        }
+        Ok(Self { year, month, day })
+    }
+
+    /// Converts a count of days since 1970-01-01. This is Howard Hinnant's `civil_from_days`.
+    pub fn from_days(days: i64) -> Self {
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as i32;
+        Self { year, month, day }
+    }
+
+    /// The count of days since 1970-01-01. This is Howard Hinnant's `days_from_civil`, the
+    /// inverse of `from_days`.
+    pub fn to_days(&self) -> i64 {
+        let year = i64::from(self.year) - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let month = i64::from(self.month);
+        let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5
+            + i64::from(self.day)
+            - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146_097 + doe - 719_468
+    }
+
+    /// Whole years from this date until the other one.
+    pub fn years_until(&self, other: &Date) -> i32 {
+        let years = other.year - self.year;
+        if (other.month, other.day) < (self.month, self.day) {
+            years - 1
+        } else {
+            years
+        }
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+/// Formats seconds since the Unix epoch like `2020-07-14 08:05 UTC`.
+pub fn format_timestamp(seconds: i64) -> String {
+    let minutes = seconds.div_euclid(60);
+    format!(
+        "{} {:02}:{:02} UTC",
+        Date::from_days(seconds.div_euclid(86400)),
+        minutes.div_euclid(60).rem_euclid(24),
+        minutes.rem_euclid(60)
+    )
+}
+
+/// The current time as seconds since the Unix epoch.
+pub fn unix_seconds() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_days() {
+        assert_eq!(Date::from_days(0).to_string(), "1970-01-01");
+        assert_eq!(Date::from_days(11016).to_string(), "2000-02-29");
+        assert_eq!(Date::from_days(18457).to_string(), "2020-07-14");
+        assert_eq!(Date::from_days(-1).to_string(), "1969-12-31");
+    }
+
+    #[test]
+    fn to_days() {
+        for days in &[-1, 0, 59, 11016, 18457, 57000] {
+            assert_eq!(Date::from_days(*days).to_days(), *days);
+        }
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00 UTC");
+        assert_eq!(
+            format_timestamp(18457 * 86400 + 8 * 3600 + 5 * 60 + 59),
+            "2020-07-14 08:05 UTC"
+        );
+    }
+
+    #[test]
+    fn parse() -> Result<()> {
+        assert_eq!(Date::parse("2020-07-14")?, Date::from_days(18457));
+        assert_eq!(Date::parse("2020-07-14T08:00:00Z")?, Date::from_days(18457));
+        assert!(Date::parse("2020-13-01").is_err());
+        assert!(Date::parse("July 14").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn years_until() -> Result<()> {
+        let date = Date::parse("2015-06-15")?;
+        assert_eq!(date.years_until(&Date::parse("2020-06-14")?), 4);
+        assert_eq!(date.years_until(&Date::parse("2020-06-15")?), 5);
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+//! Deploying a site to a directory on the same machine without readers ever seeing it half
+//! written. Each deploy copies the tree into a new release directory named after the time, then
+//! swaps a `current` symlink over to it in one step. `exarch serve DIR/current` follows the swap.
+
+use anyhow::{bail, Context, Result};
+use exarch::atomic;
+use exarch::date::{self, Date};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct DeployOpt {
+    /// The tree of Markdown files to deploy.
+    #[structopt(parse(from_os_str))]
+    source: PathBuf,
+
+    /// The directory to deploy to. Releases go in its `releases` directory, and `current` links to
+    /// the latest one.
+    #[structopt(parse(from_os_str))]
+    target: PathBuf,
+
+    /// How many releases to keep, counting the new one. Older ones are removed.
+    #[structopt(long, default_value = "5")]
+    keep: usize,
+}
+
+pub fn deploy(options: DeployOpt) -> Result<()> {
+    if options.keep == 0 {
+        bail!("--keep has to be at least 1, to keep the new release");
+    }
+    let release = deploy_to(&options.source, &options.target, options.keep)?;
+    println!(
+        "Deployed {} to {}",
+        options.source.display(),
+        release.display()
+    );
+    Ok(())
+}
+
+/// Copies the source into a new release, makes it current and removes all but the newest `keep`
+/// releases. Returns the new release's directory.
+fn deploy_to(source: &Path, target: &Path, keep: usize) -> Result<PathBuf> {
+    let releases = target.join("releases");
+    std::fs::create_dir_all(&releases)
+        .with_context(|| format!("failed to create {}", releases.display()))?;
+    let timestamp = release_name(date::unix_seconds());
+    let name = (0..)
+        .map(|n| match n {
+            0 => timestamp.clone(),
+            n => format!("{}.{}", timestamp, n),
+        })
+        .find(|name| !releases.join(name).exists())
+        .unwrap();
+    let release = releases.join(&name);
+
+    // Copied under a hidden name first, so that an interrupted copy is never taken for a release.
+    let partial = releases.join(format!(".{}.partial", name));
+    if partial.exists() {
+        std::fs::remove_dir_all(&partial)?;
+    }
+    copy_tree(source, &partial)?;
+    std::fs::rename(&partial, &release)
+        .with_context(|| format!("failed to create {}", release.display()))?;
+
+    // Relative, so that the deploy directory can be moved as a whole.
+    let current = target.join("current");
+    atomic::symlink(&Path::new("releases").join(&name), &current)
+        .with_context(|| format!("failed to point {} at the release", current.display()))?;
+
+    let mut old = std::fs::read_dir(&releases)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    old.retain(|name| !name.to_string_lossy().starts_with('.'));
+    old.sort();
+    old.truncate(old.len().saturating_sub(keep));
+    for name in old {
+        let path = releases.join(name);
+        std::fs::remove_dir_all(&path)
+            .with_context(|| format!("failed to remove {}", path.display()))?;
+    }
+    Ok(release)
+}
+
+/// Names a release after the time, like `2020-07-14T080512`, so that they sort in order.
+fn release_name(seconds: i64) -> String {
+    let of_day = seconds.rem_euclid(86400);
+    format!(
+        "{}T{:02}{:02}{:02}",
+        Date::from_days(seconds.div_euclid(86400)),
+        of_day / 3600,
+        of_day / 60 % 60,
+        of_day % 60
+    )
+}
+
+fn copy_tree(from: &Path, to: &Path) -> Result<()> {
+    std::fs::create_dir_all(to).with_context(|| format!("failed to create {}", to.display()))?;
+    let entries =
+        std::fs::read_dir(from).with_context(|| format!("failed to read {}", from.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let copy = to.join(path.file_name().unwrap_or_default());
+        if path.is_dir() {
+            copy_tree(&path, &copy)?;
+        } else {
+            std::fs::copy(&path, &copy)
+                .with_context(|| format!("failed to copy {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(release_name(0), "1970-01-01T000000");
+        assert_eq!(release_name(1594713912), "2020-07-14T080512");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn deploys() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("exarch-deploy-{}", std::process::id()));
+        let source = dir.join("source");
+        std::fs::create_dir_all(source.join("blog"))?;
+        std::fs::write(source.join("index.md"), "one")?;
+        std::fs::write(source.join("blog/post.md"), "post")?;
+        let target = dir.join("target");
+
+        let first = deploy_to(&source, &target, 2)?;
+        assert_eq!(
+            std::fs::read_to_string(target.join("current/blog/post.md"))?,
+            "post"
+        );
+        std::fs::write(source.join("index.md"), "two")?;
+        let second = deploy_to(&source, &target, 2)?;
+        assert_ne!(first, second);
+        assert_eq!(
+            std::fs::read_to_string(target.join("current/index.md"))?,
+            "two"
+        );
+        assert!(first.exists());
+
+        deploy_to(&source, &target, 2)?;
+        assert!(!first.exists());
+        assert!(second.exists());
+        assert_eq!(std::fs::read_dir(target.join("releases"))?.count(), 2);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
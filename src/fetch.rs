@@ -0,0 +1,322 @@
+//! Fetching pages from Gemini servers. Everything in exarch that makes requests goes through a
+//! `Fetcher`, so that they share TLS sessions, wait between requests to the same host instead of
+//! hammering it, and give up on servers that don't answer.
+//!
+//! Server certificates are only checked if the fetcher is given known hosts to check them
+//! against. Otherwise any certificate is accepted, which is only suitable for talking to servers
+//! you control.
+
+use crate::known_hosts::{self, Check, KnownHosts};
+use anyhow::{anyhow, bail, Context, Result};
+use async_std::future;
+use async_std::io::prelude::*;
+use async_std::io::{Read, Write};
+use async_std::net::TcpStream;
+use async_std::task;
+use async_tls::TlsConnector;
+use rustls::{
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerCertVerified, ServerCertVerifier,
+    TLSError,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// The longest a request may take, from connecting to reading the last byte, by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The time to leave between requests to the same host by default.
+pub const DEFAULT_POLITENESS: Duration = Duration::from_secs(1);
+
+/// Accepts any certificate.
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Checks certificates against the known hosts, trusting those of hosts that haven't been seen
+/// before.
+struct TrustOnFirstUse(Arc<Mutex<KnownHosts>>);
+
+impl ServerCertVerifier for TrustOnFirstUse {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let cert = presented_certs
+            .first()
+            .ok_or(TLSError::NoCertificatesPresented)?;
+        let host: &str = dns_name.into();
+        let fingerprint = known_hosts::fingerprint(cert);
+        let mut known_hosts = self
+            .0
+            .lock()
+            .map_err(|_| TLSError::General("known hosts are unavailable".to_owned()))?;
+        match known_hosts.check(host, &fingerprint) {
+            Check::Known | Check::FirstUse => Ok(ServerCertVerified::assertion()),
+            Check::Changed { known } => Err(TLSError::General(format!(
+                "the certificate of {} changed from {} to {}; if that's expected, trust it with \
+                 `exarch tofu trust {}`",
+                host, known, fingerprint, host
+            ))),
+        }
+    }
+}
+
+/// Accepts any certificate, keeping the fingerprint of the one presented.
+#[derive(Default)]
+struct CapturedFingerprint(Mutex<Option<String>>);
+
+impl ServerCertVerifier for CapturedFingerprint {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: webpki::DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        if let (Some(cert), Ok(mut captured)) = (presented_certs.first(), self.0.lock()) {
+            *captured = Some(known_hosts::fingerprint(cert));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Connects to the server just to find the fingerprint of its certificate, without checking it.
+pub async fn server_fingerprint(host: &str, port: u16) -> Result<String> {
+    let captured = Arc::new(CapturedFingerprint::default());
+    let mut config = ClientConfig::new();
+    config
+        .dangerous()
+        .set_certificate_verifier(captured.clone());
+    let connect = async {
+        let stream = TcpStream::connect((host, port))
+            .await
+            .with_context(|| format!("failed to connect to {}:{}", host, port))?;
+        TlsConnector::from(config)
+            .connect(host, stream)
+            .await
+            .context("TLS handshake failed")
+    };
+    future::timeout(DEFAULT_TIMEOUT, connect)
+        .await
+        .map_err(|_| anyhow!("{}:{} didn't respond in time", host, port))??;
+    let fingerprint = captured
+        .0
+        .lock()
+        .ok()
+        .and_then(|mut captured| captured.take());
+    fingerprint.context("server presented no certificate")
+}
+
+/// A response from a Gemini server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u8,
+    pub meta: String,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Parses a whole response, header included.
+    pub fn parse(response: &[u8]) -> Result<Self> {
+        let end = response
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .context("response has no header")?;
+        let header = std::str::from_utf8(&response[..end]).context("header isn't UTF-8")?;
+        let (status, meta) = match header.find(' ') {
+            Some(space) => (&header[..space], &header[space + 1..]),
+            None => (header, ""),
+        };
+        if status.len() != 2 || !status.bytes().all(|b| b.is_ascii_digit()) {
+            bail!("invalid header {}", header);
+        }
+        Ok(Self {
+            status: status.parse()?,
+            meta: meta.to_owned(),
+            body: response[end + 2..].to_vec(),
+        })
+    }
+}
+
+/// Makes requests to Gemini servers. Meant to be made once and shared, since the politeness delay
+/// only applies between requests made through the same fetcher.
+pub struct Fetcher {
+    config: Arc<ClientConfig>,
+    timeout: Duration,
+    politeness: Duration,
+    // When the next request to each host may start.
+    next_request: Mutex<HashMap<String, Instant>>,
+}
+
+impl Default for Fetcher {
+    fn default() -> Self {
+        let mut config = ClientConfig::new();
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoVerification));
+        Self {
+            config: Arc::new(config),
+            timeout: DEFAULT_TIMEOUT,
+            politeness: DEFAULT_POLITENESS,
+            next_request: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Fetcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long a request may take, from connecting to reading the last byte, before it fails.
+    /// Defaults to `DEFAULT_TIMEOUT`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long to wait between starting requests to the same host. Defaults to
+    /// `DEFAULT_POLITENESS`.
+    pub fn politeness(mut self, politeness: Duration) -> Self {
+        self.politeness = politeness;
+        self
+    }
+
+    /// Checks servers' certificates against the known hosts, adding hosts that haven't been seen
+    /// before. Requests to a host whose certificate changed fail.
+    pub fn known_hosts(mut self, known_hosts: Arc<Mutex<KnownHosts>>) -> Self {
+        let mut config = (*self.config).clone();
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(TrustOnFirstUse(known_hosts)));
+        self.config = Arc::new(config);
+        self
+    }
+
+    /// Presents the given client certificate chain and key to servers.
+    pub fn identity(mut self, certs: Vec<Certificate>, key: PrivateKey) -> Result<Self> {
+        let mut config = (*self.config).clone();
+        config
+            .set_single_client_cert(certs, key)
+            .context("failed to use client certificate")?;
+        self.config = Arc::new(config);
+        Ok(self)
+    }
+
+    /// Fetches the Gemini URL.
+    pub async fn fetch(&self, url: &Url) -> Result<Response> {
+        if url.scheme() != "gemini" {
+            bail!("can only fetch Gemini URLs, not {}", url);
+        }
+        let host = url.host_str().context("URL has no host")?;
+        let port = url.port().unwrap_or(1965);
+        let response = self
+            .request(host, port, format!("{}\r\n", url).as_bytes())
+            .await?;
+        Response::parse(&response)
+    }
+
+    /// Sends the raw request to the server at the given host and port, returning the whole
+    /// response, header included.
+    pub async fn request(&self, host: &str, port: u16, request: &[u8]) -> Result<Vec<u8>> {
+        self.wait_for_turn(host).await;
+        future::timeout(self.timeout, self.request_now(host, port, request))
+            .await
+            .map_err(|_| anyhow!("{}:{} didn't respond in time", host, port))?
+    }
+
+    async fn request_now(&self, host: &str, port: u16, request: &[u8]) -> Result<Vec<u8>> {
+        let stream = TcpStream::connect((host, port))
+            .await
+            .with_context(|| format!("failed to connect to {}:{}", host, port))?;
+        self.request_on(host, stream, request).await
+    }
+
+    /// Like `request`, but over a stream that's already connected, like a Unix socket, and
+    /// without waiting or timing out.
+    pub async fn request_on<S: Read + Write + Unpin>(
+        &self,
+        host: &str,
+        stream: S,
+        request: &[u8],
+    ) -> Result<Vec<u8>> {
+        let connector = TlsConnector::from(self.config.clone());
+        let mut stream = connector
+            .connect(host, stream)
+            .await
+            .context("TLS handshake failed")?;
+        stream.write_all(request).await?;
+        stream.flush().await?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await?;
+        Ok(response)
+    }
+
+    /// Waits until the politeness delay since the last request to the host has passed. The slot
+    /// is claimed before waiting, so concurrent requests to a host are spaced out too.
+    async fn wait_for_turn(&self, host: &str) {
+        let host = host.to_ascii_lowercase();
+        let now = Instant::now();
+        let start = {
+            let mut next_request = self.next_request.lock().unwrap();
+            let start = match next_request.get(&host) {
+                Some(&next) if next > now => next,
+                _ => now,
+            };
+            next_request.insert(host, start + self.politeness);
+            start
+        };
+        if start > now {
+            task::sleep(start - now).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_response() -> Result<()> {
+        assert_eq!(
+            Response::parse(b"20 text/gemini\r\n# Hi\r\n")?,
+            Response {
+                status: 20,
+                meta: "text/gemini".to_owned(),
+                body: b"# Hi\r\n".to_vec(),
+            }
+        );
+        assert_eq!(Response::parse(b"51\r\n")?.meta, "");
+        assert!(Response::parse(b"20 text/gemini").is_err());
+        assert!(Response::parse(b"2 text/gemini\r\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn politeness() {
+        let fetcher = Fetcher::new().politeness(Duration::from_millis(50));
+        let start = Instant::now();
+        task::block_on(async {
+            fetcher.wait_for_turn("example.org").await;
+            fetcher.wait_for_turn("other.example").await;
+            assert!(start.elapsed() < Duration::from_millis(50));
+            fetcher.wait_for_turn("example.org").await;
+        });
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+}
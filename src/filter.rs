@@ -0,0 +1,289 @@
+//! Post-processing of the Gemtext the server sends, configured per path, for things like a
+//! banner on every page of a section or plain ASCII for readers with limited terminals.
+
+use crate::access;
+use anyhow::{anyhow, Result};
+use async_std::io::prelude::*;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A change made to Gemtext responses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Filter {
+    /// Appends a line of text to the end of the page.
+    Banner(String),
+    /// Removes emoji.
+    StripEmoji,
+    /// Replaces characters outside ASCII with ASCII lookalikes, or `?` if there isn't one.
+    Ascii,
+}
+
+/// Parses a filter given on the command line, as /PREFIX=FILTER, where FILTER is
+/// `banner:TEXT`, `strip-emoji` or `ascii`.
+pub fn parse_filter(s: &str) -> Result<(String, Filter)> {
+    let mut parts = s.splitn(2, '=');
+    let (prefix, filter) = match (parts.next(), parts.next()) {
+        (Some(prefix), Some(filter)) if prefix.starts_with('/') => (prefix, filter),
+        _ => return Err(anyhow!("expected /PREFIX=FILTER, got {}", s)),
+    };
+    let filter = match filter {
+        "strip-emoji" => Filter::StripEmoji,
+        "ascii" => Filter::Ascii,
+        _ => match filter.strip_prefix("banner:") {
+            Some(text) => Filter::Banner(text.to_owned()),
+            None => return Err(anyhow!("unknown filter {}", filter)),
+        },
+    };
+    Ok((prefix.to_owned(), filter))
+}
+
+/// The filters to apply under each path prefix.
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+    rules: Vec<(String, Filter)>,
+}
+
+impl Filters {
+    pub fn new(rules: &[(String, Filter)]) -> Self {
+        Self {
+            rules: rules.to_vec(),
+        }
+    }
+
+    /// The filters for the path, in the order they were given. Every prefix covering the path
+    /// counts, so a banner for `/` and one for `/blog` are both added to blog posts.
+    pub fn for_path(&self, path: &str) -> Vec<&Filter> {
+        self.rules
+            .iter()
+            .filter(|(prefix, _)| access::covers(prefix, path))
+            .map(|(_, filter)| filter)
+            .collect()
+    }
+}
+
+/// Applies filters to everything written through it. `finish` must be called once everything has
+/// been written, to add any banners.
+pub struct Filtered<'a, W> {
+    inner: W,
+    filters: Vec<&'a Filter>,
+    // The end of a character split between writes.
+    partial: Vec<u8>,
+    // Filtered output that the inner writer hasn't taken yet.
+    pending: Vec<u8>,
+}
+
+impl<'a, W: Write + Unpin> Filtered<'a, W> {
+    pub fn new(inner: W, filters: Vec<&'a Filter>) -> Self {
+        Self {
+            inner,
+            filters,
+            partial: vec![],
+            pending: vec![],
+        }
+    }
+
+    /// Writes the banners and flushes everything through.
+    pub async fn finish(mut self) -> io::Result<()> {
+        let mut banners = String::new();
+        for filter in &self.filters {
+            if let Filter::Banner(text) = filter {
+                banners.push_str(&format!("\n\n{}\n", text));
+            }
+        }
+        let banners = self.filter(banners.as_bytes());
+        self.pending.extend_from_slice(&banners);
+        self.flush().await
+    }
+
+    /// Filters the text, holding back the start of a character that isn't complete yet.
+    fn filter(&mut self, buf: &[u8]) -> Vec<u8> {
+        self.partial.extend_from_slice(buf);
+        let valid = match std::str::from_utf8(&self.partial) {
+            Ok(text) => text.len(),
+            // A character cut off at the end will be completed by the next write, but invalid
+            // UTF-8 anywhere else never will be, so it's passed through.
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return std::mem::take(&mut self.partial),
+        };
+        let rest = self.partial.split_off(valid);
+        let text = String::from_utf8(std::mem::replace(&mut self.partial, rest)).unwrap();
+        let mut filtered = String::with_capacity(text.len());
+        for c in text.chars() {
+            if self.filters.contains(&&Filter::StripEmoji) && is_emoji(c) {
+                continue;
+            }
+            if self.filters.contains(&&Filter::Ascii) && !c.is_ascii() {
+                filtered.push_str(transliterate(c));
+                continue;
+            }
+            filtered.push(c);
+        }
+        filtered.into_bytes()
+    }
+
+    /// Writes as much pending output as the inner writer will take.
+    fn poll_pending(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, &self.pending) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(written)) => {
+                    self.pending.drain(..written);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: Write + Unpin> Write for Filtered<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        if !this.pending.is_empty() {
+            return Poll::Pending;
+        }
+        let filtered = this.filter(buf);
+        this.pending = filtered;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            poll => poll,
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_close(cx),
+            poll => poll,
+        }
+    }
+}
+
+/// Whether the character is an emoji, or only makes sense as part of one, like the selector that
+/// asks for a character to be shown as an emoji.
+fn is_emoji(c: char) -> bool {
+    matches!(c as u32,
+        0x1F000..=0x1FAFF
+        | 0x2600..=0x27BF
+        | 0x2B50 | 0x2B55
+        | 0x200D
+        | 0xFE0F
+        | 0xE0020..=0xE007F)
+}
+
+/// An ASCII stand-in for the character.
+fn transliterate(c: char) -> &'static str {
+    match c {
+        'À'..='Å' => "A",
+        'à'..='å' => "a",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Ç' => "C",
+        'ç' => "c",
+        'È'..='Ë' => "E",
+        'è'..='ë' => "e",
+        'Ì'..='Ï' => "I",
+        'ì'..='ï' => "i",
+        'Ð' => "D",
+        'ð' => "d",
+        'Ñ' => "N",
+        'ñ' => "n",
+        'Ò'..='Ö' | 'Ø' => "O",
+        'ò'..='ö' | 'ø' => "o",
+        'Ù'..='Ü' => "U",
+        'ù'..='ü' => "u",
+        'Ý' => "Y",
+        'ý' | 'ÿ' => "y",
+        'Þ' => "Th",
+        'þ' => "th",
+        'ß' => "ss",
+        'Œ' => "OE",
+        'œ' => "oe",
+        'Š' => "S",
+        'š' => "s",
+        'Ž' => "Z",
+        'ž' => "z",
+        'Ł' => "L",
+        'ł' => "l",
+        '‘' | '’' | '‚' | '′' => "'",
+        '“' | '”' | '„' | '″' | '«' | '»' => "\"",
+        '‐' | '‑' | '‒' | '–' | '−' => "-",
+        '—' | '―' => "--",
+        '…' => "...",
+        '•' | '·' => "*",
+        '→' => "->",
+        '←' => "<-",
+        '×' => "x",
+        '©' => "(c)",
+        '®' => "(R)",
+        '™' => "(TM)",
+        '\u{a0}' | '\u{2002}'..='\u{200a}' | '\u{202f}' => " ",
+        '\u{200b}' | '\u{ad}' => "",
+        _ => "?",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::task;
+
+    fn filtered(filters: &[Filter], chunks: &[&[u8]]) -> io::Result<String> {
+        task::block_on(async {
+            let mut out = vec![];
+            let mut writer = Filtered::new(&mut out, filters.iter().collect());
+            for chunk in chunks {
+                writer.write_all(chunk).await?;
+            }
+            writer.finish().await?;
+            Ok(String::from_utf8(out).unwrap())
+        })
+    }
+
+    #[test]
+    fn filters() -> io::Result<()> {
+        let text = "Café — ok 👍🏽!\n".as_bytes();
+        // Split in the middle of the "é" and the emoji.
+        let chunks = [&text[..4], &text[4..15], &text[15..]];
+        assert_eq!(
+            filtered(&[Filter::StripEmoji, Filter::Ascii], &chunks)?,
+            "Cafe -- ok !\n"
+        );
+        assert_eq!(filtered(&[Filter::Ascii], &chunks)?, "Cafe -- ok ??!\n");
+        assert_eq!(
+            filtered(&[Filter::Banner("Bye".to_owned())], &[b"# Hi"])?,
+            "# Hi\n\nBye\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn filters_for_paths() -> Result<()> {
+        let filters = Filters::new(&[
+            parse_filter("/=banner:Hello")?,
+            parse_filter("/blog=ascii")?,
+        ]);
+        assert_eq!(filters.for_path("/about.md").len(), 1);
+        assert_eq!(
+            filters.for_path("/blog/post.md"),
+            vec![&Filter::Banner("Hello".to_owned()), &Filter::Ascii]
+        );
+        assert!(parse_filter("/=shout").is_err());
+        assert!(parse_filter("blog=ascii").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+//! Command-line flags shared between subcommands.
+
+use crate::config::{Converter, Theme};
+use crate::markgem::{
+    AdmonitionStyle, Bibliography, ConverterOptions, EmphasisStyle, HeadingOverflow, SchemeHandling,
+};
+use anyhow::{anyhow, Context, Result};
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+/// Flags controlling how Markdown is converted to Gemini.
+#[derive(Debug, StructOpt)]
+pub struct ConverterFlags {
+    /// End the converted output with a newline.
+    #[structopt(long)]
+    trailing_newline: bool,
+
+    /// Hard-wrap text lines at this many columns. Zero means lines aren't wrapped.
+    #[structopt(long, default_value = "0")]
+    wrap: usize,
+
+    /// What to write before a paragraph that starts with Gemtext syntax like `=> ` or `# `.
+    /// Defaults to a zero-width space.
+    #[structopt(long)]
+    line_escape: Option<String>,
+
+    /// Put a "—" line between the summary of a <details> block and its body.
+    #[structopt(long)]
+    details_separator: bool,
+
+    /// Insert a list of each page's sections after its title.
+    #[structopt(long)]
+    toc: bool,
+
+    /// Add a heading with the title from each page's front matter.
+    #[structopt(long)]
+    title: bool,
+
+    /// Put the date from each page's front matter under the title added by --title.
+    #[structopt(long, requires = "title")]
+    date: bool,
+
+    /// Add a notice to the top of pages whose front matter date is at least this many years ago.
+    /// Pages can set their own threshold with `aging_notice = N` in their front matter, and when
+    /// serving, so can sections in their `_index.md`. Zero means no notice is added.
+    #[structopt(long, default_value = "0")]
+    aging_notice: u32,
+
+    /// How to write emphasis, strong emphasis and strikethrough: keep their "markers" as in
+    /// Markdown, "strip" them, write the text in "caps", or in "unicode" italic and bold letters.
+    #[structopt(long, default_value = "markers")]
+    emphasis: EmphasisStyle,
+
+    /// What to do with headings deeper than level three: "clamp" them to level three, write them
+    /// as "bold" text, or prefix them with their "depth".
+    #[structopt(long, default_value = "clamp")]
+    heading_overflow: HeadingOverflow,
+
+    /// Number headings by their place in the document, like "1. Intro" and "1.1 Background".
+    #[structopt(long)]
+    number_headings: bool,
+
+    /// Pass hand-written Gemtext in `gemtext` code blocks or between <!-- gemini:raw --> and
+    /// <!-- /gemini:raw --> lines through unchanged.
+    #[structopt(long)]
+    raw_gemtext: bool,
+
+    /// Put the host that links to other sites go to after them, like "(sr.ht)".
+    #[structopt(long)]
+    link_hosts: bool,
+
+    /// How to write links with a scheme, as SCHEME=HANDLING: as a "link" line, with the part after
+    /// the scheme "inline" after the link's text, or "drop"ped. For example, mailto=inline shows
+    /// email addresses instead of linking to them. Can be given multiple times.
+    #[structopt(
+        long = "scheme",
+        parse(try_from_str = parse_scheme),
+        number_of_values = 1
+    )]
+    schemes: Vec<(String, SchemeHandling)>,
+
+    /// Don't accept ~~strikethrough~~.
+    #[structopt(long)]
+    no_strikethrough: bool,
+
+    /// Accept GitHub-style tables.
+    #[structopt(long)]
+    tables: bool,
+
+    /// Accept footnotes.
+    #[structopt(long)]
+    footnotes: bool,
+
+    /// Accept `- [ ]` task list items.
+    #[structopt(long)]
+    tasklists: bool,
+
+    /// Convert definition lists into a bolded term followed by indented definitions.
+    #[structopt(long)]
+    definition_lists: bool,
+
+    /// Convert wiki-style [[Page Name]] links into links to the page's .gmi file.
+    #[structopt(long)]
+    wiki_links: bool,
+
+    /// Turn inline annotations like {^ an aside} into numbered notes listed at the end of their
+    /// section.
+    #[structopt(long)]
+    sidenotes: bool,
+
+    /// Render admonitions like `> [!NOTE]` and `:::warning` as a quote headed by a label line.
+    #[structopt(long)]
+    admonitions: bool,
+
+    /// How to write the label line of an admonition: "emoji" or "label".
+    #[structopt(long, default_value = "emoji")]
+    admonition_style: AdmonitionStyle,
+
+    /// A template for a Zola shortcode, as NAME=TEMPLATE. In the template, {key} is replaced by
+    /// the shortcode's `key` argument and {body} by its body. Can be given multiple times.
+    #[structopt(
+        long = "shortcode",
+        parse(try_from_str = parse_shortcode),
+        number_of_values = 1
+    )]
+    shortcodes: Vec<(String, String)>,
+
+    /// A file of abbreviations to expand the first time they appear on each page, one per line in
+    /// Markdown Extra's syntax: `*[TLS]: Transport Layer Security`.
+    #[structopt(long, parse(from_os_str))]
+    abbreviations: Option<PathBuf>,
+
+    /// A glossary file of terms to link the first time they appear on each page, one per line like
+    /// a Markdown link reference definition: `[Gemini]: /wiki/gemini.gmi`.
+    #[structopt(long, parse(from_os_str))]
+    glossary: Option<PathBuf>,
+
+    /// A BibTeX file of works that can be cited with Pandoc-style citations like [@key].
+    #[structopt(long, parse(from_os_str))]
+    bibliography: Option<PathBuf>,
+
+    // Gemtext added to the end of every page, which only themes set.
+    #[structopt(skip)]
+    footer: Option<String>,
+}
+
+impl ConverterFlags {
+    /// Takes the theme's settings, except for those whose flags were given.
+    pub fn apply_theme(&mut self, theme: &Theme, given: impl Fn(&str) -> bool) -> Result<()> {
+        self.apply_config(&theme.converter(), given)?;
+        self.footer = theme.footer.clone();
+        Ok(())
+    }
+
+    /// Takes the settings from the config's `[converter]` section, except for those whose flags
+    /// were given.
+    pub fn apply_config(&mut self, config: &Converter, given: impl Fn(&str) -> bool) -> Result<()> {
+        fn set<T>(field: &mut T, value: Option<T>, given: bool) {
+            match value {
+                Some(value) if !given => *field = value,
+                _ => {}
+            }
+        }
+        fn set_list<T>(
+            field: &mut Vec<T>,
+            values: &[String],
+            given: bool,
+            parse: impl Fn(&str) -> Result<T>,
+        ) -> Result<()> {
+            if !given && !values.is_empty() {
+                *field = values
+                    .iter()
+                    .map(|value| parse(value))
+                    .collect::<Result<_>>()?;
+            }
+            Ok(())
+        }
+        fn parse<T: FromStr<Err = anyhow::Error>>(value: &Option<String>) -> Result<Option<T>> {
+            value.as_deref().map(str::parse).transpose()
+        }
+        set(
+            &mut self.trailing_newline,
+            config.trailing_newline,
+            given("trailing-newline"),
+        );
+        set(&mut self.wrap, config.wrap, given("wrap"));
+        set(
+            &mut self.line_escape,
+            config.line_escape.clone().map(Some),
+            given("line-escape"),
+        );
+        set(
+            &mut self.details_separator,
+            config.details_separator,
+            given("details-separator"),
+        );
+        set(&mut self.toc, config.toc, given("toc"));
+        set(&mut self.title, config.title, given("title"));
+        set(&mut self.date, config.date, given("date"));
+        set(
+            &mut self.aging_notice,
+            config.aging_notice,
+            given("aging-notice"),
+        );
+        set(
+            &mut self.emphasis,
+            parse(&config.emphasis)?,
+            given("emphasis"),
+        );
+        set(
+            &mut self.heading_overflow,
+            parse(&config.heading_overflow)?,
+            given("heading-overflow"),
+        );
+        set(
+            &mut self.number_headings,
+            config.number_headings,
+            given("number-headings"),
+        );
+        set(
+            &mut self.raw_gemtext,
+            config.raw_gemtext,
+            given("raw-gemtext"),
+        );
+        set(&mut self.link_hosts, config.link_hosts, given("link-hosts"));
+        set_list(
+            &mut self.schemes,
+            &config.schemes,
+            given("schemes"),
+            parse_scheme,
+        )?;
+        set(
+            &mut self.no_strikethrough,
+            config.no_strikethrough,
+            given("no-strikethrough"),
+        );
+        set(&mut self.tables, config.tables, given("tables"));
+        set(&mut self.footnotes, config.footnotes, given("footnotes"));
+        set(&mut self.tasklists, config.tasklists, given("tasklists"));
+        set(
+            &mut self.definition_lists,
+            config.definition_lists,
+            given("definition-lists"),
+        );
+        set(&mut self.wiki_links, config.wiki_links, given("wiki-links"));
+        set(&mut self.sidenotes, config.sidenotes, given("sidenotes"));
+        set(
+            &mut self.admonitions,
+            config.admonitions,
+            given("admonitions"),
+        );
+        set(
+            &mut self.admonition_style,
+            parse(&config.admonition_style)?,
+            given("admonition-style"),
+        );
+        set_list(
+            &mut self.shortcodes,
+            &config.shortcodes,
+            given("shortcodes"),
+            parse_shortcode,
+        )?;
+        set(
+            &mut self.abbreviations,
+            config.abbreviations.clone().map(Some),
+            given("abbreviations"),
+        );
+        set(
+            &mut self.glossary,
+            config.glossary.clone().map(Some),
+            given("glossary"),
+        );
+        set(
+            &mut self.bibliography,
+            config.bibliography.clone().map(Some),
+            given("bibliography"),
+        );
+        Ok(())
+    }
+
+    pub fn options(&self) -> Result<ConverterOptions> {
+        let mut options = ConverterOptions::new()
+            .trailing_newline(self.trailing_newline)
+            .wrap_width(self.wrap)
+            .details_separator(self.details_separator)
+            .table_of_contents(self.toc)
+            .front_matter_title(self.title)
+            .front_matter_date(self.date)
+            .aging_notice(self.aging_notice)
+            .emphasis(self.emphasis)
+            .heading_overflow(self.heading_overflow)
+            .number_headings(self.number_headings)
+            .link_hosts(self.link_hosts)
+            .raw_gemtext(self.raw_gemtext)
+            .strikethrough(!self.no_strikethrough)
+            .tables(self.tables)
+            .footnotes(self.footnotes)
+            .tasklists(self.tasklists)
+            .definition_lists(self.definition_lists)
+            .wiki_links(self.wiki_links)
+            .sidenotes(self.sidenotes)
+            .admonitions(self.admonitions)
+            .admonition_style(self.admonition_style);
+        if let Some(footer) = &self.footer {
+            options = options.footer(footer);
+        }
+        if let Some(line_escape) = &self.line_escape {
+            options = options.line_escape(line_escape);
+        }
+        for (scheme, handling) in &self.schemes {
+            options = options.scheme(scheme, *handling);
+        }
+        for (name, template) in &self.shortcodes {
+            options = options.shortcode(name, template);
+        }
+        if let Some(path) = &self.abbreviations {
+            let definitions = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            for (abbreviation, expansion) in parse_abbreviations(&definitions) {
+                options = options.abbreviation(abbreviation, expansion);
+            }
+        }
+        if let Some(path) = &self.glossary {
+            let definitions = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            for (term, url) in parse_glossary(&definitions) {
+                options = options.glossary_term(term, url);
+            }
+        }
+        if let Some(path) = &self.bibliography {
+            let bibtex = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let bibliography = Bibliography::from_bibtex(&bibtex)
+                .with_context(|| format!("failed to parse {}", path.display()))?;
+            options = options.bibliography(bibliography);
+        }
+        Ok(options)
+    }
+}
+
+fn parse_shortcode(s: &str) -> Result<(String, String)> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(name), Some(template)) if !name.is_empty() => {
+            Ok((name.to_owned(), template.to_owned()))
+        }
+        _ => Err(anyhow!("expected NAME=TEMPLATE, got {}", s)),
+    }
+}
+
+fn parse_scheme(s: &str) -> Result<(String, SchemeHandling)> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(handling)) if !scheme.is_empty() => {
+            Ok((scheme.to_owned(), handling.parse()?))
+        }
+        _ => Err(anyhow!("expected SCHEME=HANDLING, got {}", s)),
+    }
+}
+
+/// Parses abbreviation definitions like `*[TLS]: Transport Layer Security`, ignoring any other
+/// lines.
+fn parse_abbreviations(definitions: &str) -> impl Iterator<Item = (&str, &str)> {
+    definitions.lines().filter_map(|line| {
+        let line = line.trim().strip_prefix("*[")?;
+        let end = line.find("]:")?;
+        Some((line[..end].trim(), line[end + 2..].trim()))
+    })
+}
+
+/// Parses glossary definitions like `[Gemini]: /wiki/gemini.gmi`, ignoring any other lines.
+fn parse_glossary(definitions: &str) -> impl Iterator<Item = (&str, &str)> {
+    definitions.lines().filter_map(|line| {
+        let line = line.trim().strip_prefix('[')?;
+        let end = line.find("]:")?;
+        let (term, url) = (line[..end].trim(), line[end + 2..].trim());
+        Some((term, url)).filter(|_| !term.is_empty() && !url.is_empty())
+    })
+}
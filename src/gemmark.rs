@@ -0,0 +1,193 @@
+//! Conversion from Gemtext back to Markdown, for republishing a capsule on the web.
+//!
+//! Gemtext is line-oriented while Markdown joins adjacent lines into paragraphs, so consecutive
+//! text and quote lines are joined with hard line breaks to keep them on separate lines.
+
+/// What kind of block a group of consecutive lines forms.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Block {
+    Text,
+    Quote,
+    List,
+    Other,
+}
+
+/// Converts the given Gemtext to Markdown.
+pub fn to_markdown(gemini: &str) -> String {
+    let mut out = String::with_capacity(gemini.len());
+    let mut previous: Option<Block> = None;
+    let mut lines = gemini.lines();
+    while let Some(line) = lines.next() {
+        if let Some(alt) = line.strip_prefix("```") {
+            start_block(&mut out, previous, Block::Other);
+            out.push_str("```");
+            out.push_str(alt.trim());
+            out.push('\n');
+            for line in &mut lines {
+                if line.starts_with("```") {
+                    break;
+                }
+                out.push_str(line);
+                out.push('\n');
+            }
+            out.push_str("```\n");
+            previous = Some(Block::Other);
+            continue;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            previous = None;
+            continue;
+        }
+        let block = if let Some(link) = line.strip_prefix("=>") {
+            start_block(&mut out, previous, Block::Other);
+            push_link(&mut out, link);
+            Block::Other
+        } else if line.starts_with('#') {
+            start_block(&mut out, previous, Block::Other);
+            let depth = line.chars().take_while(|&c| c == '#').count();
+            out.push_str(&line[..depth]);
+            out.push(' ');
+            out.push_str(&escape(line[depth..].trim()));
+            Block::Other
+        } else if let Some(item) = line.strip_prefix("* ") {
+            start_block(&mut out, previous, Block::List);
+            out.push_str("* ");
+            out.push_str(&escape(item.trim()));
+            Block::List
+        } else if let Some(quote) = line.strip_prefix('>') {
+            start_block(&mut out, previous, Block::Quote);
+            out.push_str("> ");
+            out.push_str(&escape(quote.trim()));
+            Block::Quote
+        } else {
+            start_block(&mut out, previous, Block::Text);
+            out.push_str(&escape_line_start(&escape(line)));
+            Block::Text
+        };
+        out.push('\n');
+        previous = Some(block);
+    }
+    out
+}
+
+/// Separates a new line from the previous one, depending on whether they're in the same block.
+fn start_block(out: &mut String, previous: Option<Block>, block: Block) {
+    match previous {
+        None => {
+            if !out.is_empty() {
+                out.push('\n');
+            }
+        }
+        Some(previous) if previous != block || block == Block::Other => out.push('\n'),
+        Some(Block::Text) | Some(Block::Quote) => {
+            // Turn the previous line's newline into a hard break.
+            out.insert(out.len() - 1, '\\');
+        }
+        Some(_) => (),
+    }
+}
+
+/// Writes a link line (without the `=>`) as a Markdown link.
+fn push_link(out: &mut String, link: &str) {
+    let link = link.trim();
+    let (url, label) = match link.find(char::is_whitespace) {
+        Some(end) => (&link[..end], link[end..].trim()),
+        None => (link, ""),
+    };
+    let url = if url.contains(&['(', ')'][..]) {
+        format!("<{}>", url)
+    } else {
+        url.to_owned()
+    };
+    if label.is_empty() {
+        out.push_str(&format!("[{}]({})", escape(link), url));
+    } else {
+        out.push_str(&format!("[{}]({})", escape(label), url));
+    }
+}
+
+/// Escapes characters that Markdown would treat as inline formatting.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\`*_[]<>".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes the start of a text line that Markdown would otherwise treat as starting a block, like
+/// `- item` or `1. item`.
+fn escape_line_start(line: &str) -> String {
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    let rest = &line[digits..];
+    if digits == 0 && (line.starts_with("- ") || line.starts_with("+ ") || line == "-") {
+        format!("\\{}", line)
+    } else if digits > 0 && (rest.starts_with(". ") || rest.starts_with(") ")) {
+        format!("{}\\{}", &line[..digits], rest)
+    } else {
+        line.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn headings_and_text() {
+        let gemini = indoc!(
+            "
+            # Title
+            some *text*
+            more text
+
+            ## Section
+            1. not a list"
+        );
+        let markdown = indoc!(
+            r#"
+            # Title
+
+            some \*text\*\
+            more text
+
+            ## Section
+
+            1\. not a list
+            "#
+        );
+        assert_eq!(to_markdown(gemini), markdown);
+    }
+
+    #[test]
+    fn links() {
+        let gemini = "=> gemini://example.com Example\n=> https://example.com/(x)";
+        let markdown = indoc!(
+            "
+            [Example](gemini://example.com)
+
+            [https://example.com/(x)](<https://example.com/(x)>)
+            "
+        );
+        assert_eq!(to_markdown(gemini), markdown);
+    }
+
+    #[test]
+    fn lists_and_quotes() {
+        let gemini = "* one\n* two\n> quoted\n> more";
+        let markdown = "* one\n* two\n\n> quoted\\\n> more\n";
+        assert_eq!(to_markdown(gemini), markdown);
+    }
+
+    #[test]
+    fn preformatted() {
+        let gemini = "```python\n# not a heading\n=> not a link\n```\ntext";
+        let markdown = "```python\n# not a heading\n=> not a link\n```\n\ntext\n";
+        assert_eq!(to_markdown(gemini), markdown);
+    }
+}
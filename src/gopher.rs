@@ -0,0 +1,141 @@
+//! Rendering for Gopher: plain text for documents, and gophermaps for menus. Both are produced
+//! from the Gemtext that `markgem` generates, so they get all of its conversion for free.
+//!
+//! Gophermaps use the format understood by servers like Gophernicus and pygopherd, where lines
+//! without tabs are informational text and menu items look like `TYPEdisplay<TAB>selector`, with
+//! the host and port defaulting to the server's own.
+
+use url::Url;
+
+/// Converts Gemtext to plain text. Headings are underlined, preformatted blocks lose their fences,
+/// and link lines become numbered references matching the `[n]` markers `markgem` puts in the
+/// text. Link lines that no marker points to, like the ones after linked headings or in the
+/// footer, are left unnumbered.
+pub fn to_plain_text(gemini: &str) -> String {
+    let mut out = String::with_capacity(gemini.len());
+    let mut preformatted = false;
+    let mut link_id = 0;
+    // The highest marker in the text so far. Each marker's link line comes after it, in order.
+    let mut marked = 0;
+    for line in gemini.lines() {
+        if line.starts_with("```") {
+            preformatted = !preformatted;
+            continue;
+        }
+        if preformatted {
+            out.push_str(line);
+        } else if let Some(link) = line.strip_prefix("=>") {
+            if link_id < marked {
+                link_id += 1;
+                out.push_str(&format!("[{}] ", link_id));
+            }
+            out.push_str(link.trim());
+        } else if line.starts_with('#') {
+            let depth = line.chars().take_while(|&c| c == '#').count();
+            let heading = line[depth..].trim();
+            let underline = if depth == 1 { "=" } else { "-" };
+            out.push_str(heading);
+            out.push('\n');
+            out.push_str(&underline.repeat(heading.chars().count()));
+        } else {
+            marked = markers(line).fold(marked, usize::max);
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// The numbers of the `[n]` link markers in the line.
+fn markers(line: &str) -> impl Iterator<Item = usize> + '_ {
+    line.split('[').skip(1).filter_map(|rest| {
+        let end = rest.find(']')?;
+        rest[..end].parse().ok()
+    })
+}
+
+/// Converts Gemtext to a gophermap, turning link lines into menu items.
+pub fn to_gophermap(gemini: &str) -> String {
+    let mut out = String::with_capacity(gemini.len());
+    let mut preformatted = false;
+    for line in gemini.lines() {
+        if line.starts_with("```") {
+            preformatted = !preformatted;
+            continue;
+        }
+        match line.strip_prefix("=>") {
+            Some(link) if !preformatted => out.push_str(&menu_item(link.trim())),
+            // A tab would make the line a menu item.
+            _ => out.push_str(&line.replace('\t', "    ")),
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Turns the contents of a link line into a menu item.
+fn menu_item(link: &str) -> String {
+    let (target, label) = match link.find(char::is_whitespace) {
+        Some(end) => (&link[..end], link[end..].trim()),
+        None => (link, ""),
+    };
+    let display = if label.is_empty() { target } else { label };
+    match Url::parse(target) {
+        Ok(url) if url.scheme() == "gopher" => {
+            // Gopher URLs put the item type at the start of the path, like `/1/phlog`.
+            let path = url.path();
+            let mut chars = path.trim_start_matches('/').chars();
+            let item_type = chars.next().unwrap_or('1');
+            format!(
+                "{}{}\t{}\t{}\t{}",
+                item_type,
+                display,
+                chars.as_str(),
+                url.host_str().unwrap_or_default(),
+                url.port().unwrap_or(70)
+            )
+        }
+        // The `URL:` selector is the usual convention for linking outside of Gopher.
+        Ok(_) => format!("h{}\tURL:{}", display, target),
+        Err(_) => {
+            let item_type = if target.ends_with('/') { '1' } else { '0' };
+            format!("{}{}\t{}", item_type, display, target)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn plain_text() {
+        let gemini = "# Title\n\nsome link[1]\n\n=> https://example.com\n\n```\n# code\n```";
+        let text = "Title\n=====\n\nsome link[1]\n\n[1] https://example.com\n\n# code\n";
+        assert_eq!(to_plain_text(gemini), text);
+    }
+
+    #[test]
+    fn unmarked_links() -> anyhow::Result<()> {
+        let markdown = "## [Heading](/heading)\n\nsome [link](/link)";
+        let gemini = String::from_utf8(crate::markgem::to_gemini(markdown)?)?;
+        let text = "Heading\n-------\n/heading Heading\n\nsome link[1]\n\n[1] /link\n";
+        assert_eq!(to_plain_text(&gemini), text);
+        Ok(())
+    }
+
+    #[test]
+    fn gophermap() {
+        let gemini = "intro\twith tab\n\
+                      => gopher://example.com/1/phlog Phlog\n\
+                      => https://example.com Web\n\
+                      => notes/ Notes\n\
+                      => about.txt";
+        let gophermap = "intro    with tab\n\
+                         1Phlog\t/phlog\texample.com\t70\n\
+                         hWeb\tURL:https://example.com\n\
+                         1Notes\tnotes/\n\
+                         0about.txt\tabout.txt\n";
+        assert_eq!(to_gophermap(gemini), gophermap);
+    }
+}
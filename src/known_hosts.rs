@@ -0,0 +1,142 @@
+//! Trust on first use for the certificates of Gemini servers. Most capsules use self-signed
+//! certificates, so instead of checking them against certificate authorities, the fingerprint a
+//! host presents the first time is remembered, and a different one later is refused until it's
+//! trusted explicitly.
+//!
+//! Known hosts are kept in a text file with a host and the SHA-256 fingerprint of its certificate
+//! on each line:
+//!
+//! ```text
+//! # Comments are ignored.
+//! example.org 2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae
+//! ```
+
+use anyhow::{bail, Result};
+use rustls::Certificate;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The SHA-256 fingerprint of a certificate, as lowercase hex.
+pub fn fingerprint(cert: &Certificate) -> String {
+    ring::digest::digest(&ring::digest::SHA256, &cert.0)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// What happened when a host's certificate was checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Check {
+    /// The host was already known with this certificate.
+    Known,
+    /// The host hadn't been seen before, and its certificate is now trusted.
+    FirstUse,
+    /// The host presented a different certificate from the one it was known with.
+    Changed { known: String },
+}
+
+/// The fingerprints of the certificates of hosts that have been seen before.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KnownHosts {
+    // Fingerprints by lowercase hostname.
+    hosts: BTreeMap<String, String>,
+}
+
+impl KnownHosts {
+    /// Parses known hosts in the format described in the module documentation.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut known_hosts = Self::default();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<_> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [host, fingerprint] => known_hosts.trust(host, fingerprint),
+                _ => bail!("line {}: expected HOST FINGERPRINT", number + 1),
+            }
+        }
+        Ok(known_hosts)
+    }
+
+    /// The fingerprint the host is known with, if it's known.
+    pub fn get(&self, host: &str) -> Option<&str> {
+        self.hosts
+            .get(&host.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    /// Trusts the certificate with the given fingerprint for the host, replacing any it was known
+    /// with before.
+    pub fn trust(&mut self, host: &str, fingerprint: &str) {
+        self.hosts
+            .insert(host.to_ascii_lowercase(), fingerprint.to_ascii_lowercase());
+    }
+
+    /// Forgets the host, so that whatever certificate it presents next is trusted. Returns
+    /// whether it was known.
+    pub fn forget(&mut self, host: &str) -> bool {
+        self.hosts.remove(&host.to_ascii_lowercase()).is_some()
+    }
+
+    /// The known hosts and their fingerprints, sorted by host.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.hosts
+            .iter()
+            .map(|(host, fingerprint)| (host.as_str(), fingerprint.as_str()))
+    }
+
+    /// Checks the certificate a host presented, trusting it if the host is new.
+    pub fn check(&mut self, host: &str, fingerprint: &str) -> Check {
+        match self.get(host) {
+            Some(known) if known == fingerprint => Check::Known,
+            Some(known) => Check::Changed {
+                known: known.to_owned(),
+            },
+            None => {
+                self.trust(host, fingerprint);
+                Check::FirstUse
+            }
+        }
+    }
+}
+
+impl fmt::Display for KnownHosts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (host, fingerprint) in self.iter() {
+            writeln!(f, "{} {}", host, fingerprint)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check() -> Result<()> {
+        let mut known_hosts = KnownHosts::parse("# hosts\nExample.org ABCD\n")?;
+        assert_eq!(known_hosts.check("example.org", "abcd"), Check::Known);
+        assert_eq!(
+            known_hosts.check("EXAMPLE.org", "ef01"),
+            Check::Changed {
+                known: "abcd".to_owned()
+            }
+        );
+        assert_eq!(known_hosts.check("new.example", "1234"), Check::FirstUse);
+        assert_eq!(known_hosts.check("new.example", "1234"), Check::Known);
+        assert!(known_hosts.forget("example.org"));
+        assert!(!known_hosts.forget("example.org"));
+        assert_eq!(known_hosts.to_string(), "new.example 1234\n");
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        assert!(KnownHosts::parse("example.org").is_err());
+        assert!(KnownHosts::parse("example.org abcd extra").is_err());
+    }
+}
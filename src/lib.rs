@@ -0,0 +1,44 @@
+//! Exarch's conversion between Markdown and Gemini, for use as a library. See
+//! `markgem::to_gemini_with`, `markhtml::to_html_with` and `gemmark::to_markdown`. `gopher`
+//! renders Gemtext for Gopher, `ansi` colors it for terminals, and `output` picks between all of
+//! these by name. `fetch` makes requests to Gemini servers, checking their certificates against
+//! `known_hosts`. `serve` is the server itself, and with the `testing` feature, `testing` runs it
+//! for end-to-end tests.
+
+mod access;
+mod accesslog;
+mod admin;
+pub mod ansi;
+pub mod atomic;
+mod autoindex;
+mod budget;
+mod cache;
+mod cgi;
+mod config;
+pub mod date;
+pub mod fetch;
+mod filter;
+pub mod flags;
+pub mod gemmark;
+pub mod gopher;
+pub mod known_hosts;
+mod limit;
+pub mod markgem;
+pub mod markhtml;
+mod mime;
+pub mod output;
+pub mod record;
+mod redirect;
+pub mod resolve;
+pub mod selfcheck;
+pub mod serve;
+mod shutdown;
+mod status;
+mod systemd;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod theme;
+mod titan;
+mod tls;
+mod unix;
+mod watch;
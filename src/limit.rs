@@ -0,0 +1,117 @@
+//! A cap on how many connections the server handles at once, so that a flood of connections
+//! can't use up its file descriptors. Connections over the limit wait briefly for one to close,
+//! and are dropped if none does.
+
+use async_std::future;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use log::{debug, warn};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Hands out permits for connections, up to the limit. Only the accept loop should take them,
+/// since waiting for one holds up accepting anything else.
+pub struct ConnectionLimit {
+    limit: usize,
+    // A token for each connection that can be opened. Permits put theirs back when dropped.
+    tokens: mpsc::UnboundedReceiver<()>,
+    returns: mpsc::UnboundedSender<()>,
+    open: Arc<AtomicUsize>,
+    refused: u64,
+}
+
+impl ConnectionLimit {
+    pub fn new(limit: usize) -> Self {
+        let (returns, tokens) = mpsc::unbounded();
+        for _ in 0..limit {
+            // The receiver is right here, so this can't fail.
+            returns.unbounded_send(()).unwrap();
+        }
+        Self {
+            limit,
+            tokens,
+            returns,
+            open: Arc::new(AtomicUsize::new(0)),
+            refused: 0,
+        }
+    }
+
+    /// Takes a permit for a new connection, waiting up to the given time for another connection
+    /// to close if the limit has been reached. Returns `None` if none did, in which case the
+    /// connection should be refused.
+    pub async fn acquire(&mut self, wait: Duration) -> Option<Permit> {
+        match future::timeout(wait, self.tokens.next()).await {
+            Ok(Some(())) => {
+                self.open.fetch_add(1, Ordering::AcqRel);
+                debug!("{} of {} connections open", self.open(), self.limit);
+                Some(Permit {
+                    returns: self.returns.clone(),
+                    open: self.open.clone(),
+                })
+            }
+            _ => {
+                self.refused += 1;
+                warn!(
+                    "All {} connections are in use, refusing a connection ({} refused so far)",
+                    self.limit, self.refused
+                );
+                None
+            }
+        }
+    }
+
+    /// How many connections are open.
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Acquire)
+    }
+}
+
+/// Permission to handle one connection, given back when dropped.
+pub struct Permit {
+    returns: mpsc::UnboundedSender<()>,
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::AcqRel);
+        // If the limit is gone, so is the server, and there's nothing to give the token back to.
+        let _ = self.returns.unbounded_send(());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::task;
+
+    #[test]
+    fn permits() {
+        task::block_on(async {
+            let wait = Duration::from_millis(10);
+            let mut limit = ConnectionLimit::new(2);
+            let first = limit.acquire(wait).await;
+            let second = limit.acquire(wait).await;
+            assert!(first.is_some() && second.is_some());
+            assert_eq!(limit.open(), 2);
+            assert!(limit.acquire(wait).await.is_none());
+            drop(first);
+            assert_eq!(limit.open(), 1);
+            assert!(limit.acquire(wait).await.is_some());
+        });
+    }
+
+    #[test]
+    fn waits_for_a_permit() {
+        task::block_on(async {
+            let mut limit = ConnectionLimit::new(1);
+            let permit = limit.acquire(Duration::from_secs(0)).await;
+            task::spawn(async move {
+                task::sleep(Duration::from_millis(10)).await;
+                drop(permit);
+            });
+            assert!(limit.acquire(Duration::from_secs(5)).await.is_some());
+        });
+    }
+}
@@ -1,20 +1,86 @@
 use anyhow::Result;
 use async_std::task;
+use exarch::{record, serve, theme};
 use structopt::StructOpt;
 
-mod markgem;
-mod serve;
+mod bench;
+mod bookmark;
+mod cert;
+mod convert;
+mod daemon;
+mod deploy;
+mod model;
+mod report;
+mod smoke;
+mod stats;
+mod tinylog;
+mod tofu;
+mod verify;
+// There's only ever one of these, so there's no point boxing the bigger variants.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, StructOpt)]
 #[structopt(name = "exarch", about = "A static site generator for Gemini")]
 enum Opt {
     /// Serve an existing tree of Markdown files.
     Serve(serve::ServeOpt),
+    /// Convert a single file between Markdown and Gemini.
+    Convert(convert::ConvertOpt),
+    /// Copy a tree of Markdown files to a new release directory and make it the current one in a
+    /// single step, for serving from DIR/current.
+    Deploy(deploy::DeployOpt),
+    /// Repeatedly convert a tree of Markdown files, reporting how long it takes.
+    Bench(bench::BenchOpt),
+    /// Send a request recorded by `serve --record` again, checking the response.
+    Replay(record::ReplayOpt),
+    /// Write a page of statistics about a tree of Markdown files.
+    Stats(stats::StatsOpt),
+    /// Write what exarch knows about a tree of Markdown files as JSON: its pages, sections, tags
+    /// and links.
+    Model(model::ModelOpt),
+    /// Compare two builds of a site, listing the pages added, removed and changed.
+    Verify(verify::VerifyOpt),
+    /// Report on a tree of Markdown files.
+    Report(report::ReportOpt),
+    /// Crawl a live capsule, checking that every page it links to on the same host can be
+    /// fetched. Exits with an error if any can't.
+    Smoke(smoke::SmokeOpt),
+    /// Add a link to the reading list, regenerating its page.
+    Bookmark(bookmark::BookmarkOpt),
+    /// Add an entry to a tinylog.
+    Tiny(tinylog::TinyOpt),
+    /// Generate a self-signed certificate and key for serving a capsule.
+    Cert(cert::CertOpt),
+    /// Manage the certificates trusted for other capsules.
+    Tofu(tofu::TofuOpt),
+    /// List the built-in themes, or copy one into a site to change it.
+    Theme(theme::ThemeOpt),
 }
 
 fn main() -> Result<()> {
     env_logger::builder().format_module_path(true).init();
-    let opt = Opt::from_args();
-    match opt {
-        Opt::Serve(serve_opt) => task::block_on(serve::serve(serve_opt)),
+    let matches = Opt::clap().get_matches();
+    match Opt::from_clap(&matches) {
+        Opt::Serve(mut serve_opt) => {
+            serve_opt.apply_config(matches.subcommand_matches("serve").unwrap())?;
+            serve::self_check(&serve_opt)?;
+            if serve_opt.daemon {
+                daemon::daemonize(serve_opt.pid_file.as_deref())?;
+            }
+            task::block_on(serve::serve(serve_opt))
+        }
+        Opt::Convert(convert_opt) => convert::convert(convert_opt),
+        Opt::Deploy(deploy_opt) => deploy::deploy(deploy_opt),
+        Opt::Bench(bench_opt) => bench::bench(bench_opt),
+        Opt::Replay(replay_opt) => record::replay(replay_opt),
+        Opt::Stats(stats_opt) => stats::stats(stats_opt),
+        Opt::Model(model_opt) => model::export(model_opt),
+        Opt::Verify(verify_opt) => verify::verify(verify_opt),
+        Opt::Report(report_opt) => report::report(report_opt),
+        Opt::Smoke(smoke_opt) => smoke::smoke(smoke_opt),
+        Opt::Bookmark(bookmark_opt) => bookmark::bookmark(bookmark_opt),
+        Opt::Tiny(tiny_opt) => tinylog::tiny(tiny_opt),
+        Opt::Cert(cert_opt) => cert::cert(cert_opt),
+        Opt::Tofu(tofu_opt) => tofu::tofu(tofu_opt),
+        Opt::Theme(theme_opt) => theme::theme(theme_opt),
     }
 }
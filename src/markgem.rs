@@ -1,21 +1,732 @@
-use anyhow::Result;
-use pulldown_cmark::{CowStr, Event, Options, Parser, Tag};
-use std::io::{BufWriter, Write};
+use crate::ansi::Colorizer;
+use crate::date::Date;
+use anyhow::{anyhow, Result};
+use async_std::io::{prelude::WriteExt, Write as AsyncWrite};
+use async_std::task;
+use futures::channel::mpsc;
+use futures::{executor, SinkExt, StreamExt};
+use normalize::Normalizer;
+use pulldown_cmark::{CodeBlockKind, CowStr, Event, LinkType, Options, Parser, Tag};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-/// Converts the given Markdown to Gemini, writing it to the given output. The output will be
-/// automatically buffered.
+mod abbr;
+mod admonition;
+mod cite;
+mod deflist;
+mod details;
+mod emphasis;
+mod glossary;
+mod hooks;
+mod lint;
+mod math;
+mod normalize;
+mod raw;
+mod shortcode;
+mod sidenote;
+mod text;
+mod toc;
+mod wikilink;
+
+pub use admonition::AdmonitionStyle;
+pub use cite::Bibliography;
+pub use emphasis::EmphasisStyle;
+pub use hooks::Events;
+pub use lint::{missing_alt_text, unsupported_constructs, Construct, Lint};
+
+/// What to do with headings deeper than the three levels Gemtext has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadingOverflow {
+    /// Write them as level three headings.
+    Clamp,
+    /// Write them as a bolded line of text.
+    Bold,
+    /// Write them as level three headings with their real depth in front, like `### (4) Title`.
+    Depth,
+}
+
+impl Default for HeadingOverflow {
+    fn default() -> Self {
+        HeadingOverflow::Clamp
+    }
+}
+
+impl FromStr for HeadingOverflow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "clamp" => Ok(HeadingOverflow::Clamp),
+            "bold" => Ok(HeadingOverflow::Bold),
+            "depth" => Ok(HeadingOverflow::Depth),
+            _ => Err(anyhow!("unknown heading overflow policy {}", s)),
+        }
+    }
+}
+
+/// What to do with links whose URL has a particular scheme, like `mailto:`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemeHandling {
+    /// Write them as link lines, like any other link.
+    Link,
+    /// Write the part of the URL after the scheme, like the address of a `mailto:` link, in
+    /// parentheses after the link's text.
+    Inline,
+    /// Keep the link's text but drop the link.
+    Drop,
+}
+
+impl Default for SchemeHandling {
+    fn default() -> Self {
+        SchemeHandling::Link
+    }
+}
+
+impl FromStr for SchemeHandling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "link" => Ok(SchemeHandling::Link),
+            "inline" => Ok(SchemeHandling::Inline),
+            "drop" => Ok(SchemeHandling::Drop),
+            _ => Err(anyhow!("unknown scheme handling {}", s)),
+        }
+    }
+}
+
+/// Options controlling how Markdown is converted to Gemini. Every optional feature is off by
+/// default; use the builder methods to turn them on. Syntax of your own can be added with
+/// `preprocessor` and `event_hook`, which are kept stable as the built-in extensions change.
+///
+/// ```
+/// use exarch::markgem::{to_gemini_with, ConverterOptions};
+///
+/// let options = ConverterOptions::new().wiki_links(true).trailing_newline(true);
+/// let gemini = to_gemini_with("see [[Some Page]]", &options).unwrap();
+/// assert_eq!(gemini, b"see Some Page[1]\n\n=> some-page.gmi\n");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ConverterOptions {
+    trailing_newline: bool,
+    definition_lists: bool,
+    wiki_links: bool,
+    sidenotes: bool,
+    shortcodes: HashMap<String, String>,
+    abbreviations: BTreeMap<String, String>,
+    // Terms linked the first time they appear in each document, with their URLs.
+    glossary: BTreeMap<String, String>,
+    bibliography: Option<Bibliography>,
+    wrap_width: usize,
+    line_escape: String,
+    details_separator: bool,
+    table_of_contents: bool,
+    front_matter_title: bool,
+    front_matter_date: bool,
+    aging_notice: u32,
+    emphasis: EmphasisStyle,
+    heading_overflow: HeadingOverflow,
+    number_headings: bool,
+    link_hosts: bool,
+    mirror_url: Option<String>,
+    footer: Option<String>,
+    raw_gemtext: bool,
+    ansi_colors: bool,
+    // How to handle links with each scheme. Schemes that aren't listed are written as links.
+    schemes: HashMap<String, SchemeHandling>,
+    // The Markdown extensions the parser accepts.
+    extensions: Options,
+    admonitions: bool,
+    admonition_style: AdmonitionStyle,
+    hooks: hooks::Hooks,
+}
+
+impl Default for ConverterOptions {
+    fn default() -> Self {
+        Self {
+            trailing_newline: false,
+            definition_lists: false,
+            wiki_links: false,
+            sidenotes: false,
+            shortcodes: HashMap::new(),
+            abbreviations: BTreeMap::new(),
+            glossary: BTreeMap::new(),
+            bibliography: None,
+            wrap_width: 0,
+            line_escape: DEFAULT_LINE_ESCAPE.to_owned(),
+            details_separator: false,
+            table_of_contents: false,
+            front_matter_title: false,
+            front_matter_date: false,
+            aging_notice: 0,
+            emphasis: EmphasisStyle::default(),
+            heading_overflow: HeadingOverflow::default(),
+            number_headings: false,
+            link_hosts: false,
+            mirror_url: None,
+            footer: None,
+            raw_gemtext: false,
+            ansi_colors: false,
+            schemes: HashMap::new(),
+            extensions: Options::ENABLE_STRIKETHROUGH,
+            admonitions: false,
+            admonition_style: AdmonitionStyle::default(),
+            hooks: hooks::Hooks::default(),
+        }
+    }
+}
+
+/// Written before a paragraph that would otherwise be read as a Gemtext link, heading, list item,
+/// quote or preformatting toggle. It's a zero-width space, so readers don't see it.
+pub const DEFAULT_LINE_ESCAPE: &str = "\u{200b}";
+
+impl ConverterOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the output should end with a single newline. If false, it ends with no newline at
+    /// all.
+    pub fn trailing_newline(mut self, trailing_newline: bool) -> Self {
+        self.trailing_newline = trailing_newline;
+        self
+    }
+
+    /// Hard-wraps text lines at the given width, for clients that don't wrap long lines well.
+    /// Link lines, headings and preformatted text are never wrapped. Zero, the default, means
+    /// lines aren't wrapped.
+    pub fn wrap_width(mut self, wrap_width: usize) -> Self {
+        self.wrap_width = wrap_width;
+        self
+    }
+
+    /// What to write before a paragraph that starts with Gemtext syntax, like `=> ` or `# `, so
+    /// that clients don't misread it. Defaults to `DEFAULT_LINE_ESCAPE`.
+    pub fn line_escape(mut self, line_escape: impl Into<String>) -> Self {
+        self.line_escape = line_escape.into();
+        self
+    }
+
+    /// Whether to put a "—" line between the summary of a `<details>` block and its body.
+    pub fn details_separator(mut self, details_separator: bool) -> Self {
+        self.details_separator = details_separator;
+        self
+    }
+
+    /// Whether to insert a list of the document's sections after its title. Pages can also ask
+    /// for one with `toc = true` in their front matter.
+    pub fn table_of_contents(mut self, table_of_contents: bool) -> Self {
+        self.table_of_contents = table_of_contents;
+        self
+    }
+
+    /// Whether to add a `# Title` heading at the top of pages whose front matter has a `title`.
+    pub fn front_matter_title(mut self, front_matter_title: bool) -> Self {
+        self.front_matter_title = front_matter_title;
+        self
+    }
+
+    /// Whether to put the `date` from the front matter under the title added by
+    /// `front_matter_title`.
+    pub fn front_matter_date(mut self, front_matter_date: bool) -> Self {
+        self.front_matter_date = front_matter_date;
+        self
+    }
+
+    /// Adds a notice like "This post is over 5 years old." to the top of pages whose front matter
+    /// `date` is at least this many years ago. Pages can set their own threshold with
+    /// `aging_notice = N` in their front matter, where zero turns the notice off. Zero, the
+    /// default, means no notice is added.
+    pub fn aging_notice(mut self, years: u32) -> Self {
+        self.aging_notice = years;
+        self
+    }
+
+    /// How to write emphasis, strong emphasis and strikethrough. By default their `*`, `**` and
+    /// `~~` markers are written as in Markdown.
+    pub fn emphasis(mut self, emphasis: EmphasisStyle) -> Self {
+        self.emphasis = emphasis;
+        self
+    }
+
+    /// What to do with headings deeper than level three. By default they're clamped to level
+    /// three.
+    pub fn heading_overflow(mut self, heading_overflow: HeadingOverflow) -> Self {
+        self.heading_overflow = heading_overflow;
+        self
+    }
+
+    /// Whether to number headings by their place in the document, like `# 1. Intro` and
+    /// `## 1.1 Background`, since Gemini clients show little difference between heading levels.
+    pub fn number_headings(mut self, number_headings: bool) -> Self {
+        self.number_headings = number_headings;
+        self
+    }
+
+    /// Whether to pass hand-written Gemtext through unchanged. It's written either as a code block
+    /// tagged `gemtext`, or between `<!-- gemini:raw -->` and `<!-- /gemini:raw -->` lines.
+    pub fn raw_gemtext(mut self, raw_gemtext: bool) -> Self {
+        self.raw_gemtext = raw_gemtext;
+        self
+    }
+
+    /// Whether to put the host that links to other sites go to after them, like
+    /// `=> https://sr.ht/~a/b (sr.ht)`, so that readers know where a link leads before following
+    /// it.
+    pub fn link_hosts(mut self, link_hosts: bool) -> Self {
+        self.link_hosts = link_hosts;
+        self
+    }
+
+    /// Sets the URL of the same page on the other mirror, which is linked at the end of the page:
+    /// "Read on the web" in Gemtext, and "Read on Gemini" in HTML. This keeps a capsule and its
+    /// web version cross-navigable.
+    pub fn mirror_url(mut self, mirror_url: impl Into<String>) -> Self {
+        self.mirror_url = Some(mirror_url.into());
+        self
+    }
+
+    /// Sets Gemtext to add to the end of every page, like a link back to the front page. It's
+    /// written as it is, after the page's links and before the link to the mirror.
+    pub fn footer(mut self, footer: impl Into<String>) -> Self {
+        self.footer = Some(footer.into());
+        self
+    }
+
+    /// Whether to color the output with ANSI escape codes, for reading in a terminal. See
+    /// `ansi::colorize`.
+    pub fn ansi_colors(mut self, ansi_colors: bool) -> Self {
+        self.ansi_colors = ansi_colors;
+        self
+    }
+
+    /// Sets how links whose URL has the given scheme, like `mailto`, are written. Many clients
+    /// can't open anything but Gemini links, so it can be friendlier to show an email address
+    /// than to link to it. By default every link is written as a link line.
+    pub fn scheme(mut self, scheme: impl Into<String>, handling: SchemeHandling) -> Self {
+        self.schemes
+            .insert(scheme.into().to_ascii_lowercase(), handling);
+        self
+    }
+
+    /// Whether to accept `~~strikethrough~~`. On by default.
+    pub fn strikethrough(mut self, strikethrough: bool) -> Self {
+        self.extensions
+            .set(Options::ENABLE_STRIKETHROUGH, strikethrough);
+        self
+    }
+
+    /// Whether to accept GitHub-style tables, which are written with their cells separated by
+    /// ` | `.
+    pub fn tables(mut self, tables: bool) -> Self {
+        self.extensions.set(Options::ENABLE_TABLES, tables);
+        self
+    }
+
+    /// Whether to accept footnotes, which are written as `[^label]` references and
+    /// `[^label]: text` definitions.
+    pub fn footnotes(mut self, footnotes: bool) -> Self {
+        self.extensions.set(Options::ENABLE_FOOTNOTES, footnotes);
+        self
+    }
+
+    /// Whether to accept `- [ ]` and `- [x]` task list items.
+    pub fn tasklists(mut self, tasklists: bool) -> Self {
+        self.extensions.set(Options::ENABLE_TASKLISTS, tasklists);
+        self
+    }
+
+    /// Whether to convert definition lists (a line followed by lines starting with `: `) into a
+    /// bolded term followed by indented definitions.
+    pub fn definition_lists(mut self, definition_lists: bool) -> Self {
+        self.definition_lists = definition_lists;
+        self
+    }
+
+    /// Whether to convert wiki-style `[[Page Name]]` and `[[target|label]]` links into links to
+    /// the corresponding `.gmi` page.
+    pub fn wiki_links(mut self, wiki_links: bool) -> Self {
+        self.wiki_links = wiki_links;
+        self
+    }
+
+    /// Whether to turn inline annotations like `{^ an aside}` into numbered notes, which are
+    /// listed at the end of the section they're in.
+    pub fn sidenotes(mut self, sidenotes: bool) -> Self {
+        self.sidenotes = sidenotes;
+        self
+    }
+
+    /// Whether to render admonitions, GitHub's `> [!NOTE]` and Pandoc's `:::warning`, as a quote
+    /// headed by a label line instead of leaving their markers in the text.
+    pub fn admonitions(mut self, admonitions: bool) -> Self {
+        self.admonitions = admonitions;
+        self
+    }
+
+    /// How the label line of an admonition is written.
+    pub fn admonition_style(mut self, admonition_style: AdmonitionStyle) -> Self {
+        self.admonition_style = admonition_style;
+        self
+    }
+
+    /// Adds a template for the Zola shortcode with the given name, in which `{key}` is replaced by
+    /// the shortcode's `key` argument and `{body}` by its body. Templates take precedence over the
+    /// built-in shortcodes.
+    pub fn shortcode(mut self, name: impl Into<String>, template: impl Into<String>) -> Self {
+        self.shortcodes.insert(name.into(), template.into());
+        self
+    }
+
+    /// Adds an abbreviation whose first use in each document gets expanded inline, like
+    /// "TLS (Transport Layer Security)".
+    pub fn abbreviation(
+        mut self,
+        abbreviation: impl Into<String>,
+        expansion: impl Into<String>,
+    ) -> Self {
+        self.abbreviations
+            .insert(abbreviation.into(), expansion.into());
+        self
+    }
+
+    /// Adds a glossary term whose first use as a whole word in each document, outside of code and
+    /// other links, becomes a link to the URL.
+    pub fn glossary_term(mut self, term: impl Into<String>, url: impl Into<String>) -> Self {
+        self.glossary.insert(term.into(), url.into());
+        self
+    }
+
+    /// Sets the bibliography used for Pandoc-style citations like `[@key]`, which are replaced by
+    /// numbered references listed at the end of the document.
+    pub fn bibliography(mut self, bibliography: Bibliography) -> Self {
+        self.bibliography = Some(bibliography);
+        self
+    }
+
+    /// Adds a preprocessor, which rewrites the Markdown of each document before it's parsed. It's
+    /// given the Markdown without its front matter, and returns the rewritten Markdown, or `None`
+    /// to leave it as it is. Preprocessors run in the order they were added, before the built-in
+    /// ones like shortcode expansion.
+    ///
+    /// ```
+    /// use exarch::markgem::{to_gemini_with, ConverterOptions};
+    ///
+    /// let options = ConverterOptions::new()
+    ///     .preprocessor(|markdown| Some(markdown.replace(":wave:", "👋")));
+    /// assert_eq!(to_gemini_with("hi :wave:", &options).unwrap(), "hi 👋".as_bytes());
+    /// ```
+    pub fn preprocessor(
+        mut self,
+        preprocessor: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.add_preprocessor(preprocessor);
+        self
+    }
+
+    /// Adds an event hook, which rewrites the pulldown-cmark events of each document after the
+    /// Markdown extensions turned on in these options have. Hooks run in the order they were
+    /// added. The events they return are converted like any others, so a hook can add links,
+    /// headings and so on without knowing how they're written in Gemtext.
+    ///
+    /// ```
+    /// use exarch::markgem::{to_gemini_with, ConverterOptions};
+    /// use pulldown_cmark::Event;
+    ///
+    /// let options = ConverterOptions::new().event_hook(|events| {
+    ///     Box::new(events.map(|event| match event {
+    ///         Event::Text(text) => Event::Text(text.to_uppercase().into()),
+    ///         event => event,
+    ///     }))
+    /// });
+    /// assert_eq!(to_gemini_with("*hi*", &options).unwrap(), b"*HI*");
+    /// ```
+    pub fn event_hook(
+        mut self,
+        hook: impl for<'a> Fn(Events<'a>) -> Events<'a> + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.add_event_hook(hook);
+        self
+    }
+}
+
+/// Converts the given Markdown to Gemini.
 pub fn to_gemini(markdown: &str) -> Result<Vec<u8>> {
-    let markdown = strip_matter(markdown);
+    to_gemini_with(markdown, &ConverterOptions::default())
+}
+
+/// Like `to_gemini`, but with the given options.
+pub fn to_gemini_with(markdown: &str, options: &ConverterOptions) -> Result<Vec<u8>> {
     let mut vec: Vec<u8> = vec![];
-    let converter = Converter::new(&mut vec);
-    converter.convert(Parser::new_ext(markdown, Options::ENABLE_STRIKETHROUGH))?;
-    while vec.last() == Some(&b'\n') {
-        vec.pop();
-    }
+    convert_to(markdown, options, &mut vec)?;
     Ok(vec)
 }
 
+/// Converts the given Markdown to Gemini, writing it to the given output as it's generated. The
+/// output will be automatically buffered.
+pub fn convert_to<W: Write>(markdown: &str, options: &ConverterOptions, writer: W) -> Result<()> {
+    if options.ansi_colors {
+        return convert_uncolored(markdown, options, Colorizer::new(writer));
+    }
+    convert_uncolored(markdown, options, writer)
+}
+
+fn convert_uncolored<W: Write>(
+    markdown: &str,
+    options: &ConverterOptions,
+    writer: W,
+) -> Result<()> {
+    let page = Page::new(markdown, options);
+    let markdown = preprocess(markdown, options);
+    let normalizer = Normalizer::new(
+        BufWriter::new(writer),
+        options.trailing_newline,
+        options.wrap_width,
+    );
+    let converter = Converter::new(normalizer, options);
+    converter.convert(gemini_events(&markdown, options, page))
+}
+
+/// The settings for a single page, taken from the options and the page's front matter.
+struct Page {
+    toc: bool,
+    // A heading to add at the top of the page, and the date to put under it.
+    title: Option<String>,
+    date: Option<String>,
+    // How many years old the page is, if it's old enough to warrant a notice.
+    age: Option<i32>,
+}
+
+impl Page {
+    fn new(markdown: &str, options: &ConverterOptions) -> Self {
+        let matter = |key| front_matter_value(markdown, key).map(str::to_owned);
+        let title = matter("title").filter(|_| options.front_matter_title);
+        Self {
+            toc: options.table_of_contents || front_matter_value(markdown, "toc") == Some("true"),
+            date: matter("date").filter(|_| title.is_some() && options.front_matter_date),
+            age: age(markdown, options.aging_notice),
+            title,
+        }
+    }
+}
+
+/// How many whole years ago the page's front matter `date` was, if that's at least the threshold
+/// set for the page, or the given default if it doesn't set one.
+fn age(markdown: &str, default_threshold: u32) -> Option<i32> {
+    let threshold = match front_matter_value(markdown, "aging_notice") {
+        Some(threshold) => threshold.parse().unwrap_or(default_threshold),
+        None => default_threshold,
+    };
+    if threshold == 0 {
+        return None;
+    }
+    let date = Date::parse(front_matter_value(markdown, "date")?).ok()?;
+    let age = date.years_until(&Date::today());
+    Some(age).filter(|&age| age >= threshold as i32)
+}
+
+/// Like `events`, but with the rewrites that only make sense when the output is Gemtext.
+fn gemini_events<'a>(
+    markdown: &'a str,
+    options: &'a ConverterOptions,
+    page: Page,
+) -> Box<dyn Iterator<Item = Event<'a>> + 'a> {
+    // HTML output keeps `<details>` as it is.
+    let mut events: Box<dyn Iterator<Item = Event>> = Box::new(details::Details::new(
+        events(markdown, options, "gmi"),
+        options.details_separator,
+    ));
+    let mut header = vec![];
+    if let Some(title) = &page.title {
+        header.push(Event::Start(Tag::Heading(1)));
+        header.push(Event::Text(title.clone().into()));
+        header.push(Event::End(Tag::Heading(1)));
+    }
+    if let Some(date) = page.date {
+        header.push(Event::Start(Tag::Paragraph));
+        header.push(Event::Text(date.into()));
+        header.push(Event::End(Tag::Paragraph));
+    }
+    if let Some(age) = page.age {
+        let notice = format!(
+            "This post is over {} year{} old.",
+            age,
+            if age == 1 { "" } else { "s" }
+        );
+        header.push(Event::Start(Tag::BlockQuote));
+        header.push(Event::Start(Tag::Paragraph));
+        header.push(Event::Text(notice.into()));
+        header.push(Event::End(Tag::Paragraph));
+        header.push(Event::End(Tag::BlockQuote));
+    }
+    if !header.is_empty() {
+        events = Box::new(header.into_iter().chain(events));
+    }
+    if page.toc {
+        events = Box::new(toc::TableOfContents::new(events, markdown, page.title));
+    }
+    if options.emphasis != EmphasisStyle::Markers {
+        events = Box::new(emphasis::Emphasis::new(events, options.emphasis));
+    }
+    events
+}
+
+/// How long each stage of a conversion took.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Timings {
+    /// Preprocessing the source and parsing it into events, including the event-level rewrites.
+    pub parse: Duration,
+    /// Turning the events into Gemtext.
+    pub convert: Duration,
+}
+
+/// Like `to_gemini_with`, but also reports how long each stage took. Since the stages normally
+/// run interleaved, this collects all of the events before converting them, so it uses more
+/// memory than an ordinary conversion.
+pub fn to_gemini_timed(markdown: &str, options: &ConverterOptions) -> Result<(Vec<u8>, Timings)> {
+    let start = Instant::now();
+    let page = Page::new(markdown, options);
+    let markdown = preprocess(markdown, options);
+    let events: Vec<Event> = gemini_events(&markdown, options, page).collect();
+    let parsed = Instant::now();
+    let mut vec: Vec<u8> = vec![];
+    let normalizer = Normalizer::new(
+        BufWriter::new(&mut vec),
+        options.trailing_newline,
+        options.wrap_width,
+    );
+    Converter::new(normalizer, options).convert(events.into_iter())?;
+    let timings = Timings {
+        parse: parsed - start,
+        convert: parsed.elapsed(),
+    };
+    Ok((vec, timings))
+}
+
+/// Applies the rewrites that have to happen to the Markdown source before it's parsed.
+pub(crate) fn preprocess<'a>(markdown: &'a str, options: &ConverterOptions) -> Cow<'a, str> {
+    let mut markdown = match normalize_input(markdown) {
+        Cow::Borrowed(markdown) => Cow::Borrowed(strip_matter(markdown)),
+        Cow::Owned(markdown) => Cow::Owned(strip_matter(&markdown).to_owned()),
+    };
+    if let Some(rewritten) = options.hooks.preprocess(&markdown) {
+        markdown = Cow::Owned(rewritten);
+    }
+    if options.raw_gemtext {
+        if let Cow::Owned(fenced) = raw::fence_raw(&markdown) {
+            markdown = Cow::Owned(fenced);
+        }
+    }
+    if let Cow::Owned(expanded) = shortcode::expand(&markdown, &options.shortcodes) {
+        markdown = Cow::Owned(expanded);
+    }
+    if options.admonitions {
+        if let Cow::Owned(quoted) = admonition::quote_divs(&markdown) {
+            markdown = Cow::Owned(quoted);
+        }
+    }
+    match math::fence_math(&markdown) {
+        Cow::Borrowed(_) => markdown,
+        Cow::Owned(fenced) => Cow::Owned(fenced),
+    }
+}
+
+/// Like `events`, but with the additions that only make sense when the output is HTML.
+pub(crate) fn html_events<'a>(
+    markdown: &'a str,
+    options: &'a ConverterOptions,
+) -> Box<dyn Iterator<Item = Event<'a>> + 'a> {
+    let mut footer = vec![];
+    if let Some(mirror_url) = &options.mirror_url {
+        let link = Tag::Link(LinkType::Inline, mirror_url.as_str().into(), "".into());
+        footer.push(Event::Start(Tag::Paragraph));
+        footer.push(Event::Start(link.clone()));
+        footer.push(Event::Text("Read on Gemini".into()));
+        footer.push(Event::End(link));
+        footer.push(Event::End(Tag::Paragraph));
+    }
+    Box::new(events(markdown, options, "html").chain(footer))
+}
+
+/// Parses preprocessed Markdown, applying the event-level rewrites turned on in the options.
+/// Links to other pages of the site point at files with the given extension.
+pub(crate) fn events<'a>(
+    markdown: &'a str,
+    options: &'a ConverterOptions,
+    extension: &'static str,
+) -> Box<dyn Iterator<Item = Event<'a>> + 'a> {
+    let parser = Parser::new_ext(markdown, options.extensions);
+    let mut events: Box<dyn Iterator<Item = Event>> = Box::new(text::MergeText::new(parser));
+    if options.definition_lists {
+        events = Box::new(deflist::DefinitionLists::new(events));
+    }
+    if options.admonitions {
+        events = Box::new(admonition::Admonitions::new(
+            events,
+            options.admonition_style,
+        ));
+    }
+    if options.wiki_links {
+        events = Box::new(wikilink::WikiLinks::new(events, extension));
+    }
+    if !options.glossary.is_empty() {
+        events = Box::new(glossary::Glossary::new(events, &options.glossary));
+    }
+    if options.sidenotes {
+        events = Box::new(sidenote::Sidenotes::new(events));
+    }
+    if !options.abbreviations.is_empty() {
+        events = Box::new(abbr::Abbreviations::new(events, &options.abbreviations));
+    }
+    if let Some(bibliography) = &options.bibliography {
+        events = Box::new(cite::Citations::new(events, bibliography));
+    }
+    options.hooks.apply(events)
+}
+
+/// How many chunks of output `convert_to_async` lets the converter get ahead of the writer.
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+/// Like `convert_to`, but for an async writer. The conversion runs on the blocking thread pool,
+/// handing its output over in chunks, so only a bounded amount of it is held in memory at once.
+/// The options are shared with it rather than copied.
+pub async fn convert_to_async<W: AsyncWrite + Unpin>(
+    markdown: String,
+    options: Arc<ConverterOptions>,
+    mut writer: W,
+) -> Result<()> {
+    let (sender, mut receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    // If the writer fails first, the conversion stops when it next sends a chunk.
+    let converting =
+        task::spawn_blocking(move || convert_to(&markdown, &options, ChannelWriter(sender)));
+    while let Some(chunk) = receiver.next().await {
+        writer.write_all(&chunk).await?;
+    }
+    converting.await
+}
+
+/// Sends everything written to it over a channel, blocking while the channel is full.
+struct ChannelWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        executor::block_on(self.0.send(buf.to_vec()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "receiver went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 struct Link<'a> {
+    id: usize,
     destination: CowStr<'a>,
     // Note that this is *not* the text that appears in square brackets in markdown, but a separate
     // title attribute.
@@ -23,76 +734,411 @@ struct Link<'a> {
 }
 
 struct Converter<'a, W: Write> {
-    out: BufWriter<W>,
+    out: Normalizer<BufWriter<W>>,
     // We need to keep track of this so we can write `[1]` footnote markers or similar with text.
     next_link_id: usize,
     links: Vec<Link<'a>>,
+    // Whether we're inside a link or code block, where bare URLs shouldn't be turned into links.
+    in_link: bool,
+    // The text of the link we're in.
+    link_text: String,
+    in_code_block: bool,
+    // Whether we're in a heading. Gemtext headings can't hold links, so the links in one are
+    // written straight after it, labelled with their text.
+    in_heading: bool,
+    // How many blockquotes we're inside. Every paragraph in a quote becomes a quote line.
+    quote_depth: usize,
+    // The lists we're in, with the number of the next item in each ordered one.
+    lists: Vec<Option<u64>>,
+    // For each list item we're in, whether it's still waiting for its first block, which goes on
+    // the line with its marker. Later blocks go on lines of their own.
+    items: Vec<bool>,
+    // Whether the next table cell is the first in its row.
+    first_cell: bool,
+    // Whether the next event starts a line that Gemtext would read as plain text.
+    line_start: bool,
+    line_escape: &'a str,
+    raw_gemtext: bool,
+    heading_overflow: HeadingOverflow,
+    // How many headings of each level we've seen since the last heading above it, if headings
+    // are being numbered.
+    heading_counts: Option<[usize; 6]>,
+    schemes: &'a HashMap<String, SchemeHandling>,
+    link_hosts: bool,
+    mirror_url: Option<&'a str>,
+    footer: Option<&'a str>,
+    // Whether we're in a block of raw Gemtext.
+    in_raw: bool,
 }
 
 impl<'a, W: Write> Converter<'a, W> {
-    fn new(writer: W) -> Self {
+    fn new(out: Normalizer<BufWriter<W>>, options: &'a ConverterOptions) -> Self {
         Self {
-            out: BufWriter::new(writer),
+            out,
             next_link_id: 1,
             links: vec![],
+            in_link: false,
+            link_text: String::new(),
+            in_code_block: false,
+            in_heading: false,
+            quote_depth: 0,
+            lists: vec![],
+            items: vec![],
+            first_cell: false,
+            line_start: false,
+            line_escape: &options.line_escape,
+            raw_gemtext: options.raw_gemtext,
+            heading_overflow: options.heading_overflow,
+            heading_counts: Some([0; 6]).filter(|_| options.number_headings),
+            schemes: &options.schemes,
+            link_hosts: options.link_hosts,
+            mirror_url: options.mirror_url.as_deref(),
+            footer: options.footer.as_deref(),
+            in_raw: false,
         }
     }
-    fn convert(mut self, parser: Parser<'a>) -> Result<()> {
-        for event in parser {
+    fn convert(mut self, events: impl Iterator<Item = Event<'a>>) -> Result<()> {
+        for event in events {
+            let line_start = std::mem::take(&mut self.line_start);
+            if let Event::Start(tag) = &event {
+                self.start_block_in_item(tag)?;
+            }
             match event {
                 Event::Start(Tag::Emphasis) | Event::End(Tag::Emphasis) => self.write("*")?,
                 Event::Start(Tag::Strong) | Event::End(Tag::Strong) => self.write("**")?,
                 Event::Start(Tag::Strikethrough) | Event::End(Tag::Strikethrough) => {
                     self.write("~~")?
                 }
-                Event::Start(Tag::BlockQuote) => self.write(">")?,
-                // TODO: Nested lists, properly dealing with ordered lists.
-                Event::Start(Tag::Item) => self.write("* ")?,
-                Event::End(Tag::Item) => self.write("\n")?,
-                Event::End(Tag::List(_)) => self.write("\n")?,
+                Event::Start(Tag::BlockQuote) => self.quote_depth += 1,
+                Event::End(Tag::BlockQuote) => {
+                    self.quote_depth -= 1;
+                    if self.quote_depth == 0 && self.items.is_empty() {
+                        self.write("\n")?;
+                        self.write_pending_links()?
+                    }
+                }
+                Event::Start(Tag::Paragraph) if self.quote_depth > 0 => self.write(">")?,
+                Event::Start(Tag::Paragraph) => self.line_start = self.items.is_empty(),
+                Event::Start(Tag::List(start)) => self.lists.push(start),
+                // Gemtext only has unordered lists, so ordered items are written as text, and
+                // nested items are indented after the marker like the table of contents.
+                Event::Start(Tag::Item) => {
+                    self.end_line()?;
+                    let indent = INDENT.repeat(self.lists.len().saturating_sub(1));
+                    let marker = match self.lists.last_mut() {
+                        Some(Some(number)) => {
+                            *number += 1;
+                            format!("{}{}. ", indent, *number - 1)
+                        }
+                        _ => format!("* {}", indent),
+                    };
+                    self.write(&marker)?;
+                    self.items.push(true)
+                }
+                Event::End(Tag::Item) => {
+                    self.items.pop();
+                    self.end_line()?
+                }
+                // Links are held back until the end of the outermost list, like quotes.
+                Event::End(Tag::List(_)) => {
+                    self.lists.pop();
+                    if self.lists.is_empty() {
+                        self.write("\n")?;
+                        self.write_pending_links()?
+                    }
+                }
                 Event::Start(Tag::Heading(depth)) => {
-                    self.out
-                        // Max out at 3, since that's the most Gemtext supports.
-                        .write_all(vec![b'#'; depth.min(3) as usize].as_slice())?;
-                    self.write(" ")?
+                    // Links held back from a list belong before the next section.
+                    if self.items.is_empty() {
+                        self.write_pending_links()?;
+                    }
+                    self.in_heading = true;
+                    match (depth, self.heading_overflow) {
+                        (1..=3, _) => {
+                            self.out.write_all(vec![b'#'; depth as usize].as_slice())?;
+                            self.write(" ")?
+                        }
+                        (_, HeadingOverflow::Clamp) => self.write("### ")?,
+                        (_, HeadingOverflow::Bold) => self.write("**")?,
+                        (_, HeadingOverflow::Depth) => self.write(&format!("### ({}) ", depth))?,
+                    }
+                    self.write_heading_number(depth)?
+                }
+                Event::End(Tag::Heading(depth)) => {
+                    self.in_heading = false;
+                    if depth > 3 && self.heading_overflow == HeadingOverflow::Bold {
+                        self.write("**")?;
+                    }
+                    self.write("\n")?;
+                    if self.links.is_empty() {
+                        self.write("\n")?
+                    } else {
+                        self.write_pending_links()?
+                    }
                 }
-                Event::End(Tag::Heading(_)) => self.write("\n\n")?,
+                Event::HardBreak if self.in_heading => self.write(" ")?,
+                // Links are held back until the end of the quote, so they don't split it up.
+                Event::End(Tag::Paragraph) if self.quote_depth > 0 => self.write("\n")?,
+                Event::End(Tag::Paragraph) if !self.items.is_empty() => self.end_line()?,
                 Event::End(Tag::Paragraph) => {
                     self.write("\n\n")?;
                     self.write_pending_links()?
                 }
-                Event::End(Tag::Link(_, destination, title)) => {
-                    self.handle_link(destination, title)?
+                Event::Start(Tag::Link(..)) => {
+                    self.in_link = true;
+                    self.link_text.clear()
+                }
+                Event::End(Tag::Link(kind, destination, title)) => {
+                    self.in_link = false;
+                    // Email autolinks like <me@example.org> come without their scheme.
+                    let destination = match kind {
+                        LinkType::Email if scheme(&destination).is_none() => {
+                            format!("mailto:{}", destination).into()
+                        }
+                        _ => destination,
+                    };
+                    match self.scheme_handling(&destination) {
+                        SchemeHandling::Link if self.in_heading => {
+                            self.handle_heading_link(destination, title)
+                        }
+                        SchemeHandling::Link => self.handle_link(destination, title)?,
+                        SchemeHandling::Inline => {
+                            // Autolinks already show where they go.
+                            let target = inline_target(&destination);
+                            if self.link_text != target && self.link_text != *destination {
+                                self.write(&format!(" ({})", target))?
+                            }
+                        }
+                        SchemeHandling::Drop => (),
+                    }
+                }
+                Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info)))
+                    if self.raw_gemtext && &*info == raw::INFO =>
+                {
+                    self.in_raw = true;
+                    self.out.set_verbatim(true)
+                }
+                Event::End(Tag::CodeBlock(_)) if self.in_raw => {
+                    self.in_raw = false;
+                    self.out.set_verbatim(false);
+                    self.write("\n")?
+                }
+                Event::Text(text) if self.in_raw => self.write(&text)?,
+                Event::Start(Tag::CodeBlock(kind)) => {
+                    self.in_code_block = true;
+                    self.write("```")?;
+                    if let CodeBlockKind::Fenced(alt) = kind {
+                        self.write(&alt)?;
+                    }
+                    self.write("\n")?
+                }
+                Event::End(Tag::CodeBlock(_)) => {
+                    self.in_code_block = false;
+                    self.write("```\n")?;
+                    if self.items.is_empty() {
+                        self.write("\n")?
+                    }
                 }
                 Event::Text(text) => {
-                    self.write(&text)?;
+                    if line_start && is_line_syntax(&text) {
+                        self.write(self.line_escape)?;
+                    }
+                    self.handle_text(&text)?
+                }
+                // Gemtext has no inline code, so keep the backticks like we do for emphasis.
+                Event::Code(code) => {
+                    self.write("`")?;
+                    self.write(&code)?;
+                    self.write("`")?
                 }
                 Event::SoftBreak => self.write(" ")?,
+                Event::Start(Tag::TableHead) | Event::Start(Tag::TableRow) => {
+                    self.first_cell = true
+                }
+                Event::Start(Tag::TableCell) => {
+                    let first = std::mem::take(&mut self.first_cell);
+                    self.write(if first { "" } else { " | " })?
+                }
+                Event::End(Tag::TableHead) | Event::End(Tag::TableRow) => self.write("\n")?,
+                Event::End(Tag::Table(_)) => self.write("\n")?,
+                Event::FootnoteReference(label) => self.write(&format!("[^{}]", label))?,
+                Event::Start(Tag::FootnoteDefinition(label)) => {
+                    self.write(&format!("[^{}]: ", label))?
+                }
+                Event::TaskListMarker(checked) => {
+                    self.write(if checked { "[x] " } else { "[ ] " })?
+                }
                 _ => (),
             }
         }
+        if let Some(footer) = self.footer {
+            self.write_pending_links()?;
+            self.write(&format!("\n\n{}\n", footer.trim()))?;
+        }
+        if let Some(mirror_url) = self.mirror_url {
+            self.write_pending_links()?;
+            self.write(&format!("\n\n=> {} Read on the web\n", mirror_url))?;
+        }
+        self.out.finish()?;
         Ok(())
     }
 
-    fn handle_link(&mut self, destination: CowStr<'a>, title: CowStr<'a>) -> Result<()> {
-        self.links.push(Link { destination, title });
-        self.write(&format!("[{}]", self.next_link_id))?;
-        self.next_link_id += 1;
+    /// Puts a block that's in a list item on a line of its own, unless it's the paragraph the item
+    /// starts with. Paragraphs are indented so that they read as part of the item.
+    fn start_block_in_item(&mut self, tag: &Tag) -> Result<()> {
+        let block = matches!(
+            tag,
+            Tag::Paragraph
+                | Tag::BlockQuote
+                | Tag::CodeBlock(_)
+                | Tag::List(_)
+                | Tag::Heading(_)
+                | Tag::Table(_)
+        );
+        let first = match self.items.last_mut() {
+            Some(first) if block => std::mem::replace(first, false),
+            _ => return Ok(()),
+        };
+        if first && *tag == Tag::Paragraph {
+            return Ok(());
+        }
+        self.end_line()?;
+        if *tag == Tag::Paragraph && self.quote_depth == 0 {
+            self.write(&INDENT.repeat(self.items.len()))?;
+        }
         Ok(())
     }
 
+    /// Ends the current line, if anything has been written to it.
+    fn end_line(&mut self) -> Result<()> {
+        if self.out.at_line_start() {
+            return Ok(());
+        }
+        self.write("\n")
+    }
+
+    /// Counts a heading at the given depth and writes its number, like `1.2 `, if headings are
+    /// being numbered. Levels above the first heading in the document are left out rather than
+    /// numbered zero.
+    fn write_heading_number(&mut self, depth: u32) -> Result<()> {
+        let counts = match &mut self.heading_counts {
+            Some(counts) => counts,
+            None => return Ok(()),
+        };
+        let level = (depth as usize).max(1).min(counts.len()) - 1;
+        counts[level] += 1;
+        for count in &mut counts[level + 1..] {
+            *count = 0;
+        }
+        let first = counts.iter().position(|&count| count > 0).unwrap_or(level);
+        let number: Vec<_> = counts[first..=level]
+            .iter()
+            .map(|count| count.to_string())
+            .collect();
+        let number = number.join(".");
+        if first == level {
+            self.write(&format!("{}. ", number))
+        } else {
+            self.write(&format!("{} ", number))
+        }
+    }
+
+    /// Writes the marker for a link, reusing the marker of an earlier link to the same place if
+    /// its line hasn't been written yet.
+    fn handle_link(&mut self, destination: CowStr<'a>, title: CowStr<'a>) -> Result<()> {
+        let existing = self
+            .links
+            .iter()
+            .find(|link| link.destination == destination)
+            .map(|link| link.id);
+        let id = match existing {
+            Some(id) => id,
+            None => {
+                let id = self.next_link_id;
+                self.next_link_id += 1;
+                self.links.push(Link {
+                    id,
+                    destination,
+                    title,
+                });
+                id
+            }
+        };
+        self.write(&format!("[{}]", id))
+    }
+
+    /// Holds back a link in a heading to be written after it, without a marker. Its title is the
+    /// link's text unless it has one of its own.
+    fn handle_heading_link(&mut self, destination: CowStr<'a>, title: CowStr<'a>) {
+        if self
+            .links
+            .iter()
+            .any(|link| link.destination == destination)
+        {
+            return;
+        }
+        let title = if title.is_empty() {
+            self.link_text.trim().to_owned().into()
+        } else {
+            title
+        };
+        self.links.push(Link {
+            id: 0,
+            destination,
+            title,
+        });
+    }
+
+    /// How to write a link to the given destination.
+    fn scheme_handling(&self, destination: &str) -> SchemeHandling {
+        scheme(destination)
+            .and_then(|scheme| self.schemes.get(&scheme.to_ascii_lowercase()))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Writes the given text, turning any bare URLs in it into links.
+    fn handle_text(&mut self, mut text: &str) -> Result<()> {
+        if self.in_link {
+            self.link_text.push_str(text);
+        }
+        if self.in_link || self.in_code_block {
+            return self.write(text);
+        }
+        while let Some(range) = find_bare_url(text) {
+            self.write(&text[..range.start])?;
+            let url = &text[range.clone()];
+            self.write(url)?;
+            // The URL is already written out, so there's nothing to inline.
+            if self.scheme_handling(url) == SchemeHandling::Link {
+                self.handle_link(url.to_owned().into(), "".into())?;
+            }
+            text = &text[range.end..];
+        }
+        self.write(text)
+    }
+
     /// Writes all of the links in `self.links`. Adds additional padding if any links were written.
     fn write_pending_links(&mut self) -> Result<()> {
         if self.links.is_empty() {
             return Ok(());
         }
-        let links = std::mem::replace(&mut self.links, vec![]);
+        let links = std::mem::take(&mut self.links);
         for link in links {
             self.write("=> ")?;
             self.write(&link.destination)?;
-            if !link.title.is_empty() {
+            let mut label = link.title.to_string();
+            if self.link_hosts {
+                if let Some(host) = link_host(&link.destination) {
+                    if !label.is_empty() {
+                        label.push(' ');
+                    }
+                    label.push_str(&format!("({})", host));
+                }
+            }
+            if !label.is_empty() {
                 self.write(" ")?;
-                self.write(&link.title)?;
+                self.write(&label)?;
             }
             self.write("\n")?;
         }
@@ -106,9 +1152,97 @@ impl<'a, W: Write> Converter<'a, W> {
     }
 }
 
+/// How far each level of a nested list, and paragraphs after the first in an item, are indented.
+const INDENT: &str = "  ";
+
+/// Line prefixes that Gemtext gives a meaning to.
+const LINE_SYNTAX: &[&str] = &["=>", "#", "* ", ">", "```"];
+
+/// Whether text at the start of a line would be read as something other than plain text.
+fn is_line_syntax(text: &str) -> bool {
+    LINE_SYNTAX.iter().any(|prefix| text.starts_with(prefix))
+}
+
+/// The scheme of a URL, or `None` if it's relative.
+fn scheme(url: &str) -> Option<&str> {
+    let end = url.find(':')?;
+    let scheme = &url[..end];
+    let valid = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    Some(scheme).filter(|_| valid)
+}
+
+/// The host a link to another site goes to, or `None` if it's relative.
+fn link_host(destination: &str) -> Option<&str> {
+    let rest = &destination[scheme(destination)?.len() + 1..];
+    let authority = rest.strip_prefix("//")?;
+    let authority = authority.split(|c| "/?#".contains(c)).next()?;
+    // Leave out any user and port.
+    let host = authority.rsplit('@').next()?;
+    let host = match host.rfind(':') {
+        Some(colon) if !host.ends_with(']') => &host[..colon],
+        _ => host,
+    };
+    Some(host).filter(|host| !host.is_empty())
+}
+
+/// What to write in place of a link to the given URL: the part after the scheme without any
+/// query, like the address of a `mailto:` link, or the whole URL if it has an authority.
+fn inline_target(url: &str) -> &str {
+    let rest = match scheme(url) {
+        Some(scheme) => &url[scheme.len() + 1..],
+        None => url,
+    };
+    if rest.starts_with("//") {
+        return url;
+    }
+    rest.split('?').next().unwrap_or(rest)
+}
+
+/// URL schemes that are turned into links when they appear bare in text.
+const BARE_URL_SCHEMES: &[&str] = &["gemini://", "gopher://", "https://", "http://"];
+
+/// Finds the first bare URL in the text, returning its byte range. Trailing punctuation isn't
+/// considered part of the URL, so `see https://example.com.` links to the right place.
+fn find_bare_url(text: &str) -> Option<Range<usize>> {
+    let mut offset = 0;
+    while offset < text.len() {
+        let rest = &text[offset..];
+        let (start, scheme) = BARE_URL_SCHEMES
+            .iter()
+            .filter_map(|scheme| rest.find(scheme).map(|start| (start, scheme)))
+            .min()?;
+        let start = offset + start;
+        let len = text[start..]
+            .find(char::is_whitespace)
+            .unwrap_or_else(|| text.len() - start);
+        let url = text[start..start + len].trim_end_matches(|c| ".,:;!?'\")".contains(c));
+        // Skip things like `xhttp://`, or a scheme with nothing after it.
+        let mid_word = matches!(text[..start].chars().next_back(), Some(c) if c.is_alphanumeric());
+        if !mid_word && url.len() > scheme.len() {
+            return Some(start..start + url.len());
+        }
+        offset = start + scheme.len();
+    }
+    None
+}
+
+/// Strips any byte order mark and turns CRLF line endings into LF, since files edited on Windows
+/// often have both.
+fn normalize_input(markdown: &str) -> Cow<'_, str> {
+    let markdown = markdown.strip_prefix('\u{feff}').unwrap_or(markdown);
+    if markdown.contains('\r') {
+        Cow::Owned(markdown.replace("\r\n", "\n"))
+    } else {
+        Cow::Borrowed(markdown)
+    }
+}
+
 /// Removes the Zola front matter from some markdown text. The front matter is delimited by +++
 /// symbols.
-fn strip_matter(markdown: &str) -> &str {
+pub fn strip_matter(markdown: &str) -> &str {
     let splits: Vec<_> = markdown.splitn(3, "+++").collect();
     match splits.len() {
         1 => splits[0],
@@ -118,13 +1252,73 @@ fn strip_matter(markdown: &str) -> &str {
     }
 }
 
+/// Looks up a key in the front matter, which is read as `key = value` lines. Quotes around the
+/// value are removed.
+pub fn front_matter_value<'a>(markdown: &'a str, key: &str) -> Option<&'a str> {
+    front_matter(markdown).find_map(|(name, value)| Some(value).filter(|_| name == key))
+}
+
+/// Every key and value in the front matter, in the order they're given, read like
+/// `front_matter_value` reads them.
+pub fn front_matter(markdown: &str) -> impl Iterator<Item = (&str, &str)> {
+    let mut splits = markdown.splitn(3, "+++");
+    let matter = match (splits.next(), splits.next(), splits.next()) {
+        (Some(_), Some(matter), Some(_)) => matter,
+        _ => "",
+    };
+    matter.lines().filter_map(|line| {
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => Some((name.trim(), value.trim().trim_matches('"'))),
+            _ => None,
+        }
+    })
+}
+
+/// The values of a list in the front matter, like `tags = ["rust", "gemini"]`.
+pub fn front_matter_list<'a>(markdown: &'a str, key: &str) -> Vec<&'a str> {
+    let list = match front_matter_value(markdown, key) {
+        Some(list) => list.trim_start_matches('[').trim_end_matches(']'),
+        None => return vec![],
+    };
+    list.split(',')
+        .map(|item| item.trim().trim_matches('"'))
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Escapes text so that it's read as plain text when put into Markdown, for text from elsewhere,
+/// like a query a reader typed in.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\n' | '\r' => escaped.push(' '),
+            c if c.is_ascii_punctuation() => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use indoc::indoc;
 
     fn check_conversion(markdown: &str, gemini: &str) -> Result<()> {
-        let bytes = to_gemini(markdown)?;
+        check_conversion_with(&ConverterOptions::new(), markdown, gemini)
+    }
+
+    fn check_conversion_with(
+        options: &ConverterOptions,
+        markdown: &str,
+        gemini: &str,
+    ) -> Result<()> {
+        let bytes = to_gemini_with(markdown, options)?;
         assert_eq!(gemini, String::from_utf8(bytes)?);
         Ok(())
     }
@@ -148,28 +1342,1064 @@ mod test {
         )
     }
 
-    mod lists {
-        use super::*;
+    #[test]
+    fn one_blank_line_between_blocks() -> Result<()> {
+        check_conversion(
+            "* item\n\n# heading\n\n> quote\n\ntext\n\n```\ncode\n```\n* item",
+            "* item\n\n# heading\n\n>quote\n\ntext\n\n```\ncode\n```\n\n* item",
+        )
+    }
 
-        #[test]
-        fn unordered() -> Result<()> {
-            for marker in &["*", "-", "+"] {
-                check_conversion(
-                    &format!("{marker} first\n{marker} second", marker = marker),
-                    "* first\n* second",
-                )?;
-            }
-            Ok(())
-        }
+    #[test]
+    fn crlf() -> Result<()> {
+        let markdown = "\u{feff}+++\r\ntitle = \"x\"\r\n+++\r\n# one\r\n\r\nsome\r\ntext  \r\n\r\n```\r\ncode\r\n```\r\n";
+        check_conversion(markdown, "# one\n\nsome text\n\n```\ncode\n```")
+    }
+
+    #[test]
+    fn heading_overflow() -> Result<()> {
+        let markdown = "### three\n#### four\n###### six";
+        let options = ConverterOptions::new().heading_overflow(HeadingOverflow::Bold);
+        check_conversion_with(&options, markdown, "### three\n\n**four**\n\n**six**")?;
+        let options = ConverterOptions::new().heading_overflow(HeadingOverflow::Depth);
+        check_conversion_with(
+            &options,
+            markdown,
+            "### three\n\n### (4) four\n\n### (6) six",
+        )
+    }
+
+    #[test]
+    fn trailing_newline() -> Result<()> {
+        let options = ConverterOptions::new().trailing_newline(true);
+        check_conversion_with(&options, "foo\n\nbar\n\n\n", "foo\n\nbar\n")
+    }
+
+    #[test]
+    fn ansi_colors() -> Result<()> {
+        let markdown = "# Hi\n\nSee [this](/a).";
+        let options = ConverterOptions::new().ansi_colors(true);
+        let plain = String::from_utf8(to_gemini(markdown)?)?;
+        check_conversion_with(&options, markdown, &crate::ansi::colorize(&plain))
+    }
+
+    #[test]
+    fn async_conversion() -> Result<()> {
+        // Long enough to take several chunks.
+        let markdown = "# heading\n\nsome [link](gemini://example.com)\n\n".repeat(1000);
+        let mut out = vec![];
+        let options = Arc::new(ConverterOptions::new());
+        task::block_on(convert_to_async(markdown.clone(), options, &mut out))?;
+        assert_eq!(out, to_gemini(&markdown)?);
+        Ok(())
+    }
+
+    #[test]
+    fn strip_emphasis() -> Result<()> {
+        let options = ConverterOptions::new().emphasis(EmphasisStyle::Strip);
+        check_conversion_with(
+            &options,
+            "*some* **strong** ~~struck~~ `*code*`",
+            "some strong struck `*code*`",
+        )
+    }
+
+    #[test]
+    fn caps_emphasis() -> Result<()> {
+        let options = ConverterOptions::new().emphasis(EmphasisStyle::Caps);
+        check_conversion_with(
+            &options,
+            "*some* **strong *and* more** ~~struck~~ `*code*`",
+            "SOME STRONG AND MORE ~~struck~~ `*code*`",
+        )
+    }
+
+    #[test]
+    fn unicode_emphasis() -> Result<()> {
+        let options = ConverterOptions::new().emphasis(EmphasisStyle::Unicode);
+        check_conversion_with(
+            &options,
+            "*Hi 2* **Hi 2** ***Hi*** ~~no~~ **see https://a.org**",
+            "𝘏𝘪 2 𝗛𝗶 𝟮 𝙃𝙞 n\u{336}o\u{336} 𝘀𝗲𝗲 https://a.org[1]\n\n=> https://a.org",
+        )
+    }
+
+    #[test]
+    fn hooks() -> Result<()> {
+        let options = ConverterOptions::new()
+            .shortcode("me", "[home](/)")
+            .preprocessor(|markdown| Some(markdown.replace("%me%", "{{ me() }}")))
+            .preprocessor(|_| None)
+            .event_hook(|events| Box::new(events.filter(|event| *event != Event::SoftBreak)))
+            .event_hook(|events| {
+                Box::new(events.map(|event| match event {
+                    Event::Start(Tag::Link(kind, _, title)) => {
+                        Event::Start(Tag::Link(kind, "/about.gmi".into(), title))
+                    }
+                    Event::End(Tag::Link(kind, _, title)) => {
+                        Event::End(Tag::Link(kind, "/about.gmi".into(), title))
+                    }
+                    event => event,
+                }))
+            });
+        check_conversion_with(&options, "see\n%me%", "seehome[1]\n\n=> /about.gmi")?;
+        let html = crate::markhtml::to_html_with("%me%", &options)?;
+        assert!(String::from_utf8(html)?.contains("href=\"/about.gmi\""));
+        Ok(())
+    }
+
+    #[test]
+    fn timed_conversion() -> Result<()> {
+        let markdown = "# heading\n\n> [!NOTE]\n> some [link](gemini://example.com)";
+        let options = ConverterOptions::new().admonitions(true);
+        let (gemini, _) = to_gemini_timed(markdown, &options)?;
+        assert_eq!(gemini, to_gemini_with(markdown, &options)?);
+        Ok(())
+    }
+
+    #[test]
+    fn wrapping() -> Result<()> {
+        let options = ConverterOptions::new().wrap_width(20);
+        let markdown = "This paragraph is [long enough](gemini://example.com/a/long/url) to wrap.";
+        let gemini = indoc!(
+            "
+            This paragraph is
+            long enough[1] to
+            wrap.
+
+            => gemini://example.com/a/long/url"
+        );
+        check_conversion_with(&options, markdown, gemini)
+    }
+
+    #[test]
+    fn quotes() -> Result<()> {
+        check_conversion(
+            "> first [link](gemini://example.com)\n>\n> second\n\nafter",
+            ">first link[1]\n>second\n\n=> gemini://example.com\n\nafter",
+        )
+    }
+
+    mod escaping {
+        use super::*;
+
+        #[test]
+        fn line_syntax() -> Result<()> {
+            for markdown in &[
+                "=> not a link",
+                r"\# not a heading",
+                r"\* not an item",
+                r"\> no",
+            ] {
+                let gemini = format!("\u{200b}{}", markdown.replace('\\', ""));
+                check_conversion(markdown, &gemini)?;
+            }
+            Ok(())
+        }
+
+        #[test]
+        fn custom_escape() -> Result<()> {
+            let options = ConverterOptions::new().line_escape(" ");
+            check_conversion_with(&options, "=> x\n\n=>y", " => x\n\n =>y")
+        }
+
+        #[test]
+        fn mid_paragraph() -> Result<()> {
+            check_conversion("a => b\n\n*emphasis*", "a => b\n\n*emphasis*")
+        }
+
+        #[test]
+        fn escaped_text() -> Result<()> {
+            let text = "# [a](b) *c*\n> {{ d() }}";
+            check_conversion(&escape(text), "\u{200b}# [a](b) *c* > {{ d() }}")
+        }
+    }
+
+    mod raw_gemtext {
+        use super::*;
+
+        fn check(markdown: &str, gemini: &str) -> Result<()> {
+            check_conversion_with(&ConverterOptions::new().raw_gemtext(true), markdown, gemini)
+        }
+
+        #[test]
+        fn fenced() -> Result<()> {
+            check(
+                "before\n\n```gemtext\n=> gemini://example.com  *link*\n# banner\n```\n\nafter",
+                "before\n\n=> gemini://example.com  *link*\n# banner\n\nafter",
+            )
+        }
+
+        #[test]
+        fn comments() -> Result<()> {
+            let markdown = indoc!(
+                "
+                <!-- gemini:raw -->
+                ```
+                 /\\_/\\
+                ```
+                => /  home
+                <!-- /gemini:raw -->
+
+                after"
+            );
+            let gemini = indoc!(
+                "
+                ```
+                 /\\_/\\
+                ```
+                => /  home
+
+                after"
+            );
+            check(markdown, gemini)
+        }
+
+        #[test]
+        fn disabled_by_default() -> Result<()> {
+            check_conversion("```gemtext\n=> x\n```", "```gemtext\n=> x\n```")
+        }
+    }
+
+    mod extensions {
+        use super::*;
+
+        #[test]
+        fn tables() -> Result<()> {
+            let options = ConverterOptions::new().tables(true);
+            let markdown = "| a | b |\n|---|---|\n| 1 | 2 |\n| 3 | 4 |\n\nafter";
+            check_conversion_with(&options, markdown, "a | b\n1 | 2\n3 | 4\n\nafter")
+        }
+
+        #[test]
+        fn footnotes() -> Result<()> {
+            let options = ConverterOptions::new().footnotes(true);
+            let markdown = "text[^1]\n\n[^1]: the note";
+            check_conversion_with(&options, markdown, "text[^1]\n\n[^1]: the note")
+        }
+
+        #[test]
+        fn tasklists() -> Result<()> {
+            let options = ConverterOptions::new().tasklists(true);
+            check_conversion_with(&options, "- [ ] todo\n- [x] done", "* [ ] todo\n* [x] done")
+        }
+
+        #[test]
+        fn no_strikethrough() -> Result<()> {
+            let options = ConverterOptions::new().strikethrough(false);
+            check_conversion_with(&options, "~~a~~", "~~a~~")?;
+            check_conversion("~~a~~", "~~a~~")
+        }
+    }
+
+    mod table_of_contents {
+        use super::*;
+
+        const ARTICLE: &str = indoc!(
+            "
+            # Title
+
+            intro
+
+            ## One
+
+            ### One `a`
+
+            ## Two"
+        );
+
+        #[test]
+        fn after_title() -> Result<()> {
+            let options = ConverterOptions::new().table_of_contents(true);
+            let gemini = indoc!(
+                "
+                # Title
+
+                * One
+                *   One a
+                * Two
+
+                intro
+
+                ## One
+
+                ### One `a`
+
+                ## Two"
+            );
+            check_conversion_with(&options, ARTICLE, gemini)
+        }
+
+        #[test]
+        fn front_matter() -> Result<()> {
+            let markdown = format!("+++\ntitle = \"x\"\ntoc = true\n+++\n{}", ARTICLE);
+            let gemini = to_gemini(&markdown)?;
+            assert!(String::from_utf8(gemini)?.starts_with("# Title\n\n* One\n"));
+            Ok(())
+        }
+
+        #[test]
+        fn no_title() -> Result<()> {
+            let options = ConverterOptions::new().table_of_contents(true);
+            check_conversion_with(&options, "text\n\n## A", "* A\n\ntext\n\n## A")
+        }
+
+        #[test]
+        fn setext() -> Result<()> {
+            let options = ConverterOptions::new().table_of_contents(true);
+            check_conversion_with(
+                &options,
+                "text\n\nTwo\nlines\n---",
+                "* Two lines\n\ntext\n\n## Two lines",
+            )
+        }
+    }
+
+    mod front_matter {
+        use super::*;
+
+        const PAGE: &str = "+++\ntitle = \"A post\"\ndate = 2020-07-14\n+++\ntext\n\n## Section";
+
+        #[test]
+        fn title() -> Result<()> {
+            let options = ConverterOptions::new().front_matter_title(true);
+            check_conversion_with(&options, PAGE, "# A post\n\ntext\n\n## Section")
+        }
+
+        #[test]
+        fn date() -> Result<()> {
+            let options = ConverterOptions::new()
+                .front_matter_title(true)
+                .front_matter_date(true);
+            check_conversion_with(
+                &options,
+                PAGE,
+                "# A post\n\n2020-07-14\n\ntext\n\n## Section",
+            )
+        }
+
+        #[test]
+        fn with_toc() -> Result<()> {
+            let options = ConverterOptions::new()
+                .front_matter_title(true)
+                .table_of_contents(true);
+            check_conversion_with(
+                &options,
+                PAGE,
+                "# A post\n\n* Section\n\ntext\n\n## Section",
+            )
+        }
+
+        #[test]
+        fn off_by_default() -> Result<()> {
+            check_conversion(PAGE, "text\n\n## Section")
+        }
+
+        #[test]
+        fn values() {
+            let page = "+++\ntitle = \"A post\"\ntags = [\"a\", \"b c\"]\n+++\ntext";
+            assert_eq!(
+                front_matter(page).collect::<Vec<_>>(),
+                vec![("title", "A post"), ("tags", "[\"a\", \"b c\"]")]
+            );
+            assert_eq!(front_matter_list(page, "tags"), vec!["a", "b c"]);
+            assert!(front_matter_list(page, "categories").is_empty());
+            assert_eq!(front_matter("text").count(), 0);
+        }
+    }
+
+    mod aging_notice {
+        use super::*;
+
+        #[test]
+        fn old_page() -> Result<()> {
+            let options = ConverterOptions::new().aging_notice(5);
+            check_conversion_with(
+                &options,
+                "+++\ndate = 2000-01-01\n+++\ntext",
+                &format!(
+                    ">This post is over {} years old.\n\ntext",
+                    Date::parse("2000-01-01")?.years_until(&Date::today())
+                ),
+            )
+        }
+
+        #[test]
+        fn under_title() -> Result<()> {
+            let options = ConverterOptions::new()
+                .front_matter_title(true)
+                .aging_notice(5);
+            let gemini =
+                to_gemini_with("+++\ntitle = \"A\"\ndate = 2000-01-01\n+++\ntext", &options)?;
+            assert!(String::from_utf8(gemini)?.starts_with("# A\n\n>This post is over "));
+            Ok(())
+        }
+
+        #[test]
+        fn recent_page() -> Result<()> {
+            let options = ConverterOptions::new().aging_notice(5);
+            let date = Date::today();
+            check_conversion_with(
+                &options,
+                &format!("+++\ndate = {}\n+++\ntext", date),
+                "text",
+            )
+        }
+
+        #[test]
+        fn page_threshold() -> Result<()> {
+            let page = "+++\ndate = 2000-01-01\naging_notice = 0\n+++\ntext";
+            check_conversion_with(&ConverterOptions::new().aging_notice(5), page, "text")?;
+            let page = "+++\ndate = 2000-01-01\naging_notice = 5\n+++\ntext";
+            let gemini = to_gemini(page)?;
+            assert!(String::from_utf8(gemini)?.starts_with(">This post is over "));
+            Ok(())
+        }
+    }
+
+    mod details {
+        use super::*;
+
+        const FAQ: &str = indoc!(
+            "
+            <details>
+            <summary>Why Gemini?</summary>
+
+            Because it's *small*.
+
+            </details>"
+        );
+
+        #[test]
+        fn summary() -> Result<()> {
+            check_conversion(FAQ, "**Why Gemini?**\n\nBecause it's *small*.")
+        }
+
+        #[test]
+        fn separator() -> Result<()> {
+            let options = ConverterOptions::new().details_separator(true);
+            check_conversion_with(
+                &options,
+                FAQ,
+                "**Why Gemini?**\n\n—\n\nBecause it's *small*.",
+            )
+        }
+
+        #[test]
+        fn one_line() -> Result<()> {
+            check_conversion("<details><summary>Q</summary>A</details>", "**Q**\n\nA")
+        }
+    }
+
+    mod admonitions {
+        use super::*;
+
+        fn check(markdown: &str, gemini: &str) -> Result<()> {
+            check_conversion_with(&ConverterOptions::new().admonitions(true), markdown, gemini)
+        }
+
+        #[test]
+        fn github() -> Result<()> {
+            check(
+                "> [!WARNING]\n> Mind the gap.\n\nafter",
+                ">⚠️ Warning\n>Mind the gap.\n\nafter",
+            )
+        }
+
+        #[test]
+        fn fenced_div() -> Result<()> {
+            check(
+                ":::note\nSome *context*.\n\nMore.\n:::\n\nafter",
+                ">ℹ️ Note\n>Some *context*.\n>More.\n\nafter",
+            )
+        }
+
+        #[test]
+        fn label_style() -> Result<()> {
+            let options = ConverterOptions::new()
+                .admonitions(true)
+                .admonition_style(AdmonitionStyle::Label);
+            check_conversion_with(&options, "> [!TIP] Use a cache.", ">TIP\n>Use a cache.")
+        }
+
+        #[test]
+        fn ordinary_quote() -> Result<()> {
+            check("> [link] text", ">[link] text")
+        }
+
+        #[test]
+        fn inside_code_block() -> Result<()> {
+            check("```\n:::note\n```", "```\n:::note\n```")
+        }
+    }
+
+    mod definition_lists {
+        use super::*;
+
+        fn check(markdown: &str, gemini: &str) -> Result<()> {
+            check_conversion_with(
+                &ConverterOptions::new().definition_lists(true),
+                markdown,
+                gemini,
+            )
+        }
+
+        #[test]
+        fn simple() -> Result<()> {
+            check(
+                "Gemini\n: A protocol\n: A constellation",
+                "**Gemini**\n\n    A protocol\n\n    A constellation",
+            )
+        }
+
+        #[test]
+        fn continuation() -> Result<()> {
+            check(
+                "*Term*\n: first line\nsecond line",
+                "***Term***\n\n    first line second line",
+            )
+        }
+
+        #[test]
+        fn ordinary_paragraph() -> Result<()> {
+            check("foo\nbar: baz", "foo bar: baz")
+        }
+
+        #[test]
+        fn disabled_by_default() -> Result<()> {
+            check_conversion("term\n: definition", "term : definition")
+        }
+    }
+
+    mod wiki_links {
+        use super::*;
+
+        fn check(markdown: &str, gemini: &str) -> Result<()> {
+            check_conversion_with(&ConverterOptions::new().wiki_links(true), markdown, gemini)
+        }
+
+        #[test]
+        fn simple() -> Result<()> {
+            check(
+                "see [[Page Name]].",
+                "see Page Name[1].\n\n=> page-name.gmi",
+            )
+        }
+
+        #[test]
+        fn labelled() -> Result<()> {
+            check(
+                "see [[Notes/Some Page#Intro|this]]",
+                "see this[1]\n\n=> notes/some-page.gmi",
+            )
+        }
+
+        #[test]
+        fn code_untouched() -> Result<()> {
+            check("```\n[[Page]]\n```", "```\n[[Page]]\n```")
+        }
+
+        #[test]
+        fn unclosed() -> Result<()> {
+            check("[[Page", "[[Page")
+        }
+    }
+
+    mod shortcodes {
+        use super::*;
+
+        #[test]
+        fn youtube() -> Result<()> {
+            check_conversion(
+                "watch this:\n\n{{ youtube(id=\"dQw4w9WgXcQ\") }}",
+                "watch this:\n\nYouTube video[1]\n\n=> https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            )
+        }
+
+        #[test]
+        fn image() -> Result<()> {
+            check_conversion(
+                "{{ image(src=\"/cat.png\", alt=\"a cat\") }}",
+                "a cat[1]\n\n=> /cat.png",
+            )
+        }
+
+        #[test]
+        fn unknown_keeps_body() -> Result<()> {
+            check_conversion(
+                "{% note(kind=\"info\") %}\nbe careful\n{% end %}\n\n{{ mystery() }}",
+                "be careful",
+            )
+        }
+
+        #[test]
+        fn template() -> Result<()> {
+            let options = ConverterOptions::new().shortcode("note", "**{kind}:** {body}");
+            check_conversion_with(
+                &options,
+                "{% note(kind=\"Warning\") %}hot{% end %}",
+                "**Warning:** hot",
+            )
+        }
+
+        #[test]
+        fn code_untouched() -> Result<()> {
+            check_conversion(
+                "```\n{{ youtube(id=1) }}\n```",
+                "```\n{{ youtube(id=1) }}\n```",
+            )
+        }
+
+        #[test]
+        fn inline_code_untouched() -> Result<()> {
+            check_conversion(
+                "Write `{{ youtube(id=1) }}` for {{ vimeo(id=2) }}.",
+                "Write `{{ youtube(id=1) }}` for Vimeo video[1].\n\n=> https://vimeo.com/2",
+            )
+        }
+
+        #[test]
+        fn indented_code_untouched() -> Result<()> {
+            check_conversion(
+                "Like this:\n\n    {% note() %}\n    hi\n    {% end %}",
+                "Like this:\n\n```\n{% note() %}\nhi\n{% end %}\n```",
+            )
+        }
+    }
+
+    mod abbreviations {
+        use super::*;
+
+        fn check(markdown: &str, gemini: &str) -> Result<()> {
+            let options = ConverterOptions::new()
+                .abbreviation("TLS", "Transport Layer Security")
+                .abbreviation("TOFU", "Trust On First Use");
+            check_conversion_with(&options, markdown, gemini)
+        }
+
+        #[test]
+        fn first_occurrence() -> Result<()> {
+            check(
+                "TOFU and TLS.\n\nMore TLS.",
+                "TOFU (Trust On First Use) and TLS (Transport Layer Security).\n\nMore TLS.",
+            )
+        }
+
+        #[test]
+        fn whole_words() -> Result<()> {
+            check(
+                "TLSv1 and XTLS, then TLS",
+                "TLSv1 and XTLS, then TLS (Transport Layer Security)",
+            )
+        }
+
+        #[test]
+        fn not_in_code() -> Result<()> {
+            check(
+                "`TLS`\n\n```\nTLS\n```\n\nTLS",
+                "`TLS`\n\n```\nTLS\n```\n\nTLS (Transport Layer Security)",
+            )
+        }
+    }
+
+    mod glossary {
+        use super::*;
+
+        fn check(markdown: &str, gemini: &str) -> Result<()> {
+            let options = ConverterOptions::new()
+                .glossary_term("Gemini", "gemini.gmi")
+                .glossary_term("Gemini protocol", "protocol.gmi")
+                .glossary_term("TOFU", "tofu.gmi");
+            check_conversion_with(&options, markdown, gemini)
+        }
+
+        #[test]
+        fn first_occurrence() -> Result<()> {
+            check(
+                "TOFU, like Gemini, is simple. Gemini!\n\nMore TOFU.",
+                indoc!(
+                    "
+                TOFU[1], like Gemini[2], is simple. Gemini!
+
+                => tofu.gmi
+                => gemini.gmi
+
+                More TOFU."
+                ),
+            )
+        }
+
+        #[test]
+        fn longest_term() -> Result<()> {
+            check(
+                "The Gemini protocol and Geminis.",
+                "The Gemini protocol[1] and Geminis.\n\n=> protocol.gmi",
+            )
+        }
+
+        #[test]
+        fn not_in_code_or_links() -> Result<()> {
+            check(
+                "`TOFU` [about TOFU](a.gmi)\n\n```\nTOFU\n```\n\nTOFU",
+                indoc!(
+                    "
+                `TOFU` about TOFU[1]
+
+                => a.gmi
+
+                ```
+                TOFU
+                ```
+
+                TOFU[2]
+
+                => tofu.gmi"
+                ),
+            )
+        }
+    }
+
+    mod citations {
+        use super::*;
+
+        fn check(markdown: &str, gemini: &str) -> Result<()> {
+            let bibliography = Bibliography::from_bibtex(
+                "@article{doe, author = {Doe, J.}, title = {First}, year = 2020}
+                 @misc{roe, title = {Second}, url = {https://example.com}}",
+            )?;
+            let options = ConverterOptions::new().bibliography(bibliography);
+            check_conversion_with(&options, markdown, gemini)
+        }
+
+        #[test]
+        fn numbered() -> Result<()> {
+            let markdown = "As shown [@roe; @doe, p. 3], and again [@roe].";
+            let gemini = indoc!(
+                "
+            As shown [R1; R2, p. 3], and again [R1].
+
+            ## References
+
+            [R1] Second.[1]
+
+            => https://example.com
+
+            [R2] Doe, J. (2020). First."
+            );
+            check(markdown, gemini)
+        }
+
+        #[test]
+        fn unknown_key() -> Result<()> {
+            check("see [@nobody]", "see [@nobody]")
+        }
+
+        #[test]
+        fn not_in_code() -> Result<()> {
+            check("`[@doe]`", "`[@doe]`")
+        }
+    }
+
+    mod math {
+        use super::*;
+
+        #[test]
+        fn display_block() -> Result<()> {
+            check_conversion(
+                "before\n\n$$\na_b + c_d\n$$\n\nafter",
+                "before\n\n```math\na_b + c_d\n```\n\nafter",
+            )
+        }
+
+        #[test]
+        fn single_line() -> Result<()> {
+            check_conversion("$$ x_1 * y_2 * z $$", "```math\nx_1 * y_2 * z\n```")
+        }
+
+        #[test]
+        fn inside_code_block() -> Result<()> {
+            check_conversion("```\n$$\na_b\n$$\n```", "```\n$$\na_b\n$$\n```")
+        }
+
+        #[test]
+        fn unterminated() -> Result<()> {
+            check_conversion("$$ a_b_c", "$$ a_b_c")
+        }
+
+        #[test]
+        fn indented() -> Result<()> {
+            check_conversion("   $$\n   a_b\n$$", "```math\n   a_b\n```")?;
+            // Four spaces make an indented code block instead.
+            check_conversion("    $$\n    a_b\n    $$", "```\n$$\na_b\n$$\n```")
+        }
+
+        #[test]
+        fn closing_line() -> Result<()> {
+            check_conversion("$$\na_b\n$$  ", "```math\na_b\n```")?;
+            check_conversion("$$\na_b\nc_d $$\n\nafter", "$$ a_b c_d $$\n\nafter")
+        }
+    }
+
+    mod headings {
+        use super::*;
+
+        #[test]
+        fn setext() -> Result<()> {
+            let markdown = "Title\n=====\n\nA *sub*\nheading\n---\n\nLine  \nbreak\n===";
+            check_conversion(markdown, "# Title\n\n## A *sub* heading\n\n# Line break")
+        }
+
+        #[test]
+        fn links() -> Result<()> {
+            let markdown = indoc!(
+                r#"
+                # See [the docs](gemini://a.org) and [these](gemini://b.org "Others")
+
+                Some [text](gemini://c.org).
+
+                ## [Again](gemini://a.org), **strongly**
+                "#
+            );
+            let gemini = indoc!(
+                "
+                # See the docs and these
+                => gemini://a.org the docs
+                => gemini://b.org Others
+
+                Some text[1].
+
+                => gemini://c.org
+
+                ## Again, **strongly**
+                => gemini://a.org Again"
+            );
+            check_conversion(markdown, gemini)
+        }
+
+        #[test]
+        fn links_before_heading() -> Result<()> {
+            check_conversion(
+                "* [item](gemini://a.org)\n\n# Next",
+                "* item[1]\n\n=> gemini://a.org\n\n# Next",
+            )
+        }
+    }
+
+    mod lists {
+        use super::*;
+
+        #[test]
+        fn unordered() -> Result<()> {
+            for marker in &["*", "-", "+"] {
+                check_conversion(
+                    &format!("{marker} first\n{marker} second", marker = marker),
+                    "* first\n* second",
+                )?;
+            }
+            Ok(())
+        }
 
         #[test]
         fn ordered_list() -> Result<()> {
             check_conversion("1. foo\n1. bar\n1. baz", "1. foo\n2. bar\n3. baz")
         }
+
+        #[test]
+        fn ordered_start() -> Result<()> {
+            check_conversion("3. foo\n4. bar", "3. foo\n4. bar")
+        }
+
+        #[test]
+        fn nested() -> Result<()> {
+            let markdown = indoc!(
+                "
+                * one
+                  * two
+                    1. three
+                    1. four
+                * five"
+            );
+            let gemini = indoc!(
+                "
+                * one
+                *   two
+                    1. three
+                    2. four
+                * five"
+            );
+            check_conversion(markdown, gemini)
+        }
+
+        #[test]
+        fn blocks_in_items() -> Result<()> {
+            let markdown = indoc!(
+                "
+                * first [link](gemini://a.org)
+
+                  second paragraph
+
+                  > quote
+
+                  ```
+                  code
+                  ```
+                * next
+
+                after"
+            );
+            let gemini = indoc!(
+                "
+                * first link[1]
+                  second paragraph
+                >quote
+                ```
+                code
+                ```
+                * next
+
+                => gemini://a.org
+
+                after"
+            );
+            check_conversion(markdown, gemini)
+        }
+    }
+
+    mod entities {
+        use super::*;
+
+        #[test]
+        fn named() -> Result<()> {
+            check_conversion("a&mdash;b&nbsp;c &amp; d", "a\u{2014}b\u{a0}c & d")
+        }
+
+        #[test]
+        fn numeric() -> Result<()> {
+            check_conversion("&#x1F600; &#65;", "\u{1F600} A")
+        }
+
+        #[test]
+        fn unknown() -> Result<()> {
+            check_conversion("&bogus;", "&bogus;")
+        }
+
+        #[test]
+        fn code_span() -> Result<()> {
+            check_conversion("`&mdash;` &mdash;", "`&mdash;` \u{2014}")
+        }
+
+        #[test]
+        fn code_block() -> Result<()> {
+            check_conversion("```\n&mdash;\n```", "```\n&mdash;\n```")
+        }
+    }
+
+    mod sidenotes {
+        use super::*;
+
+        fn check(markdown: &str, gemini: &str) -> Result<()> {
+            check_conversion_with(&ConverterOptions::new().sidenotes(true), markdown, gemini)
+        }
+
+        #[test]
+        fn end_of_section() -> Result<()> {
+            check(
+                indoc!(
+                    "
+                    Text {^ an aside} and more {^ another {nested}}.
+
+                    ## Next
+
+                    Done {^ last}"
+                ),
+                indoc!(
+                    "
+                    Text[^1] and more[^2].
+
+                    [^1]: an aside
+
+                    [^2]: another {nested}
+
+                    ## Next
+
+                    Done[^3]
+
+                    [^3]: last"
+                ),
+            )
+        }
+
+        #[test]
+        fn not_annotations() -> Result<()> {
+            check(
+                "`{^ code}` and {^} and {^ open\n\n```\n{^ x}\n```",
+                "`{^ code}` and {^} and {^ open\n\n```\n{^ x}\n```",
+            )
+        }
+
+        #[test]
+        fn off_by_default() -> Result<()> {
+            check_conversion("a {^ b}", "a {^ b}")
+        }
+    }
+
+    mod numbered_headings {
+        use super::*;
+
+        fn check(markdown: &str, gemini: &str) -> Result<()> {
+            let options = ConverterOptions::new().number_headings(true);
+            check_conversion_with(&options, markdown, gemini)
+        }
+
+        #[test]
+        fn levels() -> Result<()> {
+            check(
+                "# Intro\n## Background\n## Goals\n### Speed\n# Design\n### Deep",
+                indoc!(
+                    "
+                    # 1. Intro
+
+                    ## 1.1 Background
+
+                    ## 1.2 Goals
+
+                    ### 1.2.1 Speed
+
+                    # 2. Design
+
+                    ### 2.0.1 Deep"
+                ),
+            )
+        }
+
+        #[test]
+        fn no_top_level() -> Result<()> {
+            check("## A\n### B\n## C", "## 1. A\n\n### 1.1 B\n\n## 2. C")
+        }
+
+        #[test]
+        fn overflow() -> Result<()> {
+            check("# A\n#### B", "# 1. A\n\n### 1.0.0.1 B")
+        }
     }
 
     mod links {
         use super::*;
+
+        #[test]
+        fn repeated_destination() -> Result<()> {
+            let markdown =
+                "[one](gemini://a.org), [two](gemini://b.org) and [three](gemini://a.org)";
+            let gemini = indoc!(
+                "
+                one[1], two[2] and three[1]
+
+                => gemini://a.org
+                => gemini://b.org"
+            );
+            check_conversion(markdown, gemini)
+        }
+
         #[test]
         fn one_paragraph() -> Result<()> {
             let markdown =
@@ -219,5 +2449,159 @@ mod test {
             );
             check_conversion(markdown, gemini)
         }
+
+        #[test]
+        fn hosts() -> Result<()> {
+            let options = ConverterOptions::new().link_hosts(true);
+            check_conversion_with(
+                &options,
+                r#"[a](https://sr.ht/~a/b "Cool project") [b](gemini://u@Example.org:1965?q) [c](/local) [d](mailto:x@y.z)"#,
+                indoc!(
+                    "
+                    a[1] b[2] c[3] d[4]
+
+                    => https://sr.ht/~a/b Cool project (sr.ht)
+                    => gemini://u@Example.org:1965?q (Example.org)
+                    => /local
+                    => mailto:x@y.z"
+                ),
+            )
+        }
+
+        #[test]
+        fn mirror_url() -> Result<()> {
+            let options = ConverterOptions::new().mirror_url("https://example.org/a.html");
+            check_conversion_with(
+                &options,
+                "* a [link](/b.md)",
+                indoc!(
+                    "
+                    * a link[1]
+
+                    => /b.md
+
+                    => https://example.org/a.html Read on the web"
+                ),
+            )
+        }
+
+        #[test]
+        fn footer() -> Result<()> {
+            let options = ConverterOptions::new()
+                .footer("=> / Home\n")
+                .mirror_url("https://example.org/a.html");
+            check_conversion_with(
+                &options,
+                "a [link](/b.md)",
+                indoc!(
+                    "
+                    a link[1]
+
+                    => /b.md
+
+                    => / Home
+
+                    => https://example.org/a.html Read on the web"
+                ),
+            )
+        }
+
+        #[test]
+        fn scheme_as_link() -> Result<()> {
+            check_conversion(
+                "[email me](mailto:a@b.org)",
+                "email me[1]\n\n=> mailto:a@b.org",
+            )
+        }
+
+        #[test]
+        fn scheme_inline() -> Result<()> {
+            let options = ConverterOptions::new().scheme("mailto", SchemeHandling::Inline);
+            check_conversion_with(
+                &options,
+                "[email me](mailto:a@b.org?subject=Hi) or [a@b.org](MAILTO:a@b.org)",
+                "email me (a@b.org) or a@b.org",
+            )
+        }
+
+        #[test]
+        fn scheme_inline_with_authority() -> Result<()> {
+            let options = ConverterOptions::new().scheme("ftp", SchemeHandling::Inline);
+            check_conversion_with(
+                &options,
+                "[files](ftp://a.org/pub)",
+                "files (ftp://a.org/pub)",
+            )
+        }
+
+        #[test]
+        fn scheme_dropped() -> Result<()> {
+            let options = ConverterOptions::new().scheme("tel", SchemeHandling::Drop);
+            check_conversion_with(
+                &options,
+                "[call](tel:123) or [visit](gemini://a.org)",
+                "call or visit[1]\n\n=> gemini://a.org",
+            )
+        }
+
+        #[test]
+        fn autolink() -> Result<()> {
+            check_conversion(
+                "see <https://example.com>",
+                "see https://example.com[1]\n\n=> https://example.com",
+            )
+        }
+
+        #[test]
+        fn bare_url() -> Result<()> {
+            let markdown = "see https://example.com/foo, or gemini://example.com.";
+            let gemini = indoc!(
+                "
+            see https://example.com/foo[1], or gemini://example.com[2].
+
+            => https://example.com/foo
+            => gemini://example.com"
+            );
+            check_conversion(markdown, gemini)
+        }
+
+        #[test]
+        fn email_autolink() -> Result<()> {
+            check_conversion(
+                "write to <me@example.org> or <mailto:you@example.org>",
+                indoc!(
+                    "
+                write to me@example.org[1] or mailto:you@example.org[2]
+
+                => mailto:me@example.org
+                => mailto:you@example.org"
+                ),
+            )
+        }
+
+        #[test]
+        fn email_autolink_inline() -> Result<()> {
+            let options = ConverterOptions::new().scheme("mailto", SchemeHandling::Inline);
+            check_conversion_with(
+                &options,
+                "write to <me@example.org>",
+                "write to me@example.org",
+            )
+        }
+
+        #[test]
+        fn bare_url_not_linked() -> Result<()> {
+            let options = ConverterOptions::new().scheme("https", SchemeHandling::Drop);
+            check_conversion_with(
+                &options,
+                "see https://example.com/foo",
+                "see https://example.com/foo",
+            )
+        }
+
+        #[test]
+        fn bare_url_mid_word() -> Result<()> {
+            check_conversion("xhttps://example.com", "xhttps://example.com")
+        }
     }
 }
@@ -0,0 +1,86 @@
+//! Expansion of abbreviations, since Gemtext has nothing like HTML's `<abbr>`. The first time each
+//! abbreviation appears as a whole word in a document, its expansion is added after it in
+//! parentheses, like "TLS (Transport Layer Security)".
+
+use pulldown_cmark::{Event, Tag};
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Range;
+
+/// Wraps an event stream, expanding the first occurrence of each abbreviation.
+pub struct Abbreviations<'a, 'b, I> {
+    inner: I,
+    abbreviations: &'b BTreeMap<String, String>,
+    expanded: HashSet<&'b str>,
+    in_code_block: bool,
+    _marker: std::marker::PhantomData<Event<'a>>,
+}
+
+impl<'a, 'b, I: Iterator<Item = Event<'a>>> Abbreviations<'a, 'b, I> {
+    pub fn new(inner: I, abbreviations: &'b BTreeMap<String, String>) -> Self {
+        Self {
+            inner,
+            abbreviations,
+            expanded: HashSet::new(),
+            in_code_block: false,
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    fn expand(&mut self, text: &str) -> Option<String> {
+        let mut found: Vec<(usize, &'b str, &'b str)> = self
+            .abbreviations
+            .iter()
+            .filter(|(abbreviation, _)| !self.expanded.contains(abbreviation.as_str()))
+            .filter_map(|(abbreviation, expansion)| {
+                find_word(text, abbreviation)
+                    .map(|range| (range.end, abbreviation.as_str(), expansion.as_str()))
+            })
+            .collect();
+        if found.is_empty() {
+            return None;
+        }
+        found.sort();
+        let mut out = String::with_capacity(text.len());
+        let mut written = 0;
+        for (end, abbreviation, expansion) in found {
+            self.expanded.insert(abbreviation);
+            out.push_str(&text[written..end]);
+            out.push_str(" (");
+            out.push_str(expansion);
+            out.push(')');
+            written = end;
+        }
+        out.push_str(&text[written..]);
+        Some(out)
+    }
+}
+
+impl<'a, 'b, I: Iterator<Item = Event<'a>>> Iterator for Abbreviations<'a, 'b, I> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        let event = self.inner.next()?;
+        match &event {
+            Event::Start(Tag::CodeBlock(_)) => self.in_code_block = true,
+            Event::End(Tag::CodeBlock(_)) => self.in_code_block = false,
+            Event::Text(text) if !self.in_code_block => {
+                if let Some(expanded) = self.expand(text) {
+                    return Some(Event::Text(expanded.into()));
+                }
+            }
+            _ => (),
+        }
+        Some(event)
+    }
+}
+
+/// Finds the first occurrence of `word` in `text` that isn't part of a larger word.
+pub fn find_word(text: &str, word: &str) -> Option<Range<usize>> {
+    let is_word_char = |c: Option<char>| matches!(c, Some(c) if c.is_alphanumeric());
+    text.match_indices(word)
+        .map(|(start, _)| start..start + word.len())
+        .find(|range| {
+            !is_word_char(text[..range.start].chars().next_back())
+                && !is_word_char(text[range.end..].chars().next())
+        })
+}
@@ -0,0 +1,180 @@
+//! Support for admonitions, the callout boxes that several Markdown dialects have. GitHub writes
+//! them as blockquotes starting with a marker like `> [!NOTE]`; Pandoc and Docusaurus use fenced
+//! divs like `:::warning`. Fenced divs are rewritten into the GitHub syntax before parsing, and the
+//! marker is then replaced by a label line, so the admonition comes out as a quote headed by
+//! something like "⚠️ Warning".
+
+use anyhow::{anyhow, Error};
+use pulldown_cmark::{Event, Tag};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+/// How an admonition's label line is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdmonitionStyle {
+    /// An emoji followed by the kind, like "⚠️ Warning".
+    Emoji,
+    /// Just the kind in capitals, like "WARNING".
+    Label,
+}
+
+impl Default for AdmonitionStyle {
+    fn default() -> Self {
+        AdmonitionStyle::Emoji
+    }
+}
+
+impl FromStr for AdmonitionStyle {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "emoji" => Ok(AdmonitionStyle::Emoji),
+            "label" => Ok(AdmonitionStyle::Label),
+            _ => Err(anyhow!("unknown admonition style {}", s)),
+        }
+    }
+}
+
+const DIV_FENCE: &str = ":::";
+
+/// Rewrites every fenced div in the Markdown into a blockquote starting with the div's kind as a
+/// GitHub-style marker.
+pub fn quote_divs(markdown: &str) -> Cow<'_, str> {
+    if !markdown.contains(DIV_FENCE) {
+        return Cow::Borrowed(markdown);
+    }
+    let mut out = String::with_capacity(markdown.len());
+    let mut in_fence = false;
+    let mut in_div = false;
+    for line in markdown.lines() {
+        let trimmed = line.trim();
+        if in_div {
+            if trimmed == DIV_FENCE {
+                in_div = false;
+                out.push('\n');
+            } else {
+                out.push_str("> ");
+                out.push_str(line);
+                out.push('\n');
+            }
+            continue;
+        }
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        } else if !in_fence && trimmed.starts_with(DIV_FENCE) {
+            let kind = trimmed.trim_start_matches(':').trim();
+            if !kind.is_empty() && kind.chars().all(|c| c.is_alphanumeric() || c == '-') {
+                in_div = true;
+                out.push_str(&format!("> [!{}]\n", kind.to_uppercase()));
+                continue;
+            }
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    Cow::Owned(out)
+}
+
+/// Wraps an event stream, replacing the `[!KIND]` marker at the start of a blockquote with a label
+/// paragraph. Text events must already have been merged.
+pub struct Admonitions<'a, I> {
+    inner: I,
+    pending: VecDeque<Event<'a>>,
+    style: AdmonitionStyle,
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> Admonitions<'a, I> {
+    pub fn new(inner: I, style: AdmonitionStyle) -> Self {
+        Self {
+            inner,
+            pending: VecDeque::new(),
+            style,
+        }
+    }
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> Iterator for Admonitions<'a, I> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        let event = self.inner.next()?;
+        if event != Event::Start(Tag::BlockQuote) {
+            return Some(event);
+        }
+        let paragraph = self.inner.next();
+        let text = self.inner.next();
+        let marker = match (&paragraph, &text) {
+            (Some(Event::Start(Tag::Paragraph)), Some(Event::Text(text))) => parse_marker(text)
+                .map(|(kind, rest)| (kind.to_owned(), rest.trim_start().to_owned())),
+            _ => None,
+        };
+        let (kind, rest) = match marker {
+            Some(marker) => marker,
+            None => {
+                self.pending.extend(paragraph);
+                self.pending.extend(text);
+                return Some(event);
+            }
+        };
+        self.pending.push_back(Event::Start(Tag::Paragraph));
+        self.pending
+            .push_back(Event::Text(label(&kind, self.style).into()));
+        self.pending.push_back(Event::End(Tag::Paragraph));
+        if !rest.is_empty() {
+            self.pending.push_back(Event::Start(Tag::Paragraph));
+            self.pending.push_back(Event::Text(rest.into()));
+            return Some(event);
+        }
+        // The marker is usually alone on its line, so the body starts after a line break.
+        match self.inner.next() {
+            Some(Event::SoftBreak) | Some(Event::HardBreak) => {
+                self.pending.push_back(Event::Start(Tag::Paragraph))
+            }
+            Some(Event::End(Tag::Paragraph)) | None => (),
+            Some(other) => {
+                self.pending.push_back(Event::Start(Tag::Paragraph));
+                self.pending.push_back(other);
+            }
+        }
+        Some(event)
+    }
+}
+
+/// Splits a `[!KIND]` marker off the start of the text, returning the kind and the rest.
+fn parse_marker(text: &str) -> Option<(&str, &str)> {
+    let text = text.strip_prefix("[!")?;
+    let end = text.find(']')?;
+    let kind = &text[..end];
+    if kind.is_empty() || !kind.chars().all(|c| c.is_alphanumeric() || c == '-') {
+        return None;
+    }
+    Some((kind, &text[end + 1..]))
+}
+
+fn label(kind: &str, style: AdmonitionStyle) -> String {
+    let kind = kind.to_lowercase();
+    match style {
+        AdmonitionStyle::Label => kind.to_uppercase(),
+        AdmonitionStyle::Emoji => {
+            let emoji = match kind.as_str() {
+                "note" | "info" => "ℹ️",
+                "tip" | "hint" => "💡",
+                "important" => "❗",
+                "warning" => "⚠️",
+                "caution" | "danger" => "🛑",
+                _ => "📌",
+            };
+            let mut chars = kind.chars();
+            let capitalized: String = chars
+                .next()
+                .map(|first| first.to_uppercase().chain(chars).collect())
+                .unwrap_or_default();
+            format!("{} {}", emoji, capitalized)
+        }
+    }
+}
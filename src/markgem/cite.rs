@@ -0,0 +1,310 @@
+//! Pandoc-style citations. A citation like `[@doe2020]`, `[@doe2020, p. 3]` or
+//! `[@doe2020; @roe2021]` is replaced by numbered references like `[R1, p. 3]`, numbered in the
+//! order they're first cited, and a references section listing them is added to the end of the
+//! document. The `R` keeps them distinct from the `[1]` markers used for links.
+
+use anyhow::{bail, Result};
+use log::warn;
+use pulldown_cmark::{Event, LinkType, Tag};
+use std::collections::{HashMap, VecDeque};
+
+/// A bibliography of works that can be cited, keyed by their citation key.
+#[derive(Debug, Clone, Default)]
+pub struct Bibliography {
+    entries: HashMap<String, Entry>,
+}
+
+/// A single work in a bibliography, with its fields (author, title, and so on) keyed by their
+/// lowercased names.
+#[derive(Debug, Clone, Default)]
+struct Entry {
+    fields: HashMap<String, String>,
+}
+
+impl Bibliography {
+    /// Parses a BibTeX bibliography. `@string`, `@preamble` and `@comment` entries are skipped, and
+    /// string concatenation with `#` isn't supported.
+    pub fn from_bibtex(bibtex: &str) -> Result<Self> {
+        let mut entries = HashMap::new();
+        let mut rest = bibtex;
+        while let Some(at) = rest.find('@') {
+            rest = &rest[at + 1..];
+            let open = match rest.find(&['{', '('][..]) {
+                Some(open) => open,
+                None => bail!(
+                    "expected {{ after @{}",
+                    rest.lines().next().unwrap_or_default()
+                ),
+            };
+            let kind = rest[..open].trim().to_lowercase();
+            let close = match matching_close(&rest[open..]) {
+                Some(close) => open + close,
+                None => bail!("unterminated @{} entry", kind),
+            };
+            let body = &rest[open + 1..close];
+            rest = &rest[close + 1..];
+            if kind == "string" || kind == "preamble" || kind == "comment" {
+                continue;
+            }
+            let (key, fields) = match body.find(',') {
+                Some(comma) => (body[..comma].trim(), &body[comma + 1..]),
+                None => (body.trim(), ""),
+            };
+            entries.insert(key.to_owned(), Entry::parse(fields)?);
+        }
+        Ok(Self { entries })
+    }
+}
+
+impl Entry {
+    fn parse(mut fields: &str) -> Result<Self> {
+        let mut entry = Entry::default();
+        loop {
+            fields = fields.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+            if fields.is_empty() {
+                return Ok(entry);
+            }
+            let equals = match fields.find('=') {
+                Some(equals) => equals,
+                None => bail!("expected = in {}", fields),
+            };
+            let name = fields[..equals].trim().to_lowercase();
+            let value = fields[equals + 1..].trim_start();
+            let (value, rest) = match value.chars().next() {
+                Some('{') => {
+                    let close = matching_close(value)
+                        .ok_or_else(|| anyhow::anyhow!("unterminated value for {}", name))?;
+                    (&value[1..close], &value[close + 1..])
+                }
+                Some('"') => {
+                    let close = value[1..]
+                        .find('"')
+                        .ok_or_else(|| anyhow::anyhow!("unterminated value for {}", name))?;
+                    (&value[1..close + 1], &value[close + 2..])
+                }
+                _ => {
+                    let end = value.find(',').unwrap_or(value.len());
+                    (value[..end].trim(), &value[end..])
+                }
+            };
+            let value: String = value.chars().filter(|&c| c != '{' && c != '}').collect();
+            entry
+                .fields
+                .insert(name, value.split_whitespace().collect::<Vec<_>>().join(" "));
+            fields = rest;
+        }
+    }
+
+    fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+
+    /// Formats the entry like "Doe, Jane and Roe, R. (2020). Title. Journal."
+    fn format(&self) -> String {
+        let mut formatted = String::new();
+        if let Some(author) = self.field("author").or_else(|| self.field("editor")) {
+            formatted.push_str(&author.replace(" and ", "; "));
+            formatted.push(' ');
+        }
+        if let Some(year) = self.field("year") {
+            formatted.push_str(&format!("({}). ", year));
+        }
+        let container = ["journal", "booktitle", "publisher", "howpublished"]
+            .iter()
+            .find_map(|name| self.field(name));
+        for part in self.field("title").into_iter().chain(container) {
+            formatted.push_str(part.trim_end_matches('.'));
+            formatted.push_str(". ");
+        }
+        formatted.trim_end().to_owned()
+    }
+
+    fn url(&self) -> Option<String> {
+        self.field("url").map(str::to_owned).or_else(|| {
+            self.field("doi")
+                .map(|doi| format!("https://doi.org/{}", doi))
+        })
+    }
+}
+
+/// Given a string starting with an opening bracket, finds the index of the matching close.
+fn matching_close(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '{' | '(' => depth += 1,
+            '}' | ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Wraps an event stream, replacing citations with numbered references and appending the list of
+/// references. Text events must already have been merged.
+pub struct Citations<'a, 'b, I> {
+    inner: I,
+    bibliography: &'b Bibliography,
+    // Keys in the order they were first cited.
+    cited: Vec<&'b str>,
+    pending: VecDeque<Event<'a>>,
+    in_code_block: bool,
+    finished: bool,
+}
+
+impl<'a, 'b, I: Iterator<Item = Event<'a>>> Citations<'a, 'b, I> {
+    pub fn new(inner: I, bibliography: &'b Bibliography) -> Self {
+        Self {
+            inner,
+            bibliography,
+            cited: vec![],
+            pending: VecDeque::new(),
+            in_code_block: false,
+            finished: false,
+        }
+    }
+
+    /// Replaces every citation in the text.
+    fn replace_citations(&mut self, mut text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        while let Some(start) = text.find("[@") {
+            let end = match text[start..].find(']') {
+                Some(end) => start + end,
+                None => break,
+            };
+            out.push_str(&text[..start]);
+            match self.render(&text[start + 1..end]) {
+                Some(rendered) => out.push_str(&rendered),
+                None => out.push_str(&text[start..=end]),
+            }
+            text = &text[end + 1..];
+        }
+        out.push_str(text);
+        out
+    }
+
+    /// Renders the inside of a citation like `@doe2020, p. 3; @roe2021`.
+    fn render(&mut self, citation: &str) -> Option<String> {
+        let mut parts = vec![];
+        for part in citation.split(';') {
+            let part = part.trim().strip_prefix('@')?;
+            let (key, locator) = match part.find(',') {
+                Some(comma) => (&part[..comma], Some(part[comma + 1..].trim())),
+                None => (part, None),
+            };
+            let (key, _) = match self.bibliography.entries.get_key_value(key.trim()) {
+                Some(entry) => entry,
+                None => {
+                    warn!("Unknown citation key {}", key);
+                    return None;
+                }
+            };
+            let index = match self.cited.iter().position(|&cited| cited == key) {
+                Some(index) => index,
+                None => {
+                    self.cited.push(key);
+                    self.cited.len() - 1
+                }
+            };
+            parts.push(match locator {
+                Some(locator) => format!("R{}, {}", index + 1, locator),
+                None => format!("R{}", index + 1),
+            });
+        }
+        Some(format!("[{}]", parts.join("; ")))
+    }
+
+    /// Queues up the references section.
+    fn push_references(&mut self) {
+        if self.cited.is_empty() {
+            return;
+        }
+        self.pending.push_back(Event::Start(Tag::Heading(2)));
+        self.pending.push_back(Event::Text("References".into()));
+        self.pending.push_back(Event::End(Tag::Heading(2)));
+        for (i, key) in self.cited.iter().enumerate() {
+            let entry = &self.bibliography.entries[*key];
+            self.pending.push_back(Event::Start(Tag::Paragraph));
+            let text = format!("[R{}] {}", i + 1, entry.format());
+            match entry.url() {
+                Some(url) => {
+                    let tag = || Tag::Link(LinkType::Inline, url.clone().into(), "".into());
+                    self.pending.push_back(Event::Start(tag()));
+                    self.pending.push_back(Event::Text(text.into()));
+                    self.pending.push_back(Event::End(tag()));
+                }
+                None => self.pending.push_back(Event::Text(text.into())),
+            }
+            self.pending.push_back(Event::End(Tag::Paragraph));
+        }
+    }
+}
+
+impl<'a, 'b, I: Iterator<Item = Event<'a>>> Iterator for Citations<'a, 'b, I> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        let event = match self.inner.next() {
+            Some(event) => event,
+            None if self.finished => return None,
+            None => {
+                self.finished = true;
+                self.push_references();
+                return self.pending.pop_front();
+            }
+        };
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => self.in_code_block = true,
+            Event::End(Tag::CodeBlock(_)) => self.in_code_block = false,
+            Event::Text(text) if !self.in_code_block && text.contains("[@") => {
+                return Some(Event::Text(self.replace_citations(&text).into()));
+            }
+            _ => (),
+        }
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_bibtex() -> Result<()> {
+        let bibliography = Bibliography::from_bibtex(
+            r#"
+            @comment{ignored}
+            @article{doe2020,
+              author = {Doe, Jane and Roe, Richard},
+              title = {On {Gemini}},
+              journal = "Journal of Small Protocols",
+              year = 2020,
+            }
+            @book(roe2021, title = {A Book}, publisher = {Press}, doi = {10.1/x})
+            "#,
+        )?;
+        let doe = &bibliography.entries["doe2020"];
+        assert_eq!(
+            doe.format(),
+            "Doe, Jane; Roe, Richard (2020). On Gemini. Journal of Small Protocols."
+        );
+        assert_eq!(doe.url(), None);
+        let roe = &bibliography.entries["roe2021"];
+        assert_eq!(roe.format(), "A Book. Press.");
+        assert_eq!(roe.url().as_deref(), Some("https://doi.org/10.1/x"));
+        Ok(())
+    }
+
+    #[test]
+    fn unterminated() {
+        assert!(Bibliography::from_bibtex("@article{doe, title = {x}").is_err());
+    }
+}
@@ -0,0 +1,113 @@
+//! Support for definition lists, which pulldown-cmark doesn't parse. A paragraph like
+//!
+//! ```text
+//! term
+//! : definition
+//! ```
+//!
+//! is rewritten into a bolded paragraph containing the term, followed by an indented paragraph for
+//! each definition.
+
+use pulldown_cmark::{CowStr, Event, Tag};
+use std::collections::VecDeque;
+
+/// What each definition is indented with.
+const INDENT: &str = "    ";
+
+/// Wraps an event stream, rewriting any paragraphs that look like definition lists.
+pub struct DefinitionLists<'a, I> {
+    inner: I,
+    pending: VecDeque<Event<'a>>,
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> DefinitionLists<'a, I> {
+    pub fn new(inner: I) -> Self {
+        Self {
+            inner,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> Iterator for DefinitionLists<'a, I> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        let event = self.inner.next()?;
+        if event != Event::Start(Tag::Paragraph) {
+            return Some(event);
+        }
+        let mut lines = vec![vec![]];
+        for event in &mut self.inner {
+            match event {
+                Event::End(Tag::Paragraph) => break,
+                Event::SoftBreak => lines.push(vec![]),
+                event => lines.last_mut().unwrap().push(event),
+            }
+        }
+        self.pending.extend(rewrite(lines));
+        self.pending.pop_front()
+    }
+}
+
+/// If the line starts a definition, returns the line with the `:` marker removed.
+fn definition(line: &[Event<'_>]) -> Option<CowStr<'static>> {
+    match line.first() {
+        Some(Event::Text(text)) if text.starts_with(": ") => {
+            Some(text[2..].trim_start().to_owned().into())
+        }
+        _ => None,
+    }
+}
+
+/// Turns the lines of a paragraph back into events, treating it as a definition list if the
+/// second or later line starts with `: `.
+fn rewrite(lines: Vec<Vec<Event<'_>>>) -> Vec<Event<'_>> {
+    let first_definition = lines
+        .iter()
+        .position(|line| definition(line).is_some())
+        .filter(|&index| index > 0);
+    let mut events = vec![Event::Start(Tag::Paragraph)];
+    let first_definition = match first_definition {
+        Some(index) => index,
+        None => {
+            events.extend(join(lines));
+            events.push(Event::End(Tag::Paragraph));
+            return events;
+        }
+    };
+    let mut lines = lines.into_iter();
+    events.push(Event::Start(Tag::Strong));
+    events.extend(join((&mut lines).take(first_definition).collect()));
+    // The term's paragraph is closed by the first definition.
+    events.push(Event::End(Tag::Strong));
+    for mut line in lines {
+        match definition(&line) {
+            Some(text) => {
+                events.push(Event::End(Tag::Paragraph));
+                events.push(Event::Start(Tag::Paragraph));
+                events.push(Event::Text(INDENT.into()));
+                line[0] = Event::Text(text);
+            }
+            None => events.push(Event::SoftBreak),
+        }
+        events.extend(line);
+    }
+    events.push(Event::End(Tag::Paragraph));
+    events
+}
+
+/// Joins lines back together with soft breaks.
+fn join(lines: Vec<Vec<Event<'_>>>) -> Vec<Event<'_>> {
+    let mut events = vec![];
+    for (i, line) in lines.into_iter().enumerate() {
+        if i > 0 {
+            events.push(Event::SoftBreak);
+        }
+        events.extend(line);
+    }
+    events
+}
@@ -0,0 +1,127 @@
+//! Support for collapsible `<details>` blocks, which are common on FAQ pages. Gemtext can't hide
+//! anything, so the `<summary>` becomes a bolded paragraph followed by the body as usual. Any other
+//! raw HTML is left alone, which means the converter drops it.
+
+use pulldown_cmark::{Event, Tag};
+use std::collections::VecDeque;
+
+/// The paragraph written between a summary and the body, if a separator is wanted.
+const SEPARATOR: &str = "—";
+
+/// Wraps an event stream, turning the HTML of `<details>` blocks into ordinary paragraphs.
+pub struct Details<'a, I> {
+    inner: I,
+    pending: VecDeque<Event<'a>>,
+    separator: bool,
+    in_paragraph: bool,
+    // The text of the summary we're in, if any. Summaries can span several HTML events.
+    summary: Option<String>,
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> Details<'a, I> {
+    pub fn new(inner: I, separator: bool) -> Self {
+        Self {
+            inner,
+            pending: VecDeque::new(),
+            separator,
+            in_paragraph: false,
+            summary: None,
+        }
+    }
+
+    fn handle_html(&mut self, html: &str) {
+        let lower = html.to_ascii_lowercase();
+        let mut offset = 0;
+        while offset < html.len() {
+            let (text_end, tag) = match lower[offset..].find('<') {
+                Some(start) => {
+                    let start = offset + start;
+                    match lower[start..].find('>') {
+                        Some(end) => (start, Some(&lower[start + 1..start + end])),
+                        None => (html.len(), None),
+                    }
+                }
+                None => (html.len(), None),
+            };
+            self.handle_html_text(&html[offset..text_end]);
+            let tag = match tag {
+                Some(tag) => tag,
+                None => break,
+            };
+            offset = text_end + tag.len() + 2;
+            let name = tag
+                .split(|c: char| c.is_whitespace() || c == '/')
+                .find(|name| !name.is_empty())
+                .unwrap_or_default();
+            match (tag.starts_with('/'), name) {
+                (false, "summary") => self.summary = Some(String::new()),
+                (true, "summary") => {
+                    if let Some(summary) = self.summary.take() {
+                        self.push_summary(summary.trim());
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn handle_html_text(&mut self, text: &str) {
+        if let Some(summary) = &mut self.summary {
+            summary.push_str(text);
+        } else if !text.trim().is_empty() {
+            self.pending.push_back(Event::Start(Tag::Paragraph));
+            self.pending
+                .push_back(Event::Text(text.trim().to_owned().into()));
+            self.pending.push_back(Event::End(Tag::Paragraph));
+        }
+    }
+
+    fn push_summary(&mut self, summary: &str) {
+        self.pending.push_back(Event::Start(Tag::Paragraph));
+        self.pending.push_back(Event::Start(Tag::Strong));
+        self.pending
+            .push_back(Event::Text(summary.to_owned().into()));
+        self.pending.push_back(Event::End(Tag::Strong));
+        self.pending.push_back(Event::End(Tag::Paragraph));
+        if self.separator {
+            self.pending.push_back(Event::Start(Tag::Paragraph));
+            self.pending.push_back(Event::Text(SEPARATOR.into()));
+            self.pending.push_back(Event::End(Tag::Paragraph));
+        }
+    }
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> Iterator for Details<'a, I> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            match self.inner.next()? {
+                Event::Html(html)
+                    if !self.in_paragraph && (self.summary.is_some() || is_details(&html)) =>
+                {
+                    self.handle_html(&html)
+                }
+                event => {
+                    match event {
+                        Event::Start(Tag::Paragraph) => self.in_paragraph = true,
+                        Event::End(Tag::Paragraph) => self.in_paragraph = false,
+                        _ => (),
+                    }
+                    return Some(event);
+                }
+            }
+        }
+    }
+}
+
+/// Whether the HTML has any of the tags this handles.
+fn is_details(html: &str) -> bool {
+    let lower = html.to_ascii_lowercase();
+    ["<details", "</details", "<summary", "</summary"]
+        .iter()
+        .any(|tag| lower.contains(tag))
+}
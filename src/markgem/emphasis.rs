@@ -0,0 +1,158 @@
+//! Writing emphasis, strong emphasis and strikethrough, which Gemtext has no syntax for. Besides
+//! keeping Markdown's markers, the text can be restyled with characters that look emphasized in
+//! any client.
+
+use super::find_bare_url;
+use anyhow::{anyhow, Error};
+use pulldown_cmark::{Event, Tag};
+use std::str::FromStr;
+
+/// How to write emphasis, strong emphasis and strikethrough.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmphasisStyle {
+    /// Keep the `*`, `**` and `~~` markers, as in Markdown.
+    Markers,
+    /// Drop the markers, keeping only the text.
+    Strip,
+    /// Write emphasized and strong text in capitals. Strikethrough keeps its markers, since
+    /// capitals can't show it.
+    Caps,
+    /// Write emphasized text in 𝘪𝘵𝘢𝘭𝘪𝘤 and strong text in 𝗯𝗼𝗹𝗱 mathematical letters, and put a
+    /// combining stroke through struck text. Only ASCII letters and digits have such forms, and
+    /// screen readers may read them out one at a time, so this is best kept to short phrases.
+    Unicode,
+}
+
+impl Default for EmphasisStyle {
+    fn default() -> Self {
+        EmphasisStyle::Markers
+    }
+}
+
+impl FromStr for EmphasisStyle {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "markers" => Ok(EmphasisStyle::Markers),
+            "strip" => Ok(EmphasisStyle::Strip),
+            "caps" => Ok(EmphasisStyle::Caps),
+            "unicode" => Ok(EmphasisStyle::Unicode),
+            _ => Err(anyhow!("unknown emphasis style {}", s)),
+        }
+    }
+}
+
+/// Combining long stroke overlay, which strikes through the character before it.
+const STROKE: char = '\u{336}';
+
+/// Wraps an event stream, replacing emphasis tags with restyled text. With `Markers`, the events
+/// are passed through untouched and the converter writes the markers.
+pub struct Emphasis<I> {
+    inner: I,
+    style: EmphasisStyle,
+    // How many of each are open around the current text.
+    emphasis: usize,
+    strong: usize,
+    strikethrough: usize,
+}
+
+impl<I> Emphasis<I> {
+    pub fn new(inner: I, style: EmphasisStyle) -> Self {
+        Self {
+            inner,
+            style,
+            emphasis: 0,
+            strong: 0,
+            strikethrough: 0,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.emphasis + self.strong + self.strikethrough > 0
+    }
+
+    fn restyle(&self, text: &str) -> String {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text;
+        // Bare URLs are left alone, so that they still work as links.
+        while let Some(range) = find_bare_url(rest) {
+            self.restyle_into(&rest[..range.start], &mut out);
+            out.push_str(&rest[range.clone()]);
+            rest = &rest[range.end..];
+        }
+        self.restyle_into(rest, &mut out);
+        out
+    }
+
+    fn restyle_into(&self, text: &str, out: &mut String) {
+        let (italic, bold) = (self.emphasis > 0, self.strong > 0);
+        for c in text.chars() {
+            match self.style {
+                EmphasisStyle::Caps if italic || bold => out.extend(c.to_uppercase()),
+                EmphasisStyle::Unicode => {
+                    out.push(math_letter(c, italic, bold));
+                    if self.strikethrough > 0 && !c.is_whitespace() {
+                        out.push(STROKE);
+                    }
+                }
+                _ => out.push(c),
+            }
+        }
+    }
+}
+
+/// The mathematical sans-serif form of an ASCII letter or digit, which has no gaps in Unicode
+/// unlike the serif forms.
+fn math_letter(c: char, italic: bool, bold: bool) -> char {
+    let (upper, lower, digit) = match (italic, bold) {
+        (false, false) => return c,
+        (true, false) => (0x1d608, 0x1d622, None),
+        (false, true) => (0x1d5d4, 0x1d5ee, Some(0x1d7ec)),
+        (true, true) => (0x1d63c, 0x1d656, Some(0x1d7ec)),
+    };
+    let code = match c {
+        'A'..='Z' => upper + (c as u32 - 'A' as u32),
+        'a'..='z' => lower + (c as u32 - 'a' as u32),
+        '0'..='9' => match digit {
+            Some(digit) => digit + (c as u32 - '0' as u32),
+            None => return c,
+        },
+        _ => return c,
+    };
+    std::char::from_u32(code).unwrap_or(c)
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> Iterator for Emphasis<I> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        loop {
+            let event = self.inner.next()?;
+            if self.style == EmphasisStyle::Markers {
+                return Some(event);
+            }
+            let (tag, opening) = match &event {
+                Event::Start(tag) => (tag, true),
+                Event::End(tag) => (tag, false),
+                Event::Text(text) if self.style != EmphasisStyle::Strip && self.is_open() => {
+                    return Some(Event::Text(self.restyle(text).into()));
+                }
+                _ => return Some(event),
+            };
+            let count = match tag {
+                Tag::Emphasis => &mut self.emphasis,
+                Tag::Strong => &mut self.strong,
+                // Capitals can't show strikethrough, so its markers stay.
+                Tag::Strikethrough if self.style == EmphasisStyle::Caps => return Some(event),
+                Tag::Strikethrough => &mut self.strikethrough,
+                _ => return Some(event),
+            };
+            if opening {
+                *count += 1;
+            } else {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Linking glossary terms, so that a wiki's pages link to each other without every link being
+//! written by hand. The first time each term appears as a whole word in a document, outside of
+//! code and other links, it becomes a link to the term's URL.
+
+use super::abbr::find_word;
+use pulldown_cmark::{CowStr, Event, LinkType, Tag};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+/// Wraps an event stream, linking the first occurrence of each glossary term.
+pub struct Glossary<'a, 'b, I> {
+    inner: I,
+    glossary: &'b BTreeMap<String, String>,
+    linked: HashSet<&'b str>,
+    pending: VecDeque<Event<'a>>,
+    in_code_block: bool,
+    link_depth: usize,
+}
+
+impl<'a, 'b, I: Iterator<Item = Event<'a>>> Glossary<'a, 'b, I> {
+    pub fn new(inner: I, glossary: &'b BTreeMap<String, String>) -> Self {
+        Self {
+            inner,
+            glossary,
+            linked: HashSet::new(),
+            pending: VecDeque::new(),
+            in_code_block: false,
+            link_depth: 0,
+        }
+    }
+
+    /// Queues the text with its terms linked, if it has any that haven't been linked yet, and
+    /// returns the first of its events.
+    fn link(&mut self, text: &str) -> Option<Event<'a>> {
+        let mut rest = text;
+        let mut found = false;
+        loop {
+            // The earliest term, preferring longer ones, so "Gemini protocol" wins over "Gemini".
+            let linked = &self.linked;
+            let next = self
+                .glossary
+                .iter()
+                .filter(|(term, _)| !linked.contains(term.as_str()))
+                .filter_map(|(term, url)| {
+                    find_word(rest, term).map(|range| (range, term.as_str(), url.as_str()))
+                })
+                .min_by_key(|(range, _, _)| (range.start, std::cmp::Reverse(range.end)));
+            let (range, term, url) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            self.linked.insert(term);
+            found = true;
+            if range.start > 0 {
+                self.push_text(&rest[..range.start]);
+            }
+            let tag = Tag::Link(LinkType::Inline, url.to_owned().into(), "".into());
+            self.pending.push_back(Event::Start(tag.clone()));
+            self.push_text(&rest[range.clone()]);
+            self.pending.push_back(Event::End(tag));
+            rest = &rest[range.end..];
+        }
+        if !found {
+            return None;
+        }
+        if !rest.is_empty() {
+            self.push_text(rest);
+        }
+        self.pending.pop_front()
+    }
+
+    fn push_text(&mut self, text: &str) {
+        self.pending
+            .push_back(Event::Text(CowStr::from(text.to_owned())));
+    }
+}
+
+impl<'a, 'b, I: Iterator<Item = Event<'a>>> Iterator for Glossary<'a, 'b, I> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        let event = self.inner.next()?;
+        match &event {
+            Event::Start(Tag::CodeBlock(_)) => self.in_code_block = true,
+            Event::End(Tag::CodeBlock(_)) => self.in_code_block = false,
+            Event::Start(Tag::Link(..)) | Event::Start(Tag::Image(..)) => self.link_depth += 1,
+            Event::End(Tag::Link(..)) | Event::End(Tag::Image(..)) => self.link_depth -= 1,
+            Event::Text(text) if !self.in_code_block && self.link_depth == 0 => {
+                if let Some(event) = self.link(text) {
+                    return Some(event);
+                }
+            }
+            _ => (),
+        }
+        Some(event)
+    }
+}
@@ -0,0 +1,64 @@
+//! Hooks that let library users extend the conversion with their own syntax, without depending on
+//! how the built-in extensions work. There are two kinds: preprocessors rewrite the Markdown before
+//! it's parsed, and event hooks rewrite the stream of pulldown-cmark events after it is. Both run
+//! in the order they were added, and apply to Gemtext and HTML output alike.
+
+use pulldown_cmark::Event;
+use std::fmt;
+use std::sync::Arc;
+
+/// A stream of pulldown-cmark events, as event hooks take and return it.
+pub type Events<'a> = Box<dyn Iterator<Item = Event<'a>> + 'a>;
+
+type Preprocessor = dyn Fn(&str) -> Option<String> + Send + Sync;
+type EventHook = dyn for<'a> Fn(Events<'a>) -> Events<'a> + Send + Sync;
+
+/// The hooks added to a `ConverterOptions`.
+#[derive(Clone, Default)]
+pub(crate) struct Hooks {
+    preprocessors: Vec<Arc<Preprocessor>>,
+    event_hooks: Vec<Arc<EventHook>>,
+}
+
+impl Hooks {
+    pub fn add_preprocessor(
+        &mut self,
+        preprocessor: impl Fn(&str) -> Option<String> + Send + Sync + 'static,
+    ) {
+        self.preprocessors.push(Arc::new(preprocessor));
+    }
+
+    pub fn add_event_hook(
+        &mut self,
+        hook: impl for<'a> Fn(Events<'a>) -> Events<'a> + Send + Sync + 'static,
+    ) {
+        self.event_hooks.push(Arc::new(hook));
+    }
+
+    /// Runs the preprocessors over the Markdown, returning it if any of them changed it.
+    pub fn preprocess(&self, markdown: &str) -> Option<String> {
+        let mut changed: Option<String> = None;
+        for preprocessor in &self.preprocessors {
+            if let Some(rewritten) = preprocessor(changed.as_deref().unwrap_or(markdown)) {
+                changed = Some(rewritten);
+            }
+        }
+        changed
+    }
+
+    pub fn apply<'a>(&self, mut events: Events<'a>) -> Events<'a> {
+        for hook in &self.event_hooks {
+            events = hook(events);
+        }
+        events
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("preprocessors", &self.preprocessors.len())
+            .field("event_hooks", &self.event_hooks.len())
+            .finish()
+    }
+}
@@ -0,0 +1,224 @@
+//! Checks for problems in Markdown that still convert fine, but make the result worse for some
+//! readers. Screen readers can only describe an image or a preformatted block through its alt
+//! text, which in Gemtext is the text after the opening "```". Other constructs have no Gemtext
+//! equivalent at all, and are only approximated.
+
+use super::math;
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag};
+use std::fmt;
+
+/// A problem found in a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    /// The line the problem starts on, counting from one.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Finds images and preformatted blocks without alt text.
+pub fn missing_alt_text(markdown: &str) -> Vec<Lint> {
+    let line = |offset: usize| markdown[..offset].matches('\n').count() + 1;
+    let mut lints = vec![];
+    // The start of the image we're in, and whether it has any text yet.
+    let mut image: Option<(usize, bool)> = None;
+    for (event, range) in Parser::new(markdown).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Image(..)) => image = Some((range.start, false)),
+            Event::Text(text) | Event::Code(text) if !text.trim().is_empty() => {
+                if let Some((_, described)) = &mut image {
+                    *described = true;
+                }
+            }
+            Event::End(Tag::Image(_, destination, _)) => {
+                if let Some((start, false)) = image.take() {
+                    lints.push(Lint {
+                        line: line(start),
+                        message: format!("image {} has no alt text", destination),
+                    });
+                }
+            }
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let described = match kind {
+                    CodeBlockKind::Fenced(info) => !info.trim().is_empty(),
+                    CodeBlockKind::Indented => false,
+                };
+                if !described {
+                    lints.push(Lint {
+                        line: line(range.start),
+                        message: "preformatted block has no alt text".to_owned(),
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+    lints
+}
+
+/// A Markdown construct that Gemtext can't represent, so the converter approximates or drops it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Construct {
+    Table,
+    Html,
+    Math,
+    Footnote,
+    DeepHeading,
+    NestedList,
+}
+
+impl Construct {
+    /// A short name for the construct, like `tables`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Construct::Table => "tables",
+            Construct::Html => "HTML",
+            Construct::Math => "math",
+            Construct::Footnote => "footnotes",
+            Construct::DeepHeading => "headings below level 3",
+            Construct::NestedList => "nested lists",
+        }
+    }
+
+    /// What becomes of the construct in Gemtext.
+    pub fn outcome(self) -> &'static str {
+        match self {
+            Construct::Table => "rows become plain text lines, with cells separated by `|`",
+            Construct::Html => "dropped, apart from <details> blocks",
+            Construct::Math => "shown as TeX source in a preformatted block",
+            Construct::Footnote => "references and notes are left as `[^label]` text",
+            Construct::DeepHeading => {
+                "written as level 3 headings, unless set to overflow differently"
+            }
+            Construct::NestedList => {
+                "written as top-level items, with nested ones indented to show their depth"
+            }
+        }
+    }
+}
+
+/// Finds the constructs in the Markdown that Gemtext can't represent, with the line each one
+/// starts on, counting from one. Front matter should already have been stripped, and every
+/// extension is turned on, since that's when the constructs are understood at all.
+pub fn unsupported_constructs(markdown: &str) -> Vec<(Construct, usize)> {
+    let line = |offset: usize| markdown[..offset].matches('\n').count() + 1;
+    let mut found = vec![];
+    let mut list_depth = 0;
+    let mut last_html = None;
+    let options = Options::ENABLE_TABLES | Options::ENABLE_FOOTNOTES;
+    for (event, range) in Parser::new_ext(markdown, options).into_offset_iter() {
+        let construct = match event {
+            Event::Start(Tag::Table(_)) => Construct::Table,
+            Event::Html(_) => Construct::Html,
+            Event::FootnoteReference(_) => Construct::Footnote,
+            Event::Start(Tag::Heading(depth)) if depth > 3 => Construct::DeepHeading,
+            Event::Start(Tag::List(_)) => {
+                list_depth += 1;
+                if list_depth == 2 {
+                    Construct::NestedList
+                } else {
+                    continue;
+                }
+            }
+            Event::End(Tag::List(_)) => {
+                list_depth -= 1;
+                continue;
+            }
+            _ => continue,
+        };
+        let start = line(range.start);
+        if construct == Construct::Html {
+            // A block of HTML comes as one event per line, but only its first line is reported.
+            let continued = last_html == Some(start - 1);
+            last_html = Some(start);
+            if continued {
+                continue;
+            }
+        }
+        found.push((construct, start));
+    }
+    found.extend(
+        math_blocks(markdown)
+            .into_iter()
+            .map(|line| (Construct::Math, line)),
+    );
+    found.sort_by_key(|&(construct, line)| (line, construct));
+    found
+}
+
+/// The lines that `$$` display math blocks start on. pulldown-cmark doesn't know about math, so
+/// they're found the same way `fence_math` finds them.
+fn math_blocks(markdown: &str) -> Vec<usize> {
+    let lines: Vec<&str> = markdown.lines().collect();
+    math::blocks(&lines)
+        .iter()
+        .map(|block| block.start + 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lines(markdown: &str) -> Vec<usize> {
+        missing_alt_text(markdown)
+            .iter()
+            .map(|lint| lint.line)
+            .collect()
+    }
+
+    #[test]
+    fn images() {
+        assert_eq!(lines("![a cat](cat.png)"), Vec::<usize>::new());
+        assert_eq!(lines("text\n\n![](cat.png)\n![ ](dog.png)"), vec![3, 4]);
+    }
+
+    #[test]
+    fn preformatted() {
+        assert_eq!(lines("```rust\nfn main() {}\n```"), Vec::<usize>::new());
+        assert_eq!(lines("text\n\n```\nart\n```\n\n    indented"), vec![3, 7]);
+    }
+
+    #[test]
+    fn constructs() {
+        let markdown = "# Title\n\n#### Deep\n\n| a | b |\n|---|---|\n| 1 | 2 |\n\n\
+                        <div>\nhi\n</div>\n\nText<br>[^1]\n\n* a\n  * b\n\n\
+                        $$\nx^2\n$$\n\n```\n$$ not math $$\n```\n\n[^1]: Note.";
+        assert_eq!(
+            unsupported_constructs(markdown),
+            vec![
+                (Construct::DeepHeading, 3),
+                (Construct::Table, 5),
+                (Construct::Html, 9),
+                (Construct::Html, 13),
+                (Construct::Footnote, 13),
+                (Construct::NestedList, 16),
+                (Construct::Math, 18),
+            ]
+        );
+        assert!(unsupported_constructs("Just *text*.").is_empty());
+    }
+
+    #[test]
+    fn indented_math() {
+        // Indented four spaces, it's a code block, so it's left alone.
+        assert!(unsupported_constructs("Text\n\n    $$\n    x^2\n    $$").is_empty());
+        assert_eq!(
+            unsupported_constructs("Text\n\n   $$\n   x^2\n$$"),
+            vec![(Construct::Math, 3)]
+        );
+    }
+
+    #[test]
+    fn message() {
+        assert_eq!(
+            missing_alt_text("![](cat.png)")[0].to_string(),
+            "line 1: image cat.png has no alt text"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+//! Support for `$$`-delimited display math. pulldown-cmark doesn't know about math, so it would
+//! otherwise treat the TeX source as Markdown and mangle it (underscores turning into emphasis,
+//! and so on). Instead, math blocks are rewritten into fenced code blocks tagged `math` before
+//! parsing.
+
+use std::borrow::Cow;
+
+pub(crate) const DELIMITER: &str = "$$";
+
+/// A display math block in Markdown.
+pub(crate) struct Block<'a> {
+    /// The index of the line it opens on.
+    pub start: usize,
+    /// The index of the line after the one it closes on.
+    pub end: usize,
+    /// Its TeX source, a line at a time.
+    pub body: Vec<&'a str>,
+}
+
+/// Finds the display math blocks in the Markdown's lines. A block opens with `$$` indented by at
+/// most three spaces, since more would make it an indented code block, and closes with a line
+/// that's only `$$`. Without the closing line, it's not a block. `$$` in fenced code doesn't count.
+pub(crate) fn blocks<'a>(lines: &[&'a str]) -> Vec<Block<'a>> {
+    let mut blocks = vec![];
+    let mut in_fence = false;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        let trimmed = line.trim();
+        i += 1;
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        } else if let Some(rest) = opening(line).filter(|_| !in_fence) {
+            if rest.len() >= DELIMITER.len() && rest.ends_with(DELIMITER) {
+                // Single-line block, like `$$ x^2 $$`.
+                blocks.push(Block {
+                    start: i - 1,
+                    end: i,
+                    body: vec![rest[..rest.len() - DELIMITER.len()].trim()],
+                });
+                continue;
+            }
+            let close = lines[i..]
+                .iter()
+                .position(|line| line.trim_end() == DELIMITER);
+            if let Some(close) = close {
+                let mut body: Vec<&str> = vec![];
+                if !rest.is_empty() {
+                    body.push(rest);
+                }
+                body.extend(&lines[i..i + close]);
+                blocks.push(Block {
+                    start: i - 1,
+                    end: i + close + 1,
+                    body,
+                });
+                i += close + 1;
+            }
+        }
+    }
+    blocks
+}
+
+/// Rewrites every display math block in the Markdown, as `blocks` finds them, into a fenced code
+/// block.
+pub fn fence_math(markdown: &str) -> Cow<'_, str> {
+    if !markdown.contains(DELIMITER) {
+        return Cow::Borrowed(markdown);
+    }
+    let lines: Vec<&str> = markdown.lines().collect();
+    let mut out = String::with_capacity(markdown.len());
+    let mut i = 0;
+    for block in blocks(&lines) {
+        for line in &lines[i..block.start] {
+            out.push_str(line);
+            out.push('\n');
+        }
+        push_fenced(&mut out, &block.body);
+        i = block.end;
+    }
+    for line in &lines[i..] {
+        out.push_str(line);
+        out.push('\n');
+    }
+    Cow::Owned(out)
+}
+
+/// If the line opens a math block, what follows the `$$`, trimmed.
+fn opening(line: &str) -> Option<&str> {
+    let unindented = line.trim_start_matches(' ');
+    if line.len() - unindented.len() > 3 {
+        return None;
+    }
+    unindented.strip_prefix(DELIMITER).map(str::trim)
+}
+
+fn push_fenced(out: &mut String, body: &[&str]) {
+    out.push_str("```math\n");
+    for line in body {
+        out.push_str(line);
+        out.push('\n');
+    }
+    out.push_str("```\n");
+}
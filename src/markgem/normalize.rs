@@ -0,0 +1,246 @@
+//! Cleanup of the converter's output, so that it doesn't depend on exactly which events produced
+//! it. Trailing whitespace is stripped, runs of blank lines are collapsed, and the document ends
+//! with at most one newline. Text lines can optionally be hard-wrapped, for clients that don't
+//! wrap long lines well. Preformatted blocks are left untouched.
+//!
+//! This works a line at a time, so the output can be streamed.
+
+use std::io::{self, Write};
+
+/// The most consecutive blank lines that can appear in normalized output.
+const MAX_BLANK_LINES: usize = 1;
+
+/// A writer that normalizes the Gemtext written to it before passing it on. `finish` must be
+/// called once everything has been written.
+pub struct Normalizer<W: Write> {
+    inner: W,
+    trailing_newline: bool,
+    // Zero means lines aren't wrapped.
+    wrap_width: usize,
+    // The line currently being written, which is held back until we see its end.
+    line: Vec<u8>,
+    // Blank lines are only written once we know they're followed by something.
+    blank_lines: usize,
+    wrote_line: bool,
+    preformatted: bool,
+    verbatim: bool,
+}
+
+impl<W: Write> Normalizer<W> {
+    pub fn new(inner: W, trailing_newline: bool, wrap_width: usize) -> Self {
+        Self {
+            inner,
+            trailing_newline,
+            wrap_width,
+            line: vec![],
+            blank_lines: 0,
+            wrote_line: false,
+            preformatted: false,
+            verbatim: false,
+        }
+    }
+
+    /// While verbatim, lines are passed through exactly as they're written. This should only be
+    /// changed at the start of a line.
+    pub fn set_verbatim(&mut self, verbatim: bool) {
+        self.verbatim = verbatim;
+    }
+
+    /// Whether nothing has been written since the last newline.
+    pub fn at_line_start(&self) -> bool {
+        self.line.is_empty()
+    }
+
+    /// Writes out any partial line and the trailing newline, then flushes the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.line.is_empty() {
+            let line = std::mem::take(&mut self.line);
+            self.write_line(&line)?;
+        }
+        if self.trailing_newline && self.wrote_line {
+            self.inner.write_all(b"\n")?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.verbatim {
+            // Keep track of preformatting, in case the verbatim text toggles it.
+            if line.starts_with(b"```") {
+                self.preformatted = !self.preformatted;
+            }
+            return self.emit(line);
+        }
+        let line = if line.starts_with(b"```") {
+            self.preformatted = !self.preformatted;
+            return self.emit(trim_end(line));
+        } else if self.preformatted {
+            return self.emit(line);
+        } else {
+            trim_end(line)
+        };
+        if line.is_empty() {
+            self.blank_lines += 1;
+            return Ok(());
+        }
+        match std::str::from_utf8(line) {
+            Ok(text) if self.wrap_width > 0 && text.chars().count() > self.wrap_width => {
+                for wrapped in wrap(text, self.wrap_width) {
+                    self.emit(wrapped.as_bytes())?;
+                }
+                Ok(())
+            }
+            _ => self.emit(line),
+        }
+    }
+
+    /// Writes a non-blank line, preceded by any pending blank lines.
+    fn emit(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.wrote_line {
+            self.inner.write_all(b"\n")?;
+        }
+        for _ in 0..self.blank_lines.min(MAX_BLANK_LINES) {
+            self.inner.write_all(b"\n")?;
+        }
+        self.blank_lines = 0;
+        self.inner.write_all(line)?;
+        self.wrote_line = true;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Normalizer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while let Some(newline) = rest.iter().position(|&b| b == b'\n') {
+            if self.line.is_empty() {
+                self.write_line(&rest[..newline])?;
+            } else {
+                self.line.extend_from_slice(&rest[..newline]);
+                let line = std::mem::take(&mut self.line);
+                self.write_line(&line)?;
+            }
+            rest = &rest[newline + 1..];
+        }
+        self.line.extend_from_slice(rest);
+        Ok(buf.len())
+    }
+
+    /// Only flushes complete lines, since partial ones might still get trailing whitespace.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Wraps a line of text to the given width, breaking only at spaces. Link lines and headings are
+/// never wrapped, since the rest of the line would lose its meaning; continuations of list items
+/// are indented, and continuations of quotes are quoted.
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    if line.starts_with("=>") || line.starts_with('#') {
+        vec![line.to_owned()]
+    } else if let Some(item) = line.strip_prefix("* ") {
+        prefixed_wrap(item, "* ", "  ", width)
+    } else if let Some(quote) = line.strip_prefix('>') {
+        prefixed_wrap(quote, ">", ">", width)
+    } else {
+        prefixed_wrap(line, "", "", width)
+    }
+}
+
+fn prefixed_wrap(text: &str, first_prefix: &str, prefix: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![first_prefix.to_owned()];
+    let mut len = first_prefix.chars().count();
+    let mut empty = true;
+    for word in text.split(' ').filter(|word| !word.is_empty()) {
+        let word_len = word.chars().count();
+        // Don't break before a word that would turn the new line into something other than text.
+        let breakable = !prefix.is_empty() || !(word == "*" || super::is_line_syntax(word));
+        if !empty && breakable && len + 1 + word_len > width {
+            lines.push(prefix.to_owned());
+            len = prefix.chars().count();
+            empty = true;
+        }
+        let line = lines.last_mut().unwrap();
+        if !empty {
+            line.push(' ');
+            len += 1;
+        }
+        line.push_str(word);
+        len += word_len;
+        empty = false;
+    }
+    lines
+}
+
+fn trim_end(line: &[u8]) -> &[u8] {
+    let len = line
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |last| last + 1);
+    &line[..len]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn normalize(gemini: &str, trailing_newline: bool) -> String {
+        let mut normalizer = Normalizer::new(vec![], trailing_newline, 0);
+        normalizer.write_all(gemini.as_bytes()).unwrap();
+        String::from_utf8(normalizer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn trailing_whitespace() {
+        assert_eq!(normalize("foo  \nbar\t\n", false), "foo\nbar");
+    }
+
+    #[test]
+    fn blank_lines() {
+        assert_eq!(normalize("foo\n\n\n\n\nbar", false), "foo\n\nbar");
+    }
+
+    #[test]
+    fn preformatted_untouched() {
+        let gemini = "```\nfoo  \n\n\n\n\nbar\n```";
+        assert_eq!(normalize(gemini, false), gemini);
+    }
+
+    #[test]
+    fn trailing_newline() {
+        assert_eq!(normalize("foo\n\nbar\n\n\n", true), "foo\n\nbar\n");
+        assert_eq!(normalize("\n\n", true), "");
+    }
+
+    #[test]
+    fn split_writes() {
+        let mut normalizer = Normalizer::new(vec![], false, 0);
+        for chunk in &["fo", "o  ", "\n", "\n\n\n\nb", "ar \n"] {
+            normalizer.write_all(chunk.as_bytes()).unwrap();
+        }
+        assert_eq!(normalizer.finish().unwrap(), b"foo\n\nbar");
+    }
+
+    #[test]
+    fn verbatim() {
+        let mut normalizer = Normalizer::new(vec![], false, 5);
+        normalizer.write_all(b"a  \n").unwrap();
+        normalizer.set_verbatim(true);
+        normalizer.write_all(b"b  \n\n\n\nc d e f g\n").unwrap();
+        normalizer.set_verbatim(false);
+        normalizer.write_all(b"h  ").unwrap();
+        assert_eq!(normalizer.finish().unwrap(), b"a\nb  \n\n\n\nc d e f g\nh");
+    }
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(wrap("aaa bbb ccc dd", 7), vec!["aaa bbb", "ccc dd"]);
+        assert_eq!(wrap("* aaa bbb ccc", 7), vec!["* aaa", "  bbb", "  ccc"]);
+        assert_eq!(wrap(">aaa bbb ccc", 8), vec![">aaa bbb", ">ccc"]);
+        assert_eq!(wrap("a verylongword b", 5), vec!["a", "verylongword", "b"]);
+        assert_eq!(wrap("=> gemini://example.com a b c", 5).len(), 1);
+        assert_eq!(wrap("# a long heading", 5).len(), 1);
+        assert_eq!(wrap("see => this", 4), vec!["see =>", "this"]);
+    }
+}
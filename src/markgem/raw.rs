@@ -0,0 +1,52 @@
+//! Support for hand-written Gemtext inside Markdown, for things like ASCII-art banners that the
+//! converter can't produce. Raw sections are either fenced code blocks tagged `gemtext`, or the
+//! lines between `<!-- gemini:raw -->` and `<!-- /gemini:raw -->` comments, which are rewritten
+//! into such a block before parsing.
+
+use std::borrow::Cow;
+
+/// The info string of fenced code blocks whose contents are written out as they are.
+pub const INFO: &str = "gemtext";
+
+const OPEN: &str = "<!-- gemini:raw -->";
+const CLOSE: &str = "<!-- /gemini:raw -->";
+
+/// Rewrites every raw section delimited by comments into a `gemtext` code block.
+pub fn fence_raw(markdown: &str) -> Cow<'_, str> {
+    if !markdown.contains(OPEN) {
+        return Cow::Borrowed(markdown);
+    }
+    let lines: Vec<&str> = markdown.lines().collect();
+    let mut out = String::with_capacity(markdown.len());
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+        if line.trim() == OPEN {
+            let close = lines[i..].iter().position(|line| line.trim() == CLOSE);
+            if let Some(close) = close {
+                let body = &lines[i..i + close];
+                // The fence has to be longer than any fence in the body, since raw Gemtext can
+                // have its own preformatted blocks.
+                let longest = body
+                    .iter()
+                    .map(|line| line.chars().take_while(|&c| c == '`').count())
+                    .max()
+                    .unwrap_or_default();
+                let fence = "`".repeat(longest.max(2) + 1);
+                out.push_str(&format!("{}{}\n", fence, INFO));
+                for line in body {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+                out.push_str(&fence);
+                out.push('\n');
+                i += close + 1;
+                continue;
+            }
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    Cow::Owned(out)
+}
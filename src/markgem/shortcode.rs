@@ -0,0 +1,139 @@
+//! Expansion of Zola shortcodes, which would otherwise leak into the output verbatim. Inline
+//! shortcodes look like `{{ name(key="value") }}`, and shortcodes with a body look like
+//! `{% name(key="value") %}body{% end %}`.
+//!
+//! Shortcodes expand to Markdown, which is then converted like everything else. A few common ones
+//! are built in, and others can be given as templates in which `{key}` is replaced by the
+//! argument `key` and `{body}` by the body. Unknown shortcodes are dropped, keeping their body.
+
+use lazy_static::lazy_static;
+use log::warn;
+use pulldown_cmark::{Event, Parser, Tag};
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Range;
+
+lazy_static! {
+    static ref INLINE: Regex = Regex::new(r"\{\{\s*(\w+)\((.*?)\)\s*\}\}").unwrap();
+    static ref BLOCK: Regex =
+        Regex::new(r"(?s)\{%\s*(\w+)\((.*?)\)\s*%\}(.*?)\{%\s*end\s*%\}").unwrap();
+    static ref ARG: Regex = Regex::new(r#"(\w+)\s*=\s*(?:"((?:[^"\\]|\\.)*)"|([^,\s]+))"#).unwrap();
+}
+
+/// Expands all shortcodes outside of code blocks and inline code, using `templates` for any that
+/// aren't built in. Templates take precedence over the built-in shortcodes.
+pub fn expand<'a>(markdown: &'a str, templates: &HashMap<String, String>) -> Cow<'a, str> {
+    if !markdown.contains("{{") && !markdown.contains("{%") {
+        return Cow::Borrowed(markdown);
+    }
+    let mut out = String::with_capacity(markdown.len());
+    let mut chunk = String::new();
+    let mut in_fence = false;
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        let is_fence = trimmed.starts_with("```") || trimmed.starts_with("~~~");
+        if is_fence && !in_fence {
+            out.push_str(&expand_chunk(&chunk, templates));
+            chunk.clear();
+        }
+        if in_fence || is_fence {
+            out.push_str(line);
+            out.push('\n');
+        } else {
+            chunk.push_str(line);
+            chunk.push('\n');
+        }
+        if is_fence {
+            in_fence = !in_fence;
+        }
+    }
+    out.push_str(&expand_chunk(&chunk, templates));
+    Cow::Owned(out)
+}
+
+/// Expands the shortcodes in Markdown without fenced code blocks.
+fn expand_chunk<'a>(chunk: &'a str, templates: &HashMap<String, String>) -> Cow<'a, str> {
+    let chunk = replace_outside_code(chunk, &BLOCK, |caps| {
+        render(&caps[1], &caps[2], Some(caps[3].trim()), templates)
+    });
+    match replace_outside_code(&chunk, &INLINE, |caps| {
+        render(&caps[1], &caps[2], None, templates)
+    }) {
+        Cow::Borrowed(_) => chunk,
+        Cow::Owned(expanded) => Cow::Owned(expanded),
+    }
+}
+
+/// Replaces the regex's matches like `Regex::replace_all`, apart from those that start in inline
+/// code or an indented code block.
+fn replace_outside_code<'a>(
+    markdown: &'a str,
+    regex: &Regex,
+    replace: impl Fn(&Captures) -> String,
+) -> Cow<'a, str> {
+    if !regex.is_match(markdown) {
+        return Cow::Borrowed(markdown);
+    }
+    let code = code_ranges(markdown);
+    regex.replace_all(markdown, |caps: &Captures| {
+        let whole = caps.get(0).unwrap();
+        if code.iter().any(|range| range.contains(&whole.start())) {
+            whole.as_str().to_owned()
+        } else {
+            replace(caps)
+        }
+    })
+}
+
+/// Where the inline code and code blocks in the Markdown are.
+fn code_ranges(markdown: &str) -> Vec<Range<usize>> {
+    Parser::new(markdown)
+        .into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Code(_) | Event::Start(Tag::CodeBlock(_)) => Some(range),
+            _ => None,
+        })
+        .collect()
+}
+
+fn render(
+    name: &str,
+    args: &str,
+    body: Option<&str>,
+    templates: &HashMap<String, String>,
+) -> String {
+    let args: HashMap<&str, String> = ARG
+        .captures_iter(args)
+        .map(|caps| {
+            let key = caps.get(1).unwrap().as_str();
+            let value = match caps.get(2) {
+                Some(quoted) => quoted.as_str().replace("\\\"", "\""),
+                None => caps[3].to_owned(),
+            };
+            (key, value)
+        })
+        .collect();
+    if let Some(template) = templates.get(name) {
+        let mut rendered = template.replace("{body}", body.unwrap_or_default());
+        for (key, value) in &args {
+            rendered = rendered.replace(&format!("{{{}}}", key), value);
+        }
+        return rendered;
+    }
+    let arg = |key| args.get(key).map(String::as_str);
+    match (name, arg("id"), arg("src")) {
+        ("youtube", Some(id), _) => {
+            format!("[YouTube video](https://www.youtube.com/watch?v={})", id)
+        }
+        ("vimeo", Some(id), _) => format!("[Vimeo video](https://vimeo.com/{})", id),
+        ("image", _, Some(src)) | ("figure", _, Some(src)) => {
+            let alt = arg("alt").or_else(|| arg("caption")).unwrap_or("Image");
+            format!("[{}]({})", alt, src)
+        }
+        _ => {
+            warn!("Dropping unknown shortcode {}", name);
+            body.unwrap_or_default().to_owned()
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! Tufte-style sidenotes for a medium without margins. An inline annotation like
+//! `{^ some aside}` is replaced by a numbered marker, and the notes are listed at the end of the
+//! section they're in, just before the next heading. A note has to be plain text, since it's
+//! found by looking through text events.
+
+use pulldown_cmark::{Event, Tag};
+use std::collections::VecDeque;
+
+const OPEN: &str = "{^";
+
+/// Wraps an event stream, turning annotations into numbered notes. Text events must already have
+/// been merged.
+pub struct Sidenotes<'a, I> {
+    inner: I,
+    pending: VecDeque<Event<'a>>,
+    // The notes in the current section, which haven't been written yet.
+    notes: Vec<(usize, String)>,
+    next_id: usize,
+    in_code_block: bool,
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> Sidenotes<'a, I> {
+    pub fn new(inner: I) -> Self {
+        Self {
+            inner,
+            pending: VecDeque::new(),
+            notes: vec![],
+            next_id: 1,
+            in_code_block: false,
+        }
+    }
+
+    /// Queues a paragraph for each of the current section's notes.
+    fn write_notes(&mut self) {
+        for (id, note) in self.notes.drain(..) {
+            self.pending.push_back(Event::Start(Tag::Paragraph));
+            self.pending
+                .push_back(Event::Text(format!("[^{}]: {}", id, note).into()));
+            self.pending.push_back(Event::End(Tag::Paragraph));
+        }
+    }
+
+    /// Queues the text with its annotations replaced by markers, saving the notes.
+    fn split_notes(&mut self, mut text: &str) {
+        while let Some(start) = text.find(OPEN) {
+            let end = match closing_brace(&text[start + OPEN.len()..]) {
+                Some(end) => start + OPEN.len() + end,
+                None => break,
+            };
+            let note = text[start + OPEN.len()..end].trim();
+            if note.is_empty() {
+                self.pending
+                    .push_back(Event::Text(text[..end + 1].to_owned().into()));
+                text = &text[end + 1..];
+                continue;
+            }
+            let id = self.next_id;
+            self.next_id += 1;
+            self.notes.push((id, note.to_owned()));
+            let before = text[..start].trim_end();
+            self.pending
+                .push_back(Event::Text(format!("{}[^{}]", before, id).into()));
+            text = &text[end + 1..];
+        }
+        if !text.is_empty() {
+            self.pending.push_back(Event::Text(text.to_owned().into()));
+        }
+    }
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> Iterator for Sidenotes<'a, I> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        match self.inner.next() {
+            None => {
+                self.write_notes();
+                self.pending.pop_front()
+            }
+            Some(Event::Text(text)) if !self.in_code_block && text.contains(OPEN) => {
+                self.split_notes(&text);
+                self.pending.pop_front()
+            }
+            Some(event @ Event::Start(Tag::Heading(_))) if !self.notes.is_empty() => {
+                self.write_notes();
+                self.pending.push_back(event);
+                self.pending.pop_front()
+            }
+            Some(event) => {
+                match event {
+                    Event::Start(Tag::CodeBlock(_)) => self.in_code_block = true,
+                    Event::End(Tag::CodeBlock(_)) => self.in_code_block = false,
+                    _ => (),
+                }
+                Some(event)
+            }
+        }
+    }
+}
+
+/// Finds the brace closing an annotation whose body starts the text, allowing for nested braces.
+fn closing_brace(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i),
+            '}' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
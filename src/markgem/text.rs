@@ -0,0 +1,39 @@
+//! pulldown-cmark splits text at brackets, entities and the like, so a single run of text can come
+//! out as several consecutive `Text` events. Merging them lets the rewriting passes look for
+//! syntax like `[[page]]` or `[@key]` without worrying about where the splits are.
+
+use pulldown_cmark::Event;
+use std::iter::Peekable;
+
+/// Wraps an event stream, merging consecutive text events into one.
+pub struct MergeText<'a, I: Iterator<Item = Event<'a>>> {
+    inner: Peekable<I>,
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> MergeText<'a, I> {
+    pub fn new(inner: I) -> Self {
+        Self {
+            inner: inner.peekable(),
+        }
+    }
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> Iterator for MergeText<'a, I> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        let text = match self.inner.next()? {
+            Event::Text(text) => text,
+            event => return Some(event),
+        };
+        if !matches!(self.inner.peek(), Some(Event::Text(_))) {
+            return Some(Event::Text(text));
+        }
+        let mut merged = text.into_string();
+        while let Some(Event::Text(more)) = self.inner.peek() {
+            merged.push_str(more);
+            self.inner.next();
+        }
+        Some(Event::Text(merged.into()))
+    }
+}
@@ -0,0 +1,108 @@
+//! Generation of a table of contents, since long articles are hard to navigate in Gemini clients.
+//! Gemtext has no fragments to link to, so the table is a plain list of section titles, with
+//! subsections indented under their sections.
+
+use pulldown_cmark::{Event, Parser, Tag};
+use std::collections::VecDeque;
+
+/// How far each level of subsection is indented.
+const INDENT: &str = "  ";
+
+/// Wraps an event stream, inserting a table of contents after the document's title, or at the
+/// start if it doesn't begin with a heading.
+pub struct TableOfContents<'a, I> {
+    inner: I,
+    pending: VecDeque<Event<'a>>,
+    // The entries, which are taken once they've been inserted.
+    entries: Option<Vec<(u32, String)>>,
+    started: bool,
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> TableOfContents<'a, I> {
+    /// Creates a new adapter listing the headings in the given Markdown, which should be the
+    /// source of the events. If the events start with a title that isn't in the Markdown, it
+    /// should be passed in too.
+    pub fn new(inner: I, markdown: &str, title: Option<String>) -> Self {
+        let mut entries: Vec<_> = title.into_iter().map(|title| (1, title)).collect();
+        entries.extend(headings(markdown));
+        Self {
+            inner,
+            pending: VecDeque::new(),
+            entries: Some(entries),
+            started: false,
+        }
+    }
+
+    /// Queues the list of entries, leaving out the title if there is one.
+    fn insert(&mut self, title: bool) {
+        let mut entries = self.entries.take().unwrap_or_default();
+        if title && !entries.is_empty() {
+            entries.remove(0);
+        }
+        let top = match entries.iter().map(|(depth, _)| *depth).min() {
+            Some(top) => top,
+            None => return,
+        };
+        self.pending.push_back(Event::Start(Tag::List(None)));
+        for (depth, title) in entries {
+            let indent = INDENT.repeat((depth - top) as usize);
+            self.pending.push_back(Event::Start(Tag::Item));
+            self.pending
+                .push_back(Event::Text(format!("{}{}", indent, title).into()));
+            self.pending.push_back(Event::End(Tag::Item));
+        }
+        self.pending.push_back(Event::End(Tag::List(None)));
+    }
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> Iterator for TableOfContents<'a, I> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        let event = self.inner.next()?;
+        if !self.started {
+            self.started = true;
+            if let Event::Start(Tag::Heading(_)) = event {
+                // Wait for the end of the title.
+                return Some(event);
+            }
+            self.insert(false);
+            self.pending.push_back(event);
+            return self.pending.pop_front();
+        }
+        if let (Event::End(Tag::Heading(_)), Some(_)) = (&event, &self.entries) {
+            self.pending.push_back(event);
+            self.insert(true);
+            return self.pending.pop_front();
+        }
+        Some(event)
+    }
+}
+
+/// Collects the depth and text of every heading in the Markdown.
+fn headings(markdown: &str) -> Vec<(u32, String)> {
+    let mut headings = vec![];
+    let mut current: Option<(u32, String)> = None;
+    for event in Parser::new(markdown) {
+        match event {
+            Event::Start(Tag::Heading(depth)) => current = Some((depth, String::new())),
+            Event::End(Tag::Heading(_)) => headings.extend(current.take()),
+            Event::Text(text) | Event::Code(text) => {
+                if let Some((_, title)) = &mut current {
+                    title.push_str(&text);
+                }
+            }
+            // Setext headings can span lines.
+            Event::SoftBreak | Event::HardBreak => {
+                if let Some((_, title)) = &mut current {
+                    title.push(' ');
+                }
+            }
+            _ => (),
+        }
+    }
+    headings
+}
@@ -0,0 +1,104 @@
+//! Support for wiki-style links, as used by Obsidian and other Zettelkasten tools. `[[Page Name]]`
+//! links to `page-name.gmi` with the text "Page Name", and `[[target|label]]` links to
+//! `target.gmi` with the text "label". When rendering HTML, the links go to `.html` files instead.
+
+use pulldown_cmark::{CowStr, Event, LinkType, Tag};
+use std::collections::VecDeque;
+
+/// Wraps an event stream, turning wiki-style links in text into ordinary links. Text events must
+/// already have been merged.
+pub struct WikiLinks<'a, I> {
+    inner: I,
+    pending: VecDeque<Event<'a>>,
+    in_code_block: bool,
+    extension: &'static str,
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> WikiLinks<'a, I> {
+    /// Creates a new adapter whose links point at files with the given extension.
+    pub fn new(inner: I, extension: &'static str) -> Self {
+        Self {
+            inner,
+            pending: VecDeque::new(),
+            in_code_block: false,
+            extension,
+        }
+    }
+}
+
+impl<'a, I: Iterator<Item = Event<'a>>> Iterator for WikiLinks<'a, I> {
+    type Item = Event<'a>;
+
+    fn next(&mut self) -> Option<Event<'a>> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        match self.inner.next()? {
+            Event::Text(text) if !self.in_code_block => {
+                self.pending.extend(split_links(&text, self.extension));
+                self.pending.pop_front()
+            }
+            event => {
+                match event {
+                    Event::Start(Tag::CodeBlock(_)) => self.in_code_block = true,
+                    Event::End(Tag::CodeBlock(_)) => self.in_code_block = false,
+                    _ => (),
+                }
+                Some(event)
+            }
+        }
+    }
+}
+
+/// Splits text into plain text and link events.
+fn split_links(mut text: &str, extension: &str) -> Vec<Event<'static>> {
+    let mut events = vec![];
+    while let Some(start) = text.find("[[") {
+        let end = match text[start..].find("]]") {
+            Some(end) => start + end,
+            None => break,
+        };
+        let inner = &text[start + 2..end];
+        let (target, label) = match inner.find('|') {
+            Some(bar) => (&inner[..bar], &inner[bar + 1..]),
+            None => (inner, inner),
+        };
+        if target.trim().is_empty() || inner.contains('[') {
+            events.push(Event::Text(text[..start + 2].to_owned().into()));
+            text = &text[start + 2..];
+            continue;
+        }
+        if start > 0 {
+            events.push(Event::Text(text[..start].to_owned().into()));
+        }
+        let destination: CowStr = format!("{}.{}", slugify(target), extension).into();
+        let tag = || Tag::Link(LinkType::Inline, destination.clone(), "".into());
+        events.push(Event::Start(tag()));
+        events.push(Event::Text(label.trim().to_owned().into()));
+        events.push(Event::End(tag()));
+        text = &text[end + 2..];
+    }
+    if !text.is_empty() {
+        events.push(Event::Text(text.to_owned().into()));
+    }
+    events
+}
+
+/// Turns a page name into a URL path by lowercasing it and replacing runs of anything other than
+/// letters and digits with hyphens. Slashes are kept, so `Notes/Some Page` becomes
+/// `notes/some-page`. Any `#heading` suffix is dropped, since Gemini has no fragments.
+fn slugify(target: &str) -> String {
+    let target = target.split('#').next().unwrap_or_default();
+    target
+        .split('/')
+        .map(|segment| {
+            segment
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .map(str::to_lowercase)
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
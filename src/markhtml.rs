@@ -0,0 +1,69 @@
+//! Conversion from Markdown to HTML, so the same content can be published over HTTP as well as
+//! Gemini. This goes through the same preprocessing and event rewriting as `markgem`, so both
+//! outputs come from the same document.
+
+use crate::markgem::{self, ConverterOptions};
+use anyhow::Result;
+use std::io::Write;
+
+/// Converts the given Markdown to an HTML fragment.
+pub fn to_html_with(markdown: &str, options: &ConverterOptions) -> Result<Vec<u8>> {
+    let mut vec: Vec<u8> = vec![];
+    convert_to(markdown, options, &mut vec)?;
+    Ok(vec)
+}
+
+/// Converts the given Markdown to an HTML fragment, writing it to the given output as it's
+/// generated.
+pub fn convert_to<W: Write>(markdown: &str, options: &ConverterOptions, writer: W) -> Result<()> {
+    let markdown = markgem::preprocess(markdown, options);
+    pulldown_cmark::html::write_html(writer, markgem::html_events(&markdown, options))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_conversion_with(options: &ConverterOptions, markdown: &str, html: &str) -> Result<()> {
+        let bytes = to_html_with(markdown, options)?;
+        assert_eq!(html, String::from_utf8(bytes)?);
+        Ok(())
+    }
+
+    #[test]
+    fn simple() -> Result<()> {
+        check_conversion_with(
+            &ConverterOptions::new(),
+            "+++\ntitle = \"hi\"\n+++\n# Title\n\nsome *text*",
+            "<h1>Title</h1>\n<p>some <em>text</em></p>\n",
+        )
+    }
+
+    #[test]
+    fn wiki_links() -> Result<()> {
+        check_conversion_with(
+            &ConverterOptions::new().wiki_links(true),
+            "[[Some Page]]",
+            "<p><a href=\"some-page.html\">Some Page</a></p>\n",
+        )
+    }
+
+    #[test]
+    fn mirror_url() -> Result<()> {
+        check_conversion_with(
+            &ConverterOptions::new().mirror_url("gemini://example.org/a.md"),
+            "hi",
+            "<p>hi</p>\n<p><a href=\"gemini://example.org/a.md\">Read on Gemini</a></p>\n",
+        )
+    }
+
+    #[test]
+    fn shortcodes() -> Result<()> {
+        check_conversion_with(
+            &ConverterOptions::new(),
+            "{{ youtube(id=\"abc\") }}",
+            "<p><a href=\"https://www.youtube.com/watch?v=abc\">YouTube video</a></p>\n",
+        )
+    }
+}
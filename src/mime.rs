@@ -0,0 +1,124 @@
+//! Working out what a file in the content tree is from its extension, so that everything besides
+//! Markdown pages can be sent as it is, with the right MIME type.
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// MIME types for common extensions. Anything else is sent as `application/octet-stream`.
+const DEFAULT_TYPES: &[(&str, &str)] = &[
+    ("gmi", "text/gemini"),
+    ("gemini", "text/gemini"),
+    ("txt", "text/plain"),
+    ("csv", "text/csv"),
+    ("css", "text/css"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("xml", "application/xml"),
+    ("atom", "application/atom+xml"),
+    ("rss", "application/rss+xml"),
+    ("json", "application/json"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("flac", "audio/flac"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// Extensions of the Markdown pages that get converted to Gemtext.
+const MARKDOWN: &[&str] = &["md", "markdown"];
+
+const UNKNOWN: &str = "application/octet-stream";
+
+/// What to do with a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType<'a> {
+    /// Convert it to Gemtext.
+    Markdown,
+    /// Send it unchanged, with this MIME type.
+    Raw(&'a str),
+}
+
+/// Maps extensions to MIME types.
+#[derive(Debug, Clone)]
+pub struct MimeTypes {
+    types: HashMap<String, String>,
+}
+
+impl MimeTypes {
+    /// Creates a mapping from the defaults and the given extensions and types, which take
+    /// precedence.
+    pub fn new(overrides: &[(String, String)]) -> Self {
+        let mut types: HashMap<_, _> = DEFAULT_TYPES
+            .iter()
+            .map(|(extension, mime)| (extension.to_string(), mime.to_string()))
+            .collect();
+        for (extension, mime) in overrides {
+            types.insert(extension.to_ascii_lowercase(), mime.clone());
+        }
+        Self { types }
+    }
+
+    /// What to do with the file at the given path. Markdown files, and files without an
+    /// extension, are converted unless their extension has been given a type.
+    pub fn file_type(&self, path: &Path) -> FileType<'_> {
+        let extension = match path.extension() {
+            Some(extension) => extension.to_string_lossy().to_ascii_lowercase(),
+            None => return FileType::Markdown,
+        };
+        match self.types.get(&extension) {
+            Some(mime) => FileType::Raw(mime),
+            None if MARKDOWN.contains(&extension.as_str()) => FileType::Markdown,
+            None => FileType::Raw(UNKNOWN),
+        }
+    }
+}
+
+/// Parses a mapping given on the command line, as EXTENSION=TYPE.
+pub fn parse_mapping(s: &str) -> Result<(String, String)> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(extension), Some(mime)) if !extension.is_empty() && !mime.is_empty() => Ok((
+            extension.trim_start_matches('.').to_owned(),
+            mime.to_owned(),
+        )),
+        _ => Err(anyhow!("expected EXTENSION=TYPE, got {}", s)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn file_types() {
+        let types = MimeTypes::new(&[("md".to_owned(), "text/markdown".to_owned())]);
+        let check = |path: &str| types.file_type(Path::new(path));
+        assert_eq!(check("a/page.markdown"), FileType::Markdown);
+        assert_eq!(check("a/page"), FileType::Markdown);
+        assert_eq!(check("a/page.md"), FileType::Raw("text/markdown"));
+        assert_eq!(check("cat.PNG"), FileType::Raw("image/png"));
+        assert_eq!(check("data.bin"), FileType::Raw(UNKNOWN));
+    }
+
+    #[test]
+    fn mappings() -> Result<()> {
+        assert_eq!(
+            parse_mapping(".md=text/markdown")?,
+            ("md".to_owned(), "text/markdown".to_owned())
+        );
+        assert!(parse_mapping("md").is_err());
+        Ok(())
+    }
+}
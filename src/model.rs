@@ -0,0 +1,398 @@
+//! Export of exarch's model of a site as JSON, for tools like search indexers and link checkers
+//! that want to know about the site without parsing Markdown themselves.
+//!
+//! The output is an object with:
+//!
+//! - `pages`: each page's URL path, title, date, tags, word count, every front matter value as
+//!   `metadata`, and the links in it.
+//! - `sections`: each directory with pages in it, with the title from its `_index.md` and the
+//!   paths of its pages.
+//! - `taxonomies`: for each taxonomy, currently only `tags`, the paths of the pages with each
+//!   term.
+//! - `links`: the link graph, as `from` and `to` pairs. Links to other pages on the site are
+//!   given as URL paths, and links elsewhere as they were written.
+
+use anyhow::{Context, Result};
+use exarch::markgem;
+use pulldown_cmark::{Event, Parser, Tag};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::{self, Write as _};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+use url::Url;
+
+#[derive(Debug, StructOpt)]
+pub struct ModelOpt {
+    /// The root of the tree of Markdown files.
+    #[structopt(parse(from_os_str))]
+    root: PathBuf,
+
+    /// Where to write the JSON. If it's omitted, it's written to stdout.
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Page {
+    // The URL path the page is served at, like `/blog/post.md`.
+    path: String,
+    title: Option<String>,
+    date: Option<String>,
+    tags: Vec<String>,
+    words: usize,
+    metadata: Vec<(String, String)>,
+    // Where the page links to, resolved as for the `links` graph.
+    links: Vec<String>,
+}
+
+impl Page {
+    fn new(path: String, markdown: &str) -> Self {
+        let matter = |key| markgem::front_matter_value(markdown, key).map(str::to_owned);
+        let body = markgem::strip_matter(markdown);
+        let links = Parser::new(body)
+            .filter_map(|event| match event {
+                Event::Start(Tag::Link(_, destination, _)) => {
+                    Some(resolve_link(&path, &destination))
+                }
+                _ => None,
+            })
+            .collect();
+        Self {
+            title: matter("title"),
+            date: matter("date"),
+            tags: markgem::front_matter_list(markdown, "tags")
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+            words: body.split_whitespace().count(),
+            metadata: markgem::front_matter(markdown)
+                .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                .collect(),
+            links,
+            path,
+        }
+    }
+
+    fn to_json(&self) -> Json<'_> {
+        let optional = |value: &Option<String>| value.clone().map_or(Json::Null, Json::String);
+        Json::Object(vec![
+            ("path", Json::String(self.path.clone())),
+            ("title", optional(&self.title)),
+            ("date", optional(&self.date)),
+            ("tags", Json::strings(&self.tags)),
+            ("words", Json::Number(self.words)),
+            (
+                "metadata",
+                Json::Object(
+                    self.metadata
+                        .iter()
+                        .map(|(key, value)| (key.as_str(), Json::String(value.clone())))
+                        .collect(),
+                ),
+            ),
+            ("links", Json::strings(&self.links)),
+        ])
+    }
+}
+
+/// Resolves a link on the page at the given path. Links to the same site become URL paths, and
+/// anything else is left as it is.
+fn resolve_link(page: &str, destination: &str) -> String {
+    // A file URL stands in for the site, since it has no host of its own.
+    let base = Url::parse("file:///").unwrap();
+    match base.join(page).and_then(|page| page.join(destination)) {
+        Ok(url) if url.scheme() == "file" && url.host().is_none() => {
+            let mut path = url.path().to_owned();
+            if let Some(fragment) = url.fragment() {
+                path.push('#');
+                path.push_str(fragment);
+            }
+            path
+        }
+        _ => destination.to_owned(),
+    }
+}
+
+/// Everything exarch knows about the site.
+#[derive(Debug, Default)]
+struct Model {
+    pages: Vec<Page>,
+    // Sections' titles, by the path of their directory, like `/blog/`.
+    section_titles: BTreeMap<String, Option<String>>,
+}
+
+impl Model {
+    fn to_json(&self) -> Json<'_> {
+        let mut sections: BTreeMap<&str, Vec<&Page>> = BTreeMap::new();
+        let mut tags: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for page in &self.pages {
+            let section = &page.path[..=page.path.rfind('/').unwrap_or(0)];
+            sections.entry(section).or_default().push(page);
+            for tag in &page.tags {
+                tags.entry(tag).or_default().push(&page.path);
+            }
+        }
+        let sections = sections
+            .into_iter()
+            .map(|(path, pages)| {
+                let title = self.section_titles.get(path).cloned().flatten();
+                Json::Object(vec![
+                    ("path", Json::String(path.to_owned())),
+                    ("title", title.map_or(Json::Null, Json::String)),
+                    (
+                        "pages",
+                        Json::Array(
+                            pages
+                                .iter()
+                                .map(|page| Json::String(page.path.clone()))
+                                .collect(),
+                        ),
+                    ),
+                ])
+            })
+            .collect();
+        let tags = tags
+            .into_iter()
+            .map(|(tag, pages)| (tag, Json::strings(&pages)))
+            .collect();
+        let links = self
+            .pages
+            .iter()
+            .flat_map(|page| {
+                page.links.iter().map(move |link| {
+                    Json::Object(vec![
+                        ("from", Json::String(page.path.clone())),
+                        ("to", Json::String(link.clone())),
+                    ])
+                })
+            })
+            .collect();
+        Json::Object(vec![
+            (
+                "pages",
+                Json::Array(self.pages.iter().map(Page::to_json).collect()),
+            ),
+            ("sections", Json::Array(sections)),
+            (
+                "taxonomies",
+                Json::Object(vec![("tags", Json::Object(tags))]),
+            ),
+            ("links", Json::Array(links)),
+        ])
+    }
+}
+
+pub fn export(options: ModelOpt) -> Result<()> {
+    let mut model = Model::default();
+    collect(&options.root, "/", &mut model)?;
+    let json = format!("{}\n", model.to_json());
+    match &options.output {
+        Some(output) => std::fs::write(output, json)
+            .with_context(|| format!("failed to write {}", output.display()))?,
+        None => print!("{}", json),
+    }
+    Ok(())
+}
+
+/// Adds the pages in the directory, which is at the given URL path, and everything under it. The
+/// entries are sorted so that the output doesn't change unless the site does.
+fn collect(dir: &Path, url_path: &str, model: &mut Model) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if path.is_dir() {
+            collect(&path, &format!("{}{}/", url_path, name), model)?;
+        } else if path.extension() == Some(OsStr::new("md")) {
+            let markdown = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            if name == "_index.md" {
+                let title = markgem::front_matter_value(&markdown, "title").map(str::to_owned);
+                model.section_titles.insert(url_path.to_owned(), title);
+            } else {
+                let page_path = format!("{}{}", url_path, name);
+                model.pages.push(Page::new(page_path, &markdown));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Just enough JSON to write the model, indented for reading.
+#[derive(Debug, Clone)]
+enum Json<'a> {
+    Null,
+    Number(usize),
+    String(String),
+    Array(Vec<Json<'a>>),
+    Object(Vec<(&'a str, Json<'a>)>),
+}
+
+impl Json<'_> {
+    fn strings(strings: &[impl AsRef<str>]) -> Self {
+        Json::Array(
+            strings
+                .iter()
+                .map(|s| Json::String(s.as_ref().to_owned()))
+                .collect(),
+        )
+    }
+
+    fn write(&self, out: &mut fmt::Formatter, indent: usize) -> fmt::Result {
+        let newline = |out: &mut fmt::Formatter, indent| write!(out, "\n{:1$}", "", indent * 2);
+        match self {
+            Json::Null => out.write_str("null"),
+            Json::Number(n) => write!(out, "{}", n),
+            Json::String(s) => write_string(out, s),
+            Json::Array(items) if items.is_empty() => out.write_str("[]"),
+            Json::Array(items) => {
+                out.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.write_char(',')?;
+                    }
+                    newline(out, indent + 1)?;
+                    item.write(out, indent + 1)?;
+                }
+                newline(out, indent)?;
+                out.write_char(']')
+            }
+            Json::Object(fields) if fields.is_empty() => out.write_str("{}"),
+            Json::Object(fields) => {
+                out.write_char('{')?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.write_char(',')?;
+                    }
+                    newline(out, indent + 1)?;
+                    write_string(out, key)?;
+                    out.write_str(": ")?;
+                    value.write(out, indent + 1)?;
+                }
+                newline(out, indent)?;
+                out.write_char('}')
+            }
+        }
+    }
+}
+
+impl fmt::Display for Json<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+fn write_string(out: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    out.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => out.write_str("\\\"")?,
+            '\\' => out.write_str("\\\\")?,
+            '\n' => out.write_str("\\n")?,
+            '\r' => out.write_str("\\r")?,
+            '\t' => out.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => out.write_char(c)?,
+        }
+    }
+    out.write_char('"')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn links() {
+        assert_eq!(resolve_link("/blog/a.md", "b.md"), "/blog/b.md");
+        assert_eq!(resolve_link("/blog/a.md", "../c.md#x"), "/c.md#x");
+        assert_eq!(resolve_link("/blog/a.md", "/d.md"), "/d.md");
+        assert_eq!(
+            resolve_link("/blog/a.md", "gemini://example.org/"),
+            "gemini://example.org/"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let mut model = Model::default();
+        model
+            .section_titles
+            .insert("/blog/".to_owned(), Some("Blog".to_owned()));
+        model.pages.push(Page::new(
+            "/blog/a.md".to_owned(),
+            "+++\ntitle = \"A post\"\ntags = [\"rust\"]\n+++\nSee [b](b.md).",
+        ));
+        model.pages.push(Page::new("/index.md".to_owned(), "hi"));
+        assert_eq!(
+            model.to_json().to_string(),
+            indoc!(
+                r#"
+                {
+                  "pages": [
+                    {
+                      "path": "/blog/a.md",
+                      "title": "A post",
+                      "date": null,
+                      "tags": [
+                        "rust"
+                      ],
+                      "words": 2,
+                      "metadata": {
+                        "title": "A post",
+                        "tags": "[\"rust\"]"
+                      },
+                      "links": [
+                        "/blog/b.md"
+                      ]
+                    },
+                    {
+                      "path": "/index.md",
+                      "title": null,
+                      "date": null,
+                      "tags": [],
+                      "words": 1,
+                      "metadata": {},
+                      "links": []
+                    }
+                  ],
+                  "sections": [
+                    {
+                      "path": "/",
+                      "title": null,
+                      "pages": [
+                        "/index.md"
+                      ]
+                    },
+                    {
+                      "path": "/blog/",
+                      "title": "Blog",
+                      "pages": [
+                        "/blog/a.md"
+                      ]
+                    }
+                  ],
+                  "taxonomies": {
+                    "tags": {
+                      "rust": [
+                        "/blog/a.md"
+                      ]
+                    }
+                  },
+                  "links": [
+                    {
+                      "from": "/blog/a.md",
+                      "to": "/blog/b.md"
+                    }
+                  ]
+                }"#
+            )
+            .trim_start()
+        );
+    }
+}
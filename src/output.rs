@@ -0,0 +1,295 @@
+//! The formats Markdown can be converted to. Each one is an `OutputFormat`, and a `Registry`
+//! finds them by name, so that a new format can be added, by exarch or by a library user, without
+//! changing the code that picks one.
+//!
+//! ```
+//! use anyhow::Result;
+//! use exarch::markgem::ConverterOptions;
+//! use exarch::output::{OutputFormat, Registry};
+//! use std::io::Write;
+//!
+//! struct Shouting;
+//!
+//! impl OutputFormat for Shouting {
+//!     fn name(&self) -> &str {
+//!         "shouting"
+//!     }
+//!
+//!     fn extension(&self) -> &str {
+//!         "txt"
+//!     }
+//!
+//!     fn convert(&self, markdown: &str, _: &ConverterOptions, out: &mut dyn Write) -> Result<()> {
+//!         out.write_all(markdown.to_uppercase().as_bytes())?;
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let registry = Registry::default().register(Box::new(Shouting));
+//! let mut out = vec![];
+//! registry
+//!     .get("shouting")
+//!     .unwrap()
+//!     .convert("hi", &ConverterOptions::new(), &mut out)
+//!     .unwrap();
+//! assert_eq!(out, b"HI");
+//! ```
+
+use crate::markgem::{self, ConverterOptions};
+use crate::{gopher, markhtml};
+use anyhow::Result;
+use std::io::Write;
+
+/// A format that Markdown can be converted to.
+pub trait OutputFormat: Send + Sync {
+    /// The name the format is chosen by, like `gemini`.
+    fn name(&self) -> &str;
+
+    /// Other names the format can be chosen by.
+    fn aliases(&self) -> &[&str] {
+        &[]
+    }
+
+    /// The extension of files in the format, without the dot.
+    fn extension(&self) -> &str;
+
+    /// Converts the Markdown, writing the result to the output.
+    fn convert(
+        &self,
+        markdown: &str,
+        options: &ConverterOptions,
+        out: &mut dyn Write,
+    ) -> Result<()>;
+}
+
+/// Gemtext, as `markgem` writes it.
+pub struct Gemtext;
+
+impl OutputFormat for Gemtext {
+    fn name(&self) -> &str {
+        "gemini"
+    }
+
+    fn aliases(&self) -> &[&str] {
+        &["gemtext", "gmi"]
+    }
+
+    fn extension(&self) -> &str {
+        "gmi"
+    }
+
+    fn convert(
+        &self,
+        markdown: &str,
+        options: &ConverterOptions,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        markgem::convert_to(markdown, options, out)
+    }
+}
+
+/// HTML, as `markhtml` writes it.
+pub struct Html;
+
+impl OutputFormat for Html {
+    fn name(&self) -> &str {
+        "html"
+    }
+
+    fn extension(&self) -> &str {
+        "html"
+    }
+
+    fn convert(
+        &self,
+        markdown: &str,
+        options: &ConverterOptions,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        markhtml::convert_to(markdown, options, out)
+    }
+}
+
+/// Plain text, rendered from the Gemtext. See `gopher::to_plain_text`.
+pub struct PlainText;
+
+impl OutputFormat for PlainText {
+    fn name(&self) -> &str {
+        "text"
+    }
+
+    fn aliases(&self) -> &[&str] {
+        &["txt"]
+    }
+
+    fn extension(&self) -> &str {
+        "txt"
+    }
+
+    fn convert(
+        &self,
+        markdown: &str,
+        options: &ConverterOptions,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        let gemini = String::from_utf8(markgem::to_gemini_with(markdown, options)?)?;
+        out.write_all(gopher::to_plain_text(&gemini).as_bytes())?;
+        Ok(())
+    }
+}
+
+/// A gophermap, rendered from the Gemtext. See `gopher::to_gophermap`.
+pub struct Gophermap;
+
+impl OutputFormat for Gophermap {
+    fn name(&self) -> &str {
+        "gophermap"
+    }
+
+    fn extension(&self) -> &str {
+        "gophermap"
+    }
+
+    fn convert(
+        &self,
+        markdown: &str,
+        options: &ConverterOptions,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        let gemini = String::from_utf8(markgem::to_gemini_with(markdown, options)?)?;
+        out.write_all(gopher::to_gophermap(&gemini).as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Gemtext colored for a terminal. See `ansi`.
+pub struct Ansi;
+
+impl OutputFormat for Ansi {
+    fn name(&self) -> &str {
+        "ansi"
+    }
+
+    fn extension(&self) -> &str {
+        "ansi"
+    }
+
+    fn convert(
+        &self,
+        markdown: &str,
+        options: &ConverterOptions,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        markgem::convert_to(markdown, &options.clone().ansi_colors(true), out)
+    }
+}
+
+/// The output formats, by name.
+pub struct Registry {
+    formats: Vec<Box<dyn OutputFormat>>,
+}
+
+impl Default for Registry {
+    /// A registry holding the formats exarch comes with.
+    fn default() -> Self {
+        Self::empty()
+            .register(Box::new(Gemtext))
+            .register(Box::new(Html))
+            .register(Box::new(PlainText))
+            .register(Box::new(Gophermap))
+            .register(Box::new(Ansi))
+    }
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry without any formats, not even exarch's own.
+    pub fn empty() -> Self {
+        Self { formats: vec![] }
+    }
+
+    /// Adds the format. It replaces any format already registered with the same name.
+    pub fn register(mut self, format: Box<dyn OutputFormat>) -> Self {
+        self.formats
+            .retain(|registered| registered.name() != format.name());
+        self.formats.push(format);
+        self
+    }
+
+    /// Finds the format with the given name or alias.
+    pub fn get(&self, name: &str) -> Option<&dyn OutputFormat> {
+        let find = |matches: &dyn Fn(&dyn OutputFormat) -> bool| {
+            self.formats
+                .iter()
+                .map(Box::as_ref)
+                .find(|&format| matches(format))
+        };
+        // A format's name takes precedence over another's alias.
+        find(&|format| format.name() == name)
+            .or_else(|| find(&|format| format.aliases().contains(&name)))
+    }
+
+    /// The names of the registered formats, in the order they were registered.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.formats.iter().map(|format| format.name())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn convert(registry: &Registry, name: &str, markdown: &str) -> Result<String> {
+        let mut out = vec![];
+        let format = registry.get(name).unwrap();
+        format.convert(markdown, &ConverterOptions::new(), &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn built_in_formats() -> Result<()> {
+        let registry = Registry::default();
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            vec!["gemini", "html", "text", "gophermap", "ansi"]
+        );
+        assert_eq!(convert(&registry, "gmi", "# Hi")?, "# Hi");
+        assert_eq!(convert(&registry, "html", "# Hi")?, "<h1>Hi</h1>\n");
+        assert_eq!(convert(&registry, "text", "# Hi")?, "Hi\n==\n");
+        assert!(registry.get("pdf").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn replacing_formats() -> Result<()> {
+        struct Empty;
+
+        impl OutputFormat for Empty {
+            fn name(&self) -> &str {
+                "html"
+            }
+
+            fn aliases(&self) -> &[&str] {
+                &["gemini"]
+            }
+
+            fn extension(&self) -> &str {
+                "html"
+            }
+
+            fn convert(&self, _: &str, _: &ConverterOptions, _: &mut dyn Write) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let registry = Registry::default().register(Box::new(Empty));
+        assert_eq!(convert(&registry, "html", "# Hi")?, "");
+        // Names win over aliases.
+        assert_eq!(convert(&registry, "gemini", "# Hi")?, "# Hi");
+        assert_eq!(registry.names().count(), 5);
+        Ok(())
+    }
+}
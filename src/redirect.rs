@@ -0,0 +1,136 @@
+//! Redirects for moved pages, so that old links get a `31` instead of a `51`.
+//!
+//! Redirects are read from a file with one per line, giving the old path, where it moved to, and
+//! optionally `temporary` for a `30` rather than a `31`:
+//!
+//! ```text
+//! # A renamed post.
+//! /blog/old-name.md  /blog/new-name.md
+//! # A whole section that moved, keeping the rest of the path.
+//! /notes/*  gemini://notes.example/*
+//! /sale.md  /shop.md  temporary
+//! ```
+//!
+//! A path ending in `*` matches everything under it, and a `*` at the end of the destination is
+//! replaced by whatever the `*` matched. Exact paths take precedence, then the longest prefix.
+
+use anyhow::{bail, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Redirect {
+    from: String,
+    // Whether `from` is a prefix, with its `*` removed.
+    prefix: bool,
+    to: String,
+    permanent: bool,
+}
+
+/// A set of redirects.
+#[derive(Debug, Clone, Default)]
+pub struct Redirects {
+    redirects: Vec<Redirect>,
+}
+
+impl Redirects {
+    /// Parses redirects in the format described in the module documentation.
+    pub fn parse(s: &str) -> Result<Self> {
+        let mut redirects = Self::default();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let permanent = match fields.get(2) {
+                None | Some(&"permanent") => true,
+                Some(&"temporary") => false,
+                Some(kind) => bail!("line {}: unknown kind of redirect {}", number + 1, kind),
+            };
+            if fields.len() < 2 || fields.len() > 3 {
+                bail!("line {}: expected FROM TO [temporary]", number + 1);
+            }
+            redirects.add(fields[0], fields[1], permanent);
+        }
+        Ok(redirects)
+    }
+
+    /// Adds a redirect from a path, which may end in `*`, to a destination.
+    pub fn add(&mut self, from: &str, to: &str, permanent: bool) {
+        let (from, prefix) = match from.strip_suffix('*') {
+            Some(from) => (from, true),
+            None => (from, false),
+        };
+        self.redirects.push(Redirect {
+            from: from.to_owned(),
+            prefix,
+            to: to.to_owned(),
+            permanent,
+        });
+    }
+
+    /// Finds where a request for the path should go, returning the status and destination.
+    pub fn find(&self, path: &str) -> Option<(u8, String)> {
+        let exact = self
+            .redirects
+            .iter()
+            .find(|redirect| !redirect.prefix && redirect.from == path);
+        let redirect = exact.or_else(|| {
+            self.redirects
+                .iter()
+                .filter(|redirect| redirect.prefix && path.starts_with(&redirect.from))
+                .max_by_key(|redirect| redirect.from.len())
+        })?;
+        let to = match redirect.to.strip_suffix('*') {
+            Some(to) if redirect.prefix => format!("{}{}", to, &path[redirect.from.len()..]),
+            _ => redirect.to.clone(),
+        };
+        Some((if redirect.permanent { 31 } else { 30 }, to))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use indoc::indoc;
+
+    const REDIRECTS: &str = indoc!(
+        "
+        # Comments are ignored.
+        /blog/old.md  /blog/new.md
+        /notes/*  gemini://notes.example/*
+        /notes/private/*  /gone.md  temporary
+        /notes/index.md  /notes.md
+        "
+    );
+
+    #[test]
+    fn find() -> Result<()> {
+        let redirects = Redirects::parse(REDIRECTS)?;
+        assert_eq!(
+            redirects.find("/blog/old.md"),
+            Some((31, "/blog/new.md".to_owned()))
+        );
+        assert_eq!(redirects.find("/blog/old.md/x"), None);
+        assert_eq!(
+            redirects.find("/notes/a/b.md"),
+            Some((31, "gemini://notes.example/a/b.md".to_owned()))
+        );
+        assert_eq!(
+            redirects.find("/notes/private/a.md"),
+            Some((30, "/gone.md".to_owned()))
+        );
+        assert_eq!(
+            redirects.find("/notes/index.md"),
+            Some((31, "/notes.md".to_owned()))
+        );
+        assert_eq!(redirects.find("/other.md"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Redirects::parse("/a").is_err());
+        assert!(Redirects::parse("/a /b forever").is_err());
+        assert!(Redirects::parse("/a /b temporary extra").is_err());
+    }
+}
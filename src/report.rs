@@ -0,0 +1,142 @@
+//! Reports about a tree of Markdown files, for finding what needs attention before it goes live.
+
+use anyhow::{Context, Result};
+use exarch::markgem::{self, Construct};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct ReportOpt {
+    #[structopt(subcommand)]
+    command: ReportCommand,
+}
+
+#[derive(Debug, StructOpt)]
+enum ReportCommand {
+    /// List the Markdown constructs that Gemtext can't represent, like tables, HTML, math and
+    /// footnotes, with where each one appears and what becomes of it.
+    Compat {
+        /// The root of the tree of Markdown files.
+        #[structopt(parse(from_os_str))]
+        root: PathBuf,
+    },
+}
+
+pub fn report(options: ReportOpt) -> Result<()> {
+    match options.command {
+        ReportCommand::Compat { root } => {
+            let mut report = CompatReport::default();
+            collect(&root, &root, &mut report)?;
+            print!("{}", report);
+        }
+    }
+    Ok(())
+}
+
+/// Where each unsupported construct appears, as paths relative to the root and line numbers.
+#[derive(Debug, Default)]
+struct CompatReport {
+    found: BTreeMap<Construct, Vec<(PathBuf, usize)>>,
+}
+
+impl CompatReport {
+    fn add_page(&mut self, path: &Path, markdown: &str) {
+        let body = markgem::strip_matter(markdown);
+        // The lines taken up by the front matter, so that line numbers are the file's.
+        let offset = markdown[..markdown.len() - body.len()]
+            .matches('\n')
+            .count();
+        for (construct, line) in markgem::unsupported_constructs(body) {
+            self.found
+                .entry(construct)
+                .or_default()
+                .push((path.to_owned(), line + offset));
+        }
+    }
+}
+
+impl fmt::Display for CompatReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.found.is_empty() {
+            return writeln!(f, "Everything can be represented in Gemtext.");
+        }
+        for (i, (construct, places)) in self.found.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            let mut files: Vec<_> = places.iter().map(|(path, _)| path).collect();
+            files.dedup();
+            writeln!(
+                f,
+                "{} ({} in {} file{}): {}",
+                construct.name(),
+                places.len(),
+                files.len(),
+                if files.len() == 1 { "" } else { "s" },
+                construct.outcome()
+            )?;
+            for (path, line) in places {
+                writeln!(f, "  {}:{}", path.display(), line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adds the Markdown files in the directory and everything under it, in order so that the report
+/// doesn't change unless the files do.
+fn collect(root: &Path, dir: &Path, report: &mut CompatReport) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("failed to read {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            collect(root, &path, report)?;
+        } else if path.extension() == Some(OsStr::new("md")) {
+            let markdown = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            report.add_page(path.strip_prefix(root).unwrap_or(&path), &markdown);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn render() {
+        let mut report = CompatReport::default();
+        report.add_page(
+            Path::new("a.md"),
+            "+++\ntitle = \"A\"\n+++\n| a |\n|---|\n\n<br>\n\n| b |\n|---|",
+        );
+        report.add_page(Path::new("b/c.md"), "| a |\n|---|");
+        report.add_page(Path::new("d.md"), "Nothing to see.");
+        assert_eq!(
+            report.to_string(),
+            indoc!(
+                "
+                tables (3 in 2 files): rows become plain text lines, with cells separated by `|`
+                  a.md:4
+                  a.md:9
+                  b/c.md:1
+
+                HTML (1 in 1 file): dropped, apart from <details> blocks
+                  a.md:7
+                "
+            )
+        );
+        assert_eq!(
+            CompatReport::default().to_string(),
+            "Everything can be represented in Gemtext.\n"
+        );
+    }
+}
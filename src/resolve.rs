@@ -0,0 +1,246 @@
+//! Mapping request URLs onto files in the content root.
+//!
+//! This deliberately doesn't use `PathBuf::extend` with the raw URL segments: on Windows a segment
+//! like `C:` or `a\b` changes the meaning of the path, and names like `CON` refer to devices
+//! rather than files no matter what directory they're in.
+
+use crate::markgem;
+use anyhow::{bail, Context, Result};
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use url::{Host, Url};
+
+/// Names that Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Returns the path under `root` that the URL refers to, or `None` if its path has a segment that
+/// can't safely be used as a file name. The same segments are rejected on every platform, so a
+/// content tree behaves the same no matter where it's served from.
+pub fn resolve(root: &Path, url: &Url) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for segment in url.path_segments().into_iter().flatten() {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        match segment.as_ref() {
+            "" | "." => continue,
+            segment if is_safe(segment) => path.push(segment),
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+/// The URL's percent-decoded path, with the empty and `.` segments that `resolve` skips left out,
+/// so that `//private/a.md` and `/./private/a.md` both become `/private/a.md`. Prefixes like
+/// those of `--restrict` and `--cgi` must be checked against this rather than the URL's own path,
+/// or a client could get around them by adding segments.
+pub fn url_path(url: &Url) -> String {
+    let mut path = String::new();
+    for segment in url.path_segments().into_iter().flatten() {
+        let segment = percent_decode_str(segment).decode_utf8_lossy();
+        if !segment.is_empty() && segment != "." {
+            path.push('/');
+            path.push_str(&segment);
+        }
+    }
+    if path.is_empty() || url.path().ends_with('/') {
+        path.push('/');
+    }
+    path
+}
+
+/// Finds the pages under `root` that give the URL they should be served at with a `path` or `url`
+/// key in their front matter, for keeping the URLs of an old site. The result maps each of those
+/// URLs' paths, as returned by `alias_key`, to the page.
+pub fn aliases(root: &Path) -> Result<HashMap<String, PathBuf>> {
+    let mut aliases = HashMap::new();
+    collect_aliases(root, &mut aliases)?;
+    Ok(aliases)
+}
+
+fn collect_aliases(dir: &Path, aliases: &mut HashMap<String, PathBuf>) -> Result<()> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_aliases(&path, aliases)?;
+        } else if path.extension() == Some(OsStr::new("md")) {
+            let markdown = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let alias = markgem::front_matter_value(&markdown, "path")
+                .or_else(|| markgem::front_matter_value(&markdown, "url"));
+            if let Some(alias) = alias {
+                // The value can be a whole URL or just its path.
+                let key = match Url::parse(alias) {
+                    Ok(url) => alias_key(&url),
+                    Err(_) => normalize_alias(alias),
+                };
+                aliases.insert(key, path);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The key the URL's path has in the map returned by `aliases`.
+pub fn alias_key(url: &Url) -> String {
+    normalize_alias(&percent_decode_str(url.path()).decode_utf8_lossy())
+}
+
+/// Puts a path in a standard form, with a leading slash and no trailing one.
+fn normalize_alias(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
+
+/// Converts a hostname to the ASCII form used in URLs and certificates, so that internationalized
+/// names like `bücher.example` become `xn--bcher-kva.example`.
+pub fn ascii_host(hostname: &str) -> Result<String> {
+    match Host::parse(hostname) {
+        Ok(Host::Domain(domain)) => Ok(domain),
+        Ok(_) => bail!("{} is an IP address, not a hostname", hostname),
+        Err(e) => bail!("{} isn't a valid hostname: {}", hostname, e),
+    }
+}
+
+/// Puts an internationalized host in the URL into its ASCII form. The url crate only does that
+/// for schemes it knows about, and gemini isn't one, so it leaves `bücher.example` percent-encoded
+/// instead.
+pub fn normalize_host(url: &mut Url) -> Result<()> {
+    let host = match url.host_str() {
+        Some(host) => percent_decode_str(host).decode_utf8()?.into_owned(),
+        None => return Ok(()),
+    };
+    if !host.is_ascii() {
+        url.set_host(Some(&ascii_host(&host)?))?;
+    }
+    Ok(())
+}
+
+/// Whether the segment can be used as a single path component.
+fn is_safe(segment: &str) -> bool {
+    if segment == ".."
+        || segment.contains(|c: char| c == '/' || c == '\\' || c == ':' || c.is_control())
+        // Windows silently strips these, so `foo.` would refer to `foo`.
+        || segment.ends_with('.')
+        || segment.ends_with(' ')
+    {
+        return false;
+    }
+    let stem = segment.split('.').next().unwrap_or_default();
+    !RESERVED_NAMES
+        .iter()
+        .any(|name| name.eq_ignore_ascii_case(stem.trim_end()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check(url: &str) -> Option<PathBuf> {
+        resolve(Path::new("root"), &Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn simple() {
+        assert_eq!(
+            check("gemini://host/blog/post.md"),
+            Some(Path::new("root").join("blog").join("post.md"))
+        );
+    }
+
+    #[test]
+    fn empty_and_dot_segments() {
+        assert_eq!(check("gemini://host/"), Some(PathBuf::from("root")));
+        assert_eq!(
+            check("gemini://host/a//./b/"),
+            Some(Path::new("root").join("a").join("b"))
+        );
+    }
+
+    #[test]
+    fn percent_encoded() {
+        assert_eq!(
+            check("gemini://host/caf%C3%A9.md"),
+            Some(Path::new("root").join("café.md"))
+        );
+        assert_eq!(check("gemini://host/%FF"), None);
+    }
+
+    #[test]
+    fn separators() {
+        assert_eq!(check("gemini://host/a%2Fb"), None);
+        assert_eq!(check("gemini://host/a%5Cb"), None);
+        assert_eq!(check("gemini://host/C:"), None);
+        assert_eq!(check("gemini://host/file.md:stream"), None);
+    }
+
+    #[test]
+    fn parent() {
+        assert_eq!(
+            check("gemini://host/a/%2e%2e/%2e%2e/etc"),
+            Some(PathBuf::from("root").join("etc"))
+        );
+        assert_eq!(check("gemini://host/a/.%2e%2Fetc"), None);
+    }
+
+    #[test]
+    fn reserved_names() {
+        assert_eq!(check("gemini://host/CON"), None);
+        assert_eq!(check("gemini://host/dir/nul.md"), None);
+        assert_eq!(check("gemini://host/com1.txt"), None);
+        assert!(check("gemini://host/console.md").is_some());
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces() {
+        assert_eq!(check("gemini://host/index.md."), None);
+        assert_eq!(check("gemini://host/index.md%20"), None);
+    }
+
+    #[test]
+    fn url_paths() {
+        let path = |url: &str| url_path(&Url::parse(url).unwrap());
+        assert_eq!(path("gemini://host"), "/");
+        assert_eq!(path("gemini://host/"), "/");
+        assert_eq!(path("gemini://host//private/a.md"), "/private/a.md");
+        assert_eq!(path("gemini://host/./private/./a.md"), "/private/a.md");
+        assert_eq!(path("gemini://host/blog//"), "/blog/");
+        assert_eq!(path("gemini://host/caf%C3%A9.md"), "/café.md");
+    }
+
+    #[test]
+    fn alias_keys() {
+        let key = |url: &str| alias_key(&Url::parse(url).unwrap());
+        assert_eq!(key("gemini://host/old/post.html"), "/old/post.html");
+        assert_eq!(key("gemini://host/old/caf%C3%A9/"), "/old/café");
+        assert_eq!(key("gemini://host"), "/");
+        assert_eq!(normalize_alias("old/post.html"), "/old/post.html");
+    }
+
+    #[test]
+    fn ascii_hosts() -> Result<()> {
+        assert_eq!(ascii_host("Bücher.example")?, "xn--bcher-kva.example");
+        assert_eq!(ascii_host("example.org")?, "example.org");
+        assert!(ascii_host("127.0.0.1").is_err());
+        assert!(ascii_host("a b").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn normalized_hosts() -> Result<()> {
+        let mut url = Url::parse("gemini://Bücher.example/café")?;
+        normalize_host(&mut url)?;
+        assert_eq!(url.as_str(), "gemini://xn--bcher-kva.example/caf%C3%A9");
+        Ok(())
+    }
+
+    #[test]
+    fn control_characters() {
+        assert_eq!(check("gemini://host/a%00b"), None);
+    }
+}
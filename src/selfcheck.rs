@@ -0,0 +1,274 @@
+//! Checks run before the server starts, so that misconfiguration shows up as a clear report instead
+//! of as failed requests later on.
+
+use crate::date::{self, Date};
+use crate::resolve;
+use anyhow::{anyhow, Result};
+use rustls::sign;
+use rustls::{Certificate, PrivateKey, SignatureScheme};
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Ok,
+    /// Something that's probably a mistake, but won't stop the server from working.
+    Warning,
+    /// Something that will stop the server from working.
+    Error,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self {
+            Level::Ok => "ok",
+            Level::Warning => "warn",
+            Level::Error => "FAIL",
+        };
+        f.pad(label)
+    }
+}
+
+/// The results of all of the checks.
+#[derive(Debug, Default)]
+pub struct Report {
+    checks: Vec<(Level, &'static str, String)>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, level: Level, name: &'static str, detail: impl Into<String>) {
+        self.checks.push((level, name, detail.into()));
+    }
+
+    /// Adds an ok check if the result is `Ok`, or an error with its message otherwise.
+    pub fn add_result(&mut self, name: &'static str, result: Result<String>) {
+        match result {
+            Ok(detail) => self.add(Level::Ok, name, detail),
+            Err(e) => self.add(Level::Error, name, format!("{:#}", e)),
+        }
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|(level, _, _)| *level == Level::Error)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.checks.iter().map(|(_, name, _)| name.len()).max();
+        for (level, name, detail) in &self.checks {
+            writeln!(
+                f,
+                "{:<4}  {:<width$}  {}",
+                level,
+                name,
+                detail,
+                width = width.unwrap_or_default()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that a directory exists and can be listed.
+pub fn check_dir(dir: &Path) -> Result<String> {
+    std::fs::read_dir(dir).map_err(|e| anyhow!("can't read {}: {}", dir.display(), e))?;
+    Ok(dir.display().to_string())
+}
+
+/// Checks that nothing else is listening on the port.
+pub fn check_port(port: u16) -> Result<String> {
+    std::net::TcpListener::bind(("0.0.0.0", port))
+        .map_err(|e| anyhow!("can't bind port {}: {}", port, e))?;
+    Ok(port.to_string())
+}
+
+/// The signature schemes that keys are checked with, and the corresponding algorithms to verify
+/// them with.
+const SCHEMES: &[(SignatureScheme, &webpki::SignatureAlgorithm)] = &[
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        &webpki::ECDSA_P256_SHA256,
+    ),
+    (
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        &webpki::ECDSA_P384_SHA384,
+    ),
+    (
+        SignatureScheme::RSA_PKCS1_SHA256,
+        &webpki::RSA_PKCS1_2048_8192_SHA256,
+    ),
+];
+
+/// Checks that the key belongs to the certificate, by signing something with the key and
+/// verifying the signature with the certificate.
+pub fn check_key_matches(cert: &Certificate, key: &PrivateKey) -> Result<String> {
+    const MESSAGE: &[u8] = b"exarch self-check";
+    let signing_key = sign::any_supported_type(key).map_err(|_| anyhow!("unsupported key type"))?;
+    let offered: Vec<_> = SCHEMES.iter().map(|(scheme, _)| *scheme).collect();
+    let signer = signing_key
+        .choose_scheme(&offered)
+        .ok_or_else(|| anyhow!("unsupported key algorithm"))?;
+    let algorithm = SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.get_scheme())
+        .map(|(_, algorithm)| *algorithm)
+        .ok_or_else(|| anyhow!("unsupported key algorithm"))?;
+    let signature = signer.sign(MESSAGE)?;
+    let cert = webpki::EndEntityCert::from(&cert.0)
+        .map_err(|e| anyhow!("can't parse certificate: {:?}", e))?;
+    cert.verify_signature(algorithm, MESSAGE, &signature)
+        .map_err(|_| anyhow!("the key doesn't belong to the certificate"))?;
+    Ok(format!("{:?} key", signing_key.algorithm()))
+}
+
+/// Checks that the certificate is valid for the hostname, which may be internationalized. Only
+/// subject alternative names count, not the common name.
+pub fn check_hostname(cert: &Certificate, hostname: &str) -> Result<String> {
+    let ascii = resolve::ascii_host(hostname)?;
+    let name = webpki::DNSNameRef::try_from_ascii_str(&ascii)
+        .map_err(|_| anyhow!("{} isn't a valid hostname", hostname))?;
+    let cert = webpki::EndEntityCert::from(&cert.0)
+        .map_err(|e| anyhow!("can't parse certificate: {:?}", e))?;
+    cert.verify_is_valid_for_dns_name(name).map_err(|_| {
+        anyhow!(
+            "{} isn't one of the certificate's alternative names",
+            hostname
+        )
+    })?;
+    if ascii == hostname {
+        Ok(ascii)
+    } else {
+        Ok(format!("{} ({})", hostname, ascii))
+    }
+}
+
+/// Checks how long the certificate has left, at the given time in seconds since the Unix epoch.
+/// It's a warning if it expires within the given number of days, and an error if it already has.
+pub fn check_expiry(cert: &Certificate, now: i64, warning_days: u32) -> (Level, String) {
+    let not_after = match not_after(&cert.0) {
+        Some(not_after) => not_after,
+        None => return (Level::Warning, "can't find when it expires".to_owned()),
+    };
+    let expiry = date::format_timestamp(not_after);
+    let days = (not_after - now).div_euclid(86400);
+    if not_after <= now {
+        (Level::Error, format!("expired on {}", expiry))
+    } else if days < i64::from(warning_days) {
+        let plural = if days == 1 { "" } else { "s" };
+        (
+            Level::Warning,
+            format!("expires in {} day{}, on {}", days, plural, expiry),
+        )
+    } else {
+        (Level::Ok, format!("valid until {}", expiry))
+    }
+}
+
+/// When a DER certificate stops being valid, as seconds since the Unix epoch.
+fn not_after(cert: &[u8]) -> Option<i64> {
+    // Splits off a DER value, returning its tag, its contents and whatever follows it.
+    fn value(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+        let (&tag, rest) = der.split_first()?;
+        let (&first, rest) = rest.split_first()?;
+        let (len, rest) = if first < 0x80 {
+            (usize::from(first), rest)
+        } else {
+            let count = usize::from(first & 0x7f);
+            if count > 4 || rest.len() < count {
+                return None;
+            }
+            let len = rest[..count]
+                .iter()
+                .fold(0, |len, &b| len << 8 | usize::from(b));
+            (len, &rest[count..])
+        };
+        if rest.len() < len {
+            return None;
+        }
+        Some((tag, &rest[..len], &rest[len..]))
+    }
+
+    let (_, certificate, _) = value(cert)?;
+    let (_, mut fields, _) = value(certificate)?;
+    // The version is optional, and comes before the serial number.
+    if fields.first() == Some(&0xa0) {
+        fields = value(fields)?.2;
+    }
+    // Then come the serial number, signature algorithm and issuer.
+    for _ in 0..3 {
+        fields = value(fields)?.2;
+    }
+    let (_, validity, _) = value(fields)?;
+    let (_, _, rest) = value(validity)?;
+    let (tag, time, _) = value(rest)?;
+    let time = std::str::from_utf8(time).ok()?;
+    let digits = |range: std::ops::Range<usize>| time.get(range)?.parse::<i64>().ok();
+    // UTCTime has two digits for the year, meaning 1950 to 2049, and GeneralizedTime has four.
+    let (year, rest) = match tag {
+        0x17 => {
+            let year = digits(0..2)?;
+            (if year < 50 { 2000 + year } else { 1900 + year }, 2)
+        }
+        0x18 => (digits(0..4)?, 4),
+        _ => return None,
+    };
+    let field = |n: usize| digits(rest + n * 2..rest + n * 2 + 2);
+    let date = Date {
+        year: year as i32,
+        month: field(0)? as u32,
+        day: field(1)? as u32,
+    };
+    Some(date.to_days() * 86400 + field(2)? * 3600 + field(3)? * 60 + field(4)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rustls::internal::pemfile;
+
+    #[test]
+    fn report() {
+        let mut report = Report::new();
+        report.add(Level::Ok, "root", "/srv/gemini");
+        report.add(Level::Warning, "maintenance", "no such directory");
+        assert!(!report.has_errors());
+        report.add_result("port", Err(anyhow!("in use")));
+        assert!(report.has_errors());
+        assert_eq!(
+            report.to_string(),
+            "ok    root         /srv/gemini\n\
+             warn  maintenance  no such directory\n\
+             FAIL  port         in use\n"
+        );
+    }
+
+    #[test]
+    fn expiry() {
+        let certs = pemfile::certs(&mut include_str!("../tests/data/localhost.crt").as_bytes());
+        let cert = &certs.unwrap()[0];
+        // The test certificate expires on 2126-09-21 at 07:27:07.
+        let expires = Date::parse("2126-09-21").unwrap().to_days() * 86400 + 7 * 3600 + 27 * 60 + 7;
+        assert_eq!(not_after(&cert.0), Some(expires));
+        assert_eq!(
+            check_expiry(cert, 0, 14),
+            (Level::Ok, "valid until 2126-09-21 07:27 UTC".to_owned())
+        );
+        assert_eq!(
+            check_expiry(cert, expires - 86400 - 1, 14),
+            (
+                Level::Warning,
+                "expires in 1 day, on 2126-09-21 07:27 UTC".to_owned()
+            )
+        );
+        assert_eq!(check_expiry(cert, expires, 14).0, Level::Error);
+        assert_eq!(not_after(b"\x30\x82\xff"), None);
+    }
+}
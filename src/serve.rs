@@ -1,145 +1,2803 @@
-use crate::markgem;
+use crate::access::{self, AccessRules};
+use crate::accesslog::{AccessLog, Entry, Metered};
+use crate::admin;
+use crate::atomic;
+use crate::autoindex;
+use crate::budget::{self, Budget};
+use crate::cache::{self, Cache, Lifetime, Rules};
+use crate::cgi;
+use crate::config::{self, Config};
+use crate::date;
+use crate::filter::{self, Filter, Filtered, Filters};
+use crate::flags::ConverterFlags;
+use crate::limit::{ConnectionLimit, Permit};
+use crate::markgem::{self, ConverterOptions};
+use crate::mime::{self, FileType, MimeTypes};
+use crate::record::{self, Tee};
+use crate::redirect::Redirects;
+use crate::resolve;
+use crate::selfcheck::{self, Level, Report};
+use crate::shutdown::{self, Handler, Handlers};
+use crate::status::{self, Started, StatusError};
+use crate::systemd;
+use crate::theme;
+use crate::titan;
+use crate::tls::{self, Acceptor, Connection, RustlsAcceptor};
+use crate::unix::{self, UnixSocket};
+use crate::watch;
 use anyhow::{anyhow, bail, Context, Result};
+use async_std::future;
 use async_std::io::prelude::*;
-use async_std::net::{TcpListener, TcpStream};
+use async_std::net::TcpListener;
 use async_std::prelude::*;
 use async_std::task;
-use async_tls::TlsAcceptor;
-use log::{debug, error, info};
-use rustls::{internal::pemfile, NoClientAuth, ServerConfig};
-use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
-use std::sync::Arc;
+use futures::future::{select, Either};
+use futures::stream::{self, BoxStream};
+use log::{debug, error, info, warn};
+use percent_encoding::percent_decode_str;
+use rustls::Certificate;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, Instant};
+use structopt::clap::ArgMatches;
 use structopt::StructOpt;
 use url::Url;
 
 #[derive(Debug, StructOpt)]
 pub struct ServeOpt {
+    /// Read settings from this TOML file. Flags given as well take precedence over it.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// Use the settings of this profile in the --config file over its others.
+    #[structopt(long, requires = "config")]
+    profile: Option<String>,
+
     /// Path to the TLS certificate.
-    #[structopt(short, long, parse(from_os_str))]
-    cert: PathBuf,
+    #[structopt(short, long, parse(from_os_str), required_unless = "config")]
+    cert: Option<PathBuf>,
 
     /// Path to the TLS key file.
-    #[structopt(short, long, parse(from_os_str))]
-    key: PathBuf,
+    #[structopt(short, long, parse(from_os_str), required_unless = "config")]
+    key: Option<PathBuf>,
 
     /// The root of the tree to serve.
-    #[structopt(parse(from_os_str))]
-    root: PathBuf,
+    #[structopt(parse(from_os_str), required_unless = "config")]
+    root: Option<PathBuf>,
 
-    /// What port to listen on.
+    /// What port to listen on. With systemd socket activation, the server listens on the
+    /// sockets systemd passes instead.
     #[structopt(short, long, default_value = "1965")]
     port: u16,
+
+    /// The hostname the server is reached at, which may be internationalized. At startup, the
+    /// certificate is checked to cover it, and requests for other hosts are refused.
+    #[structopt(long)]
+    hostname: Option<String>,
+
+    /// Also serve another capsule, as HOSTNAME=ROOT,CERT,KEY. Clients asking for the hostname
+    /// with SNI get its certificate, and requests for URLs on it are answered from its root. Can
+    /// be given multiple times.
+    #[structopt(long = "vhost", parse(try_from_str = parse_vhost), number_of_values = 1)]
+    vhosts: Vec<VirtualHost>,
+
+    /// Start even if the startup checks find errors.
+    #[structopt(long)]
+    ignore_failed_checks: bool,
+
+    /// A directory whose files take precedence over the ones in the root, for temporarily
+    /// overriding pages without touching the main tree.
+    #[structopt(long, parse(from_os_str))]
+    overlay: Option<PathBuf>,
+
+    /// A second content root to keep on standby. The admin socket's `switch` command swaps it
+    /// with the root without a restart, and switching again swaps them back, so a broken deploy
+    /// can be rolled back straight away.
+    #[structopt(long, parse(from_os_str))]
+    standby: Option<PathBuf>,
+
+    /// Take commands on a Unix socket at this path: `status` to see which root is active, and
+    /// `switch` to swap the root and the --standby root.
+    #[structopt(long, parse(from_os_str))]
+    admin_socket: Option<PathBuf>,
+
+    /// Also listen on a Unix socket at this path, for a front process like relayd or stunnel to
+    /// pass connections on to. Connections on it speak TLS like those on the port. The socket is
+    /// removed on shutdown. Only supported on Unix.
+    #[structopt(long, parse(from_os_str))]
+    unix_socket: Option<PathBuf>,
+
+    /// The permissions of the --unix-socket, in octal, so that the front process can connect.
+    #[structopt(long, default_value = "660", parse(try_from_str = unix::parse_mode))]
+    unix_socket_mode: u32,
+
+    /// Only listen on the --unix-socket, not on the port.
+    #[structopt(long)]
+    no_tcp: bool,
+
+    /// While this file exists, the server is in maintenance mode and answers every request with
+    /// `41`. If the file isn't empty, its contents are converted and served instead.
+    #[structopt(long, parse(from_os_str))]
+    maintenance_file: Option<PathBuf>,
+
+    /// The meta to send with `41` responses in maintenance mode.
+    #[structopt(long, default_value = "Down for maintenance")]
+    maintenance_meta: String,
+
+    /// Limit on the memory used by requests in flight, like 64M. Requests that would go over it
+    /// are answered with `44` so that the client tries again later.
+    #[structopt(long, parse(try_from_str = budget::parse_size))]
+    memory_limit: Option<usize>,
+
+    /// Keep up to this much converted Gemtext in memory, like 16M, so that popular pages aren't
+    /// converted again for every request. Pages are converted again once their files change. The
+    /// cache counts towards --memory-limit, and drops pages when it runs into it.
+    #[structopt(long, parse(try_from_str = budget::parse_size))]
+    cache_size: Option<usize>,
+
+    /// Instead of looking at a cached page's file on every request to see whether it changed,
+    /// watch the roots for changes and drop the cached pages whose files change as they do. Only
+    /// on Linux.
+    #[structopt(long)]
+    cache_watch: bool,
+
+    /// How long the pages under a prefix stay cached, as /PREFIX=LIFETIME: "never" to not cache
+    /// them at all, or a number of seconds, or of minutes, hours or days with m, h or d after it.
+    /// The most specific prefix decides. Can be given multiple times.
+    #[structopt(
+        long = "cache-rule",
+        parse(try_from_str = cache::parse_rule),
+        number_of_values = 1
+    )]
+    cache_rules: Vec<(String, Lifetime)>,
+
+    /// The most connections to handle at once. When there are this many, new connections wait
+    /// for one to close, and are dropped if none does in time.
+    #[structopt(long)]
+    max_connections: Option<usize>,
+
+    /// How long a connection over --max-connections waits for another to close, in milliseconds.
+    #[structopt(long, default_value = "500")]
+    connection_wait_ms: u64,
+
+    /// How long the TLS handshake may take, in seconds, before the connection is dropped.
+    #[structopt(long, default_value = "10")]
+    handshake_timeout: u64,
+
+    /// How long the client may take to send its request after the handshake, in seconds.
+    #[structopt(long, default_value = "10")]
+    request_timeout: u64,
+
+    /// How long sending the response may take, in seconds, including reading the content of a
+    /// Titan upload. A client that reads too slowly is cut off.
+    #[structopt(long, default_value = "300")]
+    response_timeout: u64,
+
+    /// How long to wait for connections to finish after SIGTERM or SIGINT before exiting anyway,
+    /// in seconds. No new connections are accepted in the meantime.
+    #[structopt(long, default_value = "30")]
+    shutdown_timeout: u64,
+
+    /// How often to check whether the certificates and keys have been renewed, in seconds. New
+    /// connections get renewed certificates without a restart. 0 turns the checks off.
+    #[structopt(long, default_value = "60")]
+    cert_check_interval: u64,
+
+    /// Warn when a certificate has fewer than this many days left, at startup and once a day
+    /// while running.
+    #[structopt(long, default_value = "14")]
+    expiry_warning_days: u32,
+
+    /// Start even if a certificate has already expired.
+    #[structopt(long)]
+    allow_expired: bool,
+
+    /// Append a line for every request to this file, with the client's address, the time, the
+    /// URL, the status, the bytes sent and how long it took, in a format like the Common Log
+    /// Format.
+    #[structopt(long, parse(from_os_str))]
+    access_log: Option<PathBuf>,
+
+    /// Save every request and its response to a file in this directory, for `exarch replay`.
+    /// Uploads and pages under --restrict aren't recorded, and answers to prompts for sensitive
+    /// input are redacted.
+    #[structopt(long, parse(from_os_str))]
+    record: Option<PathBuf>,
+
+    /// The name of a file to serve when a directory is requested. The first one that exists is
+    /// used. Can be given multiple times; defaults to index.md and index.gmi.
+    #[structopt(long = "index", number_of_values = 1)]
+    index_names: Vec<String>,
+
+    /// A file of redirects for moved pages, one per line as OLD-PATH NEW-URL, optionally followed
+    /// by "temporary". A path ending in * matches everything under it.
+    #[structopt(long, parse(from_os_str))]
+    redirects: Option<PathBuf>,
+
+    /// Only let clients with the certificate whose SHA-256 fingerprint is given see the paths
+    /// under a prefix, as /PREFIX=FINGERPRINT. Clients are asked for a certificate if this is
+    /// given. Can be given multiple times, including for the same prefix.
+    #[structopt(
+        long,
+        parse(try_from_str = access::parse_rule),
+        number_of_values = 1
+    )]
+    restrict: Vec<(String, String)>,
+
+    /// Let the client with the certificate whose SHA-256 fingerprint is given upload files under
+    /// a prefix with Titan, as /PREFIX=FINGERPRINT. Nothing can be uploaded anywhere else. Can be
+    /// given multiple times, including for the same prefix.
+    #[structopt(
+        long,
+        parse(try_from_str = access::parse_rule),
+        number_of_values = 1
+    )]
+    titan: Vec<(String, String)>,
+
+    /// A token that Titan uploads also have to give, as their `token` parameter.
+    #[structopt(long)]
+    titan_token: Option<String>,
+
+    /// The largest file that can be uploaded with Titan, like 16M.
+    #[structopt(long, default_value = "16M", parse(try_from_str = budget::parse_size))]
+    titan_max_size: usize,
+
+    /// Run files under this path as CGI scripts instead of serving them, like /cgi-bin. Whatever
+    /// a script writes to stdout is sent as the response, header included. Can be given multiple
+    /// times.
+    #[structopt(long = "cgi", number_of_values = 1)]
+    cgi_prefixes: Vec<String>,
+
+    /// Change the Gemtext sent for pages under a prefix, as /PREFIX=FILTER. FILTER is
+    /// banner:TEXT to add a line to the end, strip-emoji, or ascii to replace other characters
+    /// with ASCII. Every prefix covering a page applies, in the order given. Can be given multiple
+    /// times.
+    #[structopt(
+        long = "filter",
+        parse(try_from_str = filter::parse_filter),
+        number_of_values = 1
+    )]
+    filters: Vec<(String, Filter)>,
+
+    /// When a directory without an index file is requested, send a generated listing of its
+    /// files instead of failing.
+    #[structopt(long)]
+    autoindex: bool,
+
+    /// How to lay out --autoindex listings: "detailed", with each file's size and date, or
+    /// "compact".
+    #[structopt(long, default_value = "detailed")]
+    listing: autoindex::Layout,
+
+    /// The MIME type to send files with an extension as, like png=image/png. Markdown files are
+    /// converted to Gemtext, and other files are sent unchanged. Can be given multiple times.
+    #[structopt(
+        long = "mime-type",
+        parse(try_from_str = mime::parse_mapping),
+        number_of_values = 1
+    )]
+    mime_types: Vec<(String, String)>,
+
+    /// The base URL of the web mirror of the main capsule, like https://example.org. Each page ends
+    /// with a link to its HTML version there, at the same path with .md replaced by .html.
+    #[structopt(long)]
+    web_mirror: Option<String>,
+
+    /// A built-in theme, or the path of a theme file ending in .toml. Its settings are used for
+    /// the converter flags and --listing where they aren't given. See `exarch theme list`.
+    #[structopt(long)]
+    theme: Option<String>,
+
+    #[structopt(flatten)]
+    converter: ConverterFlags,
+
+    /// Run in the background, detached from the terminal. Only supported on Unix.
+    #[structopt(long)]
+    pub daemon: bool,
+
+    /// When running with --daemon, write the daemon's PID to this file.
+    #[structopt(long, parse(from_os_str), requires = "daemon")]
+    pub pid_file: Option<PathBuf>,
+
+    // Redirects from the config file, on top of those in the --redirects file.
+    #[structopt(skip)]
+    config_redirects: Vec<config::Redirect>,
+}
+
+impl ServeOpt {
+    /// Fills in the settings that weren't given on the command line from the --config file, if
+    /// there is one. The matches are those the options were parsed from.
+    pub fn apply_config(&mut self, matches: &ArgMatches) -> Result<()> {
+        let given = |name: &str| matches.occurrences_of(name) > 0;
+        let mut converters = vec![];
+        if let Some(path) = self.config.clone() {
+            let mut config = Config::load(&path)?;
+            let profile = match &self.profile {
+                Some(name) => Some(
+                    config
+                        .take_profile(name)
+                        .with_context(|| format!("failed to load {}", path.display()))?,
+                ),
+                None => None,
+            };
+            for mut config in std::iter::once(config).chain(profile) {
+                converters.push((std::mem::take(&mut config.converter), path.clone()));
+                self.merge(config, given)
+                    .with_context(|| format!("failed to load {}", path.display()))?;
+            }
+        }
+        if let Some(theme) = &self.theme {
+            let theme = theme::load(theme)?;
+            self.converter.apply_theme(&theme, given)?;
+            if let (Some(listing), false) = (&theme.listing, given("listing")) {
+                self.listing = listing.parse()?;
+            }
+        }
+        // The config's own converter settings win over its theme's.
+        for (converter, path) in converters {
+            self.converter
+                .apply_config(&converter, given)
+                .with_context(|| format!("failed to load {}", path.display()))?;
+        }
+        let missing = if self.root.is_none() {
+            "a root"
+        } else if self.cert.is_none() {
+            "--cert"
+        } else if self.key.is_none() {
+            "--key"
+        } else {
+            return Ok(());
+        };
+        bail!(
+            "serve needs {}, on the command line or in the config",
+            missing
+        )
+    }
+
+    /// Takes each setting from the config unless `given` says it was given on the command line,
+    /// going by the names of the arguments. Its redirects are added to those already taken, so
+    /// that a profile's add to the file's.
+    fn merge(&mut self, config: Config, given: impl Fn(&str) -> bool) -> Result<()> {
+        fn set<T>(field: &mut T, value: Option<T>, given: bool) {
+            match value {
+                Some(value) if !given => *field = value,
+                _ => {}
+            }
+        }
+        fn set_list<T>(
+            field: &mut Vec<T>,
+            values: Vec<String>,
+            given: bool,
+            parse: impl Fn(&str) -> Result<T>,
+        ) -> Result<()> {
+            if !given && !values.is_empty() {
+                *field = values
+                    .iter()
+                    .map(|value| parse(value))
+                    .collect::<Result<_>>()?;
+            }
+            Ok(())
+        }
+        let size = |size: Option<String>| size.as_deref().map(budget::parse_size).transpose();
+
+        set(&mut self.root, config.root.map(Some), given("root"));
+        set(
+            &mut self.overlay,
+            config.overlay.map(Some),
+            given("overlay"),
+        );
+        set(
+            &mut self.standby,
+            config.standby.map(Some),
+            given("standby"),
+        );
+
+        let listen = config.listen;
+        set(&mut self.port, listen.port, given("port"));
+        set(
+            &mut self.hostname,
+            listen.hostname.map(Some),
+            given("hostname"),
+        );
+        set(
+            &mut self.max_connections,
+            listen.max_connections.map(Some),
+            given("max-connections"),
+        );
+        set(
+            &mut self.connection_wait_ms,
+            listen.connection_wait_ms,
+            given("connection-wait-ms"),
+        );
+        set(
+            &mut self.memory_limit,
+            size(listen.memory_limit)?.map(Some),
+            given("memory-limit"),
+        );
+        set(
+            &mut self.admin_socket,
+            listen.admin_socket.map(Some),
+            given("admin-socket"),
+        );
+        set(
+            &mut self.unix_socket,
+            listen.unix_socket.map(Some),
+            given("unix-socket"),
+        );
+        set(
+            &mut self.unix_socket_mode,
+            listen
+                .unix_socket_mode
+                .as_deref()
+                .map(unix::parse_mode)
+                .transpose()?,
+            given("unix-socket-mode"),
+        );
+        set(&mut self.no_tcp, listen.no_tcp, given("no-tcp"));
+
+        let timeouts = config.timeouts;
+        set(
+            &mut self.handshake_timeout,
+            timeouts.handshake,
+            given("handshake-timeout"),
+        );
+        set(
+            &mut self.request_timeout,
+            timeouts.request,
+            given("request-timeout"),
+        );
+        set(
+            &mut self.response_timeout,
+            timeouts.response,
+            given("response-timeout"),
+        );
+        set(
+            &mut self.shutdown_timeout,
+            timeouts.shutdown,
+            given("shutdown-timeout"),
+        );
+
+        let tls = config.tls;
+        set(&mut self.cert, tls.cert.map(Some), given("cert"));
+        set(&mut self.key, tls.key.map(Some), given("key"));
+        set(
+            &mut self.cert_check_interval,
+            tls.cert_check_interval,
+            given("cert-check-interval"),
+        );
+        set(
+            &mut self.expiry_warning_days,
+            tls.expiry_warning_days,
+            given("expiry-warning-days"),
+        );
+        set(
+            &mut self.allow_expired,
+            tls.allow_expired,
+            given("allow-expired"),
+        );
+        set(
+            &mut self.ignore_failed_checks,
+            tls.ignore_failed_checks,
+            given("ignore-failed-checks"),
+        );
+
+        let logging = config.logging;
+        set(
+            &mut self.access_log,
+            logging.access_log.map(Some),
+            given("access-log"),
+        );
+        set(&mut self.record, logging.record.map(Some), given("record"));
+
+        let content = config.content;
+        set_list(
+            &mut self.index_names,
+            content.index,
+            given("index-names"),
+            |name| Ok(name.to_owned()),
+        )?;
+        set(&mut self.autoindex, content.autoindex, given("autoindex"));
+        set_list(
+            &mut self.mime_types,
+            content.mime_types,
+            given("mime-types"),
+            mime::parse_mapping,
+        )?;
+        set_list(
+            &mut self.filters,
+            content.filters,
+            given("filters"),
+            filter::parse_filter,
+        )?;
+        set_list(
+            &mut self.cgi_prefixes,
+            content.cgi,
+            given("cgi-prefixes"),
+            |prefix| Ok(prefix.to_owned()),
+        )?;
+        set(
+            &mut self.web_mirror,
+            content.web_mirror.map(Some),
+            given("web-mirror"),
+        );
+        set(
+            &mut self.maintenance_file,
+            content.maintenance_file.map(Some),
+            given("maintenance-file"),
+        );
+        set(
+            &mut self.maintenance_meta,
+            content.maintenance_meta,
+            given("maintenance-meta"),
+        );
+        set(
+            &mut self.redirects,
+            content.redirects.map(Some),
+            given("redirects"),
+        );
+        set(
+            &mut self.cache_size,
+            size(content.cache_size)?.map(Some),
+            given("cache-size"),
+        );
+        set(
+            &mut self.cache_watch,
+            content.cache_watch,
+            given("cache-watch"),
+        );
+        set_list(
+            &mut self.cache_rules,
+            content.cache_rules,
+            given("cache-rules"),
+            cache::parse_rule,
+        )?;
+        set(&mut self.theme, content.theme.map(Some), given("theme"));
+
+        let access = config.access;
+        set_list(
+            &mut self.restrict,
+            access.restrict,
+            given("restrict"),
+            access::parse_rule,
+        )?;
+        set_list(
+            &mut self.titan,
+            access.titan,
+            given("titan"),
+            access::parse_rule,
+        )?;
+        set(
+            &mut self.titan_token,
+            access.titan_token.map(Some),
+            given("titan-token"),
+        );
+        set(
+            &mut self.titan_max_size,
+            size(access.titan_max_size)?,
+            given("titan-max-size"),
+        );
+
+        if !given("vhosts") && !config.vhosts.is_empty() {
+            self.vhosts = config
+                .vhosts
+                .into_iter()
+                .map(|vhost| VirtualHost {
+                    hostname: vhost.hostname,
+                    root: vhost.root,
+                    cert: vhost.cert,
+                    key: vhost.key,
+                })
+                .collect();
+        }
+        self.config_redirects.extend(config.redirects);
+        Ok(())
+    }
+
+    fn root(&self) -> &Path {
+        self.root
+            .as_deref()
+            .expect("the root is checked by apply_config")
+    }
+
+    fn cert(&self) -> &Path {
+        self.cert
+            .as_deref()
+            .expect("the cert is checked by apply_config")
+    }
+
+    fn key(&self) -> &Path {
+        self.key
+            .as_deref()
+            .expect("the key is checked by apply_config")
+    }
+}
+
+/// Another capsule served by the same process, from its own root and with its own certificate.
+#[derive(Debug, Clone)]
+struct VirtualHost {
+    hostname: String,
+    root: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+/// Parses a virtual host given on the command line, as HOSTNAME=ROOT,CERT,KEY.
+fn parse_vhost(s: &str) -> Result<VirtualHost> {
+    let mut parts = s.splitn(2, '=');
+    let (hostname, paths) = match (parts.next(), parts.next()) {
+        (Some(hostname), Some(paths)) if !hostname.is_empty() => (hostname, paths),
+        _ => bail!("expected HOSTNAME=ROOT,CERT,KEY, got {}", s),
+    };
+    let paths: Vec<_> = paths.split(',').collect();
+    match paths.as_slice() {
+        [root, cert, key] if [root, cert, key].iter().all(|path| !path.is_empty()) => {
+            Ok(VirtualHost {
+                hostname: hostname.to_owned(),
+                root: root.into(),
+                cert: cert.into(),
+                key: key.into(),
+            })
+        }
+        _ => bail!("expected HOSTNAME=ROOT,CERT,KEY, got {}", s),
+    }
+}
+
+/// Checks that the server is set up correctly, printing a report. Fails if any of the checks
+/// failed, unless told to ignore them.
+pub fn self_check(options: &ServeOpt) -> Result<()> {
+    let mut report = Report::new();
+    report.add_result("content root", selfcheck::check_dir(options.root()));
+    if let Some(overlay) = &options.overlay {
+        report.add_result("overlay", selfcheck::check_dir(overlay));
+    }
+    if let Some(standby) = &options.standby {
+        report.add_result("standby root", selfcheck::check_dir(standby));
+    }
+    match tls::load_certificate(options.cert(), options.key()) {
+        Ok((certs, key)) => {
+            report.add(
+                Level::Ok,
+                "certificate",
+                options.cert().display().to_string(),
+            );
+            report.add_result("key", selfcheck::check_key_matches(&certs[0], &key));
+            let (level, detail) = check_expiry(options, &certs[0]);
+            report.add(level, "expiry", detail);
+            match &options.hostname {
+                // Plenty of clients only check the common name, or pin certificates instead of
+                // checking names at all, so this isn't fatal.
+                Some(hostname) => match selfcheck::check_hostname(&certs[0], hostname) {
+                    Ok(detail) => report.add(Level::Ok, "hostname", detail),
+                    Err(e) => report.add(Level::Warning, "hostname", format!("{:#}", e)),
+                },
+                None => report.add(Level::Warning, "hostname", "not given, so not checked"),
+            }
+        }
+        Err(e) => report.add(Level::Error, "certificate", format!("{:#}", e)),
+    }
+    for vhost in &options.vhosts {
+        check_vhost(&mut report, vhost, options);
+    }
+    if systemd::activated() {
+        report.add(Level::Ok, "port", "sockets passed by systemd");
+    } else if !options.no_tcp {
+        report.add_result("port", selfcheck::check_port(options.port));
+    }
+    report.add_result(
+        "converter",
+        options
+            .converter
+            .options()
+            .map(|_| "options loaded".to_owned()),
+    );
+    if let Some(maintenance_file) = &options.maintenance_file {
+        match maintenance_file.parent().map(selfcheck::check_dir) {
+            Some(Err(e)) => report.add(Level::Warning, "maintenance file", format!("{:#}", e)),
+            _ => report.add(
+                Level::Ok,
+                "maintenance file",
+                maintenance_file.display().to_string(),
+            ),
+        }
+    }
+    eprint!("{}", report);
+    if report.has_errors() && !options.ignore_failed_checks {
+        bail!("startup checks failed");
+    }
+    Ok(())
+}
+
+/// Checks a virtual host's root and certificate, like the main ones.
+fn check_vhost(report: &mut Report, vhost: &VirtualHost, options: &ServeOpt) {
+    let detail = |detail: String| format!("{}: {}", vhost.hostname, detail);
+    let loaded = selfcheck::check_dir(&vhost.root)
+        .and_then(|_| tls::load_certificate(&vhost.cert, &vhost.key))
+        .and_then(|(certs, key)| {
+            selfcheck::check_key_matches(&certs[0], &key)?;
+            Ok(certs)
+        });
+    match loaded {
+        Ok(certs) => {
+            match selfcheck::check_hostname(&certs[0], &vhost.hostname) {
+                Ok(hostname) => report.add(Level::Ok, "virtual host", detail(hostname)),
+                Err(e) => report.add(Level::Warning, "virtual host", detail(format!("{:#}", e))),
+            }
+            let (level, expiry) = check_expiry(options, &certs[0]);
+            report.add(level, "expiry", detail(expiry));
+        }
+        Err(e) => report.add(Level::Error, "virtual host", detail(format!("{:#}", e))),
+    }
+}
+
+/// Checks how long the certificate has left. An expired certificate only stops the server from
+/// starting if it isn't allowed.
+fn check_expiry(options: &ServeOpt, cert: &Certificate) -> (Level, String) {
+    let (level, detail) =
+        selfcheck::check_expiry(cert, date::unix_seconds(), options.expiry_warning_days);
+    match level {
+        Level::Error if options.allow_expired => (Level::Warning, detail),
+        level => (level, detail),
+    }
 }
 
 pub async fn serve(options: ServeOpt) -> Result<()> {
-    let listener = TcpListener::bind(("0.0.0.0", options.port))
-        .await
-        .context("failed to bind")?;
+    let listeners = systemd::take_listeners()?;
+    let listeners = if !listeners.is_empty() {
+        info!("Listening on {} sockets passed by systemd", listeners.len());
+        listeners
+    } else if options.no_tcp {
+        if options.unix_socket.is_none() {
+            bail!("--no-tcp needs a --unix-socket to listen on instead");
+        }
+        vec![]
+    } else {
+        let listener = TcpListener::bind(("0.0.0.0", options.port))
+            .await
+            .context("failed to bind")?;
+        vec![listener]
+    };
+    serve_on(listeners, options, shutdown::signalled()?).await
+}
+
+/// Serves connections from listeners that have already been bound, and from the Unix socket in
+/// the options, until `shutdown` resolves. The port in the options is ignored.
+pub async fn serve_on(
+    listeners: Vec<TcpListener>,
+    options: ServeOpt,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
     let server = Arc::new(Server::build(options).await?);
+    let mut limit = server.options.max_connections.map(ConnectionLimit::new);
+    let wait = Duration::from_millis(server.options.connection_wait_ms);
+    let handlers = Handlers::new();
+    // The startup checks have just looked at the certificates.
+    task::spawn(watch_expiry(Arc::downgrade(&server)));
+    if let Some(path) = &server.options.admin_socket {
+        let server = Arc::downgrade(&server);
+        admin::start(path, move |command| match server.upgrade() {
+            Some(server) => server.admin(command),
+            None => "error: shutting down".to_owned(),
+        })
+        .await?;
+    }
+    if server.options.cert_check_interval > 0 {
+        let interval = Duration::from_secs(server.options.cert_check_interval);
+        task::spawn(reload_certificates(Arc::downgrade(&server), interval));
+    }
+
+    let unix_socket = match &server.options.unix_socket {
+        Some(path) => {
+            let socket = UnixSocket::bind(path, server.options.unix_socket_mode).await?;
+            info!("Listening on {}", path.display());
+            Some(socket)
+        }
+        None => None,
+    };
 
-    let mut incoming = listener.incoming();
-    while let Some(stream) = incoming.next().await {
-        let stream = stream.context("bad stream")?;
-        server.clone().handle_stream(stream).await?;
+    let mut incoming: BoxStream<'_, io::Result<(Box<dyn Connection>, IpAddr)>> =
+        Box::pin(stream::empty());
+    for listener in &listeners {
+        let tcp = listener.incoming().map(|stream| {
+            let stream = stream?;
+            let peer_addr = stream.peer_addr()?.ip();
+            Ok((Box::new(stream) as Box<dyn Connection>, peer_addr))
+        });
+        incoming = Box::pin(stream::select(incoming, tcp));
+    }
+    if let Some(socket) = &unix_socket {
+        // The front process is on this machine, and doesn't say who its clients are.
+        let localhost = IpAddr::from(Ipv4Addr::LOCALHOST);
+        let unix = socket
+            .incoming()
+            .map(move |stream| stream.map(|stream| (stream, localhost)));
+        incoming = Box::pin(stream::select(incoming, unix));
+    }
+    futures::pin_mut!(shutdown);
+    loop {
+        let stream = match select(incoming.next(), shutdown.as_mut()).await {
+            Either::Left((Some(stream), _)) => stream,
+            Either::Left((None, _)) => break,
+            Either::Right(((), _)) => {
+                info!("Shutting down");
+                break;
+            }
+        };
+        let (stream, peer_addr) = stream.context("bad stream")?;
+        let permit = match &mut limit {
+            // Waiting for a permit mustn't hold up shutting down.
+            Some(limit) => match select(Box::pin(limit.acquire(wait)), shutdown.as_mut()).await {
+                Either::Left((Some(permit), _)) => Some(permit),
+                // Dropping the stream closes the connection.
+                Either::Left((None, _)) => continue,
+                Either::Right(((), _)) => {
+                    info!("Shutting down");
+                    break;
+                }
+            },
+            None => None,
+        };
+        server
+            .clone()
+            .handle_stream(stream, peer_addr, permit, handlers.start())
+            .await?;
     }
 
+    // Closing the listeners refuses new connections while the open ones finish.
+    drop(incoming);
+    drop(listeners);
+    drop(unix_socket);
+    if let Some(path) = &server.options.admin_socket {
+        let _ = std::fs::remove_file(path);
+    }
+    let deadline = Duration::from_secs(server.options.shutdown_timeout);
+    if !handlers.finish(deadline).await {
+        warn!(
+            "Connections still open after {} seconds, closing them",
+            deadline.as_secs()
+        );
+    }
     Ok(())
 }
 
+/// Checks for renewed certificates every interval, for as long as the server is running.
+async fn reload_certificates(server: Weak<Server>, interval: Duration) {
+    loop {
+        task::sleep(interval).await;
+        let server = match server.upgrade() {
+            Some(server) => server,
+            None => return,
+        };
+        match server.acceptor.reload() {
+            Ok(true) => info!("Loaded renewed certificates"),
+            Ok(false) => {}
+            Err(e) => error!(
+                "Failed to load renewed certificates, keeping the old ones: {:#}",
+                e
+            ),
+        }
+    }
+}
+
+/// Drops the cached pages in the directories as their files change, for as long as the cache is
+/// around. A change to a section's `_index.md` drops the pages in its directory too, since it can
+/// set their options.
+fn watch_cache(cache: &Arc<Cache<(PathBuf, String)>>, dirs: &[PathBuf]) -> Result<()> {
+    // Changes are to paths in the directories as they were given, but pages found by their front
+    // matter are in the canonical ones, so changes are looked for in both.
+    let trees = dirs
+        .iter()
+        .map(|dir| Ok((dir.clone(), dir.canonicalize()?)))
+        .collect::<io::Result<Vec<_>>>()?;
+    watch::watch(dirs, Arc::downgrade(cache), move |cache, path| {
+        let mut path = match path {
+            Some(path) => path.to_owned(),
+            // Some changes were missed, so any page could have changed.
+            None => return cache.retain(|_| false),
+        };
+        if path.file_name().map_or(false, |name| name == "_index.md") {
+            path.pop();
+        }
+        let changed: Vec<PathBuf> =
+            std::iter::once(path.clone())
+                .chain(trees.iter().filter_map(|(dir, tree)| {
+                    path.strip_prefix(dir).ok().map(|rest| tree.join(rest))
+                }))
+                .collect();
+        debug!("{} changed", path.display());
+        cache.retain(|(page, _)| !changed.iter().any(|changed| page.starts_with(changed)));
+    })
+}
+
+/// Logs a warning once a day for each certificate that's about to expire or has, for as long as
+/// the server is running. The certificates are loaded every time, in case they've been renewed.
+async fn watch_expiry(server: Weak<Server>) {
+    loop {
+        task::sleep(Duration::from_secs(24 * 60 * 60)).await;
+        let server = match server.upgrade() {
+            Some(server) => server,
+            None => return,
+        };
+        let options = &server.options;
+        let certs = std::iter::once((options.cert(), options.key())).chain(
+            options
+                .vhosts
+                .iter()
+                .map(|vhost| (vhost.cert.as_path(), vhost.key.as_path())),
+        );
+        for (cert, key) in certs {
+            let (level, detail) = match tls::load_certificate(cert, key) {
+                Ok((certs, _)) => check_expiry(options, &certs[0]),
+                Err(e) => (Level::Error, format!("{:#}", e)),
+            };
+            match level {
+                Level::Ok => debug!("Certificate {}: {}", cert.display(), detail),
+                Level::Warning => warn!("Certificate {}: {}", cert.display(), detail),
+                Level::Error => error!("Certificate {}: {}", cert.display(), detail),
+            }
+        }
+    }
+}
+
 struct Server {
     options: ServeOpt,
-    acceptor: TlsAcceptor,
+    converter_options: Arc<ConverterOptions>,
+    // The hostname in ASCII, as it appears in request URLs.
+    hostname: Option<String>,
+    mime_types: MimeTypes,
+    redirects: Redirects,
+    access: AccessRules,
+    // Who can upload where with Titan.
+    uploads: AccessRules,
+    filters: Filters,
+    // The main site's root, then its standby root if it has one.
+    sites: Vec<Site>,
+    // Which of them is being served.
+    active: AtomicUsize,
+    // Other capsules served by the same process, by ASCII hostname.
+    vhosts: HashMap<String, Site>,
+    acceptor: Box<dyn Acceptor>,
+    budget: Option<Arc<Budget>>,
+    access_log: Option<AccessLog>,
+    // Converted pages, by their path and the path of the URL they were requested at, since that
+    // decides the link to the web mirror.
+    cache: Option<Arc<Cache<(PathBuf, String)>>>,
+    cache_rules: Rules,
+}
+
+/// A tree of pages served by the server: the main one, or a virtual host's.
+struct Site {
+    root: PathBuf,
+    overlay: Option<PathBuf>,
+    // Pages served at a URL given in their front matter, by the URL's path, along with the
+    // canonical root they were found in. The root can be a symlink that a deploy swaps to a new
+    // tree, in which case they're found again.
+    aliases: RwLock<(PathBuf, Arc<HashMap<String, PathBuf>>)>,
+}
+
+impl Site {
+    fn new(root: PathBuf, overlay: Option<PathBuf>) -> Result<Self> {
+        let mut site = Self {
+            root,
+            overlay,
+            aliases: RwLock::default(),
+        };
+        let trees = site.trees()?;
+        site.aliases = RwLock::new((trees[0].clone(), Arc::new(site.find_aliases()?)));
+        Ok(site)
+    }
+
+    /// The canonical paths of the root and overlay, in that order, which every file served has to
+    /// be in. They're resolved every time, since a deploy can swap the root for another tree.
+    fn trees(&self) -> Result<Vec<PathBuf>> {
+        std::iter::once(&self.root)
+            .chain(&self.overlay)
+            .map(|tree| {
+                tree.canonicalize()
+                    .with_context(|| format!("failed to resolve {}", tree.display()))
+            })
+            .collect()
+    }
+
+    fn find_aliases(&self) -> Result<HashMap<String, PathBuf>> {
+        let mut aliases = resolve::aliases(&self.root)?;
+        if let Some(overlay) = &self.overlay {
+            aliases.extend(resolve::aliases(overlay)?);
+        }
+        Ok(aliases)
+    }
+
+    /// The aliases of the pages in the tree the root currently resolves to.
+    fn aliases(&self, canonical_root: &Path) -> Result<Arc<HashMap<String, PathBuf>>> {
+        let lock_error = || anyhow!("aliases lock poisoned");
+        {
+            let (root, aliases) = &*self.aliases.read().map_err(|_| lock_error())?;
+            if root == canonical_root {
+                return Ok(aliases.clone());
+            }
+        }
+        info!(
+            "{} now leads to {}, finding aliases again",
+            self.root.display(),
+            canonical_root.display()
+        );
+        let aliases = Arc::new(self.find_aliases()?);
+        *self.aliases.write().map_err(|_| lock_error())? =
+            (canonical_root.to_owned(), aliases.clone());
+        Ok(aliases)
+    }
+
+    /// Finds the file or directory that the URL refers to. Pages that ask to be served at the URL
+    /// come first, then the file in the overlay if it exists.
+    fn resolve(&self, url: &Url) -> Result<PathBuf> {
+        let trees = self.trees()?;
+        if let Some(page) = self.aliases(&trees[0])?.get(&resolve::alias_key(url)) {
+            return Ok(page.clone());
+        }
+        let overlaid = self
+            .overlay
+            .as_ref()
+            .and_then(|overlay| resolve::resolve(overlay, url))
+            .filter(|path| path.exists());
+        let path = overlaid
+            .or_else(|| resolve::resolve(&self.root, url))
+            .ok_or(StatusError::not_found())?;
+        // The segments have been checked, but a symlink can still lead out of the tree.
+        if let Ok(real) = path.canonicalize() {
+            if !trees.iter().any(|tree| real.starts_with(tree)) {
+                warn!("{} resolves outside the root, to {}", url, real.display());
+                return Err(StatusError::not_found().into());
+            }
+        }
+        Ok(path)
+    }
+
+    /// The file in the root that an upload to the URL writes. Its directory has to exist already,
+    /// and be in the root once symlinks are resolved.
+    fn writable_path(&self, url: &Url) -> Result<PathBuf> {
+        let path = resolve::resolve(&self.root, url).ok_or_else(StatusError::bad_request)?;
+        if path.is_dir() {
+            return Err(StatusError::new(59, "Can't upload to a directory").into());
+        }
+        let parent = path.parent().and_then(|parent| parent.canonicalize().ok());
+        match parent {
+            Some(parent) if parent.starts_with(&self.trees()?[0]) => Ok(path),
+            _ => {
+                warn!("Refusing upload to {}, which isn't in the root", url);
+                Err(StatusError::not_found().into())
+            }
+        }
+    }
+
+    /// Where the file is in the root or the overlay once symlinks are resolved, in the form
+    /// `resolve::url_path` returns, or `None` if it's in neither.
+    fn url_path_of(&self, path: &Path) -> Result<Option<String>> {
+        let real = match path.canonicalize() {
+            Ok(real) => real,
+            Err(_) => return Ok(None),
+        };
+        Ok(self
+            .trees()?
+            .iter()
+            .find_map(|tree| real.strip_prefix(tree).ok())
+            .map(|relative| {
+                relative
+                    .iter()
+                    .map(|segment| format!("/{}", segment.to_string_lossy()))
+                    .collect::<String>()
+            })
+            .map(|path| {
+                if path.is_empty() {
+                    "/".to_owned()
+                } else {
+                    path
+                }
+            }))
+    }
+
+    /// Whether the directory is in the root or the overlay.
+    fn contains(&self, dir: &Path) -> bool {
+        dir.starts_with(&self.root)
+            || self
+                .overlay
+                .as_ref()
+                .map_or(false, |overlay| dir.starts_with(overlay))
+    }
 }
 
+/// The files looked for when a directory is requested, unless others are given with --index.
+const DEFAULT_INDEX_NAMES: &[&str] = &["index.md", "index.gmi"];
+
+/// Roughly how much memory a request uses besides its page, mostly for TLS buffers.
+const REQUEST_OVERHEAD: usize = 64 * 1024;
+/// How many seconds clients are asked to wait when the server is over its memory limit.
+const SLOW_DOWN_SECS: u32 = 5;
+
 impl Server {
     async fn build(options: ServeOpt) -> Result<Self> {
-        let certs = File::open(&options.cert)
-            .context("failed to open certificate")
-            .and_then(|cert| {
-                pemfile::certs(&mut BufReader::new(cert))
-                    .map_err(|_| anyhow!("certificate decoding error"))
-            })?;
-        let mut keys = File::open(&options.key)
-            .context("failed to open keyfile")
-            .and_then(|key| {
-                pemfile::pkcs8_private_keys(&mut BufReader::new(key))
-                    .map_err(|_| anyhow!("keyfile decoding error"))
-            })?;
-        let mut server_config = ServerConfig::new(NoClientAuth::new());
-        server_config
-            .set_single_cert(certs, keys.remove(0))
-            .context("failed to use certificate")?;
-        let acceptor: TlsAcceptor = server_config.into();
-        Ok(Self { options, acceptor })
-    }
-
-    async fn handle_stream(self: Arc<Self>, stream: TcpStream) -> Result<()> {
-        let acceptor = self.acceptor.clone();
-        task::spawn(async {
-            if let Err(e) = self.handle_inner(stream, acceptor).await {
+        let access = AccessRules::new(&options.restrict);
+        let uploads = AccessRules::new(&options.titan);
+        let filters = Filters::new(&options.filters);
+        let mut vhosts = HashMap::new();
+        let mut certificates = vec![];
+        for vhost in &options.vhosts {
+            let hostname = resolve::ascii_host(&vhost.hostname)?;
+            certificates.push((hostname.clone(), vhost.cert.as_path(), vhost.key.as_path()));
+            vhosts.insert(hostname, Site::new(vhost.root.clone(), None)?);
+        }
+        let certificates: Vec<_> = certificates
+            .iter()
+            .map(|(hostname, cert, key)| (hostname.as_str(), *cert, *key))
+            .collect();
+        let mut acceptor =
+            RustlsAcceptor::from_files(options.cert(), options.key(), &certificates)?;
+        if !access.is_empty() || !uploads.is_empty() {
+            acceptor = acceptor.request_client_certificates();
+        }
+        let converter_options = Arc::new(options.converter.options()?);
+        let budget = options.memory_limit.map(Budget::new);
+        let hostname = options
+            .hostname
+            .as_deref()
+            .map(resolve::ascii_host)
+            .transpose()?;
+        let mime_types = MimeTypes::new(&options.mime_types);
+        let mut sites = vec![Site::new(
+            options.root().to_owned(),
+            options.overlay.clone(),
+        )?];
+        if let Some(standby) = &options.standby {
+            sites.push(Site::new(standby.clone(), options.overlay.clone())?);
+        }
+        let access_log = options
+            .access_log
+            .as_deref()
+            .map(AccessLog::open)
+            .transpose()?;
+        let mut redirects = match &options.redirects {
+            Some(path) => {
+                let redirects = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                Redirects::parse(&redirects)
+                    .with_context(|| format!("failed to parse {}", path.display()))?
+            }
+            None => Redirects::default(),
+        };
+        for redirect in &options.config_redirects {
+            redirects.add(&redirect.from, &redirect.to, redirect.permanent);
+        }
+        let cache_rules = Rules::new(&options.cache_rules);
+        let cache = options
+            .cache_size
+            .map(|size| Arc::new(Cache::new(size, budget.clone())));
+        match &cache {
+            Some(cache) if options.cache_watch => {
+                let mut dirs: Vec<PathBuf> = sites
+                    .iter()
+                    .chain(vhosts.values())
+                    .flat_map(|site| std::iter::once(&site.root).chain(&site.overlay))
+                    .cloned()
+                    .collect();
+                dirs.sort();
+                dirs.dedup();
+                watch_cache(cache, &dirs)?;
+            }
+            _ => (),
+        }
+        Ok(Self {
+            options,
+            converter_options,
+            hostname,
+            mime_types,
+            redirects,
+            access,
+            uploads,
+            filters,
+            sites,
+            active: AtomicUsize::new(0),
+            vhosts,
+            acceptor: Box::new(acceptor),
+            budget,
+            access_log,
+            cache,
+            cache_rules,
+        })
+    }
+
+    /// Handles the connection in the background, holding on to its permit and handler until it's
+    /// closed.
+    async fn handle_stream(
+        self: Arc<Self>,
+        stream: Box<dyn Connection>,
+        peer_addr: IpAddr,
+        permit: Option<Permit>,
+        handler: Handler,
+    ) -> Result<()> {
+        task::spawn(async move {
+            if let Err(e) = self.handle_inner(stream, peer_addr).await {
                 error!("Error while handling stream: {}", e);
             }
+            drop(permit);
+            drop(handler);
         });
         Ok(())
     }
 
-    async fn handle_inner(self: Arc<Self>, stream: TcpStream, acceptor: TlsAcceptor) -> Result<()> {
-        let peer_addr = stream.peer_addr()?.ip();
+    async fn handle_inner(
+        self: Arc<Self>,
+        stream: Box<dyn Connection>,
+        peer_addr: IpAddr,
+    ) -> Result<()> {
         debug!("Got connection from {}", peer_addr);
-        let mut tls_stream = acceptor
-            .accept(stream)
-            .await
-            .context("failed tcp handshake")?;
-        let url = read_request(&mut tls_stream).await?;
+        let handshake = async {
+            self.acceptor
+                .accept(stream)
+                .await
+                .context("failed tcp handshake")
+        };
+        let accepted = within(self.options.handshake_timeout, "handshake", handshake).await?;
+        let mut tls_stream = Metered::new(accepted.stream);
+        let fingerprint = accepted.client_certificate.as_ref().map(tls::fingerprint);
+        // A client that never finishes its request isn't worth answering.
+        let request = async { Ok(read_request(&mut tls_stream).await) };
+        let request = within(
+            self.options.request_timeout,
+            "waiting for the request",
+            request,
+        );
+        let request = request.await?;
+        let start = Instant::now();
+        let log = |url, tls_stream: &Metered<_>| {
+            if let Some(access_log) = &self.access_log {
+                access_log.log(&Entry {
+                    client: peer_addr,
+                    fingerprint: fingerprint.as_deref(),
+                    time: date::unix_seconds(),
+                    url,
+                    status: tls_stream.status(),
+                    bytes: tls_stream.written(),
+                    duration: start.elapsed(),
+                });
+            }
+        };
+        let (url, received) = match request {
+            Ok(request) => request,
+            Err(error) => {
+                info!("Bad request from {}: {:#}", peer_addr, error);
+                let status = status::status_for(&error);
+                // The client may well be gone already.
+                let header = format!("{}\r\n", status);
+                let _ = tls_stream.write_all(header.as_bytes()).await;
+                log(None, &tls_stream);
+                return Ok(());
+            }
+        };
         info!("{} requested {}", peer_addr, url);
-        self.reply(url, &mut tls_stream).await?;
-        tls_stream.flush().await?;
-        Ok(())
+        let response = async {
+            match &self.options.record {
+                // Uploads aren't recorded, since replaying them would change the site.
+                _ if url.scheme() == "titan" => {
+                    self.reply_upload(&url, received, fingerprint.as_deref(), &mut tls_stream)
+                        .await?
+                }
+                // Nor are restricted pages, so that they don't end up where anyone can read them.
+                Some(dir) if !self.access.covers(&resolve::url_path(&url)) => {
+                    let mut tee = Tee::new(&mut tls_stream, self.budget.as_ref());
+                    self.reply(url.clone(), peer_addr, fingerprint.as_deref(), &mut tee)
+                        .await?;
+                    match tee.into_copy() {
+                        Some(response) => {
+                            let sensitive = self.answers_sensitive_prompt(&url);
+                            let path = record::save(dir, &url, &response, sensitive)?;
+                            debug!("Recorded to {}", path.display());
+                        }
+                        None => debug!("Not recording {}, which is over the memory limit", url),
+                    }
+                }
+                _ => {
+                    self.reply(
+                        url.clone(),
+                        peer_addr,
+                        fingerprint.as_deref(),
+                        &mut tls_stream,
+                    )
+                    .await?
+                }
+            }
+            tls_stream.flush().await?;
+            Ok(())
+        };
+        let result = within(
+            self.options.response_timeout,
+            "sending the response",
+            response,
+        )
+        .await;
+        log(Some(&url), &tls_stream);
+        result
     }
 
-    async fn reply<W: Write + Unpin>(&self, url: Url, mut stream: W) -> Result<()> {
-        let mut path = self.options.root.clone();
-        if let Some(segments) = url.path_segments() {
-            path.extend(segments);
+    /// The site that the URL is on. URLs for hosts that aren't served are refused, unless no
+    /// hostname was given for the main site.
+    fn site(&self, url: &Url) -> Result<&Site, StatusError> {
+        let host = url.host_str().unwrap_or_default();
+        if let Some(site) = self.vhosts.get(host) {
+            return Ok(site);
+        }
+        match &self.hostname {
+            Some(hostname) if hostname != host => {
+                Err(StatusError::new(53, "Proxy request refused"))
+            }
+            _ => Ok(self.main_site()),
+        }
+    }
+
+    /// The main site, from whichever root is active.
+    fn main_site(&self) -> &Site {
+        &self.sites[self.active.load(Ordering::Acquire)]
+    }
+
+    /// Whether the request answers a page's prompt for sensitive input, like a password.
+    fn answers_sensitive_prompt(&self, url: &Url) -> bool {
+        if url.query().map_or(true, str::is_empty) {
+            return false;
+        }
+        let path = match self.site(url).ok().and_then(|site| site.resolve(url).ok()) {
+            Some(path) if path.is_dir() => self.find_index(&path),
+            path => path,
+        };
+        path.and_then(|path| std::fs::read_to_string(path).ok())
+            .map_or(false, |contents| {
+                matches!(input_prompt(&contents), Some((11, _)))
+            })
+    }
+
+    /// Carries out a command from the admin socket, returning the answer.
+    fn admin(&self, command: &str) -> String {
+        let describe = |active: usize| {
+            let root = |site: &Site| site.root.display().to_string();
+            match self.sites.get(1 - active) {
+                Some(standby) => format!(
+                    "active {}, standby {}",
+                    root(&self.sites[active]),
+                    root(standby)
+                ),
+                None => format!("active {}", root(&self.sites[active])),
+            }
+        };
+        match command {
+            "status" => describe(self.active.load(Ordering::Acquire)),
+            "switch" if self.sites.len() < 2 => "error: there's no --standby root".to_owned(),
+            "switch" => {
+                let active = self.active.fetch_xor(1, Ordering::AcqRel) ^ 1;
+                info!("Switched to serving {}", self.sites[active].root.display());
+                describe(active)
+            }
+            _ => format!("error: unknown command {:?}; try status or switch", command),
+        }
+    }
+
+    /// Finds the index file to serve for the directory.
+    fn find_index(&self, dir: &Path) -> Option<PathBuf> {
+        let mut names: Vec<&str> = self
+            .options
+            .index_names
+            .iter()
+            .map(String::as_str)
+            .collect();
+        if names.is_empty() {
+            names = DEFAULT_INDEX_NAMES.to_vec();
+        }
+        names
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|index| index.is_file())
+    }
+
+    /// Answers the request from a client at the given address with the given certificate
+    /// fingerprint. If it fails before anything has been sent, the client gets a status saying
+    /// why.
+    async fn reply<W: Write + Unpin>(
+        &self,
+        url: Url,
+        peer_addr: IpAddr,
+        fingerprint: Option<&str>,
+        stream: W,
+    ) -> Result<()> {
+        let mut stream = Started::new(stream);
+        match self
+            .respond(&url, peer_addr, fingerprint, &mut stream)
+            .await
+        {
+            Err(error) if !stream.started() => {
+                let status = status::status_for(&error);
+                info!("Answering {} with {}: {:#}", url, status.code, error);
+                let header = format!("{}\r\n", status);
+                stream.write_all(header.as_bytes()).await?;
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    async fn respond<W: Write + Unpin>(
+        &self,
+        url: &Url,
+        peer_addr: IpAddr,
+        fingerprint: Option<&str>,
+        mut stream: W,
+    ) -> Result<()> {
+        let site = self.site(url)?;
+        if let Some(maintenance_file) = &self.options.maintenance_file {
+            if maintenance_file.exists() {
+                return self.reply_maintenance(maintenance_file, stream).await;
+            }
+        }
+        // Every prefix is checked against the same path that's resolved to a file.
+        let url_path = resolve::url_path(url);
+        if let Some((status, destination)) = self.redirects.find(&url_path) {
+            let header = format!("{} {}\r\n", status, destination);
+            stream.write_all(header.as_bytes()).await?;
+            return Ok(());
+        }
+        self.access.check(&url_path, fingerprint)?;
+        let mut path = site.resolve(url)?;
+        if path.is_dir() {
+            match self.find_index(&path) {
+                Some(index) => path = index,
+                None if self.options.autoindex => {
+                    let listing = autoindex::listing(&path, &url_path, self.options.listing)?;
+                    stream.write_all(&b"20 text/gemini\r\n"[..]).await?;
+                    stream.write_all(listing.as_bytes()).await?;
+                    return Ok(());
+                }
+                None => {
+                    debug!("No index file in {}", path.display());
+                    return Err(StatusError::not_found().into());
+                }
+            }
+        }
+        let file_type = self.mime_types.file_type(&path);
+        // A script's source mustn't be served, whatever URL leads to it.
+        let file_path = site.url_path_of(&path)?;
+        let is_cgi = self.options.cgi_prefixes.iter().any(|prefix| {
+            access::covers(prefix, &url_path)
+                || file_path
+                    .as_ref()
+                    .map_or(false, |file_path| access::covers(prefix, file_path))
+        });
+        // A page is held in memory while the converter's output is streamed out in chunks, so
+        // twice the page's size is a generous estimate. Other files and scripts' output are
+        // streamed.
+        let cost = match file_type {
+            FileType::Markdown if !is_cgi => {
+                REQUEST_OVERHEAD + 2 * std::fs::metadata(&path)?.len() as usize
+            }
+            _ => REQUEST_OVERHEAD,
+        };
+        let _reservation = match &self.budget {
+            Some(budget) => match budget.reserve(cost) {
+                Some(reservation) => Some(reservation),
+                None => {
+                    warn!(
+                        "{} bytes in use, shedding request for {}",
+                        budget.used(),
+                        url
+                    );
+                    let header = format!("44 {}\r\n", SLOW_DOWN_SECS);
+                    stream.write_all(header.as_bytes()).await?;
+                    return Ok(());
+                }
+            },
+            None => None,
+        };
+        if is_cgi {
+            debug!("Running {}", path.display());
+            let request = cgi::Request {
+                url,
+                peer_addr,
+                fingerprint,
+                server_port: url.port().unwrap_or(self.options.port),
+            };
+            return cgi::run(&path, &request, stream).await;
         }
         debug!("Serving {}", path.display());
-        let contents = std::fs::read_to_string(path)?;
+        if let FileType::Raw(mime) = file_type {
+            let mut file = async_std::fs::File::open(&path).await?;
+            let header = format!("20 {}\r\n", mime);
+            stream.write_all(header.as_bytes()).await?;
+            if mime.starts_with("text/gemini") {
+                let mut filtered = Filtered::new(&mut stream, self.filters.for_path(&url_path));
+                async_std::io::copy(&mut file, &mut filtered).await?;
+                filtered.finish().await?;
+            } else {
+                async_std::io::copy(&mut file, &mut stream).await?;
+            }
+            return Ok(());
+        }
+        let lifetime = self.cache_rules.lifetime(&url_path);
+        let cached = match (&self.cache, lifetime) {
+            (Some(_), Some(Lifetime::Never)) | (None, _) => None,
+            (Some(cache), _) => {
+                let key = (path.clone(), url.path().to_owned());
+                // A watcher drops pages whose files change, so hits don't need to look at the file.
+                let modified = match self.options.cache_watch {
+                    false => Some(std::fs::metadata(&path)?.modified()?),
+                    true => None,
+                };
+                if let Some(gemtext) = cache.get(&key, modified) {
+                    stream.write_all(&b"20 text/gemini\r\n"[..]).await?;
+                    let mut filtered = Filtered::new(&mut stream, self.filters.for_path(&url_path));
+                    filtered.write_all(&gemtext).await?;
+                    filtered.finish().await?;
+                    return Ok(());
+                }
+                let modified = match modified {
+                    Some(modified) => modified,
+                    None => std::fs::metadata(&path)?.modified()?,
+                };
+                let lifetime = match lifetime {
+                    Some(Lifetime::For(lifetime)) => Some(lifetime),
+                    _ => None,
+                };
+                Some((cache, cache.changes(), key, modified, lifetime))
+            }
+        };
+        let contents = std::fs::read_to_string(&path)?;
+        let mut options = self.page_options(site, &path, url);
+        let input = input_prompt(&contents);
+        if let Some((status, prompt)) = input {
+            match url.query().filter(|query| !query.is_empty()) {
+                Some(query) => {
+                    let query = percent_decode_str(query).decode_utf8_lossy();
+                    let query = markgem::escape(&query);
+                    options = Arc::new(unwrap_or_clone(options).shortcode("query", query));
+                }
+                None => {
+                    let header = format!("{} {}\r\n", status, prompt);
+                    stream.write_all(header.as_bytes()).await?;
+                    return Ok(());
+                }
+            }
+        }
         stream.write_all(&b"20 text/gemini\r\n"[..]).await?;
-        let gemini = markgem::to_gemini(&contents)?;
-        stream.write_all(&gemini).await?;
+        let mut filtered = Filtered::new(&mut stream, self.filters.for_path(&url_path));
+        match cached {
+            // Pages asking for input depend on the query.
+            Some((cache, changes, key, modified, lifetime)) if input.is_none() => {
+                let gemtext: Arc<[u8]> = markgem::to_gemini_with(&contents, &options)?.into();
+                cache.insert(changes, key, modified, lifetime, gemtext.clone());
+                filtered.write_all(&gemtext).await?;
+            }
+            _ => markgem::convert_to_async(contents, options, &mut filtered).await?,
+        }
+        filtered.finish().await?;
+        Ok(())
+    }
+
+    /// The converter options for the page at the given path on the site, requested with the
+    /// given URL. Sections can set the aging notice threshold for their pages with
+    /// `aging_notice = N` in the front matter of their `_index.md`, and the closest section that
+    /// does wins.
+    fn page_options(&self, site: &Site, path: &Path, url: &Url) -> Arc<ConverterOptions> {
+        let mut options = self.converter_options.clone();
+        for dir in path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| site.contains(dir))
+        {
+            let index = match std::fs::read_to_string(dir.join("_index.md")) {
+                Ok(index) => index,
+                Err(_) => continue,
+            };
+            let years = markgem::front_matter_value(&index, "aging_notice")
+                .and_then(|years| years.parse().ok());
+            if let Some(years) = years {
+                options = Arc::new(unwrap_or_clone(options).aging_notice(years));
+                break;
+            }
+        }
+        // The web mirror is only the main site's.
+        let web_mirror = self
+            .options
+            .web_mirror
+            .as_ref()
+            .filter(|_| self.sites.iter().any(|main| std::ptr::eq(site, main)));
+        if let Some(base) = web_mirror {
+            let mirror_url = web_mirror_url(base, url.path());
+            options = Arc::new(unwrap_or_clone(options).mirror_url(mirror_url));
+        }
+        options
+    }
+
+    /// Answers a Titan upload, redirecting to the page that was written if it succeeds.
+    async fn reply_upload<S: Read + Write + Unpin>(
+        &self,
+        url: &Url,
+        received: Vec<u8>,
+        fingerprint: Option<&str>,
+        mut stream: S,
+    ) -> Result<()> {
+        let header = match self.upload(url, received, fingerprint, &mut stream).await {
+            Ok(page) => format!("30 {}\r\n", page),
+            Err(error) => {
+                let status = status::status_for(&error);
+                info!("Answering {} with {}: {:#}", url, status.code, error);
+                format!("{}\r\n", status)
+            }
+        };
+        stream.write_all(header.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Writes the file uploaded with Titan, or deletes it if the upload is empty, returning the
+    /// Gemini URL of the page. Only files in the root can be written, so a page that's also in
+    /// the overlay won't change.
+    async fn upload<R: Read + Unpin>(
+        &self,
+        url: &Url,
+        received: Vec<u8>,
+        fingerprint: Option<&str>,
+        stream: R,
+    ) -> Result<Url> {
+        let upload = titan::parse(url)?;
+        let site = self.site(&upload.url)?;
+        let url_path = resolve::url_path(&upload.url);
+        if !self.uploads.covers(&url_path) {
+            return Err(StatusError::new(50, "Uploads not accepted").into());
+        }
+        self.uploads.check(&url_path, fingerprint)?;
+        if let Some(token) = &self.options.titan_token {
+            if upload.token.as_ref() != Some(token) {
+                return Err(StatusError::new(61, "Token not accepted").into());
+            }
+        }
+        if upload.size > self.options.titan_max_size {
+            return Err(anyhow!(StatusError::new(59, "Upload too large"))
+                .context(format!("{} bytes is over the limit", upload.size)));
+        }
+        let path = site.writable_path(&upload.url)?;
+        if !upload.mime_fits(self.mime_types.file_type(&path)) {
+            return Err(StatusError::new(59, "Wrong MIME type for the path").into());
+        }
+        let _reservation = match &self.budget {
+            Some(budget) => Some(
+                budget
+                    .reserve(REQUEST_OVERHEAD + upload.size)
+                    .ok_or_else(|| anyhow!(StatusError::new(44, SLOW_DOWN_SECS.to_string())))?,
+            ),
+            None => None,
+        };
+        let content = titan::read_content(&upload, received, stream).await?;
+        if upload.is_delete() {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("failed to delete {}", path.display()))?;
+            info!("Deleted {}", path.display());
+        } else {
+            atomic::write(&path, &content)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+            info!("Wrote {} bytes to {}", content.len(), path.display());
+        }
+        Ok(upload.url)
+    }
+
+    async fn reply_maintenance<W: Write + Unpin>(&self, page: &Path, mut stream: W) -> Result<()> {
+        debug!("In maintenance mode");
+        // The file might be removed between checking for it and reading it.
+        let contents = std::fs::read_to_string(page).unwrap_or_default();
+        if contents.trim().is_empty() {
+            let header = format!("41 {}\r\n", self.options.maintenance_meta);
+            stream.write_all(header.as_bytes()).await?;
+        } else {
+            stream.write_all(&b"20 text/gemini\r\n"[..]).await?;
+            markgem::convert_to_async(contents, self.converter_options.clone(), stream).await?;
+        }
         Ok(())
     }
 }
 
+/// Runs the future, failing if it takes longer than the given number of seconds. Dropping the
+/// future drops the connection it was using.
+async fn within<T>(secs: u64, what: &str, future: impl Future<Output = Result<T>>) -> Result<T> {
+    future::timeout(Duration::from_secs(secs), future)
+        .await
+        .map_err(|_| anyhow!("timed out {} after {}s", what, secs))?
+}
+
 const MAX_URL_LENGTH: usize = 1024;
-const EOL: &'static [u8] = b"\r\n";
+const EOL: &[u8] = b"\r\n";
 
-async fn read_request<R: Read + Unpin>(mut stream: R) -> Result<Url> {
+/// Reads the request line, returning its URL and anything the client sent after it, which is the
+/// start of the content of a Titan upload.
+async fn read_request<R: Read + Unpin>(mut stream: R) -> Result<(Url, Vec<u8>)> {
     // The longest valid request is a 1024-character URL followed by CRLF, so we can statically
     // allocate this many bytes.
     let mut request = [0; MAX_URL_LENGTH + EOL.len()];
     let mut len = 0;
-    loop {
+    let end = loop {
         let bytes_read = stream.read(&mut request[len..]).await?;
         len += bytes_read;
-        if request[..len].ends_with(EOL) {
+        if let Some(end) = request[..len].windows(EOL.len()).position(|w| w == EOL) {
             // Got the full URL.
-            break;
+            break end;
         } else if bytes_read == 0 {
-            bail!("Unexpected end of request");
+            return Err(anyhow!(StatusError::bad_request()).context("unexpected end of request"));
         }
-    }
-    let request = std::str::from_utf8(&request[..len - EOL.len()])
-        .context("could not parse request as utf8")?;
-    let mut url = Url::parse(request)?;
+    };
+    let rest = request[end + EOL.len()..len].to_vec();
+    let request = std::str::from_utf8(&request[..end])
+        .map_err(|_| anyhow!(StatusError::bad_request()).context("request isn't UTF-8"))?;
+    let mut url = Url::parse(request).map_err(|error| {
+        anyhow!(StatusError::bad_request()).context(format!("invalid URL: {}", error))
+    })?;
     if url.scheme() == "" {
         url.set_scheme("gemini")
             .map_err(|_| anyhow!("Could not set URL scheme"))?;
     }
-    if url.scheme() != "gemini" {
-        bail!("Unknown url scheme {}", url.scheme())
+    resolve::normalize_host(&mut url)
+        .map_err(|error| anyhow!(StatusError::bad_request()).context(format!("{:#}", error)))?;
+    if url.scheme() != "gemini" && url.scheme() != "titan" {
+        return Err(anyhow!(StatusError::new(53, "Proxy request refused"))
+            .context(format!("unknown url scheme {}", url.scheme())));
+    }
+    Ok((url, rest))
+}
+
+/// The status and prompt to ask for input with, if the page takes input. Pages ask for it with
+/// `input = "Prompt"` in their front matter, or `sensitive_input = "Prompt"` for input that
+/// clients shouldn't echo, like passwords. When the page is requested with a query, it's shown
+/// with `{{ query() }}` replaced by what the reader typed.
+fn input_prompt(markdown: &str) -> Option<(u8, &str)> {
+    match markgem::front_matter_value(markdown, "sensitive_input") {
+        Some(prompt) => Some((11, prompt)),
+        None => markgem::front_matter_value(markdown, "input").map(|prompt| (10, prompt)),
+    }
+}
+
+/// The URL of the web version of the page at the given URL path, on the mirror with the given
+/// base URL.
+fn web_mirror_url(base: &str, path: &str) -> String {
+    let stem = path
+        .strip_suffix(".md")
+        .or_else(|| path.strip_suffix(".markdown"));
+    let path = match stem {
+        Some(stem) => Cow::Owned(format!("{}.html", stem)),
+        None => Cow::Borrowed(path),
+    };
+    format!("{}{}", base.trim_end_matches('/'), path)
+}
+
+/// The value in the `Arc`, without cloning it if this was the only reference to it.
+fn unwrap_or_clone<T: Clone>(arc: Arc<T>) -> T {
+    Arc::try_unwrap(arc).unwrap_or_else(|arc| (*arc).clone())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fetch;
+    use crate::testing::{self, TestServer};
+    use crate::tls;
+    use anyhow::Result;
+    use async_std::io::prelude::*;
+    use async_std::net::TcpStream;
+    use async_std::task;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn serves_pages() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(&[("page.md", "# Hi\n\n[a](/b)")], &[]).await?;
+            assert_eq!(
+                server.get("/page.md").await?,
+                "20 text/gemini\r\n# Hi\n\na[1]\n\n=> /b"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn mime_types() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[("notes.txt", "*a*"), ("page.gmi", "# Hi"), ("data.x", "x")],
+                &["--mime-type", "x=application/x-test"],
+            )
+            .await?;
+            assert_eq!(server.get("/notes.txt").await?, "20 text/plain\r\n*a*");
+            assert_eq!(server.get("/page.gmi").await?, "20 text/gemini\r\n# Hi");
+            assert_eq!(server.get("/data.x").await?, "20 application/x-test\r\nx");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn front_matter_path() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[(
+                    "blog/post.md",
+                    "+++\npath = \"/2015/01/post.html\"\n+++\nhi",
+                )],
+                &[],
+            )
+            .await?;
+            assert_eq!(
+                server.get("/2015/01/post.html").await?,
+                "20 text/gemini\r\nhi"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn index_files() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[
+                    ("index.md", "home"),
+                    ("blog/index.gmi", "=> post.md"),
+                    ("blog/post.md", "hi"),
+                ],
+                &[],
+            )
+            .await?;
+            assert_eq!(server.get("/").await?, "20 text/gemini\r\nhome");
+            assert_eq!(server.get("/blog/").await?, "20 text/gemini\r\n=> post.md");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn custom_index_names() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[("index.md", "home"), ("README.md", "readme")],
+                &["--index", "README.md"],
+            )
+            .await?;
+            assert_eq!(server.get("/").await?, "20 text/gemini\r\nreadme");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn autoindex() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[("files/sub/a.md", "a"), ("files/b.txt", "b")],
+                &["--autoindex"],
+            )
+            .await?;
+            let listing = server.get("/files/").await?;
+            assert!(listing.starts_with(
+                "20 text/gemini\r\n# Index of /files/\n\n=> ../ Parent directory\n=> sub/ sub/\n=> b.txt b.txt (1 B, "
+            ));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn error_statuses() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(&[("dir/a.md", "a")], &[]).await?;
+            assert_eq!(server.get("/missing.md").await?, "51 Not found\r\n");
+            assert_eq!(server.get("/dir/").await?, "51 Not found\r\n");
+            assert_eq!(server.get("/a%5Cb").await?, "51 Not found\r\n");
+            assert_eq!(
+                server.request("no scheme\r\n").await?,
+                b"59 Bad request\r\n"
+            );
+            assert_eq!(
+                server.request("https://localhost/\r\n").await?,
+                b"53 Proxy request refused\r\n"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn unreadable_page() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(&[], &[]).await?;
+            std::fs::write(server.dir().join("root/latin1.md"), b"caf\xe9")?;
+            assert_eq!(server.get("/latin1.md").await?, "50 Permanent failure\r\n");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn internationalized_names() -> Result<()> {
+        task::block_on(async {
+            let server =
+                TestServer::start(&[("café.md", "hi")], &["--hostname", "bücher.example"]).await?;
+            assert_eq!(
+                server
+                    .request("gemini://xn--bcher-kva.example/caf%C3%A9.md\r\n")
+                    .await?,
+                b"20 text/gemini\r\nhi"
+            );
+            assert_eq!(
+                server
+                    .request("gemini://bücher.example/café.md\r\n")
+                    .await?,
+                b"20 text/gemini\r\nhi"
+            );
+            assert_eq!(
+                server.get("/café.md").await?,
+                "53 Proxy request refused\r\n"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn virtual_hosts() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[("page.md", "main"), ("other/page.md", "other")],
+                &[
+                    "--hostname",
+                    "localhost",
+                    "--vhost",
+                    "Other.example={dir}/root/other,{dir}/cert.pem,{dir}/key.pem",
+                ],
+            )
+            .await?;
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\nmain");
+            assert_eq!(
+                server.request("gemini://other.example/page.md\r\n").await?,
+                b"20 text/gemini\r\nother"
+            );
+            assert_eq!(
+                server.request("gemini://third.example/page.md\r\n").await?,
+                b"53 Proxy request refused\r\n"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn timeouts() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[("page.md", "hi")],
+                &["--handshake-timeout", "1", "--request-timeout", "1"],
+            )
+            .await?;
+            let start = Instant::now();
+            // Never starting the handshake.
+            let mut stream = TcpStream::connect(("localhost", server.port())).await?;
+            assert_eq!(stream.read(&mut [0; 16]).await?, 0);
+            // Never sending a request. The connection is dropped without a response, which may
+            // look like an error to the client.
+            let response = server.request("").await;
+            assert!(response.map_or(true, |response| response.is_empty()));
+            assert!(start.elapsed() < Duration::from_secs(10));
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\nhi");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn renewed_certificates() -> Result<()> {
+        task::block_on(async {
+            let server =
+                TestServer::start(&[("page.md", "hi")], &["--cert-check-interval", "1"]).await?;
+            let original = fetch::server_fingerprint("localhost", server.port()).await?;
+            // A certificate that can't be loaded leaves the old one in place.
+            std::fs::write(server.dir().join("cert.pem"), "not a certificate")?;
+            task::sleep(Duration::from_millis(1500)).await;
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\nhi");
+            assert_eq!(
+                fetch::server_fingerprint("localhost", server.port()).await?,
+                original
+            );
+
+            std::fs::write(
+                server.dir().join("key.pem"),
+                include_str!("../tests/data/renewed.key"),
+            )?;
+            std::fs::write(
+                server.dir().join("cert.pem"),
+                include_str!("../tests/data/renewed.crt"),
+            )?;
+            task::sleep(Duration::from_millis(1500)).await;
+            assert_ne!(
+                fetch::server_fingerprint("localhost", server.port()).await?,
+                original
+            );
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\nhi");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn graceful_shutdown() -> Result<()> {
+        task::block_on(async {
+            let mut server = TestServer::start(&[("page.md", "hi")], &[]).await?;
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\nhi");
+            server.shutdown().await?;
+            assert!(server.get("/page.md").await.is_err());
+
+            let mut server =
+                TestServer::start(&[("page.md", "hi")], &["--shutdown-timeout", "1"]).await?;
+            // A connection that's still open holds up shutting down until the deadline.
+            let _stream = TcpStream::connect(("localhost", server.port())).await?;
+            task::sleep(Duration::from_millis(100)).await;
+            let start = Instant::now();
+            server.shutdown().await?;
+            assert!(start.elapsed() >= Duration::from_secs(1));
+            assert!(start.elapsed() < Duration::from_secs(10));
+
+            let mut server = TestServer::start(
+                &[("page.md", "hi")],
+                &[
+                    "--max-connections",
+                    "1",
+                    "--connection-wait-ms",
+                    "60000",
+                    "--shutdown-timeout",
+                    "1",
+                ],
+            )
+            .await?;
+            // The second connection leaves the server waiting for the first to close.
+            let _first = TcpStream::connect(("localhost", server.port())).await?;
+            let _second = TcpStream::connect(("localhost", server.port())).await?;
+            task::sleep(Duration::from_millis(100)).await;
+            let start = Instant::now();
+            server.shutdown().await?;
+            assert!(start.elapsed() < Duration::from_secs(10));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn access_log() -> Result<()> {
+        task::block_on(async {
+            let server =
+                TestServer::start(&[("page.md", "hi")], &["--access-log", "{dir}/access.log"])
+                    .await?;
+            server.get("/page.md").await?;
+            server.get("/missing.md").await?;
+            server.request("not a url\r\n").await?;
+            let log = std::fs::read_to_string(server.dir().join("access.log"))?;
+            let lines: Vec<_> = log.lines().collect();
+            assert_eq!(lines.len(), 3, "{}", log);
+            assert!(lines[0].starts_with("127.0.0.1 - - ["), "{}", lines[0]);
+            assert!(
+                lines[0].contains("] \"gemini://localhost/page.md\" 20 18 "),
+                "{}",
+                lines[0]
+            );
+            assert!(lines[1].contains("\"gemini://localhost/missing.md\" 51 "));
+            assert!(lines[2].contains("\"-\" 59 "), "{}", lines[2]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn path_traversal() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(&[("page.md", "hi")], &[]).await?;
+            std::fs::write(server.dir().join("secret.md"), "secret")?;
+            for request in &[
+                "gemini://localhost/../secret.md\r\n",
+                "gemini://localhost/%2e%2e/secret.md\r\n",
+                "gemini://localhost/a/..%2Fsecret.md\r\n",
+                "gemini://localhost//etc/passwd\r\n",
+                "gemini://localhost/%2Fetc%2Fpasswd\r\n",
+            ] {
+                assert_eq!(server.request(request).await?, b"51 Not found\r\n");
+            }
+            Ok(())
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_out_of_root() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(&[("page.md", "hi")], &[]).await?;
+            std::fs::write(server.dir().join("secret.md"), "secret")?;
+            std::os::unix::fs::symlink(
+                server.dir().join("secret.md"),
+                server.dir().join("root/link.md"),
+            )?;
+            assert_eq!(server.get("/link.md").await?, "51 Not found\r\n");
+            Ok(())
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn swapped_root() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(&[("page.md", "one")], &[]).await?;
+            let dir = server.dir();
+            std::fs::rename(dir.join("root"), dir.join("one"))?;
+            std::os::unix::fs::symlink("one", dir.join("root"))?;
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\none");
+
+            std::fs::create_dir(dir.join("two"))?;
+            std::fs::write(dir.join("two/page.md"), "two")?;
+            std::fs::write(dir.join("two/moved.md"), "+++\npath = \"/old\"\n+++\nmoved")?;
+            crate::atomic::symlink(std::path::Path::new("two"), &dir.join("root"))?;
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\ntwo");
+            assert_eq!(server.get("/old").await?, "20 text/gemini\r\nmoved");
+            Ok(())
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn standby_root() -> Result<()> {
+        use async_std::os::unix::net::UnixStream;
+        use async_std::prelude::*;
+
+        task::block_on(async {
+            let server = TestServer::start(
+                &[("page.md", "blue"), ("../standby/page.md", "green")],
+                &[
+                    "--standby",
+                    "{dir}/standby",
+                    "--admin-socket",
+                    "{dir}/admin.sock",
+                ],
+            )
+            .await?;
+            let root = server.dir().join("root");
+            let standby = server.dir().join("standby");
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\nblue");
+
+            let mut admin = UnixStream::connect(server.dir().join("admin.sock")).await?;
+            let mut answers = async_std::io::BufReader::new(admin.clone()).lines();
+            admin.write_all(b"status\nswitch\n").await?;
+            assert_eq!(
+                answers.next().await.unwrap()?,
+                format!("active {}, standby {}", root.display(), standby.display())
+            );
+            assert_eq!(
+                answers.next().await.unwrap()?,
+                format!("active {}, standby {}", standby.display(), root.display())
+            );
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\ngreen");
+
+            admin.write_all(b"switch\nrollback\n").await?;
+            answers.next().await.unwrap()?;
+            assert!(answers.next().await.unwrap()?.starts_with("error: unknown"));
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\nblue");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn redirects() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[
+                    ("new.md", "hi"),
+                    ("redirects", "/old.md /new.md\n/tmp/* /new/* temporary"),
+                ],
+                &["--redirects", "{dir}/root/redirects"],
+            )
+            .await?;
+            assert_eq!(server.get("/old.md").await?, "31 /new.md\r\n");
+            assert_eq!(server.get("/tmp/a/b").await?, "30 /new/a/b\r\n");
+            assert_eq!(server.get("/new.md").await?, "20 text/gemini\r\nhi");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn config_file() -> Result<()> {
+        task::block_on(async {
+            let config = indoc::indoc!(
+                r#"
+                [listen]
+                hostname = "example.org"
+
+                [content]
+                index = ["home.md"]
+
+                [converter]
+                emphasis = "strip"
+
+                [[redirect]]
+                from = "/old/*"
+                to = "/new/*"
+                temporary = true
+                "#
+            );
+            let server = TestServer::start(
+                &[
+                    ("home.md", "home"),
+                    ("page.md", "*a*"),
+                    ("../exarch.toml", config),
+                ],
+                // The hostname from the file would refuse requests for localhost.
+                &["--config", "{dir}/exarch.toml", "--hostname", "localhost"],
+            )
+            .await?;
+            assert_eq!(server.get("/").await?, "20 text/gemini\r\nhome");
+            assert_eq!(server.get("/old/a.md").await?, "30 /new/a.md\r\n");
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\na");
+
+            let error = TestServer::start(
+                &[("../exarch.toml", "[listen]\nprot = 1")],
+                &["--config", "{dir}/exarch.toml"],
+            )
+            .await
+            .err()
+            .unwrap();
+            assert!(format!("{:#}", error).contains("unknown setting prot in [listen]"));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn config_profile() -> Result<()> {
+        task::block_on(async {
+            let config = indoc::indoc!(
+                r#"
+                [listen]
+                hostname = "example.org"
+
+                [profile.local.listen]
+                hostname = "localhost"
+
+                [[profile.local.redirect]]
+                from = "/old.md"
+                to = "/home.md"
+                "#
+            );
+            let server = TestServer::start(
+                &[("home.md", "home"), ("../exarch.toml", config)],
+                &["--config", "{dir}/exarch.toml", "--profile", "local"],
+            )
+            .await?;
+            assert_eq!(server.get("/home.md").await?, "20 text/gemini\r\nhome");
+            assert_eq!(server.get("/old.md").await?, "31 /home.md\r\n");
+
+            let error = TestServer::start(
+                &[("../exarch.toml", config)],
+                &["--config", "{dir}/exarch.toml", "--profile", "prod"],
+            )
+            .await
+            .err()
+            .unwrap();
+            assert!(format!("{:#}", error).contains("there's no [profile.prod]"));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn cached_pages() -> Result<()> {
+        task::block_on(async {
+            let server =
+                TestServer::start(&[("page.md", "*one*")], &["--cache-size", "1M"]).await?;
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\n*one*");
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\n*one*");
+            std::fs::write(server.dir().join("root/page.md"), "two")?;
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\ntwo");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn cache_rules() -> Result<()> {
+        task::block_on(async {
+            let post = "+++\ndate = 2000-01-01\n+++\ntext";
+            let mut files = vec![];
+            for section in &["blog", "guestbook", "archive"] {
+                files.push((format!("{}/_index.md", section), "+++\n+++\n"));
+                files.push((format!("{}/post.md", section), post));
+            }
+            let files: Vec<_> = files
+                .iter()
+                .map(|(path, text)| (&path[..], *text))
+                .collect();
+            let server = TestServer::start(
+                &files,
+                &[
+                    "--cache-size",
+                    "1M",
+                    "--cache-rule",
+                    "/guestbook=never",
+                    "--cache-rule",
+                    "/archive=1s",
+                ],
+            )
+            .await?;
+            for section in &["blog", "guestbook", "archive"] {
+                let path = format!("/{}/post.md", section);
+                assert_eq!(server.get(&path).await?, "20 text/gemini\r\ntext");
+                let index = server.dir().join(format!("root/{}/_index.md", section));
+                std::fs::write(index, "+++\naging_notice = 5\n+++\n")?;
+            }
+            // Cached pages are only converted again when their own file changes, so the new
+            // threshold only shows up once they're dropped.
+            let notice = "20 text/gemini\r\n>This post is over ";
+            assert_eq!(server.get("/blog/post.md").await?, "20 text/gemini\r\ntext");
+            assert!(server.get("/guestbook/post.md").await?.starts_with(notice));
+            assert_eq!(
+                server.get("/archive/post.md").await?,
+                "20 text/gemini\r\ntext"
+            );
+            task::sleep(Duration::from_millis(1200)).await;
+            assert!(server.get("/archive/post.md").await?.starts_with(notice));
+            assert_eq!(server.get("/blog/post.md").await?, "20 text/gemini\r\ntext");
+            Ok(())
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn watched_cache() -> Result<()> {
+        task::block_on(async {
+            let post = "+++\ndate = 2000-01-01\n+++\ntext";
+            let server = TestServer::start(
+                &[
+                    ("page.md", "*one*"),
+                    ("gone.md", "gone"),
+                    ("blog/_index.md", "+++\n+++\n"),
+                    ("blog/post.md", post),
+                ],
+                &["--cache-size", "1M", "--cache-watch"],
+            )
+            .await?;
+            // The watcher hears about changes soon after they happen.
+            let eventually = |path: &'static str, expected: &'static str| {
+                let server = &server;
+                async move {
+                    let deadline = Instant::now() + Duration::from_secs(5);
+                    loop {
+                        let response = server.get(path).await?;
+                        if response.starts_with(expected) {
+                            return Ok::<_, anyhow::Error>(());
+                        }
+                        assert!(Instant::now() < deadline, "{} stayed {:?}", path, response);
+                        task::sleep(Duration::from_millis(50)).await;
+                    }
+                }
+            };
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\n*one*");
+            assert_eq!(server.get("/gone.md").await?, "20 text/gemini\r\ngone");
+            assert_eq!(server.get("/blog/post.md").await?, "20 text/gemini\r\ntext");
+            std::fs::write(server.dir().join("root/page.md"), "two")?;
+            std::fs::remove_file(server.dir().join("root/gone.md"))?;
+            eventually("/page.md", "20 text/gemini\r\ntwo").await?;
+            eventually("/gone.md", "51").await?;
+            // The post's own file doesn't change, so only the watcher knows to drop it.
+            let index = "+++\naging_notice = 5\n+++\n";
+            std::fs::write(server.dir().join("root/blog/_index.md"), index)?;
+            eventually("/blog/post.md", "20 text/gemini\r\n>This post is over ").await?;
+            Ok(())
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket() -> Result<()> {
+        use crate::fetch::Fetcher;
+        use async_std::os::unix::net::UnixStream;
+        use std::os::unix::fs::PermissionsExt;
+
+        task::block_on(async {
+            let mut server = TestServer::start(
+                &[("page.md", "hi")],
+                &[
+                    "--unix-socket",
+                    "{dir}/exarch.sock",
+                    "--unix-socket-mode",
+                    "600",
+                ],
+            )
+            .await?;
+            let path = server.dir().join("exarch.sock");
+            let stream = UnixStream::connect(&path).await?;
+            let response = Fetcher::new()
+                .request_on("localhost", stream, b"gemini://localhost/page.md\r\n")
+                .await?;
+            assert_eq!(response, b"20 text/gemini\r\nhi");
+            let mode = std::fs::metadata(&path)?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+            // The port still works too.
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\nhi");
+            server.shutdown().await?;
+            assert!(!path.exists());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn client_certificates() -> Result<()> {
+        task::block_on(async {
+            let fingerprint = tls::fingerprint(&testing::identity().0[0]);
+            let server = TestServer::start(
+                &[
+                    ("private/a.md", "secret"),
+                    ("other/a.md", "secret"),
+                    ("a.md", "hi"),
+                ],
+                &[
+                    "--restrict",
+                    &format!("/private={}", fingerprint),
+                    "--restrict",
+                    "/other=00",
+                ],
+            )
+            .await?;
+            assert_eq!(server.get("/a.md").await?, "20 text/gemini\r\nhi");
+            assert_eq!(
+                server.get("/private/a.md").await?,
+                "60 Certificate required\r\n"
+            );
+            assert_eq!(
+                server.get_as("/private/a.md", testing::identity()).await?,
+                "20 text/gemini\r\nsecret"
+            );
+            assert_eq!(
+                server.get_as("/other/a.md", testing::identity()).await?,
+                "61 Certificate not authorised\r\n"
+            );
+            // Empty and dot segments are dropped when the path is resolved, so they mustn't get
+            // around the restriction either.
+            for path in &["//private/a.md", "/./private/a.md", "/private//a.md"] {
+                assert_eq!(server.get(path).await?, "60 Certificate required\r\n");
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn titan_uploads() -> Result<()> {
+        task::block_on(async {
+            let fingerprint = tls::fingerprint(&testing::identity().0[0]);
+            let server = TestServer::start(
+                &[("notes/old.md", "old"), ("a.md", "hi")],
+                &[
+                    "--titan",
+                    &format!("/notes={}", fingerprint),
+                    "--titan-token",
+                    "secret",
+                    "--titan-max-size",
+                    "1K",
+                ],
+            )
+            .await?;
+            let server = &server;
+            let upload = |path: &str, params: &str, content: &str| {
+                let request = format!(
+                    "titan://localhost{};size={}{}\r\n{}",
+                    path,
+                    content.len(),
+                    params,
+                    content
+                );
+                async move {
+                    server
+                        .request_as(request.as_bytes(), testing::identity())
+                        .await
+                }
+            };
+            assert_eq!(
+                upload("/notes/new.md", ";token=secret", "# New").await?,
+                "30 gemini://localhost/notes/new.md\r\n"
+            );
+            assert_eq!(
+                server.get("/notes/new.md").await?,
+                "20 text/gemini\r\n# New"
+            );
+            assert_eq!(
+                upload("/notes/old.md", ";token=secret", "").await?,
+                "30 gemini://localhost/notes/old.md\r\n"
+            );
+            assert_eq!(server.get("/notes/old.md").await?, "51 Not found\r\n");
+            assert_eq!(
+                upload("/notes/new.md", ";token=wrong", "x").await?,
+                "61 Token not accepted\r\n"
+            );
+            assert_eq!(
+                upload("/notes/new.md", ";token=secret;mime=image/png", "x").await?,
+                "59 Wrong MIME type for the path\r\n"
+            );
+            assert_eq!(
+                upload("/notes/big.md", ";token=secret", &"x".repeat(2000)).await?,
+                "59 Upload too large\r\n"
+            );
+            assert_eq!(
+                upload("/a.md", ";token=secret", "x").await?,
+                "50 Uploads not accepted\r\n"
+            );
+            assert_eq!(
+                server
+                    .request("titan://localhost/notes/a.md;size=1;token=secret\r\nx")
+                    .await?,
+                b"60 Certificate required\r\n"
+            );
+            assert_eq!(server.get("/a.md").await?, "20 text/gemini\r\nhi");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn converter_flags() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(&[("page.md", "*a*")], &["--emphasis", "strip"]).await?;
+            assert_eq!(server.get("/page.md").await?, "20 text/gemini\r\na");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn theme() -> Result<()> {
+        task::block_on(async {
+            let theme = "emphasis = \"strip\"\nlisting = \"compact\"\nfooter = \"=> / Home\"";
+            let server = TestServer::start(
+                &[
+                    ("page.md", "*a* **b**"),
+                    ("files/x.txt", "x"),
+                    ("../theme.toml", theme),
+                ],
+                &[
+                    "--theme",
+                    "{dir}/theme.toml",
+                    "--autoindex",
+                    "--emphasis",
+                    "caps",
+                ],
+            )
+            .await?;
+            // Flags that are given win over the theme.
+            assert_eq!(
+                server.get("/page.md").await?,
+                "20 text/gemini\r\nA B\n\n=> / Home"
+            );
+            assert!(server.get("/files/").await?.ends_with("=> x.txt x.txt\n"));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn maintenance() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[("page.md", "hi")],
+                &["--maintenance-file", "{dir}/maintenance"],
+            )
+            .await?;
+            let flag = server.dir().join("maintenance");
+            assert!(server.get("/page.md").await?.starts_with("20 "));
+            std::fs::write(&flag, "")?;
+            assert_eq!(server.get("/page.md").await?, "41 Down for maintenance\r\n");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn section_aging_notice() -> Result<()> {
+        task::block_on(async {
+            let post = "+++\ndate = 2000-01-01\n+++\ntext";
+            let server = TestServer::start(
+                &[
+                    ("blog/_index.md", "+++\naging_notice = 5\n+++\n"),
+                    ("blog/post.md", post),
+                    ("post.md", post),
+                ],
+                &[],
+            )
+            .await?;
+            assert!(server
+                .get("/blog/post.md")
+                .await?
+                .starts_with("20 text/gemini\r\n>This post is over "));
+            assert_eq!(server.get("/post.md").await?, "20 text/gemini\r\ntext");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn web_mirror() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[("blog/post.md", "hi"), ("index.md", "home")],
+                &["--web-mirror", "https://example.org/"],
+            )
+            .await?;
+            assert_eq!(
+                server.get("/blog/post.md").await?,
+                "20 text/gemini\r\nhi\n\n=> https://example.org/blog/post.html Read on the web"
+            );
+            assert_eq!(
+                server.get("/").await?,
+                "20 text/gemini\r\nhome\n\n=> https://example.org/ Read on the web"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn input() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[
+                    (
+                        "search.md",
+                        "+++\ninput = \"Search for\"\n+++\nResults for {{ query() }}",
+                    ),
+                    ("login.md", "+++\nsensitive_input = \"Password\"\n+++\nhi"),
+                ],
+                &[],
+            )
+            .await?;
+            assert_eq!(server.get("/search.md").await?, "10 Search for\r\n");
+            assert_eq!(server.get("/search.md?").await?, "10 Search for\r\n");
+            assert_eq!(
+                server.get("/search.md?%5Bcats%5D(x)%20%26%20dogs").await?,
+                "20 text/gemini\r\nResults for [cats](x) & dogs"
+            );
+            assert_eq!(server.get("/login.md").await?, "11 Password\r\n");
+            Ok(())
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cgi() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        task::block_on(async {
+            let server = TestServer::start(
+                &[
+                    (
+                        "cgi-bin/hello",
+                        "#!/bin/sh\nprintf '20 text/gemini\\r\\n%s %s' \"$QUERY_STRING\" \"$SERVER_PROTOCOL\"\n",
+                    ),
+                    ("cgi-bin/fail", "#!/bin/sh\nexit 1\n"),
+                    ("hello", "not run"),
+                ],
+                &["--cgi", "/cgi-bin"],
+            )
+            .await?;
+            for script in &["hello", "fail"] {
+                let path = server.dir().join("root/cgi-bin").join(script);
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+            }
+            assert_eq!(
+                server.get("/cgi-bin/hello?a%20b").await?,
+                "20 text/gemini\r\na%20b GEMINI"
+            );
+            assert_eq!(server.get("/cgi-bin/fail").await?, "42 CGI error\r\n");
+            // However the script is reached, its source isn't served.
+            std::os::unix::fs::symlink(
+                server.dir().join("root/cgi-bin/hello"),
+                server.dir().join("root/link"),
+            )?;
+            for path in &["//cgi-bin/hello?x", "/./cgi-bin/hello?x", "/link?x"] {
+                assert_eq!(server.get(path).await?, "20 text/gemini\r\nx GEMINI");
+            }
+            assert_eq!(server.get("/hello").await?, "20 text/gemini\r\nnot run");
+            Ok(())
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hung_cgi() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        task::block_on(async {
+            let server = TestServer::start(
+                &[(
+                    "cgi-bin/hang",
+                    "#!/bin/sh\necho $$ > ../hang.pid\nexec sleep 60\n",
+                )],
+                &["--cgi", "/cgi-bin", "--response-timeout", "1"],
+            )
+            .await?;
+            let script = server.dir().join("root/cgi-bin/hang");
+            std::fs::set_permissions(script, std::fs::Permissions::from_mode(0o755))?;
+            let response = server.get("/cgi-bin/hang").await;
+            assert!(response.map_or(true, |response| response.is_empty()));
+            let pid: i32 = std::fs::read_to_string(server.dir().join("root/hang.pid"))?
+                .trim()
+                .parse()?;
+            // The script is killed when the response times out, and reaped.
+            let start = Instant::now();
+            while unsafe { libc::kill(pid, 0) } == 0 {
+                assert!(
+                    start.elapsed() < Duration::from_secs(5),
+                    "{} still running",
+                    pid
+                );
+                task::sleep(Duration::from_millis(50)).await;
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn filters() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[
+                    ("blog/post.md", "Café ☕"),
+                    ("raw.gmi", "Café"),
+                    ("page.md", "hi"),
+                ],
+                &[
+                    "--filter",
+                    "/=banner:=> / Home",
+                    "--filter",
+                    "/blog=strip-emoji",
+                    "--filter",
+                    "/=ascii",
+                ],
+            )
+            .await?;
+            assert_eq!(
+                server.get("/blog/post.md").await?,
+                "20 text/gemini\r\nCafe \n\n=> / Home\n"
+            );
+            assert_eq!(
+                server.get("/raw.gmi").await?,
+                "20 text/gemini\r\nCafe\n\n=> / Home\n"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn recording() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[
+                    ("a.md", "hi"),
+                    ("private/a.md", "secret"),
+                    (
+                        "login.md",
+                        "+++\nsensitive_input = \"Password\"\n+++\nYou typed {{ query() }}",
+                    ),
+                ],
+                &["--record", "{dir}", "--restrict", "/private=00"],
+            )
+            .await?;
+            server.get("/a.md").await?;
+            server.get("//private/a.md").await?;
+            server.get("/login.md?hunter2").await?;
+            let mut recordings = vec![];
+            for entry in std::fs::read_dir(server.dir())? {
+                let path = entry?.path();
+                if path
+                    .extension()
+                    .map_or(false, |extension| extension == "gemini")
+                {
+                    recordings.push(std::fs::read_to_string(path)?);
+                }
+            }
+            recordings.sort();
+            let url = |path: &str| format!("gemini://localhost{}", path);
+            assert_eq!(
+                recordings,
+                vec![
+                    format!("{}\r\n20 text/gemini\r\nhi", url("/a.md")),
+                    format!("{}\r\n20 text/gemini\r\n", url("/login.md?redacted")),
+                ]
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn memory_limit() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(&[("page.md", "hi")], &["--memory-limit", "1k"]).await?;
+            assert_eq!(server.get("/page.md").await?, "44 5\r\n");
+            Ok(())
+        })
     }
-    Ok(url)
 }
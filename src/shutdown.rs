@@ -0,0 +1,142 @@
+//! Shutting down gracefully: on SIGTERM or SIGINT the server stops accepting connections and gives
+//! the ones it's handling time to finish, so that restarting it doesn't cut off responses.
+
+use anyhow::Result;
+use async_std::future;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use std::future::Future;
+use std::time::Duration;
+
+/// Resolves when the process is asked to stop with SIGTERM or SIGINT. A second signal exits
+/// straight away, for when waiting isn't wanted.
+///
+/// The handlers are process-wide, so this should only be called once.
+#[cfg(unix)]
+pub fn signalled() -> Result<impl Future<Output = ()>> {
+    use anyhow::Context;
+    use futures::channel::oneshot;
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    // The write end of a pipe, which the handler writes a byte to for each signal. Writing is
+    // about the only thing a signal handler can safely do.
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn handle(_: libc::c_int) {
+        let fd = PIPE.load(Ordering::Relaxed);
+        unsafe { libc::write(fd, [0u8].as_ptr() as *const libc::c_void, 1) };
+    }
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(std::io::Error::last_os_error()).context("failed to create a pipe");
+    }
+    PIPE.store(fds[1], Ordering::Relaxed);
+    for &signal in &[libc::SIGTERM, libc::SIGINT] {
+        let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(std::io::Error::last_os_error()).context("failed to handle signals");
+        }
+    }
+
+    let (signalled, received) = oneshot::channel();
+    let mut pipe = unsafe { File::from_raw_fd(fds[0]) };
+    std::thread::spawn(move || {
+        let mut byte = [0];
+        if pipe.read_exact(&mut byte).is_ok() {
+            let _ = signalled.send(());
+        }
+        if pipe.read_exact(&mut byte).is_ok() {
+            log::warn!("Signalled again, exiting without waiting for connections");
+            std::process::exit(1);
+        }
+    });
+    // If the thread is gone, there'll never be a signal.
+    Ok(async {
+        if received.await.is_err() {
+            future::pending::<()>().await;
+        }
+    })
+}
+
+#[cfg(not(unix))]
+pub fn signalled() -> Result<impl Future<Output = ()>> {
+    Ok(future::pending())
+}
+
+/// Keeps track of the connections being handled, so that shutting down can wait for them.
+pub struct Handlers {
+    // Every handler holds a clone of this. The receiver sees the end of the channel once they've
+    // all been dropped.
+    sender: mpsc::Sender<()>,
+    receiver: mpsc::Receiver<()>,
+}
+
+/// Held by a connection's handler until it finishes.
+pub struct Handler {
+    _sender: mpsc::Sender<()>,
+}
+
+impl Default for Handlers {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel(0);
+        Self { sender, receiver }
+    }
+}
+
+impl Handlers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts tracking a handler.
+    pub fn start(&self) -> Handler {
+        Handler {
+            _sender: self.sender.clone(),
+        }
+    }
+
+    /// Waits up to the deadline for every handler to finish, returning whether they did.
+    pub async fn finish(self, deadline: Duration) -> bool {
+        let Self {
+            sender,
+            mut receiver,
+        } = self;
+        drop(sender);
+        future::timeout(deadline, receiver.next()).await.is_ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use async_std::task;
+
+    #[test]
+    fn waits_for_handlers() {
+        task::block_on(async {
+            assert!(Handlers::new().finish(Duration::from_secs(0)).await);
+
+            let handlers = Handlers::new();
+            let handler = handlers.start();
+            let stuck = handlers.start();
+            task::spawn(async move {
+                task::sleep(Duration::from_millis(10)).await;
+                drop(handler);
+            });
+            assert!(!handlers.finish(Duration::from_millis(100)).await);
+            drop(stuck);
+
+            let handlers = Handlers::new();
+            let handler = handlers.start();
+            task::spawn(async move {
+                task::sleep(Duration::from_millis(10)).await;
+                drop(handler);
+            });
+            assert!(handlers.finish(Duration::from_secs(5)).await);
+        });
+    }
+}
@@ -0,0 +1,260 @@
+//! Smoke tests for a live capsule: crawling it from a URL and checking that every page it links
+//! to on the same host can be fetched, for catching broken deploys before anyone else does.
+
+use crate::tofu::KnownHostsFlags;
+use anyhow::{bail, Result};
+use async_std::task;
+use exarch::fetch::{Fetcher, Response};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::time::Duration;
+use structopt::StructOpt;
+use url::Url;
+
+#[derive(Debug, StructOpt)]
+pub struct SmokeOpt {
+    /// Where to start crawling, like gemini://example.org/. Only pages on the same host and port
+    /// are checked.
+    url: Url,
+
+    /// The most pages to check.
+    #[structopt(short = "n", long, default_value = "100")]
+    max_pages: usize,
+
+    /// How long to wait between requests, in milliseconds.
+    #[structopt(long, default_value = "100")]
+    delay_ms: u64,
+
+    #[structopt(flatten)]
+    known_hosts: KnownHostsFlags,
+}
+
+/// The longest header the specification allows: a status, a space and 1024 bytes of meta.
+const MAX_HEADER: usize = 1024 + 3;
+
+pub fn smoke(options: SmokeOpt) -> Result<()> {
+    if options.url.scheme() != "gemini" {
+        bail!("can only crawl Gemini URLs, not {}", options.url);
+    }
+    let known_hosts = options.known_hosts.load_shared()?;
+    let fetcher = Fetcher::new()
+        .politeness(Duration::from_millis(options.delay_ms))
+        .known_hosts(known_hosts.clone());
+    let crawl = task::block_on(crawl(&fetcher, &options.url, options.max_pages));
+    options.known_hosts.save_shared(&known_hosts)?;
+    print!("{}", crawl);
+    if !crawl.failures.is_empty() {
+        bail!("{} of {} pages failed", crawl.failures.len(), crawl.checked);
+    }
+    Ok(())
+}
+
+/// A page that couldn't be fetched, or whose response was wrong.
+#[derive(Debug, PartialEq, Eq)]
+struct Failure {
+    url: Url,
+    // The page whose link led to this one, unless it's where the crawl started.
+    linked_from: Option<Url>,
+    problem: String,
+}
+
+/// What a crawl found.
+#[derive(Debug, Default)]
+struct Crawl {
+    checked: usize,
+    failures: Vec<Failure>,
+}
+
+impl fmt::Display for Crawl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for failure in &self.failures {
+            write!(f, "FAIL {}: {}", failure.url, failure.problem)?;
+            match &failure.linked_from {
+                Some(from) => writeln!(f, " (linked from {})", from)?,
+                None => writeln!(f)?,
+            }
+        }
+        writeln!(
+            f,
+            "Checked {} page{}, {} failed",
+            self.checked,
+            if self.checked == 1 { "" } else { "s" },
+            self.failures.len()
+        )
+    }
+}
+
+/// Fetches pages breadth first from the start, following links and redirects to the same host,
+/// until there are none left or the most pages have been checked.
+async fn crawl(fetcher: &Fetcher, start: &Url, max_pages: usize) -> Crawl {
+    let mut crawl = Crawl::default();
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+    seen.insert(start.clone());
+    queue.push_back((start.clone(), None));
+    while let Some((url, linked_from)) = queue.pop_front() {
+        if crawl.checked == max_pages {
+            break;
+        }
+        crawl.checked += 1;
+        let next = match fetch(fetcher, &url).await {
+            Ok(next) => next,
+            Err(problem) => {
+                crawl.failures.push(Failure {
+                    url,
+                    linked_from,
+                    problem,
+                });
+                continue;
+            }
+        };
+        for link in next {
+            if is_internal(start, &link) && seen.insert(link.clone()) {
+                queue.push_back((link, Some(url.clone())));
+            }
+        }
+    }
+    crawl
+}
+
+/// Fetches and checks the page, returning the URLs it leads to.
+async fn fetch(fetcher: &Fetcher, url: &Url) -> Result<Vec<Url>, String> {
+    let host = url.host_str().ok_or("URL has no host")?;
+    let request = format!("{}\r\n", url);
+    let response = fetcher
+        .request(host, url.port().unwrap_or(1965), request.as_bytes())
+        .await
+        .map_err(|e| format!("{:#}", e))?;
+    let header = response.split(|&b| b == b'\r').next().unwrap_or_default();
+    if header.len() > MAX_HEADER {
+        return Err(format!("header is {} bytes long", header.len()));
+    }
+    let response = Response::parse(&response).map_err(|e| format!("{:#}", e))?;
+    check(url, &response)
+}
+
+/// Checks that the response is a success or a redirect with a sensible meta, returning the URLs it
+/// leads to.
+fn check(url: &Url, response: &Response) -> Result<Vec<Url>, String> {
+    match response.status {
+        20..=29 => {
+            if !is_mime_type(&response.meta) {
+                return Err(format!("{} isn't a MIME type", quoted(&response.meta)));
+            }
+            if !response.meta.starts_with("text/gemini") {
+                return Ok(vec![]);
+            }
+            let body = std::str::from_utf8(&response.body).map_err(|_| "body isn't UTF-8")?;
+            Ok(links(url, body))
+        }
+        30..=39 => match url.join(&response.meta) {
+            Ok(target) if !response.meta.is_empty() => Ok(vec![without_fragment(target)]),
+            _ => Err(format!(
+                "redirect to {}, which isn't a URL",
+                quoted(&response.meta)
+            )),
+        },
+        status => Err(format!("{} {}", status, response.meta)),
+    }
+}
+
+/// Whether the meta of a success is a MIME type, parameters and all.
+fn is_mime_type(meta: &str) -> bool {
+    let is_token = |s: &str| {
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?=".contains(c))
+    };
+    let mut parts = meta.split(';');
+    let essence: Vec<_> = parts.next().unwrap_or_default().splitn(2, '/').collect();
+    matches!(essence[..], [kind, subtype] if is_token(kind) && is_token(subtype))
+        && parts.all(|parameter| {
+            let pair: Vec<_> = parameter.trim().splitn(2, '=').collect();
+            matches!(pair[..], [name, value] if is_token(name) && !value.is_empty())
+        })
+}
+
+/// The URLs linked to from Gemtext, resolved against the page's URL.
+fn links(url: &Url, gemini: &str) -> Vec<Url> {
+    let mut preformatted = false;
+    let mut links = vec![];
+    for line in gemini.lines() {
+        if line.starts_with("```") {
+            preformatted = !preformatted;
+        } else if let (false, Some(link)) = (preformatted, line.strip_prefix("=>")) {
+            if let Some(target) = link.split_whitespace().next() {
+                if let Ok(target) = url.join(target) {
+                    links.push(without_fragment(target));
+                }
+            }
+        }
+    }
+    links
+}
+
+fn without_fragment(mut url: Url) -> Url {
+    url.set_fragment(None);
+    url
+}
+
+/// Whether the URL is on the capsule being crawled.
+fn is_internal(start: &Url, url: &Url) -> bool {
+    url.scheme() == "gemini"
+        && url.host() == start.host()
+        && url.port().unwrap_or(1965) == start.port().unwrap_or(1965)
+}
+
+fn quoted(meta: &str) -> String {
+    format!("\"{}\"", meta.escape_default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use exarch::testing::TestServer;
+
+    #[test]
+    fn mime_types() {
+        assert!(is_mime_type("text/gemini"));
+        assert!(is_mime_type("text/gemini; charset=utf-8; lang=en"));
+        assert!(is_mime_type("image/svg+xml"));
+        assert!(!is_mime_type(""));
+        assert!(!is_mime_type("text"));
+        assert!(!is_mime_type("text/"));
+        assert!(!is_mime_type("text gemini/x"));
+        assert!(!is_mime_type("text/gemini; charset"));
+    }
+
+    #[test]
+    fn crawl_capsule() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(
+                &[
+                    ("index.md", "[a](a.md)\n\n[missing](missing.md)"),
+                    (
+                        "a.md",
+                        "[home](/)\n\n[elsewhere](gemini://example.org/)\n\n[b](b/)",
+                    ),
+                    ("b/index.md", "```\n=> c.md\n```"),
+                ],
+                &[],
+            )
+            .await?;
+            let fetcher = Fetcher::new().politeness(Duration::from_secs(0));
+            let start = Url::parse(&format!("gemini://localhost:{}/", server.port()))?;
+            let found = crawl(&fetcher, &start, 100).await;
+            assert_eq!(found.checked, 4);
+            assert_eq!(
+                found.failures,
+                vec![Failure {
+                    url: start.join("missing.md")?,
+                    linked_from: Some(start.clone()),
+                    problem: "51 Not found".to_owned(),
+                }]
+            );
+
+            assert_eq!(crawl(&fetcher, &start, 2).await.checked, 2);
+            Ok(())
+        })
+    }
+}
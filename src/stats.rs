@@ -0,0 +1,142 @@
+//! Generation of a page of statistics about a capsule: how many pages and words it has, how many
+//! posts were written each year, and how often each tag is used. All of it comes from the source
+//! files, so it doesn't need any information about visitors.
+
+use anyhow::{Context, Result};
+use exarch::markgem;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct StatsOpt {
+    /// The root of the tree of Markdown files.
+    #[structopt(parse(from_os_str))]
+    root: PathBuf,
+
+    /// Where to write the page, such as `stats.gmi` under the root. If it's omitted, the page is
+    /// written to stdout.
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+}
+
+/// The widest a bar in the posts-per-year chart gets.
+const MAX_BAR: usize = 40;
+
+#[derive(Debug, Default)]
+struct Stats {
+    pages: usize,
+    words: usize,
+    // Posts per year, taken from the `date` in their front matter.
+    years: BTreeMap<String, usize>,
+    tags: BTreeMap<String, usize>,
+}
+
+impl Stats {
+    fn add_page(&mut self, markdown: &str) {
+        self.pages += 1;
+        self.words += markgem::strip_matter(markdown).split_whitespace().count();
+        if let Some(date) = markgem::front_matter_value(markdown, "date") {
+            if date.len() >= 4 && date[..4].chars().all(|c| c.is_ascii_digit()) {
+                *self.years.entry(date[..4].to_owned()).or_default() += 1;
+            }
+        }
+        for tag in markgem::front_matter_list(markdown, "tags") {
+            *self.tags.entry(tag.to_owned()).or_default() += 1;
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# Statistics\n");
+        let _ = writeln!(out, "* {} pages", self.pages);
+        let _ = writeln!(out, "* {} words", self.words);
+        if !self.years.is_empty() {
+            let _ = writeln!(out, "\n## Posts per year\n");
+            let _ = writeln!(out, "```posts per year");
+            let most = self.years.values().copied().max().unwrap_or(1);
+            for (year, &count) in &self.years {
+                let bar = (count * MAX_BAR + most - 1) / most;
+                let _ = writeln!(out, "{} {} {}", year, "█".repeat(bar), count);
+            }
+            let _ = writeln!(out, "```");
+        }
+        if !self.tags.is_empty() {
+            let _ = writeln!(out, "\n## Tags\n");
+            let mut tags: Vec<_> = self.tags.iter().collect();
+            tags.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (tag, count) in tags {
+                let _ = writeln!(out, "* {} ({})", tag, count);
+            }
+        }
+        out
+    }
+}
+
+pub fn stats(options: StatsOpt) -> Result<()> {
+    let mut stats = Stats::default();
+    collect(&options.root, &mut stats)?;
+    let page = stats.render();
+    match &options.output {
+        Some(output) => std::fs::write(output, page)
+            .with_context(|| format!("failed to write {}", output.display()))?,
+        None => print!("{}", page),
+    }
+    Ok(())
+}
+
+fn collect(dir: &Path, stats: &mut Stats) -> Result<()> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect(&path, stats)?;
+        } else if path.extension() == Some(OsStr::new("md")) {
+            let markdown = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            stats.add_page(&markdown);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn render() {
+        let mut stats = Stats::default();
+        stats.add_page("+++\ndate = 2019-05-01\ntags = [\"rust\", \"gemini\"]\n+++\none two");
+        stats.add_page("+++\ndate = \"2020-01-01\"\ntags = [\"gemini\"]\n+++\nthree");
+        stats.add_page("+++\ndate = 2020-02-01\n+++\nfour five six");
+        stats.add_page("no front matter");
+        assert_eq!(
+            stats.render(),
+            indoc!(
+                "
+                # Statistics
+
+                * 4 pages
+                * 9 words
+
+                ## Posts per year
+
+                ```posts per year
+                2019 ████████████████████ 1
+                2020 ████████████████████████████████████████ 2
+                ```
+
+                ## Tags
+
+                * gemini (2)
+                * rust (1)
+                "
+            )
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! Turning errors into Gemini status codes. Without a response header, clients can't tell a
+//! missing page from a network failure.
+
+use anyhow::Error;
+use async_std::io::Write;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// An error that should be reported to the client with the given status and meta.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusError {
+    pub code: u8,
+    pub meta: String,
+}
+
+impl StatusError {
+    pub fn new(code: u8, meta: impl Into<String>) -> Self {
+        Self {
+            code,
+            meta: meta.into(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(51, "Not found")
+    }
+
+    pub fn bad_request() -> Self {
+        Self::new(59, "Bad request")
+    }
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.meta)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// The status to answer with after the error. Files that don't exist are `51`, files that can't
+/// be read as text are `50`, and anything else is assumed to be temporary.
+pub fn status_for(error: &Error) -> StatusError {
+    if let Some(status) = error.downcast_ref::<StatusError>() {
+        return status.clone();
+    }
+    let io_error = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<io::Error>());
+    match io_error.map(io::Error::kind) {
+        Some(io::ErrorKind::NotFound) => StatusError::not_found(),
+        Some(io::ErrorKind::InvalidData) => StatusError::new(50, "Permanent failure"),
+        _ => StatusError::new(40, "Temporary failure"),
+    }
+}
+
+/// Passes writes through to the inner writer, noting whether anything was written. Once the
+/// header has gone out, an error can't be reported with a status any more.
+pub struct Started<W> {
+    inner: W,
+    started: bool,
+}
+
+impl<W> Started<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            started: false,
+        }
+    }
+
+    pub fn started(&self) -> bool {
+        self.started
+    }
+}
+
+impl<W: Write + Unpin> Write for Started<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.started |= written > 0;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn statuses() {
+        let io_error = |kind| Error::new(io::Error::new(kind, "oops"));
+        assert_eq!(
+            status_for(&io_error(io::ErrorKind::NotFound)),
+            StatusError::not_found()
+        );
+        assert_eq!(status_for(&io_error(io::ErrorKind::InvalidData)).code, 50);
+        assert_eq!(status_for(&io_error(io::ErrorKind::Other)).code, 40);
+        let wrapped = Err::<(), _>(io::Error::from(io::ErrorKind::NotFound))
+            .context("failed to read")
+            .unwrap_err();
+        assert_eq!(status_for(&wrapped).code, 51);
+        assert_eq!(
+            status_for(&Error::new(StatusError::new(53, "No proxying"))).code,
+            53
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! Socket activation: systemd binds the port itself and hands the listening sockets over, so the
+//! server needn't be allowed to bind port 1965 and can be started when the first client connects.
+//! The sockets are passed as file descriptors from 3 on, with `LISTEN_FDS` saying how many and
+//! `LISTEN_PID` which process they're for.
+
+use anyhow::Result;
+use async_std::net::TcpListener;
+
+/// The first file descriptor systemd passes.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Whether systemd passed this process any sockets.
+pub fn activated() -> bool {
+    passed().is_some()
+}
+
+/// How many sockets systemd passed this process.
+fn passed() -> Option<i32> {
+    count(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+fn count(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Option<i32> {
+    if listen_pid?.parse::<u32>().ok()? != pid {
+        return None;
+    }
+    listen_fds?.parse().ok().filter(|&count| count > 0)
+}
+
+/// Takes the sockets systemd passed this process, which must all be listening TCP sockets. The
+/// environment variables are removed so that CGI scripts don't think the sockets are theirs.
+#[cfg(unix)]
+pub fn take_listeners() -> Result<Vec<TcpListener>> {
+    use anyhow::Context;
+    use std::os::unix::io::FromRawFd;
+
+    let count = match passed() {
+        Some(count) => count,
+        None => return Ok(vec![]),
+    };
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // Nor should CGI scripts inherit them.
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("bad socket {} from systemd", fd));
+            }
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener
+                .local_addr()
+                .with_context(|| format!("socket {} from systemd isn't a TCP socket", fd))?;
+            Ok(TcpListener::from(listener))
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn take_listeners() -> Result<Vec<TcpListener>> {
+    Ok(vec![])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        assert_eq!(count(Some("42"), Some("2"), 42), Some(2));
+        // The sockets were meant for another process, like the parent of a CGI script.
+        assert_eq!(count(Some("41"), Some("2"), 42), None);
+        assert_eq!(count(Some("42"), Some("0"), 42), None);
+        assert_eq!(count(None, Some("1"), 42), None);
+        assert_eq!(count(Some("42"), None, 42), None);
+    }
+}
@@ -0,0 +1,162 @@
+//! Support for end-to-end tests, which run a real server on an ephemeral port and talk to it with
+//! the fetcher. Other crates can use it through the `testing` feature.
+
+use crate::fetch::Fetcher;
+use crate::serve::{self, ServeOpt};
+use anyhow::Result;
+use async_std::future;
+use async_std::net::TcpListener;
+use async_std::task::{self, JoinHandle};
+use futures::channel::oneshot;
+use rustls::{internal::pemfile, Certificate, PrivateKey};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// A self-signed certificate for `localhost`, and its key.
+const CERT: &str = include_str!("../tests/data/localhost.crt");
+const KEY: &str = include_str!("../tests/data/localhost.key");
+
+/// The test certificate and its key, for use as a client certificate.
+pub fn identity() -> (Vec<Certificate>, PrivateKey) {
+    let certs = pemfile::certs(&mut CERT.as_bytes()).unwrap();
+    let mut keys = pemfile::pkcs8_private_keys(&mut KEY.as_bytes()).unwrap();
+    (certs, keys.remove(0))
+}
+
+/// Distinguishes the directories of servers started by the same process.
+static SERVERS: AtomicUsize = AtomicUsize::new(0);
+
+/// A server running in the background, serving a temporary directory. The directory is removed
+/// when this is dropped, but the server keeps running until the process exits unless it's shut
+/// down.
+pub struct TestServer {
+    dir: PathBuf,
+    port: u16,
+    server: Option<JoinHandle<Result<()>>>,
+    shutdown: Option<oneshot::Sender<()>>,
+    // Tests make many requests in a row, so there's no point waiting between them.
+    fetcher: Fetcher,
+}
+
+impl TestServer {
+    /// Starts a server whose root holds the given pages, given as paths and contents. The
+    /// arguments are passed on as though they were given to `exarch serve`, with `{dir}` replaced
+    /// by the server's directory.
+    pub async fn start(pages: &[(&str, &str)], args: &[&str]) -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!(
+            "exarch-test-{}-{}",
+            std::process::id(),
+            SERVERS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let root = dir.join("root");
+        std::fs::create_dir_all(&root)?;
+        for (path, contents) in pages {
+            let path = root.join(path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, contents)?;
+        }
+        std::fs::write(dir.join("cert.pem"), CERT)?;
+        std::fs::write(dir.join("key.pem"), KEY)?;
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let port = listener.local_addr()?.port();
+        let dir_arg = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let mut full_args = vec![
+            "serve".to_owned(),
+            "--cert".to_owned(),
+            dir_arg("cert.pem"),
+            "--key".to_owned(),
+            dir_arg("key.pem"),
+            dir_arg("root"),
+        ];
+        let dir_str = dir.to_string_lossy();
+        full_args.extend(args.iter().map(|arg| arg.replace("{dir}", &dir_str)));
+        let matches = ServeOpt::clap().get_matches_from_safe(full_args)?;
+        let mut options = ServeOpt::from_clap(&matches);
+        options.apply_config(&matches)?;
+        let (shutdown, signalled) = oneshot::channel();
+        let server = task::spawn(serve::serve_on(vec![listener], options, async {
+            // Dropping the sender without shutting down leaves the server running.
+            if signalled.await.is_err() {
+                future::pending::<()>().await;
+            }
+        }));
+        let fetcher = Fetcher::new().politeness(Duration::from_secs(0));
+        Ok(Self {
+            dir,
+            port,
+            server: Some(server),
+            shutdown: Some(shutdown),
+            fetcher,
+        })
+    }
+
+    /// Shuts the server down as a signal would, waiting for it to stop.
+    pub async fn shutdown(&mut self) -> Result<()> {
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        match self.server.take() {
+            Some(server) => server.await,
+            None => Ok(()),
+        }
+    }
+
+    /// The port the server is listening on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The directory holding the server's root, certificate and key.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Sends a raw request, returning the raw response.
+    pub async fn request(&self, request: &str) -> Result<Vec<u8>> {
+        self.fetcher
+            .request("localhost", self.port, request.as_bytes())
+            .await
+    }
+
+    /// Like `request`, but presenting the given client certificate and key, and returning the
+    /// response as a string.
+    pub async fn request_as(
+        &self,
+        request: &[u8],
+        identity: (Vec<Certificate>, PrivateKey),
+    ) -> Result<String> {
+        let (certs, key) = identity;
+        let fetcher = Fetcher::new().identity(certs, key)?;
+        let response = fetcher.request("localhost", self.port, request).await?;
+        Ok(String::from_utf8(response)?)
+    }
+
+    /// Like `get`, but presenting the given client certificate and key.
+    pub async fn get_as(
+        &self,
+        path: &str,
+        identity: (Vec<Certificate>, PrivateKey),
+    ) -> Result<String> {
+        let request = format!("gemini://localhost{}\r\n", path);
+        self.request_as(request.as_bytes(), identity).await
+    }
+
+    /// Requests the path, returning the response as a string.
+    pub async fn get(&self, path: &str) -> Result<String> {
+        let response = self
+            .request(&format!("gemini://localhost{}\r\n", path))
+            .await?;
+        Ok(String::from_utf8(response)?)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
@@ -0,0 +1,183 @@
+//! Themes: ready-made choices of how pages and listings look, so that a new capsule has a tidy
+//! structure without going through every converter flag. A theme can be forked into a site to
+//! change it, and given to `serve --theme` by its path instead of its name.
+
+use crate::config::Theme;
+use anyhow::{bail, Context, Result};
+use indoc::indoc;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+/// The built-in themes, by name.
+const BUILT_IN: &[(&str, &str)] = &[
+    (
+        "plain",
+        indoc!(
+            r#"
+            description = "Pages as they're written, with nothing added."
+            emphasis = "markers"
+            admonition_style = "emoji"
+            heading_overflow = "clamp"
+            listing = "detailed"
+            "#
+        ),
+    ),
+    (
+        "journal",
+        indoc!(
+            r#"
+            description = "For a gemlog: each post under its title and date, with a way home."
+            title = true
+            date = true
+            emphasis = "markers"
+            admonition_style = "emoji"
+            heading_overflow = "bold"
+            listing = "detailed"
+            footer = "=> / ☙ Back to the front page"
+            "#
+        ),
+    ),
+    (
+        "wiki",
+        indoc!(
+            r#"
+            description = "For reference pages: numbered sections, a table of contents, and where links lead."
+            toc = true
+            number_headings = true
+            link_hosts = true
+            emphasis = "markers"
+            admonition_style = "label"
+            heading_overflow = "depth"
+            listing = "compact"
+            footer = "=> / Wiki index"
+            "#
+        ),
+    ),
+    (
+        "minimal",
+        indoc!(
+            r#"
+            description = "Plain text, with no Markdown markers or emoji."
+            emphasis = "strip"
+            admonition_style = "label"
+            heading_overflow = "clamp"
+            listing = "compact"
+            "#
+        ),
+    ),
+];
+
+/// The file a theme is forked into.
+const FORKED_NAME: &str = "theme.toml";
+
+#[derive(Debug, StructOpt)]
+pub struct ThemeOpt {
+    #[structopt(subcommand)]
+    command: ThemeCommand,
+}
+
+#[derive(Debug, StructOpt)]
+enum ThemeCommand {
+    /// List the built-in themes.
+    List,
+    /// Copy a built-in theme into a directory as theme.toml, to change it and use it with
+    /// `serve --theme DIR/theme.toml`.
+    Fork {
+        name: String,
+
+        /// The directory to copy the theme into, like the site's.
+        #[structopt(parse(from_os_str), default_value = ".")]
+        dir: PathBuf,
+    },
+}
+
+pub fn theme(options: ThemeOpt) -> Result<()> {
+    match options.command {
+        ThemeCommand::List => {
+            for (name, text) in BUILT_IN {
+                let description = Theme::parse(text)?.description.unwrap_or_default();
+                println!("{:<8} {}", name, description);
+            }
+            Ok(())
+        }
+        ThemeCommand::Fork { name, dir } => {
+            let path = fork(&name, &dir)?;
+            println!("Wrote {}", path.display());
+            Ok(())
+        }
+    }
+}
+
+/// Loads a built-in theme by its name, or a theme file by its path.
+pub fn load(theme: &str) -> Result<Theme> {
+    if let Some(text) = built_in(theme) {
+        return Theme::parse(text);
+    }
+    if !theme.ends_with(".toml") {
+        bail!(
+            "unknown theme {}; the built-in ones are {}",
+            theme,
+            names().join(", ")
+        );
+    }
+    let text =
+        std::fs::read_to_string(theme).with_context(|| format!("failed to read {}", theme))?;
+    Theme::parse(&text).with_context(|| format!("failed to load {}", theme))
+}
+
+fn built_in(name: &str) -> Option<&'static str> {
+    BUILT_IN
+        .iter()
+        .find(|(built_in, _)| *built_in == name)
+        .map(|(_, text)| *text)
+}
+
+fn names() -> Vec<&'static str> {
+    BUILT_IN.iter().map(|(name, _)| *name).collect()
+}
+
+/// Writes a built-in theme into the directory, unless there's a theme there already.
+fn fork(name: &str, dir: &Path) -> Result<PathBuf> {
+    let text = match built_in(name) {
+        Some(text) => text,
+        None => bail!(
+            "unknown theme {}; the built-in ones are {}",
+            name,
+            names().join(", ")
+        ),
+    };
+    let path = dir.join(FORKED_NAME);
+    if path.exists() {
+        bail!("{} already exists", path.display());
+    }
+    let text = format!("# Forked from the built-in {} theme.\n{}", name, text);
+    std::fs::write(&path, text).with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn built_in_themes_load() -> Result<()> {
+        for name in names() {
+            let theme = load(name).with_context(|| format!("in {}", name))?;
+            assert!(theme.description.is_some());
+        }
+        assert!(load("fancy").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn forks() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("exarch-theme-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = fork("wiki", &dir)?;
+        let forked = load(&path.to_string_lossy())?;
+        assert_eq!(forked, load("wiki")?);
+        assert!(fork("wiki", &dir).is_err());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
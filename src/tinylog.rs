@@ -0,0 +1,83 @@
+//! Support for tinylogs, single pages of short timestamped posts, newest first. Each entry is a
+//! `## 2020-07-14 08:05 UTC` heading followed by its text, which is the format tinylog
+//! aggregators expect.
+
+use anyhow::{bail, Context, Result};
+use exarch::atomic;
+use exarch::date;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct TinyOpt {
+    /// The text of the entry.
+    text: String,
+
+    /// The tinylog to add the entry to. It's created if it doesn't exist.
+    #[structopt(short, long, parse(from_os_str), default_value = "tinylog.gmi")]
+    file: PathBuf,
+
+    /// The title to give the tinylog when creating it.
+    #[structopt(long, default_value = "Tinylog")]
+    title: String,
+}
+
+pub fn tiny(options: TinyOpt) -> Result<()> {
+    if options.text.trim().is_empty() {
+        bail!("entries can't be empty");
+    }
+    let log = match std::fs::read_to_string(&options.file) {
+        Ok(log) => log,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => format!("# {}\n", options.title),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read {}", options.file.display()))
+        }
+    };
+    let stamp = date::format_timestamp(date::unix_seconds());
+    atomic::write(&options.file, add_entry(&log, &stamp, &options.text))
+        .with_context(|| format!("failed to write {}", options.file.display()))
+}
+
+/// Adds an entry above the existing ones, keeping the header above the first entry in place.
+fn add_entry(log: &str, stamp: &str, text: &str) -> String {
+    let first_entry = log
+        .match_indices("## ")
+        .map(|(i, _)| i)
+        .find(|&i| i == 0 || log[..i].ends_with('\n'));
+    let (header, entries) = log.split_at(first_entry.unwrap_or(log.len()));
+    let mut out = header.trim_end().to_owned();
+    out.push_str(&format!("\n\n## {}\n{}\n", stamp, text.trim()));
+    if !entries.is_empty() {
+        out.push('\n');
+        out.push_str(entries);
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn entries() {
+        let log = add_entry("# Tinylog\nauthor: @me\n", "2020-07-14 08:05 UTC", "first");
+        let log = add_entry(&log, "2020-07-15 09:00 UTC", " second\nline ");
+        assert_eq!(
+            log,
+            indoc!(
+                "
+                # Tinylog
+                author: @me
+
+                ## 2020-07-15 09:00 UTC
+                second
+                line
+
+                ## 2020-07-14 08:05 UTC
+                first
+                "
+            )
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Uploads over Titan, Gemini's sibling protocol for sending content to a server. A request is a
+//! `titan://` URL whose path ends with parameters, followed by the content itself:
+//!
+//! ```text
+//! titan://example.org/notes/today.md;size=12;mime=text/markdown;token=hunter2\r\n
+//! # Hi there!
+//! ```
+//!
+//! `size` is required and gives the length of the content in bytes. A size of 0 deletes the
+//! file. `mime` and `token` are optional.
+
+use crate::mime::FileType;
+use crate::status::StatusError;
+use anyhow::{anyhow, Result};
+use async_std::io::prelude::*;
+use percent_encoding::percent_decode_str;
+use url::Url;
+
+/// An upload, as described by the URL it was requested with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upload {
+    /// The Gemini URL of the page being uploaded.
+    pub url: Url,
+    pub size: usize,
+    pub mime: Option<String>,
+    pub token: Option<String>,
+}
+
+impl Upload {
+    /// Whether the upload deletes the page rather than replacing it.
+    pub fn is_delete(&self) -> bool {
+        self.size == 0
+    }
+
+    /// Whether the MIME type the client gave, if any, fits a file of the given type. Markdown
+    /// pages take any text.
+    pub fn mime_fits(&self, file_type: FileType) -> bool {
+        let mime = match &self.mime {
+            Some(mime) => essence(mime),
+            None => return true,
+        };
+        match file_type {
+            FileType::Markdown => mime.starts_with("text/"),
+            FileType::Raw(expected) => mime.eq_ignore_ascii_case(essence(expected)),
+        }
+    }
+}
+
+/// The MIME type without its parameters.
+fn essence(mime: &str) -> &str {
+    mime.split(';').next().unwrap_or_default().trim()
+}
+
+/// Parses a Titan URL. Fails with `59` if the parameters are missing or malformed.
+pub fn parse(url: &Url) -> Result<Upload, StatusError> {
+    let invalid = |detail: &str| StatusError::new(59, format!("Bad Titan request: {}", detail));
+    let mut parts = url.path().split(';');
+    let path = parts.next().unwrap_or_default();
+    let (mut size, mut mime, mut token) = (None, None, None);
+    for parameter in parts {
+        let mut parameter = parameter.splitn(2, '=');
+        let (name, value) = match (parameter.next(), parameter.next()) {
+            (Some(name), Some(value)) => (name, value),
+            _ => return Err(invalid("expected NAME=VALUE parameters")),
+        };
+        let value = percent_decode_str(value)
+            .decode_utf8()
+            .map_err(|_| invalid("parameter isn't UTF-8"))?
+            .into_owned();
+        match name {
+            "size" => size = Some(value.parse().map_err(|_| invalid("invalid size"))?),
+            "mime" => mime = Some(value),
+            "token" => token = Some(value),
+            // Parameters from later versions of the protocol can safely be ignored.
+            _ => {}
+        }
+    }
+    let size = size.ok_or_else(|| invalid("no size"))?;
+    let mut gemini_url = url.clone();
+    gemini_url
+        .set_scheme("gemini")
+        .map_err(|_| invalid("can't be a Gemini URL"))?;
+    gemini_url.set_path(path);
+    Ok(Upload {
+        url: gemini_url,
+        size,
+        mime,
+        token,
+    })
+}
+
+/// Reads the content of the upload: whatever was received along with the request, followed by
+/// the rest from the stream.
+pub async fn read_content<R: Read + Unpin>(
+    upload: &Upload,
+    mut received: Vec<u8>,
+    mut stream: R,
+) -> Result<Vec<u8>> {
+    if received.len() > upload.size {
+        return Err(anyhow!(StatusError::bad_request()).context("more content than its size"));
+    }
+    let start = received.len();
+    received.resize(upload.size, 0);
+    stream
+        .read_exact(&mut received[start..])
+        .await
+        .map_err(|error| anyhow!(StatusError::bad_request()).context(error))?;
+    Ok(received)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_url() -> Result<()> {
+        let url = Url::parse("titan://localhost/a%20b.md;size=12;mime=text/markdown;token=a%3Bb")?;
+        assert_eq!(
+            parse(&url)?,
+            Upload {
+                url: Url::parse("gemini://localhost/a%20b.md")?,
+                size: 12,
+                mime: Some("text/markdown".to_owned()),
+                token: Some("a;b".to_owned()),
+            }
+        );
+        let status = |url| {
+            parse(&Url::parse(url).unwrap())
+                .map(|_| ())
+                .map_err(|e| e.code)
+        };
+        assert_eq!(status("titan://localhost/a.md;size=0;new=1"), Ok(()));
+        assert_eq!(status("titan://localhost/a.md"), Err(59));
+        assert_eq!(status("titan://localhost/a.md;size=big"), Err(59));
+        assert_eq!(status("titan://localhost/a.md;size"), Err(59));
+        Ok(())
+    }
+
+    #[test]
+    fn mime_fits() {
+        let upload = |mime| {
+            let url = Url::parse(&format!("titan://localhost/a;size=1{}", mime)).unwrap();
+            parse(&url).unwrap()
+        };
+        assert!(upload("").mime_fits(FileType::Raw("image/png")));
+        assert!(upload(";mime=text/plain").mime_fits(FileType::Markdown));
+        assert!(!upload(";mime=image/png").mime_fits(FileType::Markdown));
+        assert!(upload(";mime=text/gemini").mime_fits(FileType::Raw("text/gemini; lang=en")));
+        assert!(!upload(";mime=text/plain").mime_fits(FileType::Raw("text/gemini")));
+    }
+}
@@ -0,0 +1,281 @@
+//! The TLS layer, kept behind a trait so that the rest of the server doesn't depend on which TLS
+//! library is in use, or which version of it.
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_std::io::{Read, Write};
+use async_tls::TlsAcceptor;
+use futures::future::BoxFuture;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    internal::pemfile, Certificate, ClientCertVerified, ClientCertVerifier, ClientHello,
+    DistinguishedNames, NoClientAuth, NoServerSessionStorage, PrivateKey, ResolvesServerCert,
+    ServerConfig, TLSError,
+};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+pub use crate::known_hosts::fingerprint;
+
+/// A connection from a client, over TCP or a Unix socket, before or after its TLS handshake.
+pub trait Connection: Read + Write + Unpin + Send {}
+
+impl<T: Read + Write + Unpin + Send> Connection for T {}
+
+/// A connection along with the certificate the client presented, if it presented one.
+pub struct Accepted {
+    pub stream: Box<dyn Connection>,
+    pub client_certificate: Option<Certificate>,
+}
+
+/// Performs the server side of TLS handshakes.
+pub trait Acceptor: Send + Sync {
+    fn accept(&self, stream: Box<dyn Connection>) -> BoxFuture<'_, io::Result<Accepted>>;
+
+    /// Loads the certificates again if their files have changed since they were last loaded,
+    /// returning whether they had. Connections accepted afterwards get the new certificates.
+    fn reload(&self) -> Result<bool>;
+}
+
+/// An acceptor using rustls.
+pub struct RustlsAcceptor {
+    config: Arc<ServerConfig>,
+    client_certificates: bool,
+    resolver: Arc<ReloadingResolver>,
+}
+
+impl RustlsAcceptor {
+    /// Creates an acceptor serving the certificate chain and PKCS #8 key in the given PEM files.
+    /// Clients that ask for one of the virtual hosts with SNI get its certificate instead; they're
+    /// given as the ASCII hostname, the certificate and the key.
+    pub fn from_files(
+        cert: &Path,
+        key: &Path,
+        virtual_hosts: &[(&str, &Path, &Path)],
+    ) -> Result<Self> {
+        let files = CertificateFiles {
+            default: (cert.to_owned(), key.to_owned()),
+            hosts: virtual_hosts
+                .iter()
+                .map(|(hostname, cert, key)| {
+                    (
+                        hostname.to_ascii_lowercase(),
+                        (cert.to_path_buf(), key.to_path_buf()),
+                    )
+                })
+                .collect(),
+        };
+        let modified = files.modified();
+        let resolver = Arc::new(ReloadingResolver {
+            current: RwLock::new(Arc::new(files.load()?)),
+            modified: Mutex::new(modified),
+            files,
+        });
+        let mut server_config = ServerConfig::new(NoClientAuth::new());
+        server_config.cert_resolver = resolver.clone();
+        Ok(Self {
+            config: Arc::new(server_config),
+            client_certificates: false,
+            resolver,
+        })
+    }
+
+    /// Asks clients for a certificate. Gemini client certificates are usually self-signed, so
+    /// any certificate is accepted as long as the client holds its key; it's up to the server to
+    /// decide what it's allowed to see.
+    pub fn request_client_certificates(mut self) -> Self {
+        self.client_certificates = true;
+        self
+    }
+
+    /// The configuration for a handshake, and where the client's certificate will be put.
+    fn handshake_config(&self) -> (Arc<ServerConfig>, Option<Arc<CapturedCertificate>>) {
+        if !self.client_certificates {
+            return (self.config.clone(), None);
+        }
+        // async-tls doesn't give access to the session once the handshake is done, so the
+        // verifier keeps the certificate for us. That means each connection needs its own.
+        let captured = Arc::new(CapturedCertificate::default());
+        let mut config = ServerConfig::new(captured.clone());
+        config.cert_resolver = self.config.cert_resolver.clone();
+        // A resumed session skips the verifier, which would lose the certificate.
+        config.session_storage = Arc::new(NoServerSessionStorage {});
+        (Arc::new(config), Some(captured))
+    }
+}
+
+impl Acceptor for RustlsAcceptor {
+    fn accept(&self, stream: Box<dyn Connection>) -> BoxFuture<'_, io::Result<Accepted>> {
+        Box::pin(async move {
+            let (config, captured) = self.handshake_config();
+            let stream = TlsAcceptor::from(config).accept(stream).await?;
+            let client_certificate = captured.and_then(|captured| captured.take());
+            Ok(Accepted {
+                stream: Box::new(stream),
+                client_certificate,
+            })
+        })
+    }
+
+    fn reload(&self) -> Result<bool> {
+        self.resolver.reload()
+    }
+}
+
+/// Where the certificates and keys are loaded from.
+struct CertificateFiles {
+    default: (PathBuf, PathBuf),
+    // By lowercase ASCII hostname.
+    hosts: HashMap<String, (PathBuf, PathBuf)>,
+}
+
+impl CertificateFiles {
+    fn load(&self) -> Result<SniResolver> {
+        let (cert, key) = &self.default;
+        let mut resolver = SniResolver {
+            default: certified_key(cert, key)?,
+            hosts: HashMap::new(),
+        };
+        for (hostname, (cert, key)) in &self.hosts {
+            resolver
+                .hosts
+                .insert(hostname.clone(), certified_key(cert, key)?);
+        }
+        Ok(resolver)
+    }
+
+    /// When each file was last modified, in a consistent order. Files that can't be read are left
+    /// out, so that one reappearing counts as a change.
+    fn modified(&self) -> Vec<(PathBuf, SystemTime)> {
+        let mut pairs: Vec<_> = self.hosts.values().collect();
+        pairs.sort();
+        std::iter::once(&self.default)
+            .chain(pairs)
+            .flat_map(|(cert, key)| vec![cert, key])
+            .filter_map(|path| {
+                let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+                Some((path.clone(), modified))
+            })
+            .collect()
+    }
+}
+
+/// An `SniResolver` that can be replaced with one loaded from renewed certificates while
+/// handshakes are going on. Handshakes that have already started finish with the old one.
+struct ReloadingResolver {
+    files: CertificateFiles,
+    current: RwLock<Arc<SniResolver>>,
+    modified: Mutex<Vec<(PathBuf, SystemTime)>>,
+}
+
+impl ReloadingResolver {
+    fn reload(&self) -> Result<bool> {
+        let modified = self.files.modified();
+        let mut last_modified = self.modified.lock().map_err(|_| anyhow!("lock poisoned"))?;
+        if *last_modified == modified {
+            return Ok(false);
+        }
+        // Only remember the change once it's loaded, so that a certificate that was half written
+        // is tried again.
+        let resolver = self.files.load()?;
+        *self.current.write().map_err(|_| anyhow!("lock poisoned"))? = Arc::new(resolver);
+        *last_modified = modified;
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let current = self.current.read().ok()?.clone();
+        current.resolve(client_hello)
+    }
+}
+
+/// Picks the certificate for the hostname the client asked for with SNI. Clients that didn't ask
+/// for one of the virtual hosts get the default certificate.
+struct SniResolver {
+    default: CertifiedKey,
+    // By lowercase ASCII hostname.
+    hosts: HashMap<String, CertifiedKey>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let requested = client_hello
+            .server_name()
+            .map(|name| <&str>::from(name).to_ascii_lowercase());
+        let key = requested
+            .and_then(|name| self.hosts.get(&name))
+            .unwrap_or(&self.default);
+        Some(key.clone())
+    }
+}
+
+/// Loads a certificate chain and its key, ready to be served.
+fn certified_key(cert: &Path, key: &Path) -> Result<CertifiedKey> {
+    let (certs, key) = load_certificate(cert, key)?;
+    let signing_key = sign::any_supported_type(&key)
+        .map_err(|_| anyhow!("failed to use certificate: unsupported key type"))?;
+    Ok(CertifiedKey::new(certs, Arc::new(signing_key)))
+}
+
+/// Accepts any client certificate, or none, keeping the one presented.
+#[derive(Default)]
+struct CapturedCertificate(Mutex<Option<Certificate>>);
+
+impl CapturedCertificate {
+    fn take(&self) -> Option<Certificate> {
+        self.0.lock().ok()?.take()
+    }
+}
+
+impl ClientCertVerifier for CapturedCertificate {
+    fn client_auth_mandatory(&self, _sni: Option<&webpki::DNSName>) -> Option<bool> {
+        Some(false)
+    }
+
+    fn client_auth_root_subjects(
+        &self,
+        _sni: Option<&webpki::DNSName>,
+    ) -> Option<DistinguishedNames> {
+        Some(DistinguishedNames::new())
+    }
+
+    // rustls still checks that the client can sign with the certificate's key.
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+        _sni: Option<&webpki::DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        if let (Some(cert), Ok(mut captured)) = (presented_certs.first(), self.0.lock()) {
+            *captured = Some(cert.clone());
+        }
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// Loads the certificate chain and its private key.
+pub fn load_certificate(cert: &Path, key: &Path) -> Result<(Vec<Certificate>, PrivateKey)> {
+    let certs = File::open(cert)
+        .context("failed to open certificate")
+        .and_then(|cert| {
+            pemfile::certs(&mut BufReader::new(cert))
+                .map_err(|_| anyhow!("certificate decoding error"))
+        })?;
+    if certs.is_empty() {
+        bail!("no certificates in {}", cert.display());
+    }
+    let mut keys = File::open(key)
+        .context("failed to open keyfile")
+        .and_then(|key| {
+            pemfile::pkcs8_private_keys(&mut BufReader::new(key))
+                .map_err(|_| anyhow!("keyfile decoding error"))
+        })?;
+    if keys.is_empty() {
+        bail!("no PKCS #8 keys in {}", key.display());
+    }
+    Ok((certs, keys.remove(0)))
+}
@@ -0,0 +1,173 @@
+//! Managing the known hosts that outbound requests check certificates against, so that a capsule
+//! changing its certificate can be looked into and trusted rather than silently accepted.
+
+use anyhow::{anyhow, Context, Result};
+use async_std::task;
+use exarch::atomic;
+use exarch::fetch;
+use exarch::known_hosts::KnownHosts;
+use exarch::resolve;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use structopt::StructOpt;
+
+/// Where the known hosts are kept.
+#[derive(Debug, StructOpt)]
+pub struct KnownHostsFlags {
+    /// The file of known hosts and their certificates' fingerprints. Defaults to
+    /// exarch/known_hosts in the user's data directory.
+    #[structopt(long = "known-hosts", parse(from_os_str))]
+    path: Option<PathBuf>,
+}
+
+impl KnownHostsFlags {
+    fn path(&self) -> Result<PathBuf> {
+        if let Some(path) = &self.path {
+            return Ok(path.clone());
+        }
+        let data = std::env::var_os("XDG_DATA_HOME")
+            .filter(|data| !data.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share"))
+            })
+            .ok_or_else(|| anyhow!("can't find the data directory, so give --known-hosts"))?;
+        Ok(data.join("exarch").join("known_hosts"))
+    }
+
+    /// Loads the known hosts. If the file doesn't exist yet, no hosts are known.
+    pub fn load(&self) -> Result<KnownHosts> {
+        let path = self.path()?;
+        match std::fs::read_to_string(&path) {
+            Ok(data) => KnownHosts::parse(&data)
+                .with_context(|| format!("failed to parse {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(KnownHosts::default()),
+            Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, known_hosts: &KnownHosts) -> Result<()> {
+        let path = self.path()?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create {}", parent.display()))?;
+        }
+        atomic::write(&path, known_hosts.to_string())
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    /// Loads the known hosts to be shared with a fetcher, which adds to them as it meets new
+    /// hosts. They should be saved afterwards with `save_shared`.
+    pub fn load_shared(&self) -> Result<Arc<Mutex<KnownHosts>>> {
+        Ok(Arc::new(Mutex::new(self.load()?)))
+    }
+
+    pub fn save_shared(&self, known_hosts: &Mutex<KnownHosts>) -> Result<()> {
+        let known_hosts = known_hosts
+            .lock()
+            .map_err(|_| anyhow!("known hosts are unavailable"))?;
+        self.save(&known_hosts)
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct TofuOpt {
+    #[structopt(flatten)]
+    known_hosts: KnownHostsFlags,
+
+    #[structopt(subcommand)]
+    command: TofuCommand,
+}
+
+#[derive(Debug, StructOpt)]
+enum TofuCommand {
+    /// List the known hosts and the fingerprints of their certificates.
+    List,
+    /// Trust the certificate a host presents now, or the one with the given fingerprint.
+    Trust {
+        host: String,
+
+        /// The SHA-256 fingerprint of the certificate to trust.
+        #[structopt(long)]
+        fingerprint: Option<String>,
+
+        /// The port to connect to to find the host's certificate.
+        #[structopt(short, long, default_value = "1965")]
+        port: u16,
+    },
+    /// Forget a host, so that the next certificate it presents is trusted.
+    Forget { host: String },
+}
+
+pub fn tofu(options: TofuOpt) -> Result<()> {
+    let mut known_hosts = options.known_hosts.load()?;
+    match options.command {
+        TofuCommand::List => {
+            print!("{}", known_hosts);
+            return Ok(());
+        }
+        TofuCommand::Trust {
+            host,
+            fingerprint,
+            port,
+        } => {
+            let host = resolve::ascii_host(&host)?;
+            let fingerprint = match fingerprint {
+                Some(fingerprint) => fingerprint.replace(':', ""),
+                None => task::block_on(fetch::server_fingerprint(&host, port))?,
+            };
+            if let Some(known) = known_hosts.get(&host) {
+                println!("{} was known with {}", host, known);
+            }
+            known_hosts.trust(&host, &fingerprint);
+            println!(
+                "trusting {} with {}",
+                host,
+                fingerprint.to_ascii_lowercase()
+            );
+        }
+        TofuCommand::Forget { host } => {
+            let host = resolve::ascii_host(&host)?;
+            if !known_hosts.forget(&host) {
+                println!("{} wasn't known", host);
+                return Ok(());
+            }
+        }
+    }
+    options.known_hosts.save(&known_hosts)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use exarch::fetch::Fetcher;
+    use exarch::testing::TestServer;
+    use std::time::Duration;
+    use url::Url;
+
+    #[test]
+    fn trust_on_first_use() -> Result<()> {
+        task::block_on(async {
+            let server = TestServer::start(&[("page.md", "hi")], &[]).await?;
+            let known_hosts = Arc::new(Mutex::new(KnownHosts::default()));
+            let fetcher = || {
+                Fetcher::new()
+                    .politeness(Duration::from_secs(0))
+                    .known_hosts(known_hosts.clone())
+            };
+            let url = Url::parse(&format!("gemini://localhost:{}/page.md", server.port()))?;
+            assert_eq!(fetcher().fetch(&url).await?.status, 20);
+            let fingerprint = fetch::server_fingerprint("localhost", server.port()).await?;
+            assert_eq!(
+                known_hosts.lock().unwrap().get("localhost"),
+                Some(fingerprint.as_str())
+            );
+            assert_eq!(fetcher().fetch(&url).await?.status, 20);
+            // A fetcher that already has a session with the host resumes it without seeing the
+            // certificate again, so this needs a new one.
+            known_hosts.lock().unwrap().trust("localhost", "abcd");
+            assert!(fetcher().fetch(&url).await.is_err());
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,78 @@
+//! Listening on a Unix socket, so that the server can sit behind a front process like relayd or
+//! stunnel that passes connections on to it. Connections on the socket speak TLS like those on
+//! the port; the front process only moves bytes.
+
+use crate::tls::Connection;
+use anyhow::{anyhow, Result};
+use futures::stream::BoxStream;
+use std::io;
+use std::path::Path;
+
+/// Parses permissions given in octal, like `660`.
+pub fn parse_mode(s: &str) -> Result<u32> {
+    u32::from_str_radix(s, 8).map_err(|_| anyhow!("expected permissions in octal, got {}", s))
+}
+
+/// A bound Unix socket. The socket file is removed when it's dropped.
+#[cfg(unix)]
+pub struct UnixSocket {
+    listener: async_std::os::unix::net::UnixListener,
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixSocket {
+    /// Binds a socket at the path with the given permissions. A socket left at the path by a
+    /// server that didn't shut down cleanly is replaced.
+    pub async fn bind(path: &Path, mode: u32) -> Result<Self> {
+        use anyhow::{bail, Context};
+        use async_std::os::unix::net::UnixListener;
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => bail!("{} exists and isn't a socket", path.display()),
+            Err(_) => {}
+        }
+        let listener = UnixListener::bind(path)
+            .await
+            .with_context(|| format!("failed to bind {}", path.display()))?;
+        let socket = Self {
+            listener,
+            path: path.to_owned(),
+        };
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        Ok(socket)
+    }
+
+    pub fn incoming(&self) -> BoxStream<'_, io::Result<Box<dyn Connection>>> {
+        use futures::StreamExt;
+
+        Box::pin(
+            self.listener
+                .incoming()
+                .map(|stream| stream.map(|stream| Box::new(stream) as Box<dyn Connection>)),
+        )
+    }
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(not(unix))]
+pub struct UnixSocket;
+
+#[cfg(not(unix))]
+impl UnixSocket {
+    pub async fn bind(_path: &Path, _mode: u32) -> Result<Self> {
+        anyhow::bail!("--unix-socket is only supported on Unix")
+    }
+
+    pub fn incoming(&self) -> BoxStream<'_, io::Result<Box<dyn Connection>>> {
+        Box::pin(futures::stream::empty())
+    }
+}
@@ -0,0 +1,264 @@
+//! Comparing two builds of a site, so that a CI job can stop a deploy that changes more than
+//! expected until someone has looked at it. Each side is summed up as a manifest: a line for every
+//! file with its SHA-256 hash, size, path and title, separated by tabs. A side can be given as a
+//! directory or as a manifest saved from an earlier run, so the old build needn't be kept around.
+//! Nothing in either directory is written to.
+
+use anyhow::{bail, Context, Result};
+use exarch::markgem;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct VerifyOpt {
+    /// The old build: a directory, or a manifest saved with --save-manifest.
+    #[structopt(parse(from_os_str))]
+    old: PathBuf,
+
+    /// The new build: a directory, or a manifest saved with --save-manifest.
+    #[structopt(parse(from_os_str))]
+    new: PathBuf,
+
+    /// Fail if more than this many pages were added, removed or changed.
+    #[structopt(long)]
+    max_changes: Option<usize>,
+
+    /// Write the new build's manifest to this file, to compare the next build against.
+    #[structopt(long, parse(from_os_str))]
+    save_manifest: Option<PathBuf>,
+}
+
+/// What's known about a file without keeping its contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct File {
+    hash: String,
+    size: u64,
+    title: Option<String>,
+}
+
+/// The files in a build, by their paths relative to its root, like `blog/post.md`.
+type Manifest = BTreeMap<String, File>;
+
+pub fn verify(options: VerifyOpt) -> Result<()> {
+    let old = load(&options.old)?;
+    let new = load(&options.new)?;
+    if let Some(path) = &options.save_manifest {
+        std::fs::write(path, format_manifest(&new))
+            .with_context(|| format!("failed to write {}", path.display()))?;
+    }
+    let (report, changes) = compare(&old, &new);
+    print!("{}", report);
+    match options.max_changes {
+        Some(max_changes) if changes > max_changes => bail!(
+            "{} pages changed, more than --max-changes {}",
+            changes,
+            max_changes
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn load(path: &Path) -> Result<Manifest> {
+    if path.is_dir() {
+        let mut manifest = Manifest::new();
+        collect(path, path, &mut manifest)?;
+        Ok(manifest)
+    } else {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        parse_manifest(&text).with_context(|| format!("failed to parse {}", path.display()))
+    }
+}
+
+fn collect(root: &Path, dir: &Path, manifest: &mut Manifest) -> Result<()> {
+    let entries =
+        std::fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect(root, &path, manifest)?;
+            continue;
+        }
+        let contents =
+            std::fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        let name = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        manifest.insert(name, File::new(&contents));
+    }
+    Ok(())
+}
+
+impl File {
+    fn new(contents: &[u8]) -> Self {
+        let hash = ring::digest::digest(&ring::digest::SHA256, contents)
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        Self {
+            hash,
+            size: contents.len() as u64,
+            title: std::str::from_utf8(contents).ok().and_then(title),
+        }
+    }
+}
+
+/// The title from a page's front matter, or else its first top-level heading, which is written
+/// the same way in Markdown and Gemtext.
+fn title(text: &str) -> Option<String> {
+    let title = match markgem::front_matter_value(text, "title") {
+        Some(title) => title,
+        None => markgem::strip_matter(text)
+            .lines()
+            .find_map(|line| line.strip_prefix("# "))?,
+    };
+    let title = title.trim().replace(&['\t', '\n', '\r'][..], " ");
+    if title.is_empty() {
+        None
+    } else {
+        Some(title)
+    }
+}
+
+fn format_manifest(manifest: &Manifest) -> String {
+    let mut out = String::new();
+    for (path, file) in manifest {
+        let _ = writeln!(
+            out,
+            "{}\t{}\t{}\t{}",
+            file.hash,
+            file.size,
+            path,
+            file.title.as_deref().unwrap_or("")
+        );
+    }
+    out
+}
+
+fn parse_manifest(text: &str) -> Result<Manifest> {
+    let mut manifest = Manifest::new();
+    for (number, line) in text.lines().enumerate() {
+        let fields: Vec<_> = line.splitn(4, '\t').collect();
+        let (hash, size, path, title) = match fields[..] {
+            [hash, size, path, title] => (hash, size, path, title),
+            _ => bail!("line {} doesn't have four fields", number + 1),
+        };
+        let size = size
+            .parse()
+            .with_context(|| format!("line {} has a bad size", number + 1))?;
+        let file = File {
+            hash: hash.to_owned(),
+            size,
+            title: Some(title.to_owned()).filter(|title| !title.is_empty()),
+        };
+        manifest.insert(path.to_owned(), file);
+    }
+    Ok(manifest)
+}
+
+/// Lists what was added, removed and changed, followed by a summary, and counts the changes.
+fn compare(old: &Manifest, new: &Manifest) -> (String, usize) {
+    let mut out = String::new();
+    let (mut added, mut removed, mut changed, mut unchanged) = (0, 0, 0, 0);
+    let describe = |path: &str, file: &File| match &file.title {
+        Some(title) => format!("{} \"{}\"", path, title),
+        None => path.to_owned(),
+    };
+    for (path, file) in old {
+        if !new.contains_key(path) {
+            removed += 1;
+            let _ = writeln!(out, "removed {}", describe(path, file));
+        }
+    }
+    for (path, file) in new {
+        match old.get(path) {
+            None => {
+                added += 1;
+                let _ = writeln!(
+                    out,
+                    "added   {} ({} bytes)",
+                    describe(path, file),
+                    file.size
+                );
+            }
+            Some(old_file) if old_file.hash != file.hash => {
+                changed += 1;
+                let mut summary = format!("{:+} bytes", file.size as i64 - old_file.size as i64);
+                if old_file.title != file.title {
+                    let _ = write!(
+                        summary,
+                        ", retitled from \"{}\"",
+                        old_file.title.as_deref().unwrap_or("")
+                    );
+                }
+                let _ = writeln!(out, "changed {} ({})", describe(path, file), summary);
+            }
+            Some(_) => unchanged += 1,
+        }
+    }
+    let _ = writeln!(
+        out,
+        "{} added, {} removed, {} changed, {} unchanged",
+        added, removed, changed, unchanged
+    );
+    (out, added + removed + changed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn titles() {
+        assert_eq!(
+            title("+++\ntitle = \"Front\"\n+++\n# Heading"),
+            Some("Front".to_owned())
+        );
+        assert_eq!(
+            title("intro\n# Heading\n# Other"),
+            Some("Heading".to_owned())
+        );
+        assert_eq!(title("## Subheading"), None);
+    }
+
+    #[test]
+    fn compares() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("exarch-verify-{}", std::process::id()));
+        let (old_dir, new_dir) = (dir.join("old"), dir.join("new"));
+        std::fs::create_dir_all(old_dir.join("blog"))?;
+        std::fs::create_dir_all(new_dir.join("blog"))?;
+        std::fs::write(old_dir.join("index.md"), "# Home")?;
+        std::fs::write(new_dir.join("index.md"), "# Home")?;
+        std::fs::write(old_dir.join("blog/post.md"), "# Post\nold")?;
+        std::fs::write(new_dir.join("blog/post.md"), "# New post\nnewer")?;
+        std::fs::write(old_dir.join("gone.md"), "gone")?;
+        std::fs::write(new_dir.join("blog/next.md"), "# Next")?;
+
+        let old = load(&old_dir)?;
+        // Going through a saved manifest loses nothing.
+        std::fs::write(dir.join("manifest"), format_manifest(&old))?;
+        assert_eq!(load(&dir.join("manifest"))?, old);
+        let (report, changes) = compare(&old, &load(&new_dir)?);
+        assert_eq!(
+            report,
+            indoc!(
+                "
+                removed gone.md
+                added   blog/next.md \"Next\" (6 bytes)
+                changed blog/post.md \"New post\" (+6 bytes, retitled from \"Post\")
+                1 added, 1 removed, 1 changed, 1 unchanged
+                "
+            )
+        );
+        assert_eq!(changes, 3);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
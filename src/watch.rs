@@ -0,0 +1,221 @@
+//! Watching directory trees for changes, so that cached pages can be dropped as their files
+//! change instead of looking at the files on every request. It uses inotify, which only Linux has,
+//! with a watch on every directory in the trees, including the ones made while they're watched.
+
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Weak;
+
+/// Watches the trees under the directories in the background for files and directories being
+/// created, written, moved or removed, calling `changed` with the target and the path of each, or
+/// with `None` when so much changed at once that some of it was missed. It stops once the target
+/// has been dropped.
+#[cfg(target_os = "linux")]
+pub fn watch<T: Send + Sync + 'static>(
+    dirs: &[PathBuf],
+    target: Weak<T>,
+    changed: impl Fn(&T, Option<&Path>) + Send + 'static,
+) -> Result<()> {
+    use anyhow::Context;
+    use log::error;
+
+    let mut inotify = inotify::Inotify::new()?;
+    for dir in dirs {
+        inotify
+            .add_tree(dir)
+            .with_context(|| format!("failed to watch {}", dir.display()))?;
+    }
+    std::thread::spawn(move || loop {
+        let changes = match inotify.wait(inotify::POLL_INTERVAL) {
+            Ok(changes) => changes,
+            Err(e) => {
+                error!("Stopped watching for changes: {}", e);
+                return;
+            }
+        };
+        let target = match target.upgrade() {
+            Some(target) => target,
+            None => return,
+        };
+        for path in changes {
+            changed(&target, path.as_deref());
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn watch<T: Send + Sync + 'static>(
+    _dirs: &[PathBuf],
+    _target: Weak<T>,
+    _changed: impl Fn(&T, Option<&Path>) + Send + 'static,
+) -> Result<()> {
+    anyhow::bail!("watching for changes needs inotify, which only Linux has")
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use log::warn;
+    use std::collections::HashMap;
+    use std::convert::TryInto;
+    use std::ffi::{CString, OsStr};
+    use std::fs::File;
+    use std::io::{self, Read};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    /// How often the watching thread looks at whether it should stop.
+    pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    /// The events that change what's in a directory.
+    const EVENTS: u32 = libc::IN_ATTRIB
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_DELETE_SELF
+        | libc::IN_MODIFY
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_MOVE_SELF
+        | libc::IN_ONLYDIR;
+
+    /// The size of an event without its name: its watch, mask, cookie and the name's length.
+    const HEADER: usize = 16;
+
+    pub struct Inotify {
+        file: File,
+        // The directory each watch is on.
+        dirs: HashMap<i32, PathBuf>,
+    }
+
+    impl Inotify {
+        pub fn new() -> io::Result<Self> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self {
+                file: unsafe { File::from_raw_fd(fd) },
+                dirs: HashMap::new(),
+            })
+        }
+
+        /// Watches the directory and every one under it.
+        pub fn add_tree(&mut self, dir: &Path) -> io::Result<()> {
+            let path = CString::new(dir.as_os_str().as_bytes())?;
+            let wd =
+                unsafe { libc::inotify_add_watch(self.file.as_raw_fd(), path.as_ptr(), EVENTS) };
+            if wd == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            self.dirs.insert(wd, dir.to_owned());
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    self.add_tree(&entry.path())?;
+                }
+            }
+            Ok(())
+        }
+
+        /// Waits up to the timeout for changes, returning the paths that changed, with `None`
+        /// where changes were missed.
+        pub fn wait(&mut self, timeout: Duration) -> io::Result<Vec<Option<PathBuf>>> {
+            let mut poll = libc::pollfd {
+                fd: self.file.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            if unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as i32) } == -1 {
+                let error = io::Error::last_os_error();
+                return match error.kind() {
+                    io::ErrorKind::Interrupted => Ok(vec![]),
+                    _ => Err(error),
+                };
+            }
+            let mut changes = vec![];
+            let mut buf = [0; 4096];
+            loop {
+                let len = match self.file.read(&mut buf) {
+                    Ok(0) => return Ok(changes),
+                    Ok(len) => len,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(changes),
+                    Err(e) => return Err(e),
+                };
+                let mut events = &buf[..len];
+                while events.len() >= HEADER {
+                    let field =
+                        |n: usize| u32::from_ne_bytes(events[n * 4..n * 4 + 4].try_into().unwrap());
+                    let (wd, mask) = (field(0) as i32, field(1));
+                    let end = (HEADER + field(3) as usize).min(events.len());
+                    // The name is padded with zeroes.
+                    let name = events[HEADER..end].split(|&b| b == 0).next();
+                    events = &events[end..];
+                    if mask & libc::IN_Q_OVERFLOW != 0 {
+                        changes.push(None);
+                        continue;
+                    }
+                    if mask & libc::IN_IGNORED != 0 {
+                        self.dirs.remove(&wd);
+                        continue;
+                    }
+                    let path = match (self.dirs.get(&wd), name) {
+                        (Some(dir), Some(name)) if !name.is_empty() => {
+                            dir.join(OsStr::from_bytes(name))
+                        }
+                        (Some(dir), _) => dir.clone(),
+                        (None, _) => continue,
+                    };
+                    let created = libc::IN_CREATE | libc::IN_MOVED_TO;
+                    if mask & libc::IN_ISDIR != 0 && mask & created != 0 {
+                        // Whatever was put in it before it's watched is covered by its own path.
+                        if let Err(e) = self.add_tree(&path) {
+                            warn!("Failed to watch {}: {}", path.display(), e);
+                        }
+                    }
+                    changes.push(Some(path));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn changes() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("exarch-watch-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("a"))?;
+        let changes = Arc::new(Mutex::new(vec![]));
+        watch(
+            std::slice::from_ref(&dir),
+            Arc::downgrade(&changes),
+            |changes, path: Option<&Path>| {
+                changes.lock().unwrap().push(path.map(Path::to_owned));
+            },
+        )?;
+        let saw = |path: PathBuf| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !changes.lock().unwrap().contains(&Some(path.clone())) {
+                assert!(Instant::now() < deadline, "missed {}", path.display());
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+        std::fs::write(dir.join("a/page.md"), "hi")?;
+        saw(dir.join("a/page.md"));
+        // Directories made after it started are watched too.
+        std::fs::create_dir(dir.join("b"))?;
+        saw(dir.join("b"));
+        std::fs::write(dir.join("b/page.md"), "hi")?;
+        saw(dir.join("b/page.md"));
+        std::fs::remove_file(dir.join("a/page.md"))?;
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}